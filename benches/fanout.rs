@@ -0,0 +1,68 @@
+//! Compares relay fan-out with one `send_to` per recipient against batched `sendmmsg`.
+//!
+//! Run with `cargo bench --features mmsg`.
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use physync::mmsg::send_batch;
+use physync::{BufferPool, OutgoingPacketBuilder};
+
+const PAYLOAD_SIZE: usize = 128;
+
+fn recipients(count: usize) -> (Vec<UdpSocket>, Vec<SocketAddr>) {
+    let sockets = (0..count)
+        .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+        .collect::<Vec<_>>();
+    let addrs = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+    (sockets, addrs)
+}
+
+fn fanout(c: &mut Criterion) {
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sender.set_nonblocking(true).unwrap();
+
+    let mut group = c.benchmark_group("fanout");
+    for &count in &[8_usize, 32, 64] {
+        // keep the receiving sockets alive so the sends are not refused
+        let (_sockets, addrs) = recipients(count);
+        let mut pool = BufferPool::default();
+        let payload = Bytes::from(vec![0xab; PAYLOAD_SIZE]);
+        let packets = addrs
+            .iter()
+            .map(|addr| {
+                let out = OutgoingPacketBuilder::shared(payload.clone(), pool.acquire(8))
+                    .with_session_header(1)
+                    .build()
+                    .unwrap();
+                (*addr, out)
+            })
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::new("send_to", count), &packets, |b, packets| {
+            b.iter(|| {
+                for (addr, p) in packets {
+                    let _ = sender.send_to(&p.contents(), addr);
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("sendmmsg", count), &packets, |b, packets| {
+            b.iter(|| {
+                let mut sent = 0;
+                while sent < packets.len() {
+                    match send_batch(sender.as_raw_fd(), &packets[sent..]) {
+                        Ok(n) => sent += n,
+                        Err(_) => break,
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
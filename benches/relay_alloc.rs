@@ -0,0 +1,94 @@
+//! Measures allocations and time spent preparing one relayed payload for many recipients.
+//!
+//! `copied` builds every packet the way the relay used to, copying the payload into each one and
+//! concatenating it with its header. `shared` writes headers into pooled buffers and shares the
+//! reference counted payload.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use physync::{BufferPool, OutgoingPacketBuilder, MAX_HEADER_SIZE};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const PAYLOAD_SIZE: usize = 256;
+
+fn copied(payload: &[u8], recipients: u64) -> usize {
+    let mut total = 0;
+    for session in 0..recipients {
+        let out = OutgoingPacketBuilder::new(payload)
+            .with_session_header(session)
+            .build()
+            .unwrap();
+        total += out.contents().len();
+    }
+    total
+}
+
+fn shared(pool: &mut BufferPool, payload: &Bytes, recipients: u64) -> usize {
+    let mut total = 0;
+    for session in 0..recipients {
+        let out = OutgoingPacketBuilder::shared(payload.clone(), pool.acquire(MAX_HEADER_SIZE))
+            .with_session_header(session)
+            .build()
+            .unwrap();
+        total += out.len();
+    }
+    total
+}
+
+fn count_allocations<F: FnMut()>(mut f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn relay(c: &mut Criterion) {
+    let payload = Bytes::from(vec![0xab; PAYLOAD_SIZE]);
+    let mut pool = BufferPool::default();
+
+    let mut group = c.benchmark_group("relay_alloc");
+    for &recipients in &[8_u64, 64] {
+        // warm up the pool so the steady state is measured
+        shared(&mut pool, &payload, recipients);
+        println!(
+            "relay_alloc/{}: copied {} allocations, shared {} allocations",
+            recipients,
+            count_allocations(|| {
+                copied(&payload, recipients);
+            }),
+            count_allocations(|| {
+                shared(&mut pool, &payload, recipients);
+            }),
+        );
+
+        group.bench_with_input(BenchmarkId::new("copied", recipients), &recipients, |b, &n| {
+            b.iter(|| copied(&payload, n))
+        });
+        group.bench_with_input(BenchmarkId::new("shared", recipients), &recipients, |b, &n| {
+            b.iter(|| shared(&mut pool, &payload, n))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, relay);
+criterion_main!(benches);
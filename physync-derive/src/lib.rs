@@ -0,0 +1,258 @@
+//! `#[derive(NetSerialize)]` for the bit packed serialization of physync.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, TokenStream as TokenStream2};
+use quote::{format_ident, quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{
+    parenthesized, parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Expr,
+    Fields, Ident, Token, Type,
+};
+
+/// Implements `physync::serialize::NetSerialize` for a struct or an enum.
+///
+/// Fields are written in declaration order. Enums first write the index of the variant, in the
+/// bits needed for the number of variants, followed by the fields of the variant.
+///
+/// Fields without an attribute use their own `NetSerialize` impl, a `#[net(...)]` attribute
+/// selects a compact encoding:
+/// - `range(min, max)` writes an integer in the bits needed for `min..=max`, values outside
+///   of it fail to decode.
+/// - `bits = n` writes the lowest `n` bits of an unsigned integer.
+/// - `quantize(min, max, bits)` writes an `f32` quantized to `bits` bits over `min..=max`.
+/// - `skip` does not write the field, it is `Default::default()` when read.
+#[proc_macro_derive(NetSerialize, attributes(net))]
+pub fn derive_net_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|error| error.to_compile_error())
+        .into()
+}
+
+/// How a field is written, with the expressions of the attribute.
+enum Encoding {
+    Nested,
+    Range(TokenStream2, TokenStream2),
+    Bits(TokenStream2),
+    Quantize(TokenStream2, TokenStream2, TokenStream2),
+    Skip,
+}
+
+impl Parse for Encoding {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        if name == "skip" {
+            return Ok(Encoding::Skip);
+        }
+        if name == "bits" {
+            input.parse::<Token![=]>()?;
+            return Ok(Encoding::Bits(input.parse::<Expr>()?.into_token_stream()));
+        }
+
+        if name != "range" && name != "quantize" {
+            return Err(Error::new(
+                name.span(),
+                "expected `range`, `bits`, `quantize` or `skip`",
+            ));
+        }
+        let content;
+        parenthesized!(content in input);
+        let args = Punctuated::<Expr, Token![,]>::parse_terminated(&content)?;
+        let mut args = args.into_iter().map(ToTokens::into_token_stream);
+        match (args.next(), args.next(), args.next(), args.next()) {
+            (Some(min), Some(max), None, None) if name == "range" => Ok(Encoding::Range(min, max)),
+            (Some(min), Some(max), Some(bits), None) if name == "quantize" => {
+                Ok(Encoding::Quantize(min, max, bits))
+            }
+            _ if name == "range" => Err(Error::new(name.span(), "expected `range(min, max)`")),
+            _ => Err(Error::new(
+                name.span(),
+                "expected `quantize(min, max, bits)`",
+            )),
+        }
+    }
+}
+
+impl Encoding {
+    fn from_attrs(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut encoding = None;
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("net")) {
+            if encoding.is_some() {
+                return Err(Error::new_spanned(attr, "a field takes one `net` attribute"));
+            }
+            encoding = Some(attr.parse_args()?);
+        }
+        Ok(encoding.unwrap_or(Encoding::Nested))
+    }
+
+    /// Writes the field referenced by `binding`.
+    fn write(&self, binding: &Ident) -> TokenStream2 {
+        match self {
+            Encoding::Nested => {
+                quote!(::physync::serialize::NetSerialize::serialize(#binding, writer);)
+            }
+            Encoding::Range(min, max) => {
+                quote!(writer.write_ranged(*#binding as i64, (#min) as i64, (#max) as i64);)
+            }
+            Encoding::Bits(bits) => quote!(writer.write_bits(*#binding as u64, #bits);),
+            Encoding::Quantize(min, max, bits) => quote! {
+                writer.write_quantized(
+                    *#binding,
+                    &::physync::snapshot::QuantizedRange::new(#min, #max, #bits),
+                );
+            },
+            Encoding::Skip => TokenStream2::new(),
+        }
+    }
+
+    /// Reads a field of type `ty`.
+    fn read(&self, ty: &Type) -> TokenStream2 {
+        match self {
+            Encoding::Nested => {
+                quote!(<#ty as ::physync::serialize::NetSerialize>::deserialize(reader)?)
+            }
+            Encoding::Range(min, max) => {
+                quote!(reader.read_ranged((#min) as i64, (#max) as i64)? as #ty)
+            }
+            Encoding::Bits(bits) => quote!(reader.read_bits(#bits)? as #ty),
+            Encoding::Quantize(min, max, bits) => quote! {
+                reader.read_quantized(&::physync::snapshot::QuantizedRange::new(#min, #max, #bits))?
+            },
+            Encoding::Skip => quote!(::std::default::Default::default()),
+        }
+    }
+}
+
+/// Code for the fields of a struct or an enum variant.
+struct FieldsCode {
+    /// Pattern binding the fields to `__field0`, `__field1`, ...
+    pattern: TokenStream2,
+    write: TokenStream2,
+    /// Fields read in declaration order, to follow the path of the struct or the variant.
+    read: TokenStream2,
+}
+
+fn fields_code(fields: &Fields) -> syn::Result<FieldsCode> {
+    let mut patterns = Vec::new();
+    let mut writes = Vec::new();
+    let mut reads = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let encoding = Encoding::from_attrs(&field.attrs)?;
+        let binding = format_ident!("__field{}", i);
+        let bound = match encoding {
+            Encoding::Skip => quote!(_),
+            _ => quote!(#binding),
+        };
+        match &field.ident {
+            Some(name) => patterns.push(quote!(#name: #bound)),
+            None => patterns.push(bound),
+        }
+        writes.push(encoding.write(&binding));
+        let read = encoding.read(&field.ty);
+        match &field.ident {
+            Some(name) => reads.push(quote!(#name: #read)),
+            None => reads.push(read),
+        }
+    }
+
+    Ok(match fields {
+        Fields::Named(_) => FieldsCode {
+            pattern: quote!({ #(#patterns),* }),
+            write: quote!(#(#writes)*),
+            read: quote!({ #(#reads),* }),
+        },
+        Fields::Unnamed(_) => FieldsCode {
+            pattern: quote!(( #(#patterns),* )),
+            write: quote!(#(#writes)*),
+            read: quote!(( #(#reads),* )),
+        },
+        Fields::Unit => FieldsCode {
+            pattern: TokenStream2::new(),
+            write: TokenStream2::new(),
+            read: TokenStream2::new(),
+        },
+    })
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let (write, read) = match &input.data {
+        Data::Struct(data) => {
+            let FieldsCode { pattern, write, read } = fields_code(&data.fields)?;
+            (
+                quote! {
+                    let Self #pattern = self;
+                    #write
+                },
+                quote!(Ok(Self #read)),
+            )
+        }
+        Data::Enum(data) => {
+            if data.variants.is_empty() {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "cannot serialize an enum without variants",
+                ));
+            }
+            let last = Literal::i64_unsuffixed(data.variants.len() as i64 - 1);
+            let mut writes = Vec::new();
+            let mut reads = Vec::new();
+            for (i, variant) in data.variants.iter().enumerate() {
+                let name = &variant.ident;
+                let index = Literal::i64_unsuffixed(i as i64);
+                let FieldsCode { pattern, write, read } = fields_code(&variant.fields)?;
+                writes.push(quote! {
+                    Self::#name #pattern => {
+                        writer.write_ranged(#index, 0, #last);
+                        #write
+                    }
+                });
+                reads.push(quote!(#index => Ok(Self::#name #read),));
+            }
+            (
+                quote! {
+                    match self {
+                        #(#writes)*
+                    }
+                },
+                quote! {
+                    match reader.read_ranged(0, #last)? {
+                        #(#reads)*
+                        _ => unreachable!("read_ranged checks the variant index"),
+                    }
+                },
+            )
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "cannot serialize a union",
+            ))
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::physync::serialize::NetSerialize));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::physync::serialize::NetSerialize for #name #ty_generics #where_clause {
+            fn serialize<__B: ::physync::serialize::BufMut>(
+                &self,
+                writer: &mut ::physync::serialize::BitWriter<__B>,
+            ) {
+                #write
+            }
+
+            fn deserialize(
+                reader: &mut ::physync::serialize::BitReader<'_>,
+            ) -> ::physync::Result<Self> {
+                #read
+            }
+        }
+    })
+}
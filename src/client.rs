@@ -1,109 +1,1668 @@
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use log::debug;
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::Bytes;
+use log::{debug, error};
 use rand::{random, Rng, thread_rng};
 use tokio::net::udp::{RecvHalf, SendHalf};
 use tokio::net::UdpSocket;
-use tokio::time::interval_at;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::time::timeout;
 
-use crate::{OutgoingPacketBuilder, Packet};
-use crate::errors::Result;
-use crate::net::constants::{CONNECT_PAYLOAD_SIZE, DEFAULT_MTU};
+use crate::{OutgoingPacket, OutgoingPacketBuilder, Packet};
+use crate::errors::{ErrorKind, Result};
+use crate::features::interest::interest_payload;
+use crate::features::introducer::{punch_payload, read_introduction, read_punch, register_payload};
+use crate::features::time_sync::{ping_payload, read_pong};
+use crate::features::negotiation::{put_offer, read_accept, read_reject};
+use crate::features::{AreaOfInterest, Capabilities, ClockSync, HolePunch, Route, TickScheduler};
+use crate::net::constants::{
+    CONNECT_ATTEMPTS, CONNECT_INTERVAL, CONNECT_PAYLOAD_SIZE, DEFAULT_TICK_RATE, INTEREST_INTERVAL,
+    MAX_HEADER_SIZE, MAX_MTU, MIN_MTU, REGISTER_INTERVAL, TIME_SYNC_BURST, TIME_SYNC_INTERVAL,
+};
+use crate::net::{bind_udp, resolve, select_remote, select_remotes, unspecified_for};
 use crate::packet::{PacketReader, PacketType};
+use crate::protocol_version::ProtocolVersion;
+use crate::input::{read_input_ack, Input, InputHistory};
+use crate::message::{Dispatcher, Message, MessageBatch, MessageQueue};
+use crate::replication::{
+    decode_authority, encode_authority, ReplicatedWorld, ReplicationDecoder, ReplicationEncoder,
+};
+use crate::rollback::RollbackSession;
+use crate::snapshot::{DeltaDecoder, InterpolationBuffer, RigidBodyState, SnapshotConfig};
+
+/// Last input tick processed by the server and the states of a snapshot.
+type ReceivedSnapshot = (Option<u32>, Vec<RigidBodyState>);
+
+/// Acknowledgement the reading task hands to the sending task.
+#[derive(Debug)]
+enum Ack {
+    /// Tick of a received snapshot.
+    Snapshot(u32),
+    /// Size of a received MTU probe.
+    MtuProbe(usize),
+}
 
 pub struct Client {
     socket: UdpSocket,
+    /// Address of the server, the first candidate that answered once connected.
     remote: SocketAddr,
+    /// Addresses of the server the connect request is sent to in turn.
+    candidates: Vec<SocketAddr>,
     id: u64,
     buf: Vec<u8>,
     session: u64,
+    /// Optional features offered to the server, the agreed ones once connected.
+    capabilities: Capabilities,
+    ticks: TickScheduler,
+    snapshot_config: SnapshotConfig,
+    shared: Shared,
+}
+
+/// State shared between a [Client] and the tasks it runs.
+#[derive(Clone, Debug)]
+struct Shared {
+    clock: ServerClock,
+    world: InterpolatedWorld,
+    inputs: InputChannel,
+    rollback: Arc<Mutex<RollbackSession>>,
+    replica: Replica,
+    interest: InterestChannel,
+    messages: MessageChannel,
+    link: PeerLink,
+}
+
+/// Estimate of the server clock, shared with the tasks of a running [Client].
+///
+/// The server clock counts from the server's tick 0, the client clock from the creation of the
+/// client. The estimate is refined by time sync exchanges while connecting and every
+/// [TIME_SYNC_INTERVAL](../net/constants/constant.TIME_SYNC_INTERVAL.html) after.
+#[derive(Clone, Debug)]
+pub struct ServerClock {
+    epoch: Instant,
+    sync: Arc<Mutex<ClockSync>>,
+    /// Tick period of the server, sent with every time sync answer.
+    period: Arc<Mutex<Option<Duration>>>,
+}
+
+impl ServerClock {
+    fn new(epoch: Instant) -> Self {
+        ServerClock {
+            epoch,
+            sync: Default::default(),
+            period: Default::default(),
+        }
+    }
+
+    /// Checks if at least one time sync exchange completed.
+    pub fn is_synchronized(&self) -> bool {
+        self.sync.lock().unwrap().is_synchronized()
+    }
+
+    /// Returns how far the server clock is ahead of the client clock at `time`, in
+    /// microseconds.
+    pub fn offset(&self, time: Instant) -> Option<f64> {
+        self.sync.lock().unwrap().offset_at(self.local_time(time))
+    }
+
+    /// Returns how much faster the server clock runs, in seconds per second.
+    pub fn drift(&self) -> f64 {
+        self.sync.lock().unwrap().drift()
+    }
+
+    /// Returns the shortest recent round trip time to the server.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.sync.lock().unwrap().rtt()
+    }
+
+    /// Returns the tick period of the server, once a time sync exchange completed.
+    pub fn tick_period(&self) -> Option<Duration> {
+        *self.period.lock().unwrap()
+    }
+
+    /// Returns the estimated time passed on the server since its tick 0 at local `time`.
+    pub fn server_time(&self, time: Instant) -> Option<Duration> {
+        self.sync
+            .lock()
+            .unwrap()
+            .to_remote(self.local_time(time))
+            .map(Duration::from_micros)
+    }
+
+    fn local_time(&self, time: Instant) -> u64 {
+        time.saturating_duration_since(self.epoch).as_micros() as u64
+    }
+
+    /// Adds the exchange answered by a `TimePong` `payload` received at `time`.
+    fn add_pong(&self, payload: &[u8], time: Instant) -> Result<()> {
+        let (sample, period) = read_pong(payload, self.local_time(time))?;
+        self.sync.lock().unwrap().add_sample(sample);
+        *self.period.lock().unwrap() = Some(period);
+        Ok(())
+    }
+
+    /// Creates a `TimePing` packet sent at `time`.
+    fn ping(&self, session: u64, tick: u32, time: Instant) -> Result<OutgoingPacket> {
+        OutgoingPacketBuilder::new(&ping_payload(self.local_time(time)))
+            .with_default_header(PacketType::TimePing)
+            .with_session_header(session)
+            .with_tick_header(tick)
+            .build()
+    }
+}
+
+/// Snapshots received by a running [Client], played back behind the server clock.
+#[derive(Clone, Debug)]
+pub struct InterpolatedWorld {
+    clock: ServerClock,
+    buffer: Arc<Mutex<InterpolationBuffer>>,
+}
+
+impl InterpolatedWorld {
+    /// Returns the interpolated state of all bodies at local `time`, call once per frame.
+    ///
+    /// The world is empty until the server clock is synchronized.
+    pub fn sample(&self, time: Instant) -> Vec<RigidBodyState> {
+        match self.clock.server_time(time) {
+            Some(now) => self.buffer.lock().unwrap().sample(now),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns how far playback lags behind the server.
+    pub fn delay(&self) -> Duration {
+        self.buffer.lock().unwrap().delay()
+    }
+
+    /// Stores the snapshot `states` taken at server time `time`.
+    fn push(&self, time: Duration, states: Vec<RigidBodyState>) {
+        self.buffer.lock().unwrap().push(time, states);
+    }
+}
+
+/// Inputs of a running [Client], repeated to the server until it processed them.
+#[derive(Clone, Debug, Default)]
+pub struct InputChannel {
+    history: Arc<Mutex<InputHistory>>,
+}
+
+impl InputChannel {
+    /// Queues the input of `tick`, inputs have to be pushed in tick order.
+    ///
+    /// Fails when the input is larger than
+    /// [MAX_INPUT_SIZE](../net/constants/constant.MAX_INPUT_SIZE.html).
+    pub fn push(&self, tick: u32, payload: Bytes) -> Result<()> {
+        self.history.lock().unwrap().push(tick, payload)
+    }
+
+    /// Returns the last input tick the server processed.
+    pub fn acked(&self) -> Option<u32> {
+        self.history.lock().unwrap().acked()
+    }
+
+    /// Returns the inputs the server has not processed yet, oldest first.
+    ///
+    /// Replaying them on top of the latest snapshot predicts the state of the bodies they
+    /// control.
+    pub fn unacked(&self) -> Vec<Input> {
+        self.history.lock().unwrap().unacked().cloned().collect()
+    }
+
+    fn ack(&self, tick: u32) {
+        self.history.lock().unwrap().ack(tick);
+    }
+
+    fn payload(&self) -> Option<Bytes> {
+        self.history.lock().unwrap().payload()
+    }
+}
+
+/// Area of interest of a running [Client], reported to the server.
+#[derive(Clone, Debug, Default)]
+pub struct InterestChannel {
+    state: Arc<Mutex<(Option<AreaOfInterest>, Option<Instant>)>>,
+}
+
+impl InterestChannel {
+    /// Restricts the updates the server sends to `area`, it is reported with the next tick.
+    pub fn set(&self, area: AreaOfInterest) {
+        *self.state.lock().unwrap() = (Some(area), None);
+    }
+
+    pub fn get(&self) -> Option<AreaOfInterest> {
+        self.state.lock().unwrap().0
+    }
+
+    /// Returns the area to report at `time`, when it changed or was last reported
+    /// [INTEREST_INTERVAL] ago.
+    fn due(&self, time: Instant) -> Option<AreaOfInterest> {
+        let mut state = self.state.lock().unwrap();
+        let (area, reported) = &mut *state;
+        let area = (*area)?;
+        if matches!(reported, Some(reported) if time < *reported + INTEREST_INTERVAL) {
+            return None;
+        }
+        *reported = Some(time);
+        Some(area)
+    }
+}
+
+/// Typed messages exchanged by a running [Client] with the server.
+///
+/// Messages sent within a tick are coalesced into as few packets as possible and sent when the
+/// next tick starts, [flush](#method.flush) sends them right away.
+#[derive(Clone, Debug, Default)]
+pub struct MessageChannel {
+    dispatcher: Arc<Mutex<Dispatcher>>,
+    outgoing: Arc<Mutex<MessageQueue>>,
+    flushed: Arc<Notify>,
+}
+
+impl MessageChannel {
+    /// Calls `handler` with every message of type `M` received from the server.
+    pub fn register<M, F>(&self, handler: F)
+    where
+        M: Message,
+        F: FnMut(SocketAddr, M) + Send + 'static,
+    {
+        self.dispatcher.lock().unwrap().register(handler);
+    }
+
+    /// Queues the messages of `batch` in a packet of their own, it is sent with the next tick.
+    pub fn send(&self, batch: MessageBatch) {
+        self.outgoing.lock().unwrap().push_batch(batch);
+    }
+
+    /// Queues a single message, it is sent with the next tick together with the other messages
+    /// queued until then.
+    ///
+    /// Fails when the message does not fit into a packet.
+    pub fn send_message<M: Message>(&self, message: &M) -> Result<()> {
+        self.outgoing.lock().unwrap().push(message)
+    }
+
+    /// Sends the queued messages without waiting for the next tick.
+    pub fn flush(&self) {
+        self.flushed.notify();
+    }
+
+    fn dispatch(&self, from: SocketAddr, payload: &[u8]) -> Result<usize> {
+        self.dispatcher.lock().unwrap().dispatch(from, payload)
+    }
+
+    /// Takes the payloads of the queued batches.
+    fn take(&self) -> Vec<Bytes> {
+        self.outgoing.lock().unwrap().take()
+    }
+}
+
+/// Direct path of a running [Client] to the client the server introduced it to.
+///
+/// Rollback inputs go straight to the introduced client once hole punching opened the path
+/// and nobody else plays in the match, through the server before and when punching fails.
+/// The server gets them in any case, it keeps track of the frame of every client.
+#[derive(Clone, Debug)]
+pub struct PeerLink {
+    state: Arc<Mutex<LinkState>>,
+}
+
+#[derive(Debug)]
+struct LinkState {
+    /// Address the client is bound to.
+    local: SocketAddr,
+    /// Key the client registers with at the introducer.
+    key: Option<u64>,
+    registered: Option<Instant>,
+    punch: Option<HolePunch>,
+}
+
+impl PeerLink {
+    fn new(local: SocketAddr) -> Self {
+        PeerLink {
+            state: Arc::new(Mutex::new(LinkState {
+                local,
+                key: None,
+                registered: None,
+                punch: None,
+            })),
+        }
+    }
+
+    /// Returns the id of the introduced client, `None` before the introduction.
+    pub fn peer_id(&self) -> Option<u64> {
+        self.state.lock().unwrap().punch.as_ref().map(HolePunch::peer_id)
+    }
+
+    /// Returns where packets to the introduced client go, `None` before the introduction.
+    pub fn route(&self) -> Option<Route> {
+        self.state.lock().unwrap().punch.as_ref().map(HolePunch::route)
+    }
+
+    fn set_key(&self, key: u64) {
+        self.state.lock().unwrap().key = Some(key);
+    }
+
+    /// Returns the key to register at `time`, it is repeated every [REGISTER_INTERVAL] until
+    /// the introduction arrives.
+    fn registration_due(&self, time: Instant) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let key = state.key.filter(|_| state.punch.is_none())?;
+        if matches!(state.registered, Some(registered) if time < registered + REGISTER_INTERVAL) {
+            return None;
+        }
+        state.registered = Some(time);
+        Some(key)
+    }
+
+    /// Starts hole punching towards the client with `peer_id` at `endpoint`, repeated
+    /// introductions are ignored.
+    fn introduce(&self, peer_id: u64, endpoint: SocketAddr, time: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.key.is_none() || state.punch.is_some() {
+            return;
+        }
+        // a dual-stack socket reaches IPv4 clients at their IPv4-mapped address
+        let reachable = select_remote(&state.local, &[endpoint]);
+        state.punch = Some(HolePunch::new(peer_id, reachable, time));
+    }
+
+    /// Handles a punch of the client with `origin` as id and `key` as session, arriving from
+    /// `from` at `time`.
+    ///
+    /// Fails with `SessionMismatch` unless it comes from the introduced client.
+    fn receive_punch(
+        &self,
+        key: u64,
+        origin: u64,
+        from: SocketAddr,
+        answer: bool,
+        time: Instant,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let registered = state.key;
+        match &mut state.punch {
+            Some(punch) if registered == Some(key) && punch.peer_id() == origin => {
+                punch.receive(from, answer, time);
+                Ok(())
+            }
+            _ => Err(ErrorKind::SessionMismatch),
+        }
+    }
+
+    /// Notes the arrival of inputs of the introduced client over the open path at `time`.
+    fn heard(&self, from: SocketAddr, time: Instant) {
+        if let Some(punch) = self.state.lock().unwrap().punch.as_mut() {
+            punch.heard(from, time);
+        }
+    }
+
+    /// Returns the key and the address of the punch due at `time`, and whether it is an answer.
+    fn next_punch(&self, time: Instant) -> Option<(u64, SocketAddr, bool)> {
+        let mut state = self.state.lock().unwrap();
+        let key = state.key?;
+        let (addr, answer) = state.punch.as_mut()?.next_punch(time)?;
+        Some((key, addr, answer))
+    }
+
+    /// Returns the key and the address of the introduced client while the path to it is open.
+    fn direct(&self) -> Option<(u64, SocketAddr)> {
+        let state = self.state.lock().unwrap();
+        match state.punch.as_ref()?.route() {
+            Route::Direct(addr) => Some((state.key?, addr)),
+            _ => None,
+        }
+    }
+}
+
+/// Replica of the entities the server replicates to a running [Client].
+#[derive(Clone, Debug, Default)]
+pub struct Replica {
+    state: Arc<Mutex<ReplicaState>>,
+}
+
+#[derive(Debug, Default)]
+struct ReplicaState {
+    world: ReplicatedWorld,
+    decoder: ReplicationDecoder,
+    encoder: ReplicationEncoder,
+    /// Session id the server identifies this client by, once connected.
+    id: Option<u64>,
+    /// Tick of the latest replication packet not acknowledged yet.
+    ack: Option<u32>,
+    /// Authority requests not answered yet, `true` marks a release.
+    requests: BTreeMap<u32, bool>,
+}
+
+impl Replica {
+    /// Runs `f` on the replicated entities.
+    ///
+    /// Components changed on entities this client owns are sent to the server, other local
+    /// changes are overwritten by the next change from the server.
+    pub fn with_world<R, F: FnOnce(&mut ReplicatedWorld) -> R>(&self, f: F) -> R {
+        f(&mut self.state.lock().unwrap().world)
+    }
+
+    /// Returns the owner id of the entities this client has authority over, the session id
+    /// agreed with the server. `None` before connecting.
+    pub fn id(&self) -> Option<u64> {
+        self.state.lock().unwrap().id
+    }
+
+    /// Asks the server for authority over `entity`.
+    ///
+    /// A granted request shows up as change of the owner, a refused one as
+    /// [AuthorityDenied](crate::replication::ReplicationEvent::AuthorityDenied) event.
+    pub fn request_authority(&self, entity: u32) {
+        self.state.lock().unwrap().requests.insert(entity, false);
+    }
+
+    /// Gives authority over `entity` back to the server.
+    pub fn release_authority(&self, entity: u32) {
+        self.state.lock().unwrap().requests.insert(entity, true);
+    }
+
+    fn receive(&self, tick: u32, datagram: &Bytes) -> Result<()> {
+        let mut rdr = Cursor::new(&datagram[..]);
+        let acked = read_input_ack(&mut rdr)?;
+        let diff = datagram.slice(rdr.position() as usize..);
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(acked) = acked {
+            state.encoder.ack(acked);
+        }
+        if !diff.is_empty() {
+            if let Some(diff) = state.decoder.decode(tick, &diff)? {
+                state.world.apply_remote(diff);
+            }
+        }
+        state.ack = Some(tick);
+        Ok(())
+    }
+
+    fn authority_response(&self, payload: &[u8]) -> Result<()> {
+        let (entity, owned) = decode_authority(payload)?;
+        let mut state = self.state.lock().unwrap();
+        if let Some(release) = state.requests.remove(&entity) {
+            if !release && !owned {
+                state.world.deny_authority(entity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the replication tick to acknowledge, the changes of entities owned by this client
+    /// and the pending authority requests.
+    fn outgoing(&self, tick: u32) -> (Option<u32>, Option<Bytes>, Vec<Bytes>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        // the path MTU is not discovered towards the server, the minimum always fits
+        let budget = MIN_MTU as usize - MAX_HEADER_SIZE;
+        let changes = state
+            .id
+            .and_then(|owner| state.encoder.encode_owned(&state.world, tick, owner, budget));
+        let requests = state
+            .requests
+            .iter()
+            .map(|(entity, release)| encode_authority(*entity, *release))
+            .collect();
+        (state.ack.take(), changes, requests)
+    }
 }
 
 impl Client {
+    /// Creates a client for the server at `addr`, given as `hostname:port` or an IPv4/IPv6
+    /// socket address, bound to an OS assigned port on all interfaces.
+    ///
+    /// The socket is dual-stack to reach every address `addr` resolves to, on hosts without
+    /// IPv6 it only reaches the family of the first one.
     pub async fn new(addr: &str) -> Result<Self> {
-        let socket = UdpSocket::bind("127.0.0.1:0").await?;
-        println!("Listening on: {}", socket.local_addr()?);
+        let candidates = resolve(addr).await?;
+        let dual_stack = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+        Self::bind_internal(dual_stack, select_remotes(&dual_stack, &candidates)).or_else(|_| {
+            let local = unspecified_for(&candidates[0]);
+            Self::bind_internal(local, select_remotes(&local, &candidates))
+        })
+    }
+
+    /// Creates a client for the server at `addr` bound to `bind_addr`, both may be hostnames.
+    ///
+    /// # Remark
+    /// - The server addresses are the ones reachable from the bound socket, binding to the
+    ///   unspecified IPv6 address reaches both IPv4 and IPv6 servers. They are tried in turn
+    ///   when connecting, addresses of the same family first.
+    pub async fn with_bind(addr: &str, bind_addr: &str) -> Result<Self> {
+        let candidates = resolve(addr).await?;
+        for local in resolve(bind_addr).await? {
+            let remotes = select_remotes(&local, &candidates);
+            if !remotes.is_empty() {
+                return Self::bind_internal(local, remotes);
+            }
+        }
+
+        Err(ErrorKind::InvalidAddress(format!(
+            "{} (not reachable from {})",
+            addr, bind_addr
+        )))
+    }
+
+    fn bind_internal(local: SocketAddr, candidates: Vec<SocketAddr>) -> Result<Self> {
+        let remote = match candidates.first() {
+            Some(remote) => *remote,
+            None => {
+                let reason = format!("no server address reachable from {}", local);
+                return Err(ErrorKind::InvalidAddress(reason));
+            }
+        };
+        let socket = UdpSocket::from_std(bind_udp(local, false)?)?;
+        let local = socket.local_addr()?;
+        println!("Listening on: {}", local);
+        let clock = ServerClock::new(Instant::now());
+        let id = random();
 
         Ok(Client {
             socket,
-            remote: addr.parse().unwrap(),
-            id: random(),
-            buf: vec![0; DEFAULT_MTU as usize],
+            remote,
+            candidates,
+            id,
+            buf: vec![0; MAX_MTU as usize],
             session: 0,
+            capabilities: Capabilities::local(),
+            ticks: TickScheduler::new(DEFAULT_TICK_RATE, Instant::now()),
+            snapshot_config: SnapshotConfig::default(),
+            shared: Shared {
+                world: InterpolatedWorld {
+                    clock: clock.clone(),
+                    buffer: Default::default(),
+                },
+                clock,
+                inputs: Default::default(),
+                rollback: Arc::new(Mutex::new(RollbackSession::new(id))),
+                replica: Default::default(),
+                interest: Default::default(),
+                messages: Default::default(),
+                link: PeerLink::new(local),
+            },
         })
     }
 
+    /// Restarts the simulation clock at tick 0, running at `rate` ticks per second.
+    ///
+    /// The client sends one data packet per tick, stamped with the tick it belongs to.
+    pub fn with_tick_rate(mut self, rate: u32) -> Self {
+        self.ticks = TickScheduler::new(rate, Instant::now());
+        self
+    }
+
+    /// Limits the optional features offered to the server to `capabilities`, the ones this
+    /// build does not support are never offered.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities & Capabilities::local();
+        self
+    }
+
+    /// Sets the quantization of snapshots, it has to match the config of the server.
+    pub fn with_snapshot_config(mut self, config: SnapshotConfig) -> Self {
+        self.snapshot_config = config;
+        self
+    }
+
+    /// Sets how far snapshot playback lags behind the server, a longer delay hides more late
+    /// or lost snapshots.
+    pub fn with_interpolation_delay(self, delay: Duration) -> Self {
+        self.shared.world.buffer.lock().unwrap().set_delay(delay);
+        self
+    }
+
+    /// Registers `key` with the server running as introducer, which pairs this client with the
+    /// other one registering the same key.
+    ///
+    /// Both are told the public address of each other and punch holes into their NATs, see
+    /// [link](#method.link).
+    pub fn with_rendezvous(self, key: u64) -> Self {
+        self.shared.link.set_key(key);
+        self
+    }
+
+    /// Returns the received snapshots, which stay up to date while the client runs.
+    pub fn world(&self) -> InterpolatedWorld {
+        self.shared.world.clone()
+    }
+
+    /// Returns the channel the app sends its inputs through.
+    pub fn inputs(&self) -> InputChannel {
+        self.shared.inputs.clone()
+    }
+
+    /// Returns the rollback session fed with the inputs of the other peers of a match.
+    ///
+    /// Local inputs added to it are broadcast every tick through the server.
+    pub fn rollback(&self) -> Arc<Mutex<RollbackSession>> {
+        self.shared.rollback.clone()
+    }
+
+    /// Returns the area of interest reported to the server, bodies and peers outside of it
+    /// are left out of what the server sends.
+    pub fn interest(&self) -> InterestChannel {
+        self.shared.interest.clone()
+    }
+
+    /// Returns the channel of the typed messages exchanged with the server.
+    pub fn messages(&self) -> MessageChannel {
+        self.shared.messages.clone()
+    }
+
+    /// Returns the direct path to the client the server introduced this one to.
+    pub fn link(&self) -> PeerLink {
+        self.shared.link.clone()
+    }
+
+    /// Returns the replica of the entities the server replicates.
+    pub fn replica(&self) -> Replica {
+        self.shared.replica.clone()
+    }
+
+    /// Returns the id the server tags this client's relayed rollback inputs with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the optional features agreed on with the server once connected, the offered ones
+    /// before.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Returns the simulation clock inputs are tagged with.
+    pub fn ticks(&self) -> TickScheduler {
+        self.ticks
+    }
+
+    /// Returns the estimate of the server clock, which stays up to date while the client runs.
+    pub fn server_clock(&self) -> ServerClock {
+        self.shared.clock.clone()
+    }
+
+    /// Connects to the server, agreeing on the protocol version and the optional features.
+    ///
+    /// The request is sent to the addresses of the server in turn every [CONNECT_INTERVAL],
+    /// the first one answering is kept. Fails with `ReadTimeout` when none answered
+    /// [CONNECT_ATTEMPTS] requests, with `ProtocolVersionUnsupported` when the server rejects
+    /// all versions of this build.
+    ///
+    /// [CONNECT_INTERVAL]: ../net/constants/constant.CONNECT_INTERVAL.html
+    /// [CONNECT_ATTEMPTS]: ../net/constants/constant.CONNECT_ATTEMPTS.html
     pub async fn connect(&mut self) -> Result<()> {
         let mut payload = [0_u8; CONNECT_PAYLOAD_SIZE];
         thread_rng().fill(&mut payload[..]);
+        put_offer(&mut payload, ProtocolVersion::supported(), self.capabilities, false);
 
         // connection request
         let out = OutgoingPacketBuilder::new(&payload)
             .with_default_header(PacketType::Connect)
             .with_session_header(0)
+            .with_tick_header(self.ticks.tick_at(Instant::now()))
             .with_session_header(self.id)
-            .build();
-        let packet = Packet::new(self.remote, out.contents());
-
-        self.socket.send_to(packet.payload(), packet.addr()).await?;
-
-        let (size, _) = self.socket.recv_from(self.buf.as_mut()).await?;
+            .build()?;
+        let size = self.request_challenge(&out.contents()).await?;
         let mut reader = PacketReader::new(&self.buf[..size]);
+        if reader.read_base_header()?.packet_type() == PacketType::Reject {
+            reader.read_tick_header()?;
+            let versions = read_reject(&reader.read_payload()?)?;
+            return Err(ErrorKind::ProtocolVersionUnsupported(*versions.start(), *versions.end()));
+        }
         let session = reader.read_session_header()?;
         self.session = session.session_id();
+        self.shared.replica.state.lock().unwrap().id = Some(self.session);
+        reader.read_id_header()?;
+        let (version, capabilities) = read_accept(&reader.read_payload()?)?;
+        if !ProtocolVersion::supported().contains(&version) {
+            return Err(ErrorKind::ProtocolVersionMismatch);
+        }
+        self.capabilities = capabilities;
 
         // challenge response
         let out = OutgoingPacketBuilder::new(&payload)
             .with_default_header(PacketType::Connect)
             .with_session_header(self.session)
+            .with_tick_header(self.ticks.tick_at(Instant::now()))
             .with_session_header(self.id)
-            .build();
+            .build()?;
         let packet = Packet::new(self.remote, out.contents());
         self.socket.send_to(packet.payload(), packet.addr()).await?;
 
+        self.synchronize().await
+    }
+
+    /// Runs a burst of time sync exchanges, lost pings are skipped.
+    async fn synchronize(&mut self) -> Result<()> {
+        for _ in 0..TIME_SYNC_BURST {
+            let time = Instant::now();
+            let ping = self.shared.clock.ping(self.session, self.ticks.tick_at(time), time)?;
+            self.socket.send_to(&ping.contents(), &self.remote).await?;
+
+            let pong = timeout(TIME_SYNC_INTERVAL, Self::receive_pong(&mut self.socket, &mut self.buf));
+            match pong.await {
+                Ok(Ok(size)) => {
+                    let mut reader = PacketReader::new(&self.buf[..size]);
+                    reader.read_tick_header()?;
+                    self.shared.clock.add_pong(&reader.read_payload()?, Instant::now())?;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => debug!("time sync ping lost"),
+            }
+        }
+        debug!(
+            "server clock offset {:?}us, rtt {:?}",
+            self.shared.clock.offset(Instant::now()),
+            self.shared.clock.rtt()
+        );
         Ok(())
     }
 
+    /// Sends the connect `request` to the addresses of the server in turn until one answers,
+    /// which is kept as the server. Returns the size of the answer.
+    async fn request_challenge(&mut self, request: &[u8]) -> Result<usize> {
+        let mut lost = None;
+        for remote in self.candidates.iter().cycle().take(CONNECT_ATTEMPTS as usize) {
+            self.socket.send_to(request, remote).await?;
+            let answer = Self::receive_from(&mut self.socket, &mut self.buf, &self.candidates);
+            match timeout(CONNECT_INTERVAL, answer).await {
+                Ok(Ok((size, from))) => {
+                    self.remote = from;
+                    return Ok(size);
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(elapsed) => {
+                    debug!("no answer to connect request from {}", remote);
+                    lost = Some(elapsed);
+                }
+            }
+        }
+        // there is at least one attempt to one candidate
+        Err(ErrorKind::ReadTimeout(lost.unwrap()))
+    }
+
+    /// Reads datagrams until one of the `candidates` sends one, returns its size and sender.
+    async fn receive_from(
+        socket: &mut UdpSocket,
+        buf: &mut [u8],
+        candidates: &[SocketAddr],
+    ) -> Result<(usize, SocketAddr)> {
+        loop {
+            let (size, from) = socket.recv_from(buf).await?;
+            if candidates.contains(&from) {
+                return Ok((size, from));
+            }
+            debug!("ignoring datagram from {} while connecting", from);
+        }
+    }
+
+    /// Reads datagrams until a `TimePong` arrives and returns its size.
+    async fn receive_pong(socket: &mut UdpSocket, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let (size, _) = socket.recv_from(buf).await?;
+            let mut reader = PacketReader::new(&buf[..size]);
+            if let Ok(header) = reader.read_base_header() {
+                if header.packet_type() == PacketType::TimePong {
+                    return Ok(size);
+                }
+            }
+        }
+    }
+
     pub async fn run(mut self) -> Result<()> {
         self.connect().await?;
 
         let (rx, tx) = self.socket.split();
-        tokio::spawn(Client::reading(rx));
-        Client::sending(tx, self.session, self.remote).await?;
+        let (acks_tx, acks_rx) = unbounded_channel();
+        tokio::spawn(Client::reading(
+            rx,
+            self.remote,
+            self.capabilities.contains(Capabilities::COMPRESSION),
+            self.shared.clone(),
+            self.snapshot_config,
+            acks_tx,
+        ));
+        Client::sending(tx, self.session, self.id, self.remote, self.ticks, self.shared, acks_rx)
+            .await?;
 
         Ok(())
     }
 
-    pub async fn reading(mut rx: RecvHalf) -> Result<()> {
-        let mut buf: Vec<u8> = vec![0; DEFAULT_MTU as usize];
+    /// Reads datagrams from the server at `remote` and the introduced client, snapshots and MTU
+    /// probes to acknowledge are sent to `acks`.
+    ///
+    /// Snapshots are played back stamped with their tick times the tick period of the server.
+    /// Compressed payloads are only read when `compression` was agreed with the server.
+    async fn reading(
+        mut rx: RecvHalf,
+        remote: SocketAddr,
+        compression: bool,
+        shared: Shared,
+        config: SnapshotConfig,
+        acks: UnboundedSender<Ack>,
+    ) -> Result<()> {
+        let mut buf: Vec<u8> = vec![0; MAX_MTU as usize];
+        let mut snapshots = DeltaDecoder::new();
         loop {
             let time = Instant::now();
-            let (size, _) = rx.recv_from(&mut buf).await?;
-            let mut reader = PacketReader::new(&buf[..size]);
-            let header = reader.read_base_header()?;
-
-            debug!("received {:?} @{:?}", header.packet_type(), time.elapsed())
+            let (size, from) = rx.recv_from(&mut buf).await?;
+            let reader = PacketReader::new(&buf[..size]).with_compression(compression);
+            let read = Self::read_datagram(reader, from, remote, &shared, &config, &mut snapshots, time);
+            match read {
+                Ok((ptype, tick, states)) => {
+                    debug!("received {:?} of tick {} @{:?}", ptype, tick, time.elapsed());
+                    if let Some((input_ack, states)) = states {
+                        if let Some(input_tick) = input_ack {
+                            shared.inputs.ack(input_tick);
+                        }
+                        match shared.clock.tick_period() {
+                            Some(period) => shared.world.push(period * tick, states),
+                            None => debug!("dropping snapshot of tick {}, the server clock is unknown", tick),
+                        }
+                        if acks.send(Ack::Snapshot(tick)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    if ptype == PacketType::MtuProbe && acks.send(Ack::MtuProbe(size)).is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => error!("dropping malformed datagram: {}", e),
+            }
         }
     }
 
+    /// Handles the datagram of `reader`, returns its type, tick and for snapshots the acknowledged
+    /// input tick along with the states.
+    ///
+    /// Only punches and, once the path is open, rollback inputs are accepted from others than
+    /// the server at `remote`.
+    fn read_datagram(
+        mut reader: PacketReader,
+        from: SocketAddr,
+        remote: SocketAddr,
+        shared: &Shared,
+        config: &SnapshotConfig,
+        snapshots: &mut DeltaDecoder,
+        time: Instant,
+    ) -> Result<(PacketType, u32, Option<ReceivedSnapshot>)> {
+        let ptype = reader.read_base_header()?.packet_type();
+        let tick = reader.read_tick_header()?.tick();
+        if from != remote {
+            let key = reader.read_session_header()?.session_id();
+            let direct = shared.link.direct() == Some((key, from));
+            if !(ptype == PacketType::Punch || (ptype == PacketType::FrameInput && direct)) {
+                return Err(ErrorKind::SessionMismatch);
+            }
+        }
+        let states = match ptype {
+            PacketType::TimePong => {
+                shared.clock.add_pong(&reader.read_payload()?, time)?;
+                None
+            }
+            PacketType::FrameInput => {
+                if from != remote {
+                    shared.link.heard(from, time);
+                }
+                let origin = reader.read_id_header()?.session_id();
+                let payload = Bytes::copy_from_slice(&reader.read_payload()?);
+                shared.rollback.lock().unwrap().receive(origin, &payload)?;
+                None
+            }
+            PacketType::Replication => {
+                let payload = Bytes::copy_from_slice(&reader.read_payload()?);
+                shared.replica.receive(tick, &payload)?;
+                None
+            }
+            PacketType::AuthorityResponse => {
+                shared.replica.authority_response(&reader.read_payload()?)?;
+                None
+            }
+            PacketType::Message => {
+                shared.messages.dispatch(from, &reader.read_payload()?)?;
+                None
+            }
+            PacketType::Introduction => {
+                let (peer_id, endpoint) = read_introduction(&reader.read_payload()?)?;
+                shared.link.introduce(peer_id, endpoint, time);
+                None
+            }
+            PacketType::Punch => {
+                let key = reader.read_session_header()?.session_id();
+                let origin = reader.read_id_header()?.session_id();
+                let answer = read_punch(&reader.read_payload()?)?;
+                shared.link.receive_punch(key, origin, from, answer, time)?;
+                None
+            }
+            PacketType::FrameAdvantage => {
+                let advantage = Cursor::new(&reader.read_payload()?).read_i32::<BigEndian>()?;
+                shared.rollback.lock().unwrap().set_frame_advantage(advantage);
+                None
+            }
+            PacketType::Snapshot => {
+                let payload = reader.read_payload()?;
+                let mut rdr = Cursor::new(&payload[..]);
+                let input_ack = read_input_ack(&mut rdr)?;
+                match snapshots.decode(config, tick, &payload[rdr.position() as usize..])? {
+                    Some(states) => {
+                        debug!("snapshot of tick {} with {} bodies", tick, states.len());
+                        Some((input_ack, states))
+                    }
+                    None => {
+                        debug!("dropping snapshot of tick {}, a newer one arrived", tick);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        Ok((ptype, tick, states))
+    }
 
-    pub async fn sending(mut tx: SendHalf, session: u64, remote: SocketAddr) -> Result<()> {
-        let mut i = interval_at(
-            tokio::time::Instant::now() + Duration::from_secs(3),
-            Duration::from_millis(10),
-        );
+    async fn sending(
+        mut tx: SendHalf,
+        session: u64,
+        id: u64,
+        remote: SocketAddr,
+        ticks: TickScheduler,
+        shared: Shared,
+        mut acks: UnboundedReceiver<Ack>,
+    ) -> Result<()> {
+        let clock = shared.clock;
+        let mut next_ping = Instant::now() + TIME_SYNC_INTERVAL;
+        let data_start = Instant::now() + Duration::from_secs(3);
         let mut payload = [0_u8; 128];
         thread_rng().fill(&mut payload);
         loop {
             let time = Instant::now();
-            i.tick().await;
+            let (tick, flushed) = tokio::select! {
+                tick = ticks.wait() => (tick, false),
+                _ = shared.messages.flushed.notified() => (ticks.tick_at(Instant::now()), true),
+            };
+            // a flush only sends the queued messages, everything else waits for the tick
+            if flushed {
+                Self::send_messages(&mut tx, &shared.messages, session, tick, &remote).await?;
+                continue;
+            }
+
+            while let Ok(acked) = acks.try_recv() {
+                // the tick header carries the acknowledged tick or probe size
+                let (ptype, acked) = match acked {
+                    Ack::Snapshot(tick) => (PacketType::SnapshotAck, tick),
+                    Ack::MtuProbe(size) => (PacketType::MtuAck, size as u32),
+                };
+                let ack = OutgoingPacketBuilder::new(&[])
+                    .with_default_header(ptype)
+                    .with_session_header(session)
+                    .with_tick_header(acked)
+                    .build()?;
+                tx.send_to(&ack.contents(), &remote).await?;
+            }
+
+            if let Some(key) = shared.link.registration_due(time) {
+                let out = OutgoingPacketBuilder::new(&register_payload(key))
+                    .with_default_header(PacketType::Register)
+                    .with_session_header(session)
+                    .with_tick_header(tick)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+            }
+            // punches carry the registered key instead of a session
+            while let Some((key, to, answer)) = shared.link.next_punch(time) {
+                let out = OutgoingPacketBuilder::new(&punch_payload(answer))
+                    .with_default_header(PacketType::Punch)
+                    .with_session_header(key)
+                    .with_tick_header(tick)
+                    .with_session_header(id)
+                    .build()?;
+                tx.send_to(&out.contents(), &to).await?;
+            }
+
+            if let Some(redundant) = shared.inputs.payload() {
+                let out = OutgoingPacketBuilder::new(&redundant)
+                    .with_default_header(PacketType::Input)
+                    .with_session_header(session)
+                    .with_tick_header(tick)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+            }
+
+            let introduced = shared.link.peer_id();
+            let frame = {
+                let rollback = shared.rollback.lock().unwrap();
+                // the direct path only carries the match of the two introduced clients
+                let paired = rollback.peers().all(|peer| Some(peer) == introduced);
+                rollback.frame().zip(rollback.local_payload()).map(|frame| (frame, paired))
+            };
+            if let Some(((frame, redundant), paired)) = frame {
+                let out = OutgoingPacketBuilder::new(&redundant)
+                    .with_default_header(PacketType::FrameInput)
+                    .with_session_header(session)
+                    .with_tick_header(frame)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+                // the introduced client gets them directly too, tagged like the ones the server
+                // relays
+                if let Some((key, addr)) = shared.link.direct().filter(|_| paired) {
+                    let out = OutgoingPacketBuilder::new(&redundant)
+                        .with_default_header(PacketType::FrameInput)
+                        .with_session_header(key)
+                        .with_tick_header(frame)
+                        .with_session_header(id)
+                        .build()?;
+                    tx.send_to(&out.contents(), &addr).await?;
+                }
+            }
+
+            let (ack, changes, requests) = shared.replica.outgoing(tick);
+            if let Some(acked) = ack {
+                let ack = OutgoingPacketBuilder::new(&[])
+                    .with_default_header(PacketType::ReplicationAck)
+                    .with_session_header(session)
+                    .with_tick_header(acked)
+                    .build()?;
+                tx.send_to(&ack.contents(), &remote).await?;
+            }
+            for (ptype, payload) in changes
+                .map(|changes| (PacketType::EntityUpdate, changes))
+                .into_iter()
+                .chain(requests.into_iter().map(|r| (PacketType::AuthorityRequest, r)))
+            {
+                let out = OutgoingPacketBuilder::new(&payload)
+                    .with_default_header(ptype)
+                    .with_session_header(session)
+                    .with_tick_header(tick)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+            }
+
+            Self::send_messages(&mut tx, &shared.messages, session, tick, &remote).await?;
+
+            let now = Instant::now();
+            if let Some(area) = shared.interest.due(now) {
+                let out = OutgoingPacketBuilder::new(&interest_payload(&area))
+                    .with_default_header(PacketType::Interest)
+                    .with_session_header(session)
+                    .with_tick_header(tick)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+            }
+
+            if now >= data_start {
+                let out = OutgoingPacketBuilder::new(&payload)
+                    .with_default_header(PacketType::Data)
+                    .with_session_header(session)
+                    .with_tick_header(tick)
+                    .build()?;
+                tx.send_to(&out.contents(), &remote).await?;
+                debug!("send data of tick {} @{:?}", tick, time.elapsed());
+            }
+
+            if now >= next_ping {
+                let ping = clock.ping(session, tick, now)?;
+                tx.send_to(&ping.contents(), &remote).await?;
+                next_ping = now + TIME_SYNC_INTERVAL;
+                debug!(
+                    "server time {:?}, drift {:e}",
+                    clock.server_time(now),
+                    clock.drift()
+                );
+            }
+        }
+    }
+
+    /// Sends the messages queued on `messages` as message packets of `tick`.
+    async fn send_messages(
+        tx: &mut SendHalf,
+        messages: &MessageChannel,
+        session: u64,
+        tick: u32,
+        remote: &SocketAddr,
+    ) -> Result<()> {
+        for payload in messages.take() {
             let out = OutgoingPacketBuilder::new(&payload)
-                .with_default_header(PacketType::Data)
+                .with_default_header(PacketType::Message)
                 .with_session_header(session)
-                .build();
-            tx.send_to(&out.contents(), &remote).await?;
-            debug!("send data @{:?}", time.elapsed());
+                .with_tick_header(tick)
+                .build()?;
+            tx.send_to(&out.contents(), remote).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use futures::future::join_all;
+    use tokio::time::{delay_for, timeout};
+
+    use crate::message::{Message, MessageBatch};
+    use crate::net::constants::{DEFAULT_TICK_RATE, MAX_INPUT_SIZE, MAX_MTU, MTU_PROBE_PRECISION};
+    use crate::replication::ReplicationEvent;
+    use crate::serialize::NetSerialize;
+    use crate::snapshot::RigidBodyState;
+    use crate::packet::{PacketReader, PacketType};
+    use crate::{
+        AreaOfInterest, Capabilities, ErrorKind, OutgoingPacketBuilder, Peer, PeerEvent, Route,
+    };
+
+    use super::Client;
+
+    /// Waits until `done` holds, for at most a few seconds on a busy machine.
+    async fn eventually<F: FnMut() -> bool>(mut done: F) {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while !done() && Instant::now() < deadline {
+            delay_for(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn plays_back_snapshots() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            loop {
+                let x = start.elapsed().as_secs_f32();
+                server.send_snapshot(&[RigidBodyState {
+                    entity: 3,
+                    position: [x, 0.0, 0.0],
+                    linear_velocity: [1.0, 0.0, 0.0],
+                    ..Default::default()
+                }]);
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        // snapshots are stamped with the tick period of the server, not the one of the client
+        let client = Client::new(&addr)
+            .await
+            .unwrap()
+            .with_tick_rate(DEFAULT_TICK_RATE * 2)
+            .with_interpolation_delay(Duration::from_millis(50));
+        let world = client.world();
+        let clock = client.server_clock();
+        tokio::spawn(client.run());
+
+        delay_for(Duration::from_millis(500)).await;
+        assert!(clock.is_synchronized());
+        assert_eq!(clock.tick_period(), Some(Duration::from_secs(1) / DEFAULT_TICK_RATE));
+        let now = Instant::now();
+        let bodies = world.sample(now);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].entity, 3);
+        let played = clock.server_time(now).unwrap().as_secs_f32() - 0.05;
+        assert!((bodies[0].position[0] - played).abs() < 0.1, "{:?} at {}", bodies[0], played);
+    }
+
+    #[tokio::test]
+    async fn acknowledges_processed_inputs() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let (processed_tx, processed_rx) = mpsc::channel();
+        tokio::spawn(async move {
+            loop {
+                for (_, input) in server.next_inputs() {
+                    let _ = processed_tx.send(input);
+                }
+                server.send_snapshot(&[RigidBodyState::default()]);
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let client = Client::new(&addr).await.unwrap();
+        let inputs = client.inputs();
+        tokio::spawn(client.run());
+
+        for tick in 1..=10 {
+            inputs.push(tick, Bytes::from(vec![tick as u8])).unwrap();
+        }
+        let oversized = Bytes::from(vec![0; MAX_INPUT_SIZE + 1]);
+        assert!(inputs.push(11, oversized).is_err());
+        delay_for(Duration::from_millis(500)).await;
+        assert_eq!(inputs.acked(), Some(10));
+        assert!(inputs.unacked().is_empty());
+
+        // every input is processed once, in order
+        let processed = processed_rx.try_iter().collect::<Vec<_>>();
+        let ticks = processed.iter().map(|input| input.tick).collect::<Vec<_>>();
+        assert_eq!(ticks, (1..=10).collect::<Vec<_>>());
+        for input in processed {
+            assert_eq!(input.payload, Bytes::from(vec![input.tick as u8]));
+        }
+
+        inputs.push(11, Bytes::from(vec![11])).unwrap();
+        assert_eq!(inputs.unacked().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn relays_rollback_inputs() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let ahead = Client::new(&addr).await.unwrap();
+        let behind = Client::new(&addr).await.unwrap();
+        let (ahead_id, behind_id) = (ahead.id(), behind.id());
+        let (ahead_session, behind_session) = (ahead.rollback(), behind.rollback());
+        for frame in 0..20 {
+            ahead_session
+                .lock()
+                .unwrap()
+                .add_local_input(frame, Bytes::from(vec![frame as u8]))
+                .unwrap();
+        }
+        for frame in 0..5 {
+            behind_session
+                .lock()
+                .unwrap()
+                .add_local_input(frame, Bytes::from(vec![frame as u8]))
+                .unwrap();
+        }
+        tokio::spawn(ahead.run());
+        tokio::spawn(behind.run());
+
+        delay_for(Duration::from_millis(500)).await;
+        let mut ahead_session = ahead_session.lock().unwrap();
+        assert_eq!(ahead_session.confirmed_frame(behind_id), Some(4));
+        assert_eq!(ahead_session.input(behind_id, 3).payload, Bytes::from(vec![3]));
+        assert_eq!(ahead_session.frame_advantage(), 15);
+
+        let behind_session = behind_session.lock().unwrap();
+        assert_eq!(behind_session.confirmed_frame(ahead_id), Some(19));
+        assert_eq!(behind_session.frame_advantage(), -15);
+    }
+
+    #[tokio::test]
+    async fn replicates_owned_entities() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let entity = server.replication().spawn(1);
+        server.replication().set_component(entity, 0, Bytes::from_static(b"server"));
+        tokio::spawn(async move {
+            loop {
+                server.send_replication();
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let owner = Client::new(&addr).await.unwrap();
+        let other = Client::new(&addr).await.unwrap();
+        let client_id = owner.id();
+        let (owner_replica, other_replica) = (owner.replica(), other.replica());
+        tokio::spawn(owner.run());
+        tokio::spawn(other.run());
+        // entities are owned by session, not by the id the client chose
+        eventually(|| owner_replica.id().is_some()).await;
+        let owner_id = owner_replica.id().unwrap();
+        assert_ne!(owner_id, client_id);
+
+        owner_replica.request_authority(entity);
+        eventually(|| {
+            owner_replica.with_world(|world| {
+                matches!(world.entity(entity), Some(e) if e.owner() == Some(owner_id))
+            })
+        })
+        .await;
+        owner_replica.with_world(|world| {
+            assert_eq!(world.entity(entity).unwrap().owner(), Some(owner_id));
+            world.set_component(entity, 0, Bytes::from_static(b"owner"));
+        });
+        other_replica.request_authority(entity);
+        eventually(|| {
+            let state = other_replica.state.lock().unwrap();
+            let replicated = state.world.entity(entity);
+            state.requests.is_empty()
+                && matches!(replicated, Some(e) if e.component(0) == Some(&Bytes::from_static(b"owner")))
+        })
+        .await;
+
+        other_replica.with_world(|world| {
+            let replicated = world.entity(entity).unwrap();
+            assert_eq!(replicated.owner(), Some(owner_id));
+            assert_eq!(replicated.component(0), Some(&Bytes::from_static(b"owner")));
+            let events = world.drain_events().collect::<Vec<_>>();
+            assert_eq!(events[0], ReplicationEvent::Spawned { entity, type_id: 1 });
+            assert!(events.contains(&ReplicationEvent::AuthorityDenied { entity }));
+        });
+
+        // a client reusing the id of the owner does not get its session
+        let mut impostor = Client::new(&addr).await.unwrap();
+        impostor.id = client_id;
+        let impostor_replica = impostor.replica();
+        tokio::spawn(impostor.run());
+        eventually(|| impostor_replica.id().is_some()).await;
+        impostor_replica.release_authority(entity);
+        delay_for(Duration::from_millis(300)).await;
+        other_replica.with_world(|world| {
+            assert_eq!(world.entity(entity).unwrap().owner(), Some(owner_id));
+        });
+    }
+
+    #[tokio::test]
+    async fn filters_bodies_by_interest() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let body = |entity, x| RigidBodyState {
+                entity,
+                position: [x, 0.0, 0.0],
+                ..Default::default()
+            };
+            loop {
+                server.send_snapshot(&[body(1, 0.0), body(2, 400.0)]);
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let near = Client::new(&addr)
+            .await
+            .unwrap()
+            .with_interpolation_delay(Duration::from_millis(50));
+        near.interest().set(AreaOfInterest {
+            center: [10.0, 0.0, 0.0],
+            radius: 100.0,
+        });
+        let everything = Client::new(&addr)
+            .await
+            .unwrap()
+            .with_interpolation_delay(Duration::from_millis(50));
+        let (near_world, world) = (near.world(), everything.world());
+        tokio::spawn(near.run());
+        tokio::spawn(everything.run());
+
+        delay_for(Duration::from_millis(500)).await;
+        let bodies = near_world.sample(Instant::now());
+        assert_eq!(bodies.iter().map(|b| b.entity).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(world.sample(Instant::now()).len(), 2);
+    }
+
+    #[derive(Debug, PartialEq, NetSerialize)]
+    struct Chat {
+        text: Bytes,
+    }
+
+    impl Message for Chat {
+        const ID: u16 = 1;
+    }
+
+    #[tokio::test]
+    async fn exchanges_messages() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let inbox = received.clone();
+        server
+            .messages()
+            .register(move |from, chat: Chat| inbox.lock().unwrap().push((from, chat)));
+        tokio::spawn(async move {
+            loop {
+                let chats = mem::take(&mut *received.lock().unwrap());
+                for (from, chat) in chats {
+                    let mut batch = MessageBatch::new();
+                    batch.push(&chat).unwrap();
+                    batch.push(&Chat { text: Bytes::from_static(b"echo") }).unwrap();
+                    assert!(server.send_messages(&from, batch));
+                }
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let client = Client::new(&addr).await.unwrap();
+        let messages = client.messages();
+        let echoed = Arc::new(Mutex::new(Vec::new()));
+        let inbox = echoed.clone();
+        messages.register(move |_, chat: Chat| inbox.lock().unwrap().push(chat.text));
+        messages.send_message(&Chat { text: Bytes::from_static(b"hello") }).unwrap();
+        tokio::spawn(client.run());
+
+        eventually(|| echoed.lock().unwrap().len() >= 2).await;
+        assert_eq!(
+            *echoed.lock().unwrap(),
+            vec![Bytes::from_static(b"hello"), Bytes::from_static(b"echo")]
+        );
+    }
+
+    #[tokio::test]
+    async fn coalesces_and_flushes_messages() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let inbox = received.clone();
+        server
+            .messages()
+            .register(move |from, chat: Chat| inbox.lock().unwrap().push((from, chat)));
+        tokio::spawn(async move {
+            loop {
+                let chats = mem::take(&mut *received.lock().unwrap());
+                for (from, chat) in &chats {
+                    assert!(server.queue_message(from, chat).unwrap());
+                }
+                if !chats.is_empty() {
+                    server.flush_messages().await.unwrap();
+                }
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let client = Client::new(&addr).await.unwrap();
+        let messages = client.messages();
+        let echoed = Arc::new(Mutex::new(Vec::new()));
+        let inbox = echoed.clone();
+        messages.register(move |_, chat: Chat| inbox.lock().unwrap().push(chat.text));
+        tokio::spawn(client.run());
+
+        let texts = (0..20_u8).map(|i| Bytes::from(vec![i; 200])).collect::<Vec<_>>();
+        for text in &texts {
+            messages.send_message(&Chat { text: text.clone() }).unwrap();
+        }
+        messages.flush();
+
+        eventually(|| echoed.lock().unwrap().len() >= texts.len()).await;
+        assert_eq!(*echoed.lock().unwrap(), texts);
+    }
+
+    #[tokio::test]
+    async fn connects_to_the_address_that_answers() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        // nobody listens on the first address, like a server bound to IPv4 only that a
+        // hostname resolves to on IPv6 first
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let candidates = vec![silent.local_addr().unwrap(), addr];
+        let mut client = Client::bind_internal("127.0.0.1:0".parse().unwrap(), candidates).unwrap();
+        client.connect().await.unwrap();
+        assert_eq!(client.remote, addr);
+        assert_ne!(client.session, 0);
+
+        // the request is given up after a bounded number of attempts
+        let mut client = Client::bind_internal(
+            "127.0.0.1:0".parse().unwrap(),
+            vec![silent.local_addr().unwrap()],
+        )
+        .unwrap();
+        assert!(matches!(client.connect().await, Err(ErrorKind::ReadTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn negotiates_capabilities() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let mut client = Client::new(&addr).await.unwrap();
+        assert_eq!(client.capabilities(), Capabilities::local());
+        client.connect().await.unwrap();
+        assert_eq!(client.capabilities(), Capabilities::local());
+
+        let mut client = Client::new(&addr)
+            .await
+            .unwrap()
+            .with_capabilities(Capabilities::NONE);
+        client.connect().await.unwrap();
+        assert_eq!(client.capabilities(), Capabilities::NONE);
+    }
+
+    #[tokio::test]
+    async fn discovers_path_mtu() {
+        let mut server = Peer::bind_any().await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let senders = Arc::new(Mutex::new(Vec::new()));
+        let inbox = senders.clone();
+        server
+            .messages()
+            .register(move |from, _: Chat| inbox.lock().unwrap().push(from));
+        let discovered = Arc::new(Mutex::new(None));
+        let mtu = discovered.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Some(from) = senders.lock().unwrap().first() {
+                    *mtu.lock().unwrap() = server.path_mtu(from);
+                }
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let client = Client::new(&addr).await.unwrap();
+        client
+            .messages()
+            .send_message(&Chat { text: Bytes::from_static(b"hi") })
+            .unwrap();
+        tokio::spawn(client.run());
+
+        // localhost carries the largest probe
+        eventually(|| *discovered.lock().unwrap() >= Some(MAX_MTU - MTU_PROBE_PRECISION)).await;
+        assert!(discovered.lock().unwrap().unwrap() <= MAX_MTU);
+    }
+
+    #[tokio::test]
+    async fn punches_holes_between_introduced_clients() {
+        let mut server = Peer::bind_any().await.unwrap();
+        server.set_introducer(true);
+        let server_addr = server.local_addr().unwrap();
+        let addr = server_addr.to_string();
+        tokio::spawn(async move {
+            loop {
+                server.manual_poll(Instant::now()).await.unwrap();
+            }
+        });
+
+        let first = Client::new(&addr).await.unwrap().with_rendezvous(42);
+        let second = Client::new(&addr).await.unwrap().with_rendezvous(42);
+        let (first_id, second_id) = (first.id(), second.id());
+        let (first_link, second_link) = (first.link(), second.link());
+        let (first_session, second_session) = (first.rollback(), second.rollback());
+        first_session.lock().unwrap().add_local_input(0, Bytes::from_static(&[7])).unwrap();
+        second_session.lock().unwrap().add_local_input(0, Bytes::from_static(&[9])).unwrap();
+        assert_eq!(first_link.route(), None);
+        tokio::spawn(first.run());
+        tokio::spawn(second.run());
+
+        let direct = |route| matches!(route, Some(Route::Direct(addr)) if addr != server_addr);
+        eventually(|| direct(first_link.route()) && direct(second_link.route())).await;
+        assert!(direct(first_link.route()), "{:?}", first_link.route());
+        assert!(direct(second_link.route()), "{:?}", second_link.route());
+        assert_eq!(first_link.peer_id(), Some(second_id));
+        assert_eq!(second_link.peer_id(), Some(first_id));
+
+        eventually(|| second_session.lock().unwrap().confirmed_frame(first_id).is_some()).await;
+        assert_eq!(
+            second_session.lock().unwrap().input(first_id, 0).payload,
+            Bytes::from_static(&[7])
+        );
+
+        // the server still follows the frames sent over the direct path
+        for frame in 1..=10 {
+            first_session.lock().unwrap().add_local_input(frame, Bytes::from_static(&[7])).unwrap();
+            delay_for(Duration::from_millis(20)).await;
+        }
+        eventually(|| first_session.lock().unwrap().frame_advantage() == 10).await;
+        assert_eq!(first_session.lock().unwrap().frame_advantage(), 10);
+        let confirmed = || second_session.lock().unwrap().confirmed_frame(first_id);
+        eventually(|| confirmed() == Some(10)).await;
+        assert_eq!(confirmed(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn relays_client_data_once_through_mesh() {
+        let mut peers = [
+            Peer::bind_any().await.unwrap(),
+            Peer::bind_any().await.unwrap(),
+            Peer::bind_any().await.unwrap(),
+        ];
+        let addrs = peers.iter().map(|peer| peer.local_addr().unwrap()).collect::<Vec<_>>();
+        assert!(peers[1].connect(addrs[0]));
+        assert!(peers[2].connect(addrs[0]));
+        assert!(peers[2].connect(addrs[1]));
+        let mut connected = [0; 3];
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while connected.iter().any(|count| *count < 2) && Instant::now() < deadline {
+            join_all(peers.iter_mut().map(|peer| peer.manual_poll(Instant::now()))).await;
+            for (peer, count) in peers.iter_mut().zip(connected.iter_mut()) {
+                *count += peer.drain_events().filter(|e| matches!(e, PeerEvent::Connected(_))).count();
+            }
+        }
+        assert_eq!(connected, [2; 3]);
+        tokio::spawn(async move {
+            loop {
+                join_all(peers.iter_mut().map(|peer| peer.manual_poll(Instant::now()))).await;
+            }
+        });
+
+        let addr = addrs[0].to_string();
+        let mut sender = Client::new(&addr).await.unwrap();
+        let mut observer = Client::new(&addr).await.unwrap();
+        sender.connect().await.unwrap();
+        observer.connect().await.unwrap();
+
+        let data = OutgoingPacketBuilder::new(&[7; 32])
+            .with_default_header(PacketType::Data)
+            .with_session_header(sender.session)
+            .with_tick_header(0)
+            .build()
+            .unwrap();
+        sender.socket.send_to(&data.contents(), &sender.remote).await.unwrap();
+
+        // the first peer relays to the observer and the other members, which relay no further
+        let mut delivered = 0;
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while let Ok(received) = timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            observer.socket.recv_from(&mut observer.buf),
+        )
+        .await
+        {
+            let (len, _) = received.unwrap();
+            let mut reader = PacketReader::new(&observer.buf[..len]);
+            if reader.read_base_header().unwrap().packet_type() == PacketType::Data {
+                delivered += 1;
+            }
         }
+        assert_eq!(delivered, 1);
     }
 }
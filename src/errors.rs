@@ -17,10 +17,20 @@ pub enum ErrorKind {
     CouldNotReadHeader(String),
     /// Protocol version does not match
     ProtocolVersionMismatch,
+    /// No protocol version is supported by both peers, holds the range supported by the remote
+    ProtocolVersionUnsupported(u16, u16),
     /// Invalid session id
     SessionMismatch,
     /// Socket read timeout expired
     ReadTimeout(Elapsed),
+    /// The address could not be parsed, resolved or reached
+    InvalidAddress(String),
+    /// The packet with its headers, given as total size in bytes, does not fit into the MTU
+    PayloadTooLarge(usize),
+    /// Tried to read past the end of the packet
+    BufferUnderflow,
+    /// The configuration combines settings which do not work together
+    InvalidConfig(String),
 }
 
 impl Error for ErrorKind {}
@@ -40,10 +50,25 @@ impl Display for ErrorKind {
                 header
             ),
             ErrorKind::ProtocolVersionMismatch => write!(f, "The protocol versions do not match."),
+            ErrorKind::ProtocolVersionUnsupported(min, max) => write!(
+                f,
+                "The remote only supports the protocol versions {} to {}.",
+                min, max
+            ),
             ErrorKind::SessionMismatch => write!(f, "The session id does not match."),
             ErrorKind::ReadTimeout(elapsed) => {
                 write!(f, "The socket read timeout expired. Reason: {:}.", elapsed)
             }
+            ErrorKind::InvalidAddress(addr) => write!(f, "The address {} is not valid.", addr),
+            ErrorKind::PayloadTooLarge(size) => {
+                write!(f, "The packet of {} bytes does not fit into the MTU.", size)
+            }
+            ErrorKind::BufferUnderflow => {
+                write!(f, "Tried to read past the end of the packet buffer.")
+            }
+            ErrorKind::InvalidConfig(reason) => {
+                write!(f, "The configuration is not valid: {}.", reason)
+            }
         }
     }
 }
@@ -55,6 +80,16 @@ pub enum DecodingErrorKind {
     PacketType,
     /// The payload could not be read
     Payload,
+    /// The snapshot is truncated or does not match the snapshot config
+    Snapshot,
+    /// The snapshot was encoded against a baseline which is not available
+    Baseline,
+    /// The replicated entities could not be read
+    Replication,
+    /// The compressed payload could not be decompressed
+    Compression,
+    /// The header sets flags which are not supported
+    Flags,
 }
 
 impl Display for DecodingErrorKind {
@@ -62,6 +97,17 @@ impl Display for DecodingErrorKind {
         match *self {
             DecodingErrorKind::PacketType => write!(fmt, "The packet type could not be read."),
             DecodingErrorKind::Payload => write!(fmt, "The expected payload could not be read."),
+            DecodingErrorKind::Snapshot => write!(fmt, "The snapshot could not be read."),
+            DecodingErrorKind::Baseline => {
+                write!(fmt, "The baseline of the snapshot is not available.")
+            }
+            DecodingErrorKind::Replication => {
+                write!(fmt, "The replicated entities could not be read.")
+            }
+            DecodingErrorKind::Compression => {
+                write!(fmt, "The compressed payload could not be decompressed.")
+            }
+            DecodingErrorKind::Flags => write!(fmt, "The header flags are not supported."),
         }
     }
 }
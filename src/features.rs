@@ -1,5 +1,17 @@
 pub use self::connectivity::ConnectivityHandler;
+pub use self::interest::{AreaOfInterest, InterestSet, SpatialGrid};
+pub use self::introducer::{HolePunch, Introducer, Route};
+pub use self::negotiation::Capabilities;
+pub use self::path_mtu::PathMtu;
 pub use self::throughput::ThroughputMonitoring;
+pub use self::tick::TickScheduler;
+pub use self::time_sync::{ClockSync, TimeSample};
 
 mod connectivity;
-mod throughput;
\ No newline at end of file
+pub mod interest;
+pub mod introducer;
+pub mod negotiation;
+mod path_mtu;
+mod throughput;
+mod tick;
+pub mod time_sync;
\ No newline at end of file
@@ -1,20 +1,36 @@
-use std::net::SocketAddr;
+use std::time::Instant;
+
+use bytes::Bytes;
 
 use log::debug;
 
-use crate::{ErrorKind, OutgoingPacketBuilder, Packet};
+use crate::ErrorKind;
 use crate::errors::{DecodingErrorKind, Result};
 use crate::errors::ErrorKind::DecodingError;
-use crate::features::connectivity::ConnectivityState::{Connected, Disconnected, Pending};
-use crate::net::constants::CONNECT_PAYLOAD_SIZE;
+use crate::features::connectivity::ConnectivityState::{
+    Connected, Disconnected, Pending, Rejected, Requesting,
+};
+use crate::features::negotiation::{
+    accept_payload, put_offer, read_accept, read_offer, read_reject, reject_payload, Capabilities,
+};
+use crate::features::time_sync::{pong_payload, read_ping};
+use crate::features::TickScheduler;
+use crate::net::constants::{CONNECT_INTERVAL, CONNECT_PAYLOAD_SIZE};
 use crate::packet::{PacketReader, PacketType};
 use crate::packet::header::{BaseHeader, SessionHeader};
+use crate::protocol_version::ProtocolVersion;
+use bytes::{BufMut, BytesMut};
 use rand::random;
 
 #[derive(PartialEq)]
 enum ConnectivityState {
+    /// The peer got the challenge, its answer is awaited.
     Pending,
+    /// This side connects to the peer and awaits its challenge.
+    Requesting,
     Connected,
+    /// The peer supports no common protocol version, it is told so and dropped.
+    Rejected,
     Disconnected,
 }
 
@@ -22,14 +38,50 @@ pub struct ConnectivityHandler {
     state: ConnectivityState,
     id: u64,
     peer_id: Option<u64>,
+    /// Protocol version and optional features agreed on with the peer.
+    version: u16,
+    capabilities: Capabilities,
+    /// Origin timestamp and arrival of the last time sync ping not answered yet.
+    pending_ping: Option<(u64, Instant)>,
+    /// Whether this side initiated the connection.
+    outbound: bool,
+    /// Whether the other side is a peer of a mesh rather than a client.
+    mesh: bool,
+    /// Whether the connection was established at some point.
+    established: bool,
+    last_request: Option<Instant>,
+    /// The challenge of the peer has to be answered.
+    response_due: bool,
 }
 
 impl ConnectivityHandler {
-    pub fn new() -> Self {
+    /// Creates the handler of a connection the peer initiates, this side is known to it by `id`.
+    pub fn new(id: u64) -> Self {
         ConnectivityHandler {
             state: ConnectivityState::Pending,
-            id: random(),
+            id,
             peer_id: None,
+            version: 0,
+            capabilities: Capabilities::NONE,
+            pending_ping: None,
+            outbound: false,
+            mesh: false,
+            established: false,
+            last_request: None,
+            response_due: false,
+        }
+    }
+
+    /// Creates the handler of a connection this side initiates, it is known to the peer by `id`.
+    ///
+    /// The handshake mirrors the one of a client: the request offers the supported versions and
+    /// capabilities, the challenge of the peer carries the agreed ones and is answered once.
+    pub fn outbound(id: u64) -> Self {
+        ConnectivityHandler {
+            state: Requesting,
+            outbound: true,
+            mesh: true,
+            ..Self::new(id)
         }
     }
 
@@ -37,9 +89,46 @@ impl ConnectivityHandler {
         &mut self,
         header: &BaseHeader,
         reader: &mut PacketReader,
+        time: Instant,
     ) -> Result<()> {
         let session = reader.read_session_header()?;
 
+        if self.state == Requesting {
+            match header.packet_type() {
+                PacketType::Connect if session.session_id() != 0 => {
+                    let peer_id = reader.read_id_header()?.session_id();
+                    return self.accept_challenge(&session, peer_id, reader);
+                }
+                // both sides connect at the same time, the one with the lower id answers
+                PacketType::Connect => {
+                    if self.id > reader.read_id_header()?.session_id() {
+                        return Ok(());
+                    }
+                    self.state = Pending;
+                    self.outbound = false;
+                }
+                PacketType::Reject => {
+                    reader.read_tick_header()?;
+                    let versions = read_reject(&reader.read_payload()?)?;
+                    self.disconnect();
+                    return Err(ErrorKind::ProtocolVersionUnsupported(
+                        *versions.start(),
+                        *versions.end(),
+                    ));
+                }
+                _ => return Err(ErrorKind::SessionMismatch),
+            }
+        } else if self.outbound && header.packet_type() == PacketType::Connect {
+            // a late request of a peer connecting at the same time is ignored, the challenge is
+            // repeated until its answer arrives
+            if session.session_id() == 0 {
+                return Ok(());
+            }
+            self.check_session(&session)?;
+            self.response_due = true;
+            return Ok(());
+        }
+
         if header.packet_type() == PacketType::Connect {
             let peer_id = reader.read_id_header()?;
             if !reader.can_read(CONNECT_PAYLOAD_SIZE) {
@@ -47,34 +136,148 @@ impl ConnectivityHandler {
             }
 
             if self.peer_id.is_none() {
+                let (versions, offered, mesh) = read_offer(&reader.read_payload()?)?;
                 self.peer_id = Some(peer_id.session_id());
+                self.mesh = mesh;
+                match ProtocolVersion::negotiate(versions) {
+                    Some(version) => {
+                        self.version = version;
+                        self.capabilities = offered & Capabilities::local();
+                    }
+                    None => self.state = Rejected,
+                }
                 return Ok(());
             }
         }
         self.check_session(&session)?;
 
-        if header.packet_type() == PacketType::Disconnect {
-            self.disconnect();
+        match header.packet_type() {
+            PacketType::Disconnect => self.disconnect(),
+            PacketType::TimePing => {
+                reader.read_tick_header()?;
+                let origin = read_ping(&reader.read_payload()?)?;
+                self.pending_ping = Some((origin, time));
+            }
+            _ => (),
         }
 
         Ok(())
     }
 
+    /// Takes the agreed version and capabilities from the challenge of the peer with `peer_id`.
+    fn accept_challenge(
+        &mut self,
+        session: &SessionHeader,
+        peer_id: u64,
+        reader: &mut PacketReader,
+    ) -> Result<()> {
+        let (version, capabilities) = read_accept(&reader.read_payload()?)?;
+        if !ProtocolVersion::supported().contains(&version) {
+            self.disconnect();
+            return Err(ErrorKind::ProtocolVersionMismatch);
+        }
+        self.peer_id = Some(peer_id);
+        self.check_session(session)?;
+        self.version = version;
+        self.capabilities = capabilities & Capabilities::local();
+        debug!("connected!");
+        self.state = Connected;
+        self.established = true;
+        self.response_due = true;
+        Ok(())
+    }
+
     pub fn session_id(&self) -> u64 {
         self.peer_id.map(|id| id ^ self.id).unwrap_or(0)
     }
 
-    pub fn create_connection_packet(&self, addr: SocketAddr) -> Option<Packet> {
-        // challenge request to client
-        if self.state == Pending {
-            let out = OutgoingPacketBuilder::new(&[])
-                .with_session_header(self.session_id())
-                .with_session_header(self.id)
-                .build();
-            return Some(Packet::new(addr, out.contents()));
+    /// Returns the id the peer sent with its connect request.
+    pub fn peer_id(&self) -> Option<u64> {
+        self.peer_id
+    }
+
+    /// Returns the optional features both sides support.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Creates the type and payload of the next handshake packet at `time`, if one is due.
+    ///
+    /// The challenge carries the id of this side followed by the agreed version and
+    /// capabilities. A peer without a common version gets a single `Reject` and is dropped.
+    /// Outbound connections repeat their request every [CONNECT_INTERVAL] until the challenge
+    /// arrives.
+    ///
+    /// [CONNECT_INTERVAL]: ../../net/constants/constant.CONNECT_INTERVAL.html
+    pub fn create_connection_packet(&mut self, time: Instant) -> Option<(PacketType, Bytes)> {
+        match self.state {
+            Requesting => {
+                if matches!(self.last_request, Some(last) if time < last + CONNECT_INTERVAL) {
+                    return None;
+                }
+                self.last_request = Some(time);
+                Some((PacketType::Connect, self.request_payload()))
+            }
+            Connected if self.response_due => {
+                self.response_due = false;
+                Some((PacketType::Connect, self.request_payload()))
+            }
+            // challenge request to client
+            Pending => {
+                let accept = accept_payload(self.version, self.capabilities);
+                let mut payload = BytesMut::with_capacity(8 + accept.len());
+                payload.put_u64(self.id);
+                payload.put_slice(&accept);
+                Some((PacketType::Connect, payload.freeze()))
+            }
+            Rejected => {
+                self.state = Disconnected;
+                Some((PacketType::Reject, reject_payload(ProtocolVersion::supported())))
+            }
+            _ => None,
         }
+    }
 
-        None
+    /// Creates the payload answering the last time sync ping, if any, with timestamps and the
+    /// tick period of the `clock`.
+    pub fn create_time_packet(&mut self, clock: &TickScheduler, time: Instant) -> Option<Bytes> {
+        self.pending_ping.take().map(|(origin, received)| {
+            pong_payload(
+                origin,
+                clock.elapsed(received).as_micros() as u64,
+                clock.elapsed(time).as_micros() as u64,
+                clock.period(),
+            )
+        })
+    }
+
+    /// Creates the payload of a connect request, which answers the challenge once connected.
+    ///
+    /// The id of this side is followed by the offer, padded to [CONNECT_PAYLOAD_SIZE].
+    ///
+    /// [CONNECT_PAYLOAD_SIZE]: ../../net/constants/constant.CONNECT_PAYLOAD_SIZE.html
+    fn request_payload(&self) -> Bytes {
+        let mut payload = BytesMut::with_capacity(8 + CONNECT_PAYLOAD_SIZE);
+        payload.put_u64(self.id);
+        let mut offer = [0; CONNECT_PAYLOAD_SIZE];
+        put_offer(&mut offer, ProtocolVersion::supported(), Capabilities::local(), true);
+        payload.put_slice(&offer);
+        payload.freeze()
+    }
+
+    /// Checks if this side initiated the connection.
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// Checks if the other side is a peer of a mesh, either side may have initiated it.
+    pub fn is_mesh(&self) -> bool {
+        self.mesh
+    }
+
+    /// Checks if the connection was established at some point, it may be closed by now.
+    pub fn was_established(&self) -> bool {
+        self.established
     }
 
     pub fn should_drop(&self) -> bool {
@@ -85,12 +288,15 @@ impl ConnectivityHandler {
         self.state == Connected
     }
 
-    fn disconnect(&mut self) {
+    pub fn disconnect(&mut self) {
         debug!("disconnected!");
         self.state = Disconnected;
     }
 
     fn check_session(&mut self, session: &SessionHeader) -> Result<()> {
+        if self.state == Rejected {
+            return Err(ErrorKind::ProtocolVersionMismatch);
+        }
         if session.session_id() != self.session_id() {
             self.disconnect();
             return Err(ErrorKind::SessionMismatch);
@@ -99,6 +305,7 @@ impl ConnectivityHandler {
         if self.state == Pending {
             debug!("connected!");
             self.state = Connected;
+            self.established = true;
         }
         Ok(())
     }
@@ -106,6 +313,125 @@ impl ConnectivityHandler {
 
 impl Default for ConnectivityHandler {
     fn default() -> Self {
-        Self::new()
+        Self::new(random())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::RangeInclusive;
+    use std::time::Instant;
+
+    use crate::features::negotiation::{put_offer, read_accept, read_reject, Capabilities};
+    use crate::net::constants::{CONNECT_PAYLOAD_SIZE, PROTOCOL_VERSION};
+    use crate::packet::{PacketReader, PacketType};
+    use crate::protocol_version::ProtocolVersion;
+    use crate::OutgoingPacketBuilder;
+
+    use super::ConnectivityHandler;
+
+    fn connect(handler: &mut ConnectivityHandler, versions: RangeInclusive<u16>) {
+        let mut payload = [0; CONNECT_PAYLOAD_SIZE];
+        let offered = Capabilities::COMPRESSION | Capabilities::ENCRYPTION;
+        put_offer(&mut payload, versions, offered, false);
+        let request = OutgoingPacketBuilder::new(&payload)
+            .with_default_header(PacketType::Connect)
+            .with_session_header(0)
+            .with_tick_header(0)
+            .with_session_header(7)
+            .build()
+            .unwrap()
+            .contents();
+        let mut reader = PacketReader::new(&request);
+        let header = reader.read_base_header().unwrap();
+        handler.process_in(&header, &mut reader, Instant::now()).unwrap();
+    }
+
+    #[test]
+    fn negotiates_version_and_capabilities() {
+        let mut handler = ConnectivityHandler::new(1);
+        connect(&mut handler, 1..=u16::MAX);
+        assert_eq!(handler.capabilities(), Capabilities::local());
+        assert!(!handler.is_mesh());
+
+        let (ptype, challenge) = handler.create_connection_packet(Instant::now()).unwrap();
+        assert_eq!(ptype, PacketType::Connect);
+        let (version, capabilities) = read_accept(&challenge[8..]).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(capabilities, Capabilities::local());
+        assert!(!handler.should_drop());
+    }
+
+    /// Hands the next handshake packet of `from` to `to`, returns its type.
+    fn exchange(
+        from: &mut ConnectivityHandler,
+        to: &mut ConnectivityHandler,
+        time: Instant,
+    ) -> Option<PacketType> {
+        let (ptype, payload) = from.create_connection_packet(time)?;
+        let packet = OutgoingPacketBuilder::new(&payload)
+            .with_default_header(ptype)
+            .with_session_header(from.session_id())
+            .with_tick_header(0)
+            .build()
+            .unwrap()
+            .contents();
+        let mut reader = PacketReader::new(&packet);
+        let header = reader.read_base_header().unwrap();
+        let _ = to.process_in(&header, &mut reader, time);
+        Some(ptype)
+    }
+
+    #[test]
+    fn connects_outbound() {
+        let time = Instant::now();
+        let mut outbound = ConnectivityHandler::outbound(5);
+        let mut inbound = ConnectivityHandler::new(9);
+        assert!(outbound.is_outbound());
+
+        assert_eq!(exchange(&mut outbound, &mut inbound, time), Some(PacketType::Connect));
+        // the request is repeated only after a while
+        assert!(outbound.create_connection_packet(time).is_none());
+        assert_eq!(exchange(&mut inbound, &mut outbound, time), Some(PacketType::Connect));
+        assert!(outbound.is_connected() && !inbound.is_connected());
+        assert_eq!(outbound.peer_id(), Some(9));
+        assert_eq!(outbound.capabilities(), Capabilities::local());
+
+        // the answer to the challenge establishes the connection on the other side
+        assert_eq!(exchange(&mut outbound, &mut inbound, time), Some(PacketType::Connect));
+        assert!(inbound.is_connected() && inbound.was_established());
+        assert!(inbound.is_mesh() && outbound.is_mesh());
+        assert_eq!(inbound.peer_id(), Some(5));
+        assert_eq!(inbound.session_id(), outbound.session_id());
+        assert!(exchange(&mut outbound, &mut inbound, time).is_none());
+        assert!(exchange(&mut inbound, &mut outbound, time).is_none());
+    }
+
+    #[test]
+    fn resolves_simultaneous_connects() {
+        let time = Instant::now();
+        let mut low = ConnectivityHandler::outbound(3);
+        let mut high = ConnectivityHandler::outbound(8);
+        exchange(&mut low, &mut high, time);
+        exchange(&mut high, &mut low, time);
+        // the side with the lower id answers the request of the other one
+        assert!(!low.is_outbound() && high.is_outbound());
+
+        exchange(&mut low, &mut high, time);
+        exchange(&mut high, &mut low, time);
+        assert!(low.is_connected() && high.is_connected());
+        assert_eq!(low.session_id(), high.session_id());
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut handler = ConnectivityHandler::new(1);
+        connect(&mut handler, PROTOCOL_VERSION + 1..=PROTOCOL_VERSION + 3);
+
+        let (ptype, reject) = handler.create_connection_packet(Instant::now()).unwrap();
+        assert_eq!(ptype, PacketType::Reject);
+        assert_eq!(read_reject(&reject).unwrap(), ProtocolVersion::supported());
+        assert!(handler.should_drop());
+        assert!(handler.create_connection_packet(Instant::now()).is_none());
     }
 }
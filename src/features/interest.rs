@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::Result;
+use crate::net::constants::INTEREST_HYSTERESIS;
+
+/// Sphere a peer wants to receive updates about.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AreaOfInterest {
+    pub center: [f32; 3],
+    pub radius: f32,
+}
+
+/// Creates the payload of an `Interest` packet.
+pub fn interest_payload(area: &AreaOfInterest) -> Bytes {
+    let mut payload = BytesMut::with_capacity(16);
+    for coordinate in &area.center {
+        payload.put_f32(*coordinate);
+    }
+    payload.put_f32(area.radius);
+    payload.freeze()
+}
+
+/// Reads an `Interest` payload.
+pub fn read_interest(payload: &[u8]) -> Result<AreaOfInterest> {
+    let mut rdr = Cursor::new(payload);
+    let mut center = [0.0; 3];
+    for coordinate in &mut center {
+        *coordinate = rdr.read_f32::<BigEndian>()?;
+    }
+    Ok(AreaOfInterest {
+        center,
+        radius: rdr.read_f32::<BigEndian>()?,
+    })
+}
+
+/// Uniform grid of cubic cells to find the keys near a position.
+#[derive(Clone, Debug)]
+pub struct SpatialGrid<K> {
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<(K, [f32; 3])>>,
+}
+
+impl<K: Copy> SpatialGrid<K> {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    pub fn insert(&mut self, key: K, position: [f32; 3]) {
+        self.cells
+            .entry(self.cell(position))
+            .or_default()
+            .push((key, position));
+    }
+
+    /// Returns the keys within `radius` of `center` along with their distance to it.
+    pub fn query(&self, center: [f32; 3], radius: f32) -> Vec<(K, f32)> {
+        let min = self.cell([center[0] - radius, center[1] - radius, center[2] - radius]);
+        let max = self.cell([center[0] + radius, center[1] + radius, center[2] + radius]);
+        let covered = (0..3)
+            .map(|i| (max[i] as i64 - min[i] as i64 + 1) as u64)
+            .fold(1_u64, u64::saturating_mul);
+
+        let within = |(key, position): &(K, [f32; 3])| {
+            let distance = distance(center, *position);
+            if distance <= radius {
+                Some((*key, distance))
+            } else {
+                None
+            }
+        };
+        // a large area covers more cells than there are occupied ones
+        if covered > self.cells.len() as u64 {
+            return self
+                .cells
+                .iter()
+                .filter(|(cell, _)| (0..3).all(|i| cell[i] >= min[i] && cell[i] <= max[i]))
+                .flat_map(|(_, keys)| keys.iter().filter_map(within))
+                .collect();
+        }
+
+        let mut found = Vec::new();
+        for x in min[0]..=max[0] {
+            for y in min[1]..=max[1] {
+                for z in min[2]..=max[2] {
+                    if let Some(keys) = self.cells.get(&[x, y, z]) {
+                        found.extend(keys.iter().filter_map(within));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    fn cell(&self, position: [f32; 3]) -> [i32; 3] {
+        let index = |coordinate: f32| (coordinate / self.cell_size).floor() as i32;
+        [index(position[0]), index(position[1]), index(position[2])]
+    }
+}
+
+/// Keys inside the area of interest of one peer.
+///
+/// A key enters the set within the radius of the area but only leaves it beyond the radius
+/// widened by [INTEREST_HYSTERESIS], so keys moving along the boundary do not flicker in and
+/// out.
+#[derive(Clone, Debug, Default)]
+pub struct InterestSet<K: Eq + Hash> {
+    visible: HashSet<K>,
+}
+
+impl<K: Copy + Eq + Hash> InterestSet<K> {
+    pub fn new() -> Self {
+        InterestSet {
+            visible: HashSet::new(),
+        }
+    }
+
+    /// Updates the set from the keys in `grid`.
+    pub fn update(&mut self, grid: &SpatialGrid<K>, area: &AreaOfInterest) {
+        let outer = area.radius * (1.0 + INTEREST_HYSTERESIS);
+        let visible = &self.visible;
+        self.visible = grid
+            .query(area.center, outer)
+            .into_iter()
+            .filter(|(key, distance)| *distance <= area.radius || visible.contains(key))
+            .map(|(key, _)| key)
+            .collect();
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.visible.contains(key)
+    }
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    let d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interest_payload, read_interest, AreaOfInterest, InterestSet, SpatialGrid};
+
+    fn grid(positions: &[f32]) -> SpatialGrid<u32> {
+        let mut grid = SpatialGrid::new(10.0);
+        for (key, x) in positions.iter().enumerate() {
+            grid.insert(key as u32, [*x, 0.0, 0.0]);
+        }
+        grid
+    }
+
+    #[test]
+    fn finds_keys_in_radius() {
+        let grid = grid(&[0.0, 9.0, 25.0, -31.0, 500.0]);
+
+        let mut found = grid.query([0.0; 3], 30.0);
+        found.sort_by_key(|(key, _)| *key);
+        assert_eq!(found.iter().map(|(key, _)| *key).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(found[2].1, 25.0);
+
+        // covers more cells than there are occupied ones
+        assert_eq!(grid.query([0.0; 3], 10_000.0).len(), 5);
+    }
+
+    #[test]
+    fn hysteresis_keeps_keys_near_boundary() {
+        let area = AreaOfInterest { center: [0.0; 3], radius: 100.0 };
+        let mut set = InterestSet::new();
+
+        set.update(&grid(&[99.0, 105.0]), &area);
+        assert!(set.contains(&0));
+        assert!(!set.contains(&1));
+
+        // moving slightly outside keeps it, moving far enough away drops it
+        set.update(&grid(&[105.0, 105.0]), &area);
+        assert!(set.contains(&0));
+        assert!(!set.contains(&1));
+        set.update(&grid(&[111.0, 105.0]), &area);
+        assert!(!set.contains(&0));
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let area = AreaOfInterest { center: [1.0, -2.0, 3.5], radius: 50.0 };
+        assert_eq!(read_interest(&interest_payload(&area)).unwrap(), area);
+        assert!(read_interest(&[0; 12]).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+//! Introduction of clients behind NATs to each other by the server.
+//!
+//! Two clients register the same key with a server running as introducer, each one is told the
+//! id of the other and the address the server sees it at. Both then send `Punch` packets to
+//! that address at the same time, which opens their NATs for the traffic of the other. When no
+//! punch arrives within [PUNCH_ATTEMPTS] attempts their packets keep going through the server,
+//! which relays them like those of every other peer. An open path is kept alive with punches
+//! and given up once nothing arrives over it for [DIRECT_PATH_TIMEOUT].
+//!
+//! [PUNCH_ATTEMPTS]: ../../net/constants/constant.PUNCH_ATTEMPTS.html
+//! [DIRECT_PATH_TIMEOUT]: ../../net/constants/constant.DIRECT_PATH_TIMEOUT.html
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Instant;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::debug;
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::{
+    DIRECT_PATH_TIMEOUT, PUNCH_ATTEMPTS, PUNCH_INTERVAL, PUNCH_KEEPALIVE_INTERVAL,
+};
+
+/// Where the packets to an introduced peer go.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Hole punching is in progress, packets go through the server meanwhile.
+    Punching,
+    /// Straight to the peer at this address.
+    Direct(SocketAddr),
+    /// Through the server, hole punching failed.
+    Relayed,
+}
+
+/// Pairs the peers registering the same key.
+#[derive(Debug, Default)]
+pub struct Introducer {
+    /// Address and id of the peers registered for each key, two at most.
+    keys: HashMap<u64, Vec<(SocketAddr, u64)>>,
+}
+
+impl Introducer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers the peer with `id` at `addr` for `key`, returns the introductions to send.
+    ///
+    /// The second peer registering a key is introduced to the first one and the other way
+    /// around. Repeated registrations of a paired peer get its introduction again, in case it was
+    /// lost. Further peers registering a taken key are ignored.
+    pub fn register(&mut self, key: u64, addr: SocketAddr, id: u64) -> Vec<(SocketAddr, Bytes)> {
+        let peers = self.keys.entry(key).or_default();
+        let repeated = peers.iter().any(|(registered, _)| *registered == addr);
+        if !repeated {
+            if peers.len() == 2 {
+                debug!("ignoring registration of {}, key {} is taken", addr, key);
+                return Vec::new();
+            }
+            peers.push((addr, id));
+        }
+
+        match peers.as_slice() {
+            [first, second] => vec![(first.0, second), (second.0, first)]
+                .into_iter()
+                .filter(|(to, _)| !repeated || *to == addr)
+                .map(|(to, (endpoint, id))| (to, introduction_payload(*id, *endpoint)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Moves the registrations of the peer at `from` to its new address `to`.
+    pub fn migrate(&mut self, from: &SocketAddr, to: SocketAddr) {
+        for (addr, _) in self.keys.values_mut().flat_map(|peers| peers.iter_mut()) {
+            if addr == from {
+                *addr = to;
+            }
+        }
+    }
+
+    /// Forgets the registrations of the peer at `addr`.
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.keys.retain(|_, peers| {
+            peers.retain(|(registered, _)| registered != addr);
+            !peers.is_empty()
+        });
+    }
+}
+
+/// Hole punching towards a peer the introducer paired this side with.
+#[derive(Debug)]
+pub struct HolePunch {
+    peer_id: u64,
+    endpoint: SocketAddr,
+    route: Route,
+    attempts: u32,
+    next: Instant,
+    /// Arrival of the last packet over the open path.
+    heard: Instant,
+    /// A punch of the peer arrived which was not answered yet.
+    answer_due: bool,
+}
+
+impl HolePunch {
+    /// Starts punching towards the peer with `peer_id` at `endpoint`, a peer which cannot be
+    /// reached from this side is relayed right away.
+    pub fn new(peer_id: u64, endpoint: Option<SocketAddr>, time: Instant) -> Self {
+        HolePunch {
+            peer_id,
+            endpoint: endpoint.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)),
+            route: endpoint.map_or(Route::Relayed, |_| Route::Punching),
+            attempts: 0,
+            next: time,
+            heard: time,
+            answer_due: false,
+        }
+    }
+
+    /// Returns the id of the peer.
+    pub fn peer_id(&self) -> u64 {
+        self.peer_id
+    }
+
+    pub fn route(&self) -> Route {
+        self.route
+    }
+
+    /// Returns the address to send the next punch to at `time`, if one is due, and whether it
+    /// needs no answer.
+    ///
+    /// Falls back to relaying once all attempts went unanswered or the open path went quiet.
+    /// Keepalive punches over the open path need no answer.
+    pub fn next_punch(&mut self, time: Instant) -> Option<(SocketAddr, bool)> {
+        if let Route::Direct(addr) = self.route {
+            if self.answer_due {
+                self.answer_due = false;
+                return Some((addr, true));
+            }
+            if time >= self.heard + DIRECT_PATH_TIMEOUT {
+                debug!("path to {} went quiet, relaying", addr);
+                self.route = Route::Relayed;
+                return None;
+            }
+            if time < self.next {
+                return None;
+            }
+            self.next = time + PUNCH_KEEPALIVE_INTERVAL;
+            return Some((addr, true));
+        }
+        if self.route != Route::Punching || time < self.next {
+            return None;
+        }
+        if self.attempts == PUNCH_ATTEMPTS {
+            debug!("hole punching to {} failed, relaying", self.endpoint);
+            self.route = Route::Relayed;
+            return None;
+        }
+        self.attempts += 1;
+        self.next = time + PUNCH_INTERVAL;
+        Some((self.endpoint, false))
+    }
+
+    /// Handles a punch of the peer arriving from `from` at `time`, the path is open from now on.
+    ///
+    /// Its NAT may have mapped the peer to another port for this side than for the server, so
+    /// `from` replaces the introduced address. Punches which are not answers are answered once.
+    pub fn receive(&mut self, from: SocketAddr, answer: bool, time: Instant) {
+        if self.route != Route::Direct(from) {
+            self.next = time + PUNCH_KEEPALIVE_INTERVAL;
+        }
+        self.route = Route::Direct(from);
+        self.heard = time;
+        self.answer_due |= !answer;
+    }
+
+    /// Handles other traffic of the peer arriving from `from` at `time`, it keeps the open path
+    /// alive.
+    pub fn heard(&mut self, from: SocketAddr, time: Instant) {
+        if self.route == Route::Direct(from) {
+            self.heard = time;
+        }
+    }
+}
+
+/// Creates the payload of a `Register` packet.
+pub fn register_payload(key: u64) -> Bytes {
+    let mut payload = BytesMut::with_capacity(8);
+    payload.put_u64(key);
+    payload.freeze()
+}
+
+/// Reads the key of a `Register` packet.
+pub fn read_register(payload: &[u8]) -> Result<u64> {
+    Ok(Cursor::new(payload).read_u64::<BigEndian>()?)
+}
+
+/// Creates the payload of an `Introduction` packet to the peer with `id` at `endpoint`.
+///
+/// IPv4-mapped addresses are written as IPv4 addresses, the receiver may not be dual-stack.
+pub fn introduction_payload(id: u64, endpoint: SocketAddr) -> Bytes {
+    let mut payload = BytesMut::with_capacity(8 + 1 + 16 + 2);
+    payload.put_u64(id);
+    let ip = match endpoint.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(ip) => {
+            payload.put_u8(4);
+            payload.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            payload.put_u8(6);
+            payload.put_slice(&ip.octets());
+        }
+    }
+    payload.put_u16(endpoint.port());
+    payload.freeze()
+}
+
+/// Reads the id and address of the peer from an `Introduction` payload.
+pub fn read_introduction(payload: &[u8]) -> Result<(u64, SocketAddr)> {
+    let mut rdr = Cursor::new(payload);
+    let id = rdr.read_u64::<BigEndian>()?;
+    let ip = match rdr.read_u8()? {
+        4 => IpAddr::V4(Ipv4Addr::from(rdr.read_u32::<BigEndian>()?)),
+        6 => IpAddr::V6(Ipv6Addr::from(rdr.read_u128::<BigEndian>()?)),
+        _ => return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload)),
+    };
+    Ok((id, SocketAddr::new(ip, rdr.read_u16::<BigEndian>()?)))
+}
+
+/// Creates the payload of a `Punch` packet, `answer` marks the answer to a punch of the peer.
+pub fn punch_payload(answer: bool) -> Bytes {
+    Bytes::from_static(if answer { &[1] } else { &[0] })
+}
+
+/// Reads whether a `Punch` packet answers a punch of the receiver.
+pub fn read_punch(payload: &[u8]) -> Result<bool> {
+    match payload.first() {
+        Some(flag) => Ok(*flag == 1),
+        None => Err(ErrorKind::DecodingError(DecodingErrorKind::Payload)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// NAT in front of a peer, only passing inbound datagrams from addresses it sent to.
+    struct Nat {
+        /// Address the server sees the peer at.
+        public: SocketAddr,
+        /// Maps the peer to another port for every other destination than the server.
+        symmetric: bool,
+        opened: HashSet<SocketAddr>,
+    }
+
+    impl Nat {
+        fn new(public: &str, symmetric: bool) -> Self {
+            Nat {
+                public: addr(public),
+                symmetric,
+                opened: HashSet::new(),
+            }
+        }
+
+        /// Returns the address the peer is mapped to for datagrams to and from other peers.
+        fn mapping(&self) -> SocketAddr {
+            match self.symmetric {
+                true => SocketAddr::new(self.public.ip(), self.public.port() + 1),
+                false => self.public,
+            }
+        }
+
+        /// Returns the address a datagram to `to` leaves with.
+        fn send(&mut self, to: SocketAddr) -> SocketAddr {
+            self.opened.insert(to);
+            self.mapping()
+        }
+
+        fn passes(&self, from: &SocketAddr, to: &SocketAddr) -> bool {
+            self.opened.contains(from) && *to == self.mapping()
+        }
+    }
+
+    /// Introduces two peers behind `nats` and lets them punch until both settle, the whole
+    /// exchange runs in-process.
+    fn punch(mut nats: [Nat; 2]) -> [Route; 2] {
+        let mut introducer = Introducer::new();
+        assert!(introducer.register(7, nats[0].public, 100).is_empty());
+        let introductions = introducer.register(7, nats[1].public, 200);
+        assert_eq!(introductions.len(), 2);
+
+        let mut time = Instant::now();
+        let mut sides = [nats[0].public, nats[1].public].map(|public| {
+            let (_, payload) = introductions.iter().find(|(to, _)| *to == public).unwrap();
+            let (id, endpoint) = read_introduction(payload).unwrap();
+            HolePunch::new(id, Some(endpoint), time)
+        });
+        assert_eq!(sides[0].peer_id(), 200);
+
+        for _ in 0..=PUNCH_ATTEMPTS * 2 {
+            for i in 0..2 {
+                while let Some((to, answer)) = sides[i].next_punch(time) {
+                    let from = nats[i].send(to);
+                    let other = 1 - i;
+                    if nats[other].passes(&from, &to) {
+                        let flag = read_punch(&punch_payload(answer)).unwrap();
+                        sides[other].receive(from, flag, time);
+                    }
+                }
+            }
+            time += PUNCH_INTERVAL;
+        }
+        [sides[0].route(), sides[1].route()]
+    }
+
+    #[test]
+    fn punches_through_cone_nats() {
+        let routes = punch([Nat::new("1.1.1.1:4000", false), Nat::new("2.2.2.2:5000", false)]);
+        assert_eq!(routes, [Route::Direct(addr("2.2.2.2:5000")), Route::Direct(addr("1.1.1.1:4000"))]);
+    }
+
+    #[test]
+    fn gives_up_quiet_paths() {
+        let time = Instant::now();
+        let peer = addr("2.2.2.2:5000");
+        let mut punch = HolePunch::new(1, Some(peer), time);
+        punch.receive(peer, true, time);
+        assert_eq!(punch.route(), Route::Direct(peer));
+
+        // keepalives go out while inputs arrive over the path
+        assert!(punch.next_punch(time).is_none());
+        let later = time + PUNCH_KEEPALIVE_INTERVAL;
+        assert_eq!(punch.next_punch(later), Some((peer, true)));
+        assert!(punch.next_punch(later).is_none());
+        punch.heard(peer, later);
+        punch.heard(addr("3.3.3.3:1"), later + DIRECT_PATH_TIMEOUT);
+        assert!(punch.next_punch(later + DIRECT_PATH_TIMEOUT / 2).is_some());
+        assert_eq!(punch.route(), Route::Direct(peer));
+
+        punch.next_punch(later + DIRECT_PATH_TIMEOUT);
+        assert_eq!(punch.route(), Route::Relayed);
+        assert!(punch.next_punch(later + DIRECT_PATH_TIMEOUT * 2).is_none());
+    }
+
+    #[test]
+    fn falls_back_to_relaying() {
+        // the symmetric NAT uses another port for the peer than the one the server saw
+        let routes = punch([Nat::new("1.1.1.1:4000", false), Nat::new("2.2.2.2:5000", true)]);
+        assert_eq!(routes, [Route::Relayed, Route::Relayed]);
+
+        let mut unreachable = HolePunch::new(1, None, Instant::now());
+        assert_eq!(unreachable.route(), Route::Relayed);
+        assert!(unreachable.next_punch(Instant::now()).is_none());
+    }
+
+    #[test]
+    fn introduces_pairs_once() {
+        let (a, b, c) = (addr("1.1.1.1:1"), addr("[::ffff:2.2.2.2]:2"), addr("3.3.3.3:3"));
+        let mut introducer = Introducer::new();
+        assert!(introducer.register(1, a, 10).is_empty());
+        assert!(introducer.register(1, a, 10).is_empty());
+        assert_eq!(introducer.register(1, b, 20).len(), 2);
+
+        // a lost introduction is sent again, the key is taken for others
+        let again = introducer.register(1, b, 20);
+        assert_eq!(again.len(), 1);
+        assert_eq!(read_introduction(&again[0].1).unwrap(), (10, a));
+        let to_a = introducer.register(1, a, 10);
+        assert_eq!(read_introduction(&to_a[0].1).unwrap(), (20, addr("2.2.2.2:2")));
+        assert!(introducer.register(1, c, 30).is_empty());
+
+        introducer.migrate(&b, c);
+        assert_eq!(introducer.register(1, c, 30).len(), 1);
+        introducer.remove(&a);
+        assert_eq!(introducer.register(1, b, 20).len(), 2);
+
+        assert_eq!(read_register(&register_payload(9)).unwrap(), 9);
+        assert!(read_introduction(&[0; 9]).is_err());
+    }
+}
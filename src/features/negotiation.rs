@@ -0,0 +1,164 @@
+//! Agreement on the protocol version and optional features in the connect exchange.
+//!
+//! The connect request offers the range of protocol versions and the capabilities of the client,
+//! and tells whether it comes from a peer of a mesh.
+//! The server answers its challenge with the newest common version and the common capabilities,
+//! or with a `Reject` packet carrying the versions it supports.
+use std::io::Cursor;
+use std::ops::{BitAnd, BitOr, RangeInclusive};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+
+/// Size of the offer at the start of a connect request payload.
+pub const OFFER_SIZE: usize = 6;
+
+/// Optional features a peer supports, only the ones supported by both sides are used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u8);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Large payloads are lz4 compressed.
+    pub const COMPRESSION: Capabilities = Capabilities(0b001);
+    /// Payloads are encrypted, not supported by this version.
+    pub const ENCRYPTION: Capabilities = Capabilities(0b010);
+    /// Payloads larger than the MTU are split into fragments, not supported by this version.
+    pub const FRAGMENTATION: Capabilities = Capabilities(0b100);
+
+    /// Returns the capabilities of this build, compression needs the `compression` feature.
+    pub fn local() -> Self {
+        if cfg!(feature = "compression") {
+            Self::COMPRESSION
+        } else {
+            Self::NONE
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Capabilities(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+/// Writes the offer of a connect request, the rest of the payload is padding.
+///
+/// `mesh` tells the other side that the request comes from a peer of a mesh, not a client.
+pub fn put_offer(
+    payload: &mut [u8],
+    versions: RangeInclusive<u16>,
+    capabilities: Capabilities,
+    mesh: bool,
+) {
+    let mut offer = &mut payload[..OFFER_SIZE];
+    offer.put_u16(*versions.start());
+    offer.put_u16(*versions.end());
+    offer.put_u8(capabilities.bits());
+    offer.put_u8(mesh as u8);
+}
+
+/// Reads the offered versions and capabilities of a connect request and whether it comes from
+/// a peer of a mesh.
+pub fn read_offer(payload: &[u8]) -> Result<(RangeInclusive<u16>, Capabilities, bool)> {
+    let mut rdr = checked(payload, OFFER_SIZE)?;
+    let min = rdr.read_u16::<BigEndian>()?;
+    let max = rdr.read_u16::<BigEndian>()?;
+    let capabilities = Capabilities(rdr.read_u8()?);
+    Ok((min..=max, capabilities, rdr.read_u8()? == 1))
+}
+
+/// Creates the payload accepting a connect request with the agreed version and capabilities.
+pub fn accept_payload(version: u16, capabilities: Capabilities) -> Bytes {
+    let mut payload = BytesMut::with_capacity(3);
+    payload.put_u16(version);
+    payload.put_u8(capabilities.bits());
+    payload.freeze()
+}
+
+/// Reads the agreed version and capabilities.
+pub fn read_accept(payload: &[u8]) -> Result<(u16, Capabilities)> {
+    let mut rdr = checked(payload, 3)?;
+    Ok((rdr.read_u16::<BigEndian>()?, Capabilities(rdr.read_u8()?)))
+}
+
+/// Creates the payload of a `Reject` packet listing the supported `versions`.
+pub fn reject_payload(versions: RangeInclusive<u16>) -> Bytes {
+    let mut payload = BytesMut::with_capacity(4);
+    payload.put_u16(*versions.start());
+    payload.put_u16(*versions.end());
+    payload.freeze()
+}
+
+/// Reads the versions supported by the server from a `Reject` payload.
+pub fn read_reject(payload: &[u8]) -> Result<RangeInclusive<u16>> {
+    let mut rdr = checked(payload, 4)?;
+    Ok(rdr.read_u16::<BigEndian>()?..=rdr.read_u16::<BigEndian>()?)
+}
+
+fn checked(payload: &[u8], len: usize) -> Result<Cursor<&[u8]>> {
+    if payload.len() < len {
+        return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload));
+    }
+    Ok(Cursor::new(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let both = Capabilities::COMPRESSION | Capabilities::ENCRYPTION;
+        assert!(both.contains(Capabilities::ENCRYPTION));
+        assert!(!both.contains(Capabilities::FRAGMENTATION));
+        assert_eq!(both & Capabilities::COMPRESSION, Capabilities::COMPRESSION);
+        assert_eq!(Capabilities::from_bits(both.bits()), both);
+        assert_eq!(
+            Capabilities::local().contains(Capabilities::COMPRESSION),
+            cfg!(feature = "compression")
+        );
+    }
+
+    #[test]
+    fn payloads() {
+        let mut request = [0xff; 16];
+        put_offer(&mut request, 2..=5, Capabilities::FRAGMENTATION, true);
+        let (versions, offered, mesh) = read_offer(&request).unwrap();
+        assert_eq!(versions, 2..=5);
+        assert_eq!(offered, Capabilities::FRAGMENTATION);
+        assert!(mesh);
+        assert!(read_offer(&request[..5]).is_err());
+
+        let accept = accept_payload(4, Capabilities::COMPRESSION);
+        assert_eq!(read_accept(&accept).unwrap(), (4, Capabilities::COMPRESSION));
+
+        let reject = reject_payload(3..=7);
+        assert_eq!(read_reject(&reject).unwrap(), 3..=7);
+        assert!(matches!(read_reject(&reject[..3]), Err(ErrorKind::DecodingError(_))));
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::{Duration, Instant};
+
+use crate::net::constants::{
+    DEFAULT_MTU, MAX_MTU, MIN_MTU, MTU_PROBE_ATTEMPTS, MTU_PROBE_PRECISION, MTU_PROBE_TIMEOUT,
+    MTU_REPROBE_BACKOFF, MTU_REPROBE_MAX_BACKOFF,
+};
+
+/// Path MTU discovery of a single connection.
+///
+/// Probes are datagrams padded to the probed size, the peer acknowledges the size that arrived.
+/// The largest working size is searched between [MIN_MTU] and [MAX_MTU], starting with
+/// [DEFAULT_MTU]. A probe lost [MTU_PROBE_ATTEMPTS] times in a row is considered too large
+/// for the path until [MTU_REPROBE_BACKOFF] passed, the backoff doubles every time the larger
+/// sizes still do not arrive.
+///
+/// [MIN_MTU]: ../net/constants/constant.MIN_MTU.html
+/// [MAX_MTU]: ../net/constants/constant.MAX_MTU.html
+/// [DEFAULT_MTU]: ../net/constants/constant.DEFAULT_MTU.html
+/// [MTU_PROBE_ATTEMPTS]: ../net/constants/constant.MTU_PROBE_ATTEMPTS.html
+/// [MTU_REPROBE_BACKOFF]: ../net/constants/constant.MTU_REPROBE_BACKOFF.html
+#[derive(Debug)]
+pub struct PathMtu {
+    /// Largest size known to arrive.
+    confirmed: u16,
+    /// Smallest size known not to arrive.
+    limit: u16,
+    probe: Option<Probe>,
+    /// Time sizes above `limit` are probed again.
+    reprobe: Option<Instant>,
+    backoff: Duration,
+}
+
+#[derive(Debug)]
+struct Probe {
+    size: u16,
+    sent: Instant,
+    attempts: u32,
+}
+
+impl PathMtu {
+    pub fn new() -> Self {
+        PathMtu {
+            confirmed: MIN_MTU,
+            limit: MAX_MTU + 1,
+            probe: None,
+            reprobe: None,
+            backoff: MTU_REPROBE_BACKOFF,
+        }
+    }
+
+    /// Returns the largest datagram known to reach the peer.
+    pub fn mtu(&self) -> u16 {
+        self.confirmed
+    }
+
+    /// Checks if the largest working size is found.
+    #[cfg(test)]
+    pub fn is_settled(&self) -> bool {
+        self.probe.is_none() && self.limit - self.confirmed <= MTU_PROBE_PRECISION
+    }
+
+    /// Returns the size of the probe to send at `time`, if one is due.
+    pub fn probe_due(&mut self, time: Instant) -> Option<u16> {
+        match &mut self.probe {
+            Some(probe) if time.saturating_duration_since(probe.sent) < MTU_PROBE_TIMEOUT => None,
+            Some(probe) if probe.attempts < MTU_PROBE_ATTEMPTS => {
+                probe.attempts += 1;
+                probe.sent = time;
+                Some(probe.size)
+            }
+            Some(probe) => {
+                self.limit = probe.size;
+                self.probe = None;
+                self.reprobe = Some(time + self.backoff);
+                self.next_probe(time)
+            }
+            None => self.next_probe(time),
+        }
+    }
+
+    /// Handles the acknowledgement of a probe that arrived with `size` bytes.
+    ///
+    /// Late acknowledgements of earlier probes still count.
+    pub fn ack(&mut self, size: u16) {
+        if size <= self.confirmed || size >= self.limit {
+            return;
+        }
+        self.confirmed = size;
+        if matches!(&self.probe, Some(probe) if probe.size <= size) {
+            self.probe = None;
+        }
+    }
+
+    fn next_probe(&mut self, time: Instant) -> Option<u16> {
+        if self.limit - self.confirmed <= MTU_PROBE_PRECISION {
+            match self.reprobe {
+                Some(reprobe) if time >= reprobe => {
+                    self.limit = MAX_MTU + 1;
+                    self.reprobe = None;
+                    self.backoff = (self.backoff * 2).min(MTU_REPROBE_MAX_BACKOFF);
+                }
+                _ => return None,
+            }
+        }
+        let size = if self.confirmed < DEFAULT_MTU && DEFAULT_MTU < self.limit {
+            DEFAULT_MTU
+        } else {
+            self.confirmed + (self.limit - self.confirmed) / 2
+        };
+        self.probe = Some(Probe {
+            size,
+            sent: time,
+            attempts: 1,
+        });
+        Some(size)
+    }
+}
+
+impl Default for PathMtu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::net::constants::{
+        DEFAULT_MTU, MAX_MTU, MIN_MTU, MTU_PROBE_PRECISION, MTU_PROBE_TIMEOUT, MTU_REPROBE_BACKOFF,
+    };
+
+    use super::PathMtu;
+
+    /// Runs the discovery over a path carrying datagrams up to `path` bytes.
+    fn discover(path: u16) -> (PathMtu, Vec<u16>) {
+        let mut mtu = PathMtu::new();
+        let probes = discover_from(&mut mtu, path, Instant::now());
+        (mtu, probes)
+    }
+
+    /// Continues the discovery of `mtu` at `time` until it settles, returns the sent probes.
+    fn discover_from(mtu: &mut PathMtu, path: u16, mut time: Instant) -> Vec<u16> {
+        let mut probes = Vec::new();
+        loop {
+            match mtu.probe_due(time) {
+                Some(size) => {
+                    probes.push(size);
+                    if size <= path {
+                        mtu.ack(size);
+                    }
+                }
+                None if mtu.is_settled() => break,
+                None => (),
+            }
+            time += MTU_PROBE_TIMEOUT;
+            assert!(probes.len() < 100, "discovery does not settle: {:?}", probes);
+        }
+        probes
+    }
+
+    #[test]
+    fn settles_on_largest_working_size() {
+        let (mut mtu, probes) = discover(1400);
+        assert_eq!(probes[0], DEFAULT_MTU);
+        // the default size is retried before it is given up
+        assert_eq!(probes[1], DEFAULT_MTU);
+        assert!(mtu.mtu() <= 1400 && 1400 - mtu.mtu() <= MTU_PROBE_PRECISION);
+        assert!(mtu.probe_due(Instant::now()).is_none());
+
+        let (mtu, _) = discover(9000);
+        assert!(MAX_MTU - mtu.mtu() <= MTU_PROBE_PRECISION);
+
+        let (mtu, _) = discover(576);
+        assert_eq!(mtu.mtu(), MIN_MTU);
+    }
+
+    #[test]
+    fn counts_late_acks() {
+        let mut mtu = PathMtu::new();
+        let time = Instant::now();
+        assert_eq!(mtu.probe_due(time), Some(DEFAULT_MTU));
+        assert_eq!(mtu.probe_due(time), None);
+
+        mtu.ack(MIN_MTU + 10);
+        assert_eq!(mtu.mtu(), MIN_MTU + 10);
+        // the outstanding probe is still waited for
+        assert_eq!(mtu.probe_due(time), None);
+        mtu.ack(DEFAULT_MTU);
+        assert_eq!(mtu.mtu(), DEFAULT_MTU);
+        mtu.ack(MIN_MTU);
+        assert_eq!(mtu.mtu(), DEFAULT_MTU);
+    }
+
+    #[test]
+    fn probes_lost_sizes_again_after_backoff() {
+        let start = Instant::now();
+        let mut mtu = PathMtu::new();
+        discover_from(&mut mtu, 1300, start);
+        let confirmed = mtu.mtu();
+        assert!(confirmed <= 1300 && 1300 - confirmed <= MTU_PROBE_PRECISION);
+
+        // the path still does not carry more, the next attempt waits twice as long
+        let time = start + MTU_REPROBE_BACKOFF * 2;
+        assert!(mtu.probe_due(time).unwrap() > confirmed);
+        discover_from(&mut mtu, 1300, time);
+        assert!(mtu.mtu() >= confirmed && mtu.mtu() <= 1300);
+        assert_eq!(mtu.probe_due(time + MTU_REPROBE_BACKOFF * 3 / 2), None);
+
+        // the path grew meanwhile
+        let probes = discover_from(&mut mtu, MAX_MTU, time + MTU_REPROBE_BACKOFF * 5);
+        assert!(!probes.is_empty());
+        assert!(MAX_MTU - mtu.mtu() <= MTU_PROBE_PRECISION);
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::{self, Debug, Display};
+use std::fmt::{self, Debug};
 use std::time::{Duration, Instant};
 
 /// Entry for throughput monitor with measured information.
@@ -74,6 +74,7 @@ impl ThroughputMonitoring {
     }
 
     /// Reset the throughput history.
+    #[allow(dead_code)]
     pub fn reset(&mut self) {
         self.current_throughput = 0;
         self.measured_throughput.clear();
@@ -88,6 +89,7 @@ impl ThroughputMonitoring {
     }
 
     /// Returns the totals measured throughput ticks.
+    #[allow(dead_code)]
     pub fn total_measured_ticks(&self) -> u32 {
         self.measured_throughput
             .iter()
@@ -96,9 +98,12 @@ impl ThroughputMonitoring {
             + self.current_throughput
     }
 
-    pub fn report<F>(&mut self, f: F) -> () where F: Fn(&Self) -> () {
+    pub fn report<F>(&mut self, f: F)
+    where
+        F: Fn(&Self),
+    {
         if self.total.elapsed() >= self.report_duration {
-            f(&self);
+            f(self);
             self.total = Instant::now();
         }
     }
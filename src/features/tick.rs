@@ -0,0 +1,113 @@
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use tokio::time::delay_until;
+
+/// Fixed-rate simulation clock.
+///
+/// Tick `n` starts `n` periods after the scheduler was started. Ticks are derived from the
+/// elapsed time rather than counted, so a late wake up never makes the clock drift.
+#[derive(Copy, Clone, Debug)]
+pub struct TickScheduler {
+    start: Instant,
+    period: Duration,
+    /// First tick not yet returned by `advance`.
+    next: u32,
+}
+
+impl TickScheduler {
+    /// Constructs a scheduler running at `rate` ticks per second, starting at tick 0 at `start`.
+    ///
+    /// A `rate` of 0 is treated as 1.
+    pub fn new(rate: u32, start: Instant) -> Self {
+        TickScheduler {
+            start,
+            period: Duration::from_secs(1) / rate.max(1),
+            next: 0,
+        }
+    }
+
+    /// Returns the duration of a single tick.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Returns the time passed since tick 0 started, zero before it.
+    pub fn elapsed(&self, time: Instant) -> Duration {
+        time.saturating_duration_since(self.start)
+    }
+
+    /// Returns the tick running at `time`.
+    pub fn tick_at(&self, time: Instant) -> u32 {
+        (self.elapsed(time).as_nanos() / self.period.as_nanos()) as u32
+    }
+
+    /// Returns the instant `tick` starts at.
+    pub fn instant_of(&self, tick: u32) -> Instant {
+        self.start + self.period * tick
+    }
+
+    /// Returns the ticks that started since the last call, to be simulated in order.
+    ///
+    /// The range is empty when no new tick started.
+    pub fn advance(&mut self, time: Instant) -> Range<u32> {
+        let current = self.tick_at(time) + 1;
+        let ticks = self.next..current.max(self.next);
+        self.next = ticks.end;
+        ticks
+    }
+
+    /// Returns the instant the tick following the one running at `time` starts at.
+    pub fn next_deadline(&self, time: Instant) -> Instant {
+        self.instant_of(self.tick_at(time) + 1)
+    }
+
+    /// Waits until the next tick starts and returns it.
+    pub async fn wait(&self) -> u32 {
+        let deadline = self.next_deadline(Instant::now());
+        delay_until(deadline.into()).await;
+        self.tick_at(deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::features::TickScheduler;
+
+    #[test]
+    fn tick_from_elapsed_time() {
+        let start = Instant::now();
+        let ticks = TickScheduler::new(50, start);
+
+        assert_eq!(ticks.period(), Duration::from_millis(20));
+        assert_eq!(ticks.tick_at(start), 0);
+        assert_eq!(ticks.tick_at(start + Duration::from_millis(19)), 0);
+        assert_eq!(ticks.tick_at(start + Duration::from_millis(20)), 1);
+        assert_eq!(ticks.instant_of(3), start + Duration::from_millis(60));
+        assert_eq!(
+            ticks.next_deadline(start + Duration::from_millis(25)),
+            start + Duration::from_millis(40)
+        );
+    }
+
+    #[test]
+    fn advance_catches_up() {
+        let start = Instant::now();
+        let mut ticks = TickScheduler::new(100, start);
+
+        assert_eq!(ticks.advance(start), 0..1);
+        assert_eq!(ticks.advance(start + Duration::from_millis(5)), 1..1);
+        assert_eq!(ticks.advance(start + Duration::from_millis(35)), 1..4);
+    }
+
+    #[tokio::test]
+    async fn wait_returns_next_tick() {
+        let ticks = TickScheduler::new(100, Instant::now());
+
+        let tick = ticks.wait().await;
+        assert!(tick >= 1);
+        assert!(ticks.tick_at(Instant::now()) >= tick);
+    }
+}
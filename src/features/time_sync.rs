@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::{MAX_CLOCK_DRIFT, TIME_SYNC_WINDOW};
+
+/// Timestamps of a single ping/pong exchange, in microseconds.
+///
+/// `origin` and `destination` are read from the local clock when the ping was sent and the pong
+/// received, `receive` and `transmit` from the remote clock when the ping was received and the
+/// pong sent.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimeSample {
+    pub origin: u64,
+    pub receive: u64,
+    pub transmit: u64,
+    pub destination: u64,
+}
+
+impl TimeSample {
+    /// Returns how far the remote clock is ahead of the local one, in microseconds.
+    pub fn offset(&self) -> f64 {
+        let outbound = self.receive as f64 - self.origin as f64;
+        let inbound = self.transmit as f64 - self.destination as f64;
+        (outbound + inbound) / 2.0
+    }
+
+    /// Returns the round trip time without the time the remote spent holding the ping.
+    pub fn rtt(&self) -> u64 {
+        let total = self.destination.saturating_sub(self.origin);
+        total.saturating_sub(self.transmit.saturating_sub(self.receive))
+    }
+}
+
+/// Creates the payload of a `TimePing` packet sent at `origin`.
+pub fn ping_payload(origin: u64) -> Bytes {
+    let mut payload = BytesMut::with_capacity(8);
+    payload.put_u64(origin);
+    payload.freeze()
+}
+
+/// Reads the origin timestamp of a `TimePing` payload.
+pub fn read_ping(payload: &[u8]) -> Result<u64> {
+    if payload.len() < 8 {
+        return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload));
+    }
+    Ok(Cursor::new(payload).read_u64::<BigEndian>()?)
+}
+
+/// Creates the payload of a `TimePong` packet answering a ping sent at `origin`, followed by
+/// the tick `period` of the server in nanoseconds.
+pub fn pong_payload(origin: u64, receive: u64, transmit: u64, period: Duration) -> Bytes {
+    let mut payload = BytesMut::with_capacity(32);
+    payload.put_u64(origin);
+    payload.put_u64(receive);
+    payload.put_u64(transmit);
+    payload.put_u64(period.as_nanos() as u64);
+    payload.freeze()
+}
+
+/// Reads a `TimePong` payload received at `destination`, returns the exchange and the tick
+/// period of the server.
+pub fn read_pong(payload: &[u8], destination: u64) -> Result<(TimeSample, Duration)> {
+    if payload.len() < 32 {
+        return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload));
+    }
+    let mut rdr = Cursor::new(payload);
+    let sample = TimeSample {
+        origin: rdr.read_u64::<BigEndian>()?,
+        receive: rdr.read_u64::<BigEndian>()?,
+        transmit: rdr.read_u64::<BigEndian>()?,
+        destination,
+    };
+    Ok((sample, Duration::from_nanos(rdr.read_u64::<BigEndian>()?)))
+}
+
+/// Estimates the offset and drift of a remote clock from ping/pong exchanges.
+///
+/// # Remarks
+/// - Only the half of the last [TIME_SYNC_WINDOW] samples with the shortest round trip is
+///   used, queuing delays make the others both slower and less symmetric.
+/// - The offset is the median of those samples, so a single bad sample can not move it.
+/// - Drift is the slope of the offset over local time, clamped to [MAX_CLOCK_DRIFT].
+/// - Every new estimate moves the published offset only part of the way, so the estimate does
+///   not jump with jitter.
+#[derive(Clone, Debug, Default)]
+pub struct ClockSync {
+    samples: VecDeque<TimeSample>,
+    /// Smoothed offset at `reference`, in microseconds.
+    offset: Option<f64>,
+    /// Local time of the latest sample, in microseconds.
+    reference: u64,
+    drift: f64,
+}
+
+/// Weight of a new estimate in the published offset.
+const SMOOTHING: f64 = 0.25;
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds the result of an exchange and updates the estimate.
+    pub fn add_sample(&mut self, sample: TimeSample) {
+        if self.samples.len() == TIME_SYNC_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+
+        let mut best = self.samples.iter().collect::<Vec<_>>();
+        best.sort_by_key(|s| s.rtt());
+        best.truncate(best.len().div_ceil(2));
+
+        self.drift = Self::slope(&best).clamp(-MAX_CLOCK_DRIFT, MAX_CLOCK_DRIFT);
+
+        let reference = sample.destination;
+        let mut offsets = best
+            .iter()
+            .map(|s| s.offset() + self.drift * (reference as f64 - s.destination as f64))
+            .collect::<Vec<_>>();
+        offsets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let estimate = offsets[offsets.len() / 2];
+
+        self.offset = Some(match self.offset {
+            Some(_) if self.samples.len() > 1 => {
+                let previous = self.offset_at(reference).unwrap();
+                previous + (estimate - previous) * SMOOTHING
+            }
+            _ => estimate,
+        });
+        self.reference = reference;
+    }
+
+    /// Checks if at least one sample was added.
+    pub fn is_synchronized(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// Returns how far the remote clock is ahead of the local one at local time `local`, in
+    /// microseconds.
+    pub fn offset_at(&self, local: u64) -> Option<f64> {
+        self.offset
+            .map(|offset| offset + self.drift * (local as f64 - self.reference as f64))
+    }
+
+    /// Returns how much faster the remote clock runs, in seconds per second.
+    pub fn drift(&self) -> f64 {
+        self.drift
+    }
+
+    /// Returns the shortest round trip time of the recent samples.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.samples
+            .iter()
+            .map(TimeSample::rtt)
+            .min()
+            .map(Duration::from_micros)
+    }
+
+    /// Converts local time `local` to remote time, both in microseconds.
+    pub fn to_remote(&self, local: u64) -> Option<u64> {
+        self.offset_at(local)
+            .map(|offset| (local as f64 + offset).max(0.0) as u64)
+    }
+
+    /// Least squares slope of the sample offsets over local time, zero without enough spread.
+    fn slope(samples: &[&TimeSample]) -> f64 {
+        if samples.len() < 2 {
+            return 0.0;
+        }
+        let n = samples.len() as f64;
+        let mean_t = samples.iter().map(|s| s.destination as f64).sum::<f64>() / n;
+        let mean_o = samples.iter().map(|s| s.offset()).sum::<f64>() / n;
+        let (cov, var) = samples.iter().fold((0.0, 0.0), |(cov, var), s| {
+            let dt = s.destination as f64 - mean_t;
+            (cov + dt * (s.offset() - mean_o), var + dt * dt)
+        });
+        // less than a second of spread says nothing about drift
+        if var / n < 1e12 {
+            return 0.0;
+        }
+        cov / var
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use super::{ClockSync, TimeSample, ping_payload, pong_payload, read_ping, read_pong};
+
+    /// An exchange started at `local` with the remote `offset` ahead and the given one way delays.
+    fn exchange(local: u64, offset: f64, outbound: u64, hold: u64, inbound: u64) -> TimeSample {
+        let receive = (local as f64 + outbound as f64 + offset) as u64;
+        TimeSample {
+            origin: local,
+            receive,
+            transmit: receive + hold,
+            destination: local + outbound + hold + inbound,
+        }
+    }
+
+    #[test]
+    fn sample_offset_and_rtt() {
+        let sample = exchange(1_000, 5_000.0, 100, 50, 100);
+
+        assert_eq!(sample.offset(), 5_000.0);
+        assert_eq!(sample.rtt(), 200);
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        assert_eq!(read_ping(&ping_payload(42)).unwrap(), 42);
+        let period = Duration::from_secs(1) / 30;
+        assert_eq!(
+            read_pong(&pong_payload(1, 2, 3, period), 4).unwrap(),
+            (TimeSample { origin: 1, receive: 2, transmit: 3, destination: 4 }, period)
+        );
+        assert!(read_ping(&[0; 4]).is_err());
+        assert!(read_pong(&[0; 24], 0).is_err());
+    }
+
+    #[test]
+    fn ignores_jitter_and_outliers() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut sync = ClockSync::new();
+        let offset = -250_000.0;
+
+        for i in 0..64_u64 {
+            let local = 1_000_000 + i * 100_000;
+            let (outbound, inbound) = if i % 5 == 0 {
+                // a queue building up on one direction only
+                (rng.gen_range(100_000, 300_000), 10_000)
+            } else {
+                (rng.gen_range(10_000, 14_000), rng.gen_range(10_000, 14_000))
+            };
+            sync.add_sample(exchange(local, offset, outbound, 500, inbound));
+        }
+
+        let estimate = sync.offset_at(7_400_000).unwrap();
+        assert!((estimate - offset).abs() < 2_000.0, "estimate {}", estimate);
+        assert!(sync.rtt().unwrap().as_micros() < 28_000);
+    }
+
+    #[test]
+    fn tracks_drift() {
+        let mut sync = ClockSync::new();
+        // remote clock runs 100ppm faster
+        let drift = 100e-6;
+
+        for i in 0..16_u64 {
+            let local = i * 500_000;
+            sync.add_sample(exchange(local, 1_000.0 + drift * local as f64, 5_000, 0, 5_000));
+        }
+
+        assert!((sync.drift() - drift).abs() < 1e-5, "drift {}", sync.drift());
+        let later = 20_000_000;
+        let estimate = sync.offset_at(later).unwrap();
+        assert!((estimate - (1_000.0 + drift * later as f64)).abs() < 200.0);
+        assert_eq!(sync.to_remote(later), Some((later as f64 + estimate) as u64));
+    }
+}
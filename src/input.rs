@@ -0,0 +1,316 @@
+//! Player inputs sent from client to server.
+//!
+//! Clients send inputs instead of state for the bodies they control. Every `Input` packet
+//! repeats the recent inputs the server has not acknowledged, so a lost packet is covered by
+//! the next one. The server acknowledges the last input it processed in each snapshot, the
+//! client replays the newer ones on top of the snapshot to predict its own bodies.
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::{INPUT_BUFFER_SIZE, INPUT_HISTORY_SIZE, MAX_INPUT_PAYLOAD, MAX_INPUT_SIZE};
+
+/// Size of the tick and length in front of every input.
+const INPUT_HEADER_SIZE: usize = 6;
+
+/// An input of the app, tagged with the tick it applies to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Input {
+    pub tick: u32,
+    pub payload: Bytes,
+}
+
+/// Client side history of inputs not acknowledged by the server.
+#[derive(Debug, Default)]
+pub struct InputHistory {
+    inputs: VecDeque<Input>,
+    acked: Option<u32>,
+}
+
+impl InputHistory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds the input of `tick`, inputs have to be pushed in tick order.
+    ///
+    /// Inputs of ticks already pushed or acknowledged are ignored, the oldest input is dropped
+    /// when the server stops acknowledging. Fails with `PayloadTooLarge` for inputs larger
+    /// than [MAX_INPUT_SIZE].
+    ///
+    /// [MAX_INPUT_SIZE]: ../net/constants/constant.MAX_INPUT_SIZE.html
+    pub fn push(&mut self, tick: u32, payload: Bytes) -> Result<()> {
+        check_input_size(&payload)?;
+        let newest = self.inputs.back().map(|i| i.tick).or(self.acked);
+        if matches!(newest, Some(newest) if newest >= tick) {
+            return Ok(());
+        }
+        if self.inputs.len() == INPUT_HISTORY_SIZE {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back(Input { tick, payload });
+        Ok(())
+    }
+
+    /// Drops all inputs up to `tick`, which the server processed.
+    pub fn ack(&mut self, tick: u32) {
+        if matches!(self.acked, Some(acked) if acked >= tick) {
+            return;
+        }
+        self.acked = Some(tick);
+        while matches!(self.inputs.front(), Some(input) if input.tick <= tick) {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// Returns the last input tick processed by the server.
+    pub fn acked(&self) -> Option<u32> {
+        self.acked
+    }
+
+    /// Returns the inputs not processed by the server yet, oldest first, to be replayed on top
+    /// of the latest snapshot.
+    pub fn unacked(&self) -> impl Iterator<Item = &Input> {
+        self.inputs.iter()
+    }
+
+    /// Creates the payload of an `Input` packet with the most recent unacknowledged inputs that
+    /// fit into it, `None` when there are none.
+    pub fn payload(&self) -> Option<Bytes> {
+        if self.inputs.is_empty() {
+            return None;
+        }
+        Some(encode_inputs(self.inputs.iter(), MAX_INPUT_PAYLOAD))
+    }
+}
+
+/// Server side queue of inputs received from one client.
+#[derive(Debug, Default)]
+pub struct InputBuffer {
+    pending: BTreeMap<u32, Bytes>,
+    processed: Option<u32>,
+}
+
+impl InputBuffer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds the inputs of an `Input` packet, the ones already received or processed are
+    /// skipped, the inputs share the memory of `payload`.
+    pub fn receive(&mut self, payload: &Bytes) -> Result<()> {
+        for input in decode_inputs(payload)? {
+            if matches!(self.processed, Some(processed) if processed >= input.tick) {
+                continue;
+            }
+            self.pending.entry(input.tick).or_insert(input.payload);
+        }
+
+        while self.pending.len() > INPUT_BUFFER_SIZE {
+            let oldest = *self.pending.keys().next().unwrap();
+            self.pending.remove(&oldest);
+        }
+        Ok(())
+    }
+
+    /// Takes the oldest pending input and marks it processed.
+    pub fn pop(&mut self) -> Option<Input> {
+        let tick = *self.pending.keys().next()?;
+        let payload = self.pending.remove(&tick)?;
+        self.processed = Some(tick);
+        Some(Input { tick, payload })
+    }
+
+    /// Returns the number of inputs waiting to be processed.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the last processed input tick.
+    pub fn processed(&self) -> Option<u32> {
+        self.processed
+    }
+}
+
+/// Fails with `PayloadTooLarge` for an input which does not fit into input packets.
+pub(crate) fn check_input_size(payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_INPUT_SIZE {
+        return Err(ErrorKind::PayloadTooLarge(payload.len()));
+    }
+    Ok(())
+}
+
+/// Writes the newest of `inputs`, oldest first, fitting into `budget` bytes as
+/// `[u8 count]([u32 tick][u16 length][payload])*`.
+pub(crate) fn encode_inputs<'a, I>(inputs: I, budget: usize) -> Bytes
+where
+    I: DoubleEndedIterator<Item = &'a Input> + Clone,
+{
+    let mut size = 1;
+    let count = inputs
+        .clone()
+        .rev()
+        .take(u8::MAX as usize)
+        .take_while(|input| {
+            size += INPUT_HEADER_SIZE + input.payload.len();
+            size <= budget
+        })
+        .count();
+
+    let mut payload = BytesMut::with_capacity(size);
+    payload.put_u8(count as u8);
+    let skip = inputs.clone().count() - count;
+    for input in inputs.skip(skip) {
+        payload.put_u32(input.tick);
+        payload.put_u16(input.payload.len() as u16);
+        payload.put_slice(&input.payload);
+    }
+    payload.freeze()
+}
+
+/// Reads inputs written by [encode_inputs], they share the memory of `payload`.
+pub(crate) fn decode_inputs(payload: &Bytes) -> Result<Vec<Input>> {
+    let mut rdr = Cursor::new(&payload[..]);
+    let count = rdr.read_u8()?;
+    (0..count)
+        .map(|_| {
+            let tick = rdr.read_u32::<BigEndian>()?;
+            let len = rdr.read_u16::<BigEndian>()? as usize;
+            let start = rdr.position() as usize;
+            if start + len > payload.len() {
+                return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload));
+            }
+            rdr.set_position((start + len) as u64);
+            Ok(Input {
+                tick,
+                payload: payload.slice(start..start + len),
+            })
+        })
+        .collect()
+}
+
+/// Writes the acknowledged input tick preceding a snapshot, 0 for none and the tick plus one
+/// otherwise.
+pub(crate) fn put_input_ack<B: BufMut>(acked: Option<u32>, buffer: &mut B) {
+    buffer.put_u32(acked.map_or(0, |tick| tick.wrapping_add(1)));
+}
+
+/// Reads the acknowledged input tick written by [put_input_ack].
+pub(crate) fn read_input_ack(rdr: &mut Cursor<&[u8]>) -> Result<Option<u32>> {
+    let value = rdr.read_u32::<BigEndian>()?;
+    Ok(value.checked_sub(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use bytes::Bytes;
+
+    use crate::errors::ErrorKind;
+    use crate::net::constants::{MAX_INPUT_PAYLOAD, MAX_INPUT_SIZE};
+
+    use super::{put_input_ack, read_input_ack, InputBuffer, InputHistory};
+
+    fn input(tick: u32) -> Bytes {
+        Bytes::from(vec![tick as u8; 3])
+    }
+
+    #[test]
+    fn history_drops_acked() {
+        let mut history = InputHistory::new();
+        for tick in 1..=5 {
+            history.push(tick, input(tick)).unwrap();
+        }
+        history.push(4, input(9)).unwrap();
+        history.ack(3);
+        history.push(2, input(2)).unwrap();
+
+        let ticks = history.unacked().map(|i| i.tick).collect::<Vec<_>>();
+        assert_eq!(ticks, vec![4, 5]);
+        assert_eq!(history.acked(), Some(3));
+
+        history.ack(5);
+        assert!(history.payload().is_none());
+    }
+
+    #[test]
+    fn redundant_inputs_survive_loss() {
+        let mut history = InputHistory::new();
+        let mut buffer = InputBuffer::new();
+
+        history.push(1, input(1)).unwrap();
+        let first = history.payload().unwrap();
+        history.push(2, input(2)).unwrap();
+        // the packet carrying tick 1 and 2 is lost
+        let _lost = history.payload().unwrap();
+        history.push(3, input(3)).unwrap();
+        let third = history.payload().unwrap();
+
+        buffer.receive(&third).unwrap();
+        buffer.receive(&first).unwrap();
+        assert_eq!(buffer.len(), 3);
+        for tick in 1..=3 {
+            let next = buffer.pop().unwrap();
+            assert_eq!(next.tick, tick);
+            assert_eq!(next.payload, input(tick));
+        }
+        assert_eq!(buffer.processed(), Some(3));
+
+        // late duplicates of processed inputs are ignored
+        buffer.receive(&third).unwrap();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn payload_is_limited() {
+        let mut history = InputHistory::new();
+        for tick in 0..16 {
+            history.push(tick, Bytes::from(vec![0; MAX_INPUT_SIZE])).unwrap();
+        }
+        let payload = history.payload().unwrap();
+        assert!(payload.len() <= MAX_INPUT_PAYLOAD);
+        let count = payload[0] as u32;
+        assert!(count > 1 && count < 16);
+
+        // the most recent inputs are repeated
+        let mut buffer = InputBuffer::new();
+        buffer.receive(&payload).unwrap();
+        assert_eq!(buffer.pop().unwrap().tick, 16 - count);
+        assert_eq!(buffer.len() as u32, count - 1);
+    }
+
+    #[test]
+    fn rejects_oversized() {
+        let mut history = InputHistory::new();
+        let oversized = Bytes::from(vec![0; MAX_INPUT_SIZE + 1]);
+        assert!(matches!(history.push(1, oversized), Err(ErrorKind::PayloadTooLarge(_))));
+        assert!(history.payload().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let mut history = InputHistory::new();
+        history.push(1, input(1)).unwrap();
+        let payload = history.payload().unwrap();
+
+        let truncated = payload.slice(..payload.len() - 1);
+        assert!(InputBuffer::new().receive(&truncated).is_err());
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        for &acked in &[None, Some(0), Some(41)] {
+            let mut buffer = Vec::new();
+            put_input_ack(acked, &mut buffer);
+            assert_eq!(read_input_ack(&mut Cursor::new(&buffer[..])).unwrap(), acked);
+        }
+    }
+}
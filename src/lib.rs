@@ -1,6 +1,14 @@
 pub use errors::{ErrorKind, Result};
-pub use net::Peer;
-pub use packet::{Packet, OutgoingPacketBuilder, OutgoingPacket};
+pub use features::{AreaOfInterest, Capabilities, ClockSync, Route, TickScheduler, TimeSample};
+pub use net::constants::MAX_HEADER_SIZE;
+pub use net::{Peer, PeerEvent, ShardedPeer};
+pub use packet::{BufferPool, Packet, OutgoingPacketBuilder, OutgoingPacket};
+pub use serialize::{BitReader, BitWriter, NetSerialize};
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+pub use net::mmsg;
+
+// lets `#[derive(NetSerialize)]` name this crate from within
+extern crate self as physync;
 
 mod net;
 mod errors;
@@ -10,3 +18,9 @@ mod features;
 
 pub mod server;
 pub mod client;
+pub mod snapshot;
+pub mod input;
+pub mod rollback;
+pub mod replication;
+pub mod message;
+pub mod serialize;
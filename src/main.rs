@@ -1,13 +1,11 @@
 use std::error::Error;
 use std::result;
 
-use clap::{App, AppSettings, ArgMatches, load_yaml};
-use env_logger::Builder;
+use clap::{App, AppSettings, ArgMatches, load_yaml, value_t};
 use futures::TryFutureExt;
-use log::LevelFilter;
 
 use physync::client::Client;
-use physync::server::Server;
+use physync::server::{Server, ServerConfig};
 
 #[tokio::main]
 async fn main() -> result::Result<(), Box<dyn Error>> {
@@ -30,14 +28,30 @@ async fn main() -> result::Result<(), Box<dyn Error>> {
 
 async fn run_server(m: ArgMatches<'_>) -> result::Result<(), Box<dyn Error>> {
     let host = m.value_of("LISTEN_HOST").unwrap();
-    Server::new(host).and_then(Server::run).await?;
+    let config = ServerConfig {
+        shards: value_t!(m, "SHARDS", usize)?,
+        reuse_port: m.is_present("REUSE_PORT"),
+        tick_rate: value_t!(m, "TICK_RATE", u32)?,
+        introducer: m.is_present("INTRODUCER"),
+    };
+    Server::with_config(host, config).and_then(Server::run).await?;
 
     Ok(())
 }
 
 async fn run_client(m: ArgMatches<'_>) -> result::Result<(), Box<dyn Error>> {
     let host = m.value_of("CONNECT_ADDR").unwrap();
-    Client::new(host).and_then(Client::run).await?;
+    let bind = m.value_of("LISTEN_HOST").unwrap();
+    let tick_rate = value_t!(m, "TICK_RATE", u32)?;
+    let rendezvous = m.value_of("RENDEZVOUS").map(str::parse::<u64>).transpose()?;
+    Client::with_bind(host, bind)
+        .map_ok(|client| client.with_tick_rate(tick_rate))
+        .map_ok(|client| match rendezvous {
+            Some(key) => client.with_rendezvous(key),
+            None => client,
+        })
+        .and_then(Client::run)
+        .await?;
 
     Ok(())
 }
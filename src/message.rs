@@ -0,0 +1,352 @@
+//! Typed app messages packed into `Message` packets.
+//!
+//! A packet carries any number of messages, each one framed as `[u16 id][u16 length][body]`
+//! with the body serialized by [NetSerialize]. Messages are delivered to the peer they are sent
+//! to, the server does not relay them.
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::io::Cursor;
+use std::mem;
+use std::net::SocketAddr;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::debug;
+
+use crate::errors::{ErrorKind, Result};
+use crate::net::constants::{MAX_HEADER_SIZE, MIN_MTU};
+use crate::serialize::{BitWriter, NetSerialize};
+
+/// Size of the id and length in front of every message.
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// A message type with an id unique among the messages of an app.
+pub trait Message: NetSerialize + 'static {
+    const ID: u16;
+}
+
+/// Messages packed into the payload of a single packet.
+#[derive(Clone, Debug)]
+pub struct MessageBatch {
+    buffer: BytesMut,
+    capacity: usize,
+}
+
+impl MessageBatch {
+    /// Largest payload of a batch, with all headers it fits into the datagrams every path
+    /// carries.
+    pub const CAPACITY: usize = MIN_MTU as usize - MAX_HEADER_SIZE;
+
+    pub fn new() -> Self {
+        Self::with_capacity(Self::CAPACITY)
+    }
+
+    /// Creates a batch holding up to `capacity` bytes, for a peer whose path MTU is known.
+    pub fn with_capacity(capacity: usize) -> Self {
+        MessageBatch {
+            buffer: BytesMut::new(),
+            capacity,
+        }
+    }
+
+    /// Appends `message` to the batch.
+    ///
+    /// Fails with `PayloadTooLarge` when it does not fit into the batch anymore, the batch is
+    /// left unchanged.
+    pub fn push<M: Message>(&mut self, message: &M) -> Result<()> {
+        let start = self.buffer.len();
+        self.buffer.put_u16(M::ID);
+        self.buffer.put_u16(0);
+
+        let mut writer = BitWriter::wrap(&mut self.buffer);
+        message.serialize(&mut writer);
+        writer.into_inner();
+
+        let len = self.buffer.len() - start - FRAME_HEADER_SIZE;
+        if self.buffer.len() > self.capacity {
+            self.buffer.truncate(start);
+            return Err(ErrorKind::PayloadTooLarge(len + FRAME_HEADER_SIZE));
+        }
+        self.buffer[start + 2..start + FRAME_HEADER_SIZE].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(())
+    }
+
+    /// Returns the size of the packed messages in bytes.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the payload of the packet carrying the messages.
+    pub fn finish(self) -> Bytes {
+        self.buffer.freeze()
+    }
+}
+
+impl Default for MessageBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Messages waiting to be sent to one peer, packed into as few batches as possible.
+#[derive(Clone, Debug, Default)]
+pub struct MessageQueue {
+    full: Vec<Bytes>,
+    current: MessageBatch,
+}
+
+impl MessageQueue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Packs the batches started from now on into `capacity` bytes, by default they hold
+    /// [MessageBatch::CAPACITY].
+    pub fn set_capacity(&mut self, capacity: usize) {
+        if self.current.capacity != capacity {
+            self.close_current();
+            self.current = MessageBatch::with_capacity(capacity);
+        }
+    }
+
+    /// Appends `message` to the latest batch, a new batch is started when it is full.
+    ///
+    /// Fails with `PayloadTooLarge` when `message` does not even fit into an empty batch.
+    pub fn push<M: Message>(&mut self, message: &M) -> Result<()> {
+        match self.current.push(message) {
+            Err(ErrorKind::PayloadTooLarge(_)) if !self.current.is_empty() => {
+                self.close_current();
+                self.current.push(message)
+            }
+            pushed => pushed,
+        }
+    }
+
+    /// Appends `batch` as a batch of its own, after the messages queued before.
+    pub fn push_batch(&mut self, batch: MessageBatch) {
+        if !batch.is_empty() {
+            self.close_current();
+            self.full.push(batch.finish());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.full.is_empty() && self.current.is_empty()
+    }
+
+    /// Takes the payloads of all queued batches, in the order they were queued.
+    pub fn take(&mut self) -> Vec<Bytes> {
+        self.close_current();
+        mem::take(&mut self.full)
+    }
+
+    fn close_current(&mut self) {
+        if !self.current.is_empty() {
+            let next = MessageBatch::with_capacity(self.current.capacity);
+            let current = mem::replace(&mut self.current, next);
+            self.full.push(current.finish());
+        }
+    }
+}
+
+type Handler = Box<dyn FnMut(SocketAddr, &[u8]) -> Result<()> + Send>;
+
+/// Routes received messages to the handlers registered for their id.
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<u16, Handler>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Calls `handler` with the sender and every received message of type `M`.
+    ///
+    /// Replaces the handler registered for the id of `M` before.
+    pub fn register<M, F>(&mut self, mut handler: F)
+    where
+        M: Message,
+        F: FnMut(SocketAddr, M) + Send + 'static,
+    {
+        let handler = move |from, body: &[u8]| {
+            handler(from, M::from_payload(body)?);
+            Ok(())
+        };
+        self.handlers.insert(M::ID, Box::new(handler));
+    }
+
+    /// Checks if a handler is registered for messages with `id`.
+    pub fn is_registered(&self, id: u16) -> bool {
+        self.handlers.contains_key(&id)
+    }
+
+    /// Hands the messages in `payload` received from `from` to their handlers, returns the
+    /// number of handled messages.
+    ///
+    /// Messages without a handler are skipped. Fails on the first message which cannot be
+    /// decoded, the messages before it are handled already.
+    pub fn dispatch(&mut self, from: SocketAddr, payload: &[u8]) -> Result<usize> {
+        let mut rdr = Cursor::new(payload);
+        let mut handled = 0;
+        while (rdr.position() as usize) < payload.len() {
+            let id = rdr.read_u16::<BigEndian>()?;
+            let len = rdr.read_u16::<BigEndian>()? as usize;
+            let start = rdr.position() as usize;
+            let body = payload
+                .get(start..start + len)
+                .ok_or(ErrorKind::BufferUnderflow)?;
+            rdr.set_position((start + len) as u64);
+
+            match self.handlers.get_mut(&id) {
+                Some(handler) => {
+                    handler(from, body)?;
+                    handled += 1;
+                }
+                None => debug!("no handler for message {} from {}", id, from),
+            }
+        }
+        Ok(handled)
+    }
+}
+
+impl Debug for Dispatcher {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("ids", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use crate::serialize::NetSerialize;
+    use crate::ErrorKind;
+
+    use super::{Dispatcher, Message, MessageBatch, MessageQueue};
+
+    #[derive(Debug, PartialEq, NetSerialize)]
+    struct Chat {
+        channel: u8,
+        text: Vec<u8>,
+    }
+
+    impl Message for Chat {
+        const ID: u16 = 1;
+    }
+
+    #[derive(Debug, PartialEq, NetSerialize)]
+    struct Ping(#[net(range(0, 1000))] u16);
+
+    impl Message for Ping {
+        const ID: u16 = 2;
+    }
+
+    fn sender() -> SocketAddr {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    #[test]
+    fn dispatches_by_id() {
+        let mut batch = MessageBatch::new();
+        batch.push(&Ping(7)).unwrap();
+        batch.push(&Chat { channel: 3, text: b"hi".to_vec() }).unwrap();
+        batch.push(&Ping(999)).unwrap();
+        let payload = batch.finish();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut dispatcher = Dispatcher::new();
+        let pings = received.clone();
+        dispatcher.register(move |from, ping: Ping| pings.lock().unwrap().push((from, ping.0)));
+
+        // chat messages have no handler and are skipped
+        assert_eq!(dispatcher.dispatch(sender(), &payload).unwrap(), 2);
+        assert_eq!(*received.lock().unwrap(), vec![(sender(), 7), (sender(), 999)]);
+        assert!(!dispatcher.is_registered(Chat::ID));
+
+        let chats = received.clone();
+        dispatcher.register(move |_, chat: Chat| chats.lock().unwrap().push((sender(), chat.text.len() as u16)));
+        assert_eq!(dispatcher.dispatch(sender(), &payload).unwrap(), 3);
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized() {
+        let mut batch = MessageBatch::new();
+        batch.push(&Chat { channel: 1, text: vec![0; 100] }).unwrap();
+        let payload = batch.finish();
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(|_, _: Chat| ());
+        assert!(matches!(
+            dispatcher.dispatch(sender(), &payload[..50]),
+            Err(ErrorKind::BufferUnderflow)
+        ));
+
+        let mut batch = MessageBatch::new();
+        let large = Chat { channel: 1, text: vec![0; MessageBatch::CAPACITY] };
+        assert!(matches!(batch.push(&large), Err(ErrorKind::PayloadTooLarge(_))));
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn queue_coalesces_into_full_batches() {
+        let mut queue = MessageQueue::new();
+        assert!(queue.is_empty());
+        let chat = Chat { channel: 0, text: vec![0; 500] };
+        for _ in 0..5 {
+            queue.push(&chat).unwrap();
+        }
+        queue.push(&Ping(1)).unwrap();
+        let mut batch = MessageBatch::new();
+        batch.push(&Ping(2)).unwrap();
+        queue.push_batch(batch);
+
+        // two chats fit into a batch, the ping fills up the last one
+        let payloads = queue.take();
+        assert_eq!(payloads.len(), 4);
+        assert!(payloads.iter().all(|p| p.len() <= MessageBatch::CAPACITY));
+        assert!(queue.is_empty());
+
+        let pings = Arc::new(Mutex::new(Vec::new()));
+        let received = pings.clone();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(move |_, ping: Ping| received.lock().unwrap().push(ping.0));
+        for payload in &payloads {
+            dispatcher.dispatch(sender(), payload).unwrap();
+        }
+        assert_eq!(*pings.lock().unwrap(), vec![1, 2]);
+
+        let large = Chat { channel: 1, text: vec![0; MessageBatch::CAPACITY] };
+        assert!(queue.push(&large).is_err());
+        assert!(queue.take().is_empty());
+    }
+
+    #[test]
+    fn queue_packs_into_capacity() {
+        let mut queue = MessageQueue::new();
+        let chat = Chat { channel: 0, text: vec![0; 500] };
+        queue.push(&chat).unwrap();
+        // a smaller path closes the batch before
+        queue.set_capacity(600);
+        queue.push(&chat).unwrap();
+        queue.push(&chat).unwrap();
+        assert_eq!(queue.take().len(), 3);
+
+        queue.set_capacity(MessageBatch::CAPACITY + 500);
+        for _ in 0..3 {
+            queue.push(&chat).unwrap();
+        }
+        let payloads = queue.take();
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].len() > MessageBatch::CAPACITY);
+    }
+}
@@ -1,11 +1,19 @@
 // exports identifiers from private sub-modules in the current module namespace
-pub use self::peer::Peer;
+pub use self::peer::{Peer, PeerEvent};
 pub use self::connection::Connection;
 pub use self::socket::Socket;
+pub use self::shard::ShardedPeer;
+pub use self::address::{resolve, select_remote, select_remotes, unspecified_for};
+pub use self::socket::bind_udp;
 
 mod socket;
+mod address;
 mod peer;
 mod connection;
 mod connection_manager;
+mod shard;
+
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+pub mod mmsg;
 
 pub mod constants;
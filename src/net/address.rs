@@ -0,0 +1,140 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::net::lookup_host;
+
+use crate::errors::{ErrorKind, Result};
+
+/// Resolves `host`, given as `hostname:port` or an IPv4/IPv6 socket address, to all of its
+/// addresses.
+///
+/// Fails with [InvalidAddress](../../enum.ErrorKind.html) when `host` is malformed or does not
+/// resolve to any address.
+pub async fn resolve(host: &str) -> Result<Vec<SocketAddr>> {
+    let addrs = lookup_host(host)
+        .await
+        .map_err(|_| ErrorKind::InvalidAddress(host.to_string()))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(ErrorKind::InvalidAddress(host.to_string()));
+    }
+    Ok(addrs)
+}
+
+/// Returns the unspecified address of the same family as `remote`, with an OS assigned port.
+pub fn unspecified_for(remote: &SocketAddr) -> SocketAddr {
+    let ip = match remote {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+/// Checks if a socket bound to `local` accepts both IPv4 and IPv6 traffic.
+///
+/// # Remark
+/// - Only sockets bound to the unspecified IPv6 address are dual-stack, and only because
+///   [bind_udp](../socket/fn.bind_udp.html) turns `IPV6_V6ONLY` off for them.
+pub fn is_dual_stack(local: &SocketAddr) -> bool {
+    match local {
+        SocketAddr::V6(addr) => addr.ip().is_unspecified(),
+        SocketAddr::V4(_) => false,
+    }
+}
+
+/// Picks the address from `candidates` a socket bound to `local` can reach.
+///
+/// Addresses of the same family are preferred, a dual-stack socket falls back to an IPv4
+/// candidate as an IPv4-mapped IPv6 address.
+pub fn select_remote(local: &SocketAddr, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+    select_remotes(local, candidates).into_iter().next()
+}
+
+/// Returns all addresses from `candidates` a socket bound to `local` can reach, in the order
+/// of [select_remote].
+pub fn select_remotes(local: &SocketAddr, candidates: &[SocketAddr]) -> Vec<SocketAddr> {
+    let mut remotes = candidates
+        .iter()
+        .filter(|remote| remote.is_ipv4() == local.is_ipv4())
+        .copied()
+        .collect::<Vec<_>>();
+
+    if is_dual_stack(local) {
+        remotes.extend(candidates.iter().filter(|r| r.is_ipv4()).map(|remote| {
+            let ip = match remote.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            SocketAddr::new(IpAddr::V6(ip), remote.port())
+        }));
+    }
+
+    remotes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::ErrorKind;
+
+    use super::{is_dual_stack, resolve, select_remote, select_remotes, unspecified_for};
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn selects_same_family() {
+        let candidates = [addr("[::1]:4000"), addr("127.0.0.1:4000")];
+
+        assert_eq!(
+            select_remote(&addr("0.0.0.0:0"), &candidates),
+            Some(addr("127.0.0.1:4000"))
+        );
+        assert_eq!(
+            select_remote(&addr("[::1]:0"), &candidates),
+            Some(addr("[::1]:4000"))
+        );
+    }
+
+    #[test]
+    fn maps_ipv4_on_dual_stack() {
+        let candidates = [addr("10.0.0.1:4000")];
+
+        assert_eq!(
+            select_remote(&addr("[::]:0"), &candidates),
+            Some(addr("[::ffff:10.0.0.1]:4000"))
+        );
+        assert_eq!(select_remote(&addr("[::1]:0"), &candidates), None);
+    }
+
+    #[test]
+    fn dual_stack_reaches_all() {
+        let candidates = [addr("[::1]:4000"), addr("127.0.0.1:4000")];
+
+        assert_eq!(
+            select_remotes(&addr("[::]:0"), &candidates),
+            vec![addr("[::1]:4000"), addr("[::ffff:127.0.0.1]:4000")]
+        );
+        assert_eq!(
+            select_remotes(&addr("0.0.0.0:0"), &candidates),
+            vec![addr("127.0.0.1:4000")]
+        );
+    }
+
+    #[test]
+    fn unspecified_matches_family() {
+        assert_eq!(unspecified_for(&addr("10.0.0.1:4000")), addr("0.0.0.0:0"));
+        assert_eq!(unspecified_for(&addr("[::1]:4000")), addr("[::]:0"));
+        assert!(is_dual_stack(&unspecified_for(&addr("[::1]:4000"))));
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_malformed() {
+        match resolve("not an address").await {
+            Err(ErrorKind::InvalidAddress(addr)) => assert_eq!(addr, "not an address"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(resolve("127.0.0.1:4000").await.unwrap(), vec![addr("127.0.0.1:4000")]);
+    }
+}
@@ -1,32 +1,111 @@
 use std::fmt;
 use std::fmt::{Debug, Formatter};
+use std::mem;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 
+use bytes::{BufMut, Bytes, BytesMut};
+
 use crate::errors::Result;
-use crate::features::ConnectivityHandler;
-use crate::net::constants::{DEFAULT_HEARTBEAT, DEFAULT_IDLE_TIMEOUT};
+use crate::features::interest::read_interest;
+use crate::features::introducer::read_register;
+use crate::features::{
+    AreaOfInterest, Capabilities, ConnectivityHandler, InterestSet, PathMtu, SpatialGrid,
+    TickScheduler,
+};
+use crate::net::constants::{
+    BASE_HEADER_SIZE, DEFAULT_HEARTBEAT, DEFAULT_IDLE_TIMEOUT, MAX_HEADER_SIZE, MAX_MTU,
+    SESSION_HEADER_SIZE, TICK_HEADER_SIZE,
+};
 use crate::packet::PacketReader;
 use crate::packet::PacketType;
-use crate::{ErrorKind, OutgoingPacketBuilder, Packet};
+use crate::input::{put_input_ack, Input, InputBuffer};
+use crate::message::MessageQueue;
+use crate::replication::{
+    decode_authority, ReplicatedWorld, ReplicationDecoder, ReplicationDiff, ReplicationEncoder,
+};
+use crate::snapshot::{
+    DeltaEncoder, PriorityAccumulator, PriorityConfig, RigidBodyState, SnapshotConfig,
+};
+use crate::{ErrorKind, OutgoingPacket, OutgoingPacketBuilder, Packet};
 
 use log::debug;
 
+/// Zeros the payloads of MTU probes are cut from.
+static PROBE_PADDING: [u8; MAX_MTU as usize] = [0; MAX_MTU as usize];
+
 pub struct Connection {
     last_seen: Instant,
     last_sent: Instant,
     peer_address: SocketAddr,
 
     connectivity: ConnectivityHandler,
+    snapshots: DeltaEncoder,
+    priorities: PriorityAccumulator,
+    last_snapshot: Option<Instant>,
+    interest: Option<AreaOfInterest>,
+    visible_peers: InterestSet<SocketAddr>,
+    visible_bodies: InterestSet<u32>,
+    inputs: InputBuffer,
+    /// Latest rollback frame received from the peer.
+    frame: Option<u32>,
+    /// Whether the frame advantage has to be reported since a new frame arrived.
+    advantage_due: bool,
+    replication: ReplicationEncoder,
+    entity_updates: ReplicationDecoder,
+    /// Latest entity update received and the latest one acknowledged to the peer.
+    update_ack: (Option<u32>, Option<u32>),
+    received_updates: Vec<ReplicationDiff>,
+    authority_requests: Vec<(u32, bool)>,
+    messages: Vec<Bytes>,
+    /// Messages to the peer, coalesced until the end of the tick.
+    queued_messages: MessageQueue,
+    path_mtu: PathMtu,
+    /// Latest key the peer registered with the introducer.
+    registration: Option<u64>,
 }
 
 impl Connection {
-    pub fn new(peer_address: SocketAddr, time: Instant) -> Self {
+    /// Creates a connection the peer at `peer_address` initiates, this side is known to it by
+    /// `id`.
+    pub fn new(peer_address: SocketAddr, id: u64, time: Instant) -> Self {
+        Self::with_connectivity(peer_address, ConnectivityHandler::new(id), time)
+    }
+
+    /// Creates a connection to the peer at `peer_address`, the connect request is sent with the
+    /// next update.
+    pub fn outbound(peer_address: SocketAddr, id: u64, time: Instant) -> Self {
+        Self::with_connectivity(peer_address, ConnectivityHandler::outbound(id), time)
+    }
+
+    fn with_connectivity(
+        peer_address: SocketAddr,
+        connectivity: ConnectivityHandler,
+        time: Instant,
+    ) -> Self {
         Connection {
             last_seen: time,
             last_sent: time,
             peer_address,
-            connectivity: ConnectivityHandler::new(),
+            connectivity,
+            snapshots: DeltaEncoder::new(),
+            priorities: PriorityAccumulator::new(),
+            last_snapshot: None,
+            interest: None,
+            visible_peers: InterestSet::new(),
+            visible_bodies: InterestSet::new(),
+            inputs: InputBuffer::new(),
+            frame: None,
+            advantage_due: false,
+            replication: ReplicationEncoder::new(),
+            entity_updates: ReplicationDecoder::new(),
+            update_ack: (None, None),
+            received_updates: Vec::new(),
+            authority_requests: Vec::new(),
+            messages: Vec::new(),
+            queued_messages: MessageQueue::new(),
+            path_mtu: PathMtu::new(),
+            registration: None,
         }
     }
 
@@ -40,10 +119,11 @@ impl Connection {
         time.saturating_duration_since(self.last_sent)
     }
 
-    pub fn process_in(&mut self, payload: &[u8], time: Instant) -> Result<Option<Packet>> {
+    pub fn process_in(&mut self, datagram: Bytes, time: Instant) -> Result<Option<Packet>> {
         self.last_seen = time;
 
-        let mut reader = PacketReader::new(payload);
+        let compression = self.capabilities().contains(Capabilities::COMPRESSION);
+        let mut reader = PacketReader::new(&datagram).with_compression(compression);
         let header = reader.read_base_header()?;
         if !header.is_current_protocol() {
             return Err(ErrorKind::ProtocolVersionMismatch);
@@ -55,47 +135,398 @@ impl Connection {
             self.peer_address
         );
 
-        self.connectivity.process_in(&header, &mut reader)?;
+        self.connectivity.process_in(&header, &mut reader, time)?;
 
-        if header.packet_type() == PacketType::Data {
-            let payload = reader.read_payload();
-            return Ok(Some(Packet::new(self.peer_address, payload)));
+        match header.packet_type() {
+            PacketType::Data => {
+                let tick = reader.read_tick_header()?;
+                let payload = reader.read_shared_payload(&datagram)?;
+                return Ok(Some(
+                    Packet::new(self.peer_address, payload).with_tick(tick.tick()),
+                ));
+            }
+            PacketType::FrameInput => {
+                let frame = reader.read_tick_header()?.tick();
+                let origin = self.connectivity.peer_id().unwrap_or_default();
+                let payload = reader.read_shared_payload(&datagram)?;
+                if !matches!(self.frame, Some(last) if last >= frame) {
+                    self.frame = Some(frame);
+                    self.advantage_due = true;
+                }
+                return Ok(Some(
+                    Packet::new(self.peer_address, payload)
+                        .with_tick(frame)
+                        .with_frame_origin(origin),
+                ));
+            }
+            PacketType::SnapshotAck => {
+                let tick = reader.read_tick_header()?;
+                self.snapshots.ack(tick.tick());
+            }
+            PacketType::Input => {
+                reader.read_tick_header()?;
+                let payload = reader.read_shared_payload(&datagram)?;
+                self.inputs.receive(&payload)?;
+            }
+            PacketType::Interest => {
+                reader.read_tick_header()?;
+                let area = read_interest(&reader.read_payload()?)?;
+                self.set_interest(Some(area));
+            }
+            PacketType::MtuAck => {
+                // the tick header carries the size of the probe that arrived
+                let size = reader.read_tick_header()?.tick();
+                self.path_mtu.ack(size.min(u32::from(u16::MAX)) as u16);
+            }
+            PacketType::ReplicationAck => {
+                let tick = reader.read_tick_header()?;
+                self.replication.ack(tick.tick());
+            }
+            PacketType::EntityUpdate => {
+                let tick = reader.read_tick_header()?.tick();
+                let payload = reader.read_shared_payload(&datagram)?;
+                if let Some(diff) = self.entity_updates.decode(tick, &payload)? {
+                    self.received_updates.push(diff);
+                    self.update_ack.0 = Some(tick);
+                }
+            }
+            PacketType::AuthorityRequest => {
+                reader.read_tick_header()?;
+                let request = decode_authority(&reader.read_payload()?)?;
+                self.authority_requests.push(request);
+            }
+            PacketType::Message => {
+                reader.read_tick_header()?;
+                self.messages.push(reader.read_shared_payload(&datagram)?);
+            }
+            PacketType::Register => {
+                reader.read_tick_header()?;
+                self.registration = Some(read_register(&reader.read_payload()?)?);
+            }
+            _ => (),
         }
 
         Ok(None)
     }
 
-    pub fn process_out(&mut self, packet: &Packet, ptype: PacketType, time: Instant) -> Packet {
-        self.last_sent = time;
+    /// Encodes the snapshot of `states` at `tick` against the latest snapshot the peer
+    /// acknowledged, or in full when there is none.
+    ///
+    /// The snapshot is preceded by the last input tick processed from the peer. When not all
+    /// changed bodies fit into a packet, the ones with the highest priority are sent.
+    pub fn encode_snapshot(
+        &mut self,
+        config: &SnapshotConfig,
+        priority: &PriorityConfig,
+        tick: u32,
+        states: &[RigidBodyState],
+        time: Instant,
+    ) -> Bytes {
+        let elapsed = self
+            .last_snapshot
+            .map_or(Duration::default(), |last| time.saturating_duration_since(last));
+        self.last_snapshot = Some(time);
+        self.priorities.accumulate(priority, states, elapsed);
+
+        // the input ack precedes the snapshot
+        let budget = self.mtu() as usize - MAX_HEADER_SIZE - 4;
+        let snapshot =
+            self.snapshots
+                .encode_prioritized(config, tick, states, &mut self.priorities, budget);
+        let mut payload = BytesMut::with_capacity(4 + snapshot.len());
+        put_input_ack(self.inputs.processed(), &mut payload);
+        payload.extend_from_slice(&snapshot);
+        payload.freeze()
+    }
+
+    /// Sets the position the peer looks from, nearer bodies are sent more often.
+    pub fn set_focus(&mut self, focus: Option<[f32; 3]>) {
+        self.priorities.set_focus(focus);
+    }
+
+    /// Returns the area the peer wants to receive updates about, `None` for everything.
+    pub fn interest(&self) -> Option<AreaOfInterest> {
+        self.interest
+    }
+
+    /// Restricts the updates the peer receives to `area`, its center becomes the focus of
+    /// the snapshot priorities.
+    pub fn set_interest(&mut self, area: Option<AreaOfInterest>) {
+        self.interest = area;
+        self.set_focus(area.map(|area| area.center));
+    }
+
+    /// Updates which peers, located in `grid` at the center of their area of interest, are in
+    /// the area of interest of this one.
+    pub fn update_visible_peers(&mut self, grid: &SpatialGrid<SocketAddr>) {
+        if let Some(area) = &self.interest {
+            self.visible_peers.update(grid, area);
+        }
+    }
+
+    /// Checks if `packet` should be relayed to the peer.
+    ///
+    /// Only data packets are filtered, by the location of their sender. Senders which did not
+    /// report a location, flagged by `located`, reach everyone.
+    pub fn wants_relay(&self, packet: &Packet, located: bool) -> bool {
+        packet.packet_type() != PacketType::Data
+            || self.interest.is_none()
+            || !located
+            || self.visible_peers.contains(&packet.addr())
+    }
+
+    /// Returns the `states` inside the area of interest of the peer, located in `grid`, or
+    /// `None` when the peer wants all of them.
+    pub fn visible_states(
+        &mut self,
+        grid: &SpatialGrid<u32>,
+        states: &[RigidBodyState],
+    ) -> Option<Vec<RigidBodyState>> {
+        let area = self.interest.as_ref()?;
+        self.visible_bodies.update(grid, area);
+        let visible = &self.visible_bodies;
+        Some(
+            states
+                .iter()
+                .filter(|state| visible.contains(&state.entity))
+                .copied()
+                .collect(),
+        )
+    }
+
+    /// Encodes the changes of `world` the peer did not acknowledge yet, preceded by the last
+    /// entity update received from it. The packet stays within the path MTU, the changes left
+    /// out follow in later packets.
+    ///
+    /// Returns `None` when there is nothing new to tell the peer.
+    pub fn encode_replication(&mut self, world: &ReplicatedWorld, tick: u32) -> Option<Bytes> {
+        let budget = self.mtu() as usize - MAX_HEADER_SIZE - 4;
+        let receiver = self.peer_id().map(|_| self.session_id());
+        let diff = self.replication.encode(world, tick, receiver, budget);
+        let (received, acked) = self.update_ack;
+        if diff.is_none() && received == acked {
+            return None;
+        }
+        self.update_ack.1 = received;
+
+        let diff = diff.unwrap_or_default();
+        let mut payload = BytesMut::with_capacity(4 + diff.len());
+        put_input_ack(received, &mut payload);
+        payload.extend_from_slice(&diff);
+        Some(payload.freeze())
+    }
+
+    /// Takes the entity changes received from the peer since the last call.
+    pub fn take_entity_updates(&mut self) -> Vec<ReplicationDiff> {
+        mem::take(&mut self.received_updates)
+    }
+
+    /// Takes the authority requests received from the peer since the last call, `true` marks
+    /// a release.
+    pub fn take_authority_requests(&mut self) -> Vec<(u32, bool)> {
+        mem::take(&mut self.authority_requests)
+    }
+
+    /// Returns the optional features agreed on with the peer.
+    pub fn capabilities(&self) -> Capabilities {
+        self.connectivity.capabilities()
+    }
+
+    /// Takes the payloads of the message packets received since the last call.
+    pub fn take_messages(&mut self) -> Vec<Bytes> {
+        mem::take(&mut self.messages)
+    }
+
+    /// Takes the key the peer registered with the introducer since the last call.
+    pub fn take_registration(&mut self) -> Option<u64> {
+        self.registration.take()
+    }
+
+    /// Returns the messages queued to the peer, they are sent together at the end of the tick
+    /// in packets within the path MTU.
+    pub fn queued_messages(&mut self) -> &mut MessageQueue {
+        self.queued_messages.set_capacity(self.mtu() as usize - MAX_HEADER_SIZE);
+        &mut self.queued_messages
+    }
+
+    /// Returns the id the peer sent with its connect request.
+    pub fn peer_id(&self) -> Option<u64> {
+        self.connectivity.peer_id()
+    }
 
-        let out = OutgoingPacketBuilder::new(packet.payload())
+    /// Takes the oldest input received from the peer, it is acknowledged with the next
+    /// snapshot.
+    pub fn pop_input(&mut self) -> Option<Input> {
+        self.inputs.pop()
+    }
+
+    /// Prepends the headers for this connection to `payload`, the headers are written into
+    /// `header` while the payload is shared as is.
+    ///
+    /// `tick` is the simulation tick the payload belongs to.
+    pub fn process_out(
+        &mut self,
+        payload: Bytes,
+        header: BytesMut,
+        ptype: PacketType,
+        tick: u32,
+        time: Instant,
+    ) -> Result<(SocketAddr, OutgoingPacket)> {
+        let mut builder = OutgoingPacketBuilder::shared(payload, header)
             .with_default_header(ptype)
             .with_session_header(self.connectivity.session_id())
-            .build();
+            .with_tick_header(tick);
+        // snapshots and replication are large and per connection, relayed payloads are not
+        if matches!(ptype, PacketType::Snapshot | PacketType::Replication)
+            && self.capabilities().contains(Capabilities::COMPRESSION)
+        {
+            builder = builder.with_compression();
+        }
+        let out = builder.build()?;
+        self.last_sent = time;
+
+        Ok((self.peer_address, out))
+    }
+
+    /// Prepends the headers for this connection to a packet received from another peer, fails
+    /// with `PayloadTooLarge` when it exceeds the path MTU.
+    ///
+    /// `FrameInput` packets additionally carry the id of the peer they came from.
+    pub fn relay_out(
+        &mut self,
+        packet: &Packet,
+        header: BytesMut,
+        time: Instant,
+    ) -> Result<(SocketAddr, OutgoingPacket)> {
+        let mut builder = OutgoingPacketBuilder::shared(packet.shared_payload(), header)
+            .with_default_header(packet.packet_type())
+            .with_session_header(self.connectivity.session_id())
+            .with_tick_header(packet.tick());
+        if packet.packet_type() == PacketType::FrameInput {
+            builder = builder.with_session_header(packet.origin());
+        }
+        let out = builder.build()?;
+        // the path to this peer may be narrower than the one of the sender
+        if out.len() > self.mtu() as usize {
+            return Err(ErrorKind::PayloadTooLarge(out.len()));
+        }
+        self.last_sent = time;
+
+        Ok((self.peer_address, out))
+    }
+
+    /// Returns the largest datagram known to reach the peer, payloads sent to it are sized to
+    /// fit.
+    pub fn mtu(&self) -> u16 {
+        self.path_mtu.mtu()
+    }
+
+    /// Creates the next path MTU probe once connected, padded to the probed size.
+    pub fn create_mtu_probe(
+        &mut self,
+        tick: u32,
+        time: Instant,
+    ) -> Result<Option<(SocketAddr, OutgoingPacket)>> {
+        if !self.is_connected() {
+            return Ok(None);
+        }
+        let size = match self.path_mtu.probe_due(time) {
+            Some(size) => size as usize,
+            None => return Ok(None),
+        };
+        let headers = (BASE_HEADER_SIZE + SESSION_HEADER_SIZE + TICK_HEADER_SIZE) as usize;
+        let padding = Bytes::from_static(&PROBE_PADDING[..size - headers]);
+        self.process_out(
+            padding,
+            BytesMut::with_capacity(MAX_HEADER_SIZE),
+            PacketType::MtuProbe,
+            tick,
+            time,
+        )
+        .map(Some)
+    }
+
+    /// Returns the latest rollback frame received from the peer.
+    pub fn frame(&self) -> Option<u32> {
+        self.frame
+    }
+
+    /// Creates a `FrameAdvantage` packet after a new frame arrived, reporting how many frames
+    /// the peer runs ahead of `slowest`, the lowest frame of the other peers.
+    pub fn create_advantage_packet(
+        &mut self,
+        slowest: u32,
+        tick: u32,
+        time: Instant,
+    ) -> Result<Option<(SocketAddr, OutgoingPacket)>> {
+        let frame = match self.frame {
+            Some(frame) if self.advantage_due => frame,
+            _ => return Ok(None),
+        };
+        self.advantage_due = false;
 
-        Packet::new(self.peer_address, out.contents())
+        let advantage = (i64::from(frame) - i64::from(slowest))
+            .clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32;
+        let mut payload = BytesMut::with_capacity(4);
+        payload.put_i32(advantage);
+        self.process_out(
+            payload.freeze(),
+            BytesMut::with_capacity(MAX_HEADER_SIZE),
+            PacketType::FrameAdvantage,
+            tick,
+            time,
+        )
+        .map(Some)
     }
 
-    pub fn update(&mut self, time: Instant) -> Option<Packet> {
+    /// Creates connect, time sync and heartbeat packets if needed, stamped with the tick of the
+    /// local `clock`.
+    pub fn update(
+        &mut self,
+        clock: &TickScheduler,
+        time: Instant,
+    ) -> Result<Option<(SocketAddr, OutgoingPacket)>> {
+        let tick = clock.tick_at(time);
         debug!(
             "last seen {:?}, last sent {:?} @{:?}",
             self.last_seen(time),
             self.last_sent(time),
             self
         );
-        if let Some(connect) = self
-            .connectivity
-            .create_connection_packet(self.peer_address)
-        {
+        if let Some((ptype, connect)) = self.connectivity.create_connection_packet(time) {
             debug!("connect!");
-            return Some(self.process_out(&connect, PacketType::Connect, time));
+            return self.process_out(
+                connect,
+                BytesMut::with_capacity(MAX_HEADER_SIZE),
+                ptype,
+                tick,
+                time,
+            )
+            .map(Some);
+        } else if let Some(pong) = self.connectivity.create_time_packet(clock, time) {
+            return self
+                .process_out(
+                    pong,
+                    BytesMut::with_capacity(MAX_HEADER_SIZE),
+                    PacketType::TimePong,
+                    tick,
+                    time,
+                )
+                .map(Some);
         } else if self.last_sent(time) >= DEFAULT_HEARTBEAT {
             debug!("heartbeat!");
-            let heartbeat = Packet::new(self.peer_address, Box::default());
-            return Some(self.process_out(&heartbeat, PacketType::Heartbeat, time));
+            return self
+                .process_out(
+                    Bytes::new(),
+                    BytesMut::with_capacity(MAX_HEADER_SIZE),
+                    PacketType::Heartbeat,
+                    tick,
+                    time,
+                )
+                .map(Some);
         }
 
-        None
+        Ok(None)
     }
 
     pub fn should_drop(&self, time: Instant) -> bool {
@@ -111,7 +542,70 @@ impl Connection {
     }
 
     pub fn is_ready(&self, sender: &SocketAddr) -> bool {
-        self.connectivity.is_connected() && *sender != self.peer_address
+        self.is_connected() && *sender != self.peer_address
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connectivity.is_connected()
+    }
+
+    /// Returns the session id both sides stamp their packets with, 0 before the handshake.
+    pub fn session_id(&self) -> u64 {
+        self.connectivity.session_id()
+    }
+
+    /// Processes a datagram of the peer arriving from `peer_address`, the connection moves
+    /// there when it is valid and the path MTU is discovered again.
+    ///
+    /// An invalid datagram leaves the connection untouched.
+    pub fn process_migrated(
+        &mut self,
+        datagram: Bytes,
+        peer_address: SocketAddr,
+        time: Instant,
+    ) -> Result<Option<Packet>> {
+        let (from, last_seen) = (self.peer_address, self.last_seen);
+        self.peer_address = peer_address;
+        match self.process_in(datagram, time) {
+            Ok(packet) => {
+                self.path_mtu = PathMtu::new();
+                Ok(packet)
+            }
+            Err(e) => {
+                self.peer_address = from;
+                self.last_seen = last_seen;
+                Err(e)
+            }
+        }
+    }
+
+    /// Checks if this side initiated the connection.
+    pub fn is_outbound(&self) -> bool {
+        self.connectivity.is_outbound()
+    }
+
+    /// Checks if the peer is a member of a mesh rather than a client.
+    pub fn is_mesh(&self) -> bool {
+        self.connectivity.is_mesh()
+    }
+
+    /// Checks if the connection was established at some point, it may be closed by now.
+    pub fn was_established(&self) -> bool {
+        self.connectivity.was_established()
+    }
+
+    /// Creates the packet telling the peer that the connection is closed, the connection is
+    /// dropped with the next update.
+    pub fn disconnect(&mut self, tick: u32, time: Instant) -> Result<(SocketAddr, OutgoingPacket)> {
+        let out = self.process_out(
+            Bytes::new(),
+            BytesMut::with_capacity(MAX_HEADER_SIZE),
+            PacketType::Disconnect,
+            tick,
+            time,
+        );
+        self.connectivity.disconnect();
+        out
     }
 }
 
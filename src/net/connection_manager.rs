@@ -1,99 +1,923 @@
-use std::collections::HashMap;
+use std::collections::vec_deque;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
+use std::mem;
 use std::net::SocketAddr;
 use std::time::Instant;
+use std::vec::Drain;
+
+use bytes::{Bytes, BytesMut};
 
 use log::{debug, error, info};
+use rand::random;
+use tokio::time::delay_until;
 
 use crate::errors::Result;
-use crate::features::ThroughputMonitoring;
-use crate::net::{Connection, Socket};
-use crate::net::constants::DEFAULT_MTU;
-use crate::Packet;
-use crate::packet::PacketType;
+use crate::features::{
+    AreaOfInterest, Capabilities, Introducer, SpatialGrid, ThroughputMonitoring, TickScheduler,
+};
+use crate::net::{Connection, PeerEvent, Socket};
+use crate::net::shard::SessionDirectory;
+use crate::net::constants::{
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_TICK_RATE, INTEREST_CELL_SIZE, MAX_HEADER_SIZE, MAX_MTU,
+    MAX_PEER_EVENTS, MIGRATION_QUIET_TIME, RECV_BATCH_SIZE,
+};
+use crate::{OutgoingPacket, Packet};
+use crate::packet::{BufferPool, PacketReader, PacketType};
+use crate::input::Input;
+use crate::message::{Dispatcher, Message, MessageBatch};
+use crate::replication::{encode_authority, ReplicatedWorld};
+use crate::snapshot::{PriorityConfig, RigidBodyState, SnapshotConfig};
 
 // would be nicer to have a trait dependency on socket impl, but traits does not support async
 #[derive(Debug)]
 pub struct ConnectionManager {
+    /// Id this side is known by to all peers.
+    id: u64,
     connections: HashMap<SocketAddr, Connection>,
-    buffer: Vec<u8>,
+    /// Addresses of the established connections by their session id, to follow peers whose
+    /// address changes.
+    sessions: HashMap<u64, SocketAddr>,
+    events: VecDeque<PeerEvent>,
+    /// Host of the mesh reported last, `None` is this side.
+    host: Option<SocketAddr>,
+    pool: BufferPool,
+    buffers: Vec<BytesMut>,
+    received: Vec<(usize, SocketAddr)>,
+    outgoing: Vec<(SocketAddr, OutgoingPacket)>,
+    /// Data packets to relay to other shards, along with the center of the sender's area of
+    /// interest.
+    relayed: Vec<(Packet, Option<[f32; 3]>)>,
+    /// Shards owning the sessions when this is a shard of a server, `None` otherwise.
+    directory: Option<SessionDirectory>,
+    /// Datagrams of sessions owned by other shards, along with the index of the owner.
+    forwarded: Vec<(usize, Bytes, SocketAddr)>,
+    /// Rollback frames of the peers of other shards, by the index of their shard.
+    foreign_frames: HashMap<usize, Vec<(SocketAddr, u32)>>,
+    /// Tick the rollback frames were shared with other shards in last, `None` when there were
+    /// none.
+    frames_shared: Option<u32>,
     socket: Socket,
+    ticks: TickScheduler,
+    snapshot_config: SnapshotConfig,
+    priority_config: PriorityConfig,
+    world: ReplicatedWorld,
+    dispatcher: Dispatcher,
+    /// Tick the queued messages were sent in last.
+    messages_flushed: Option<u32>,
+    /// Pairs registering peers when running as introducer.
+    introducer: Option<Introducer>,
+    peer_grid: SpatialGrid<SocketAddr>,
+    /// Centers of the areas of interest of peers of other shards, with the time they were
+    /// reported.
+    foreign_centers: HashMap<SocketAddr, ([f32; 3], Instant)>,
+    body_grid: SpatialGrid<u32>,
     monitor_in: ThroughputMonitoring,
     monitor_out: ThroughputMonitoring,
 }
 
 impl ConnectionManager {
     pub fn new(socket: Socket) -> Self {
+        let mut pool = BufferPool::default();
+        let buffers = (0..RECV_BATCH_SIZE)
+            .map(|_| pool.acquire_filled(MAX_MTU as usize))
+            .collect();
+
         ConnectionManager {
+            id: random(),
             connections: HashMap::new(),
-            buffer: vec![0; DEFAULT_MTU as usize],
+            sessions: HashMap::new(),
+            events: VecDeque::new(),
+            host: None,
+            pool,
+            buffers,
+            received: Vec::with_capacity(RECV_BATCH_SIZE),
+            outgoing: Vec::new(),
+            relayed: Vec::new(),
+            directory: None,
+            forwarded: Vec::new(),
+            foreign_frames: HashMap::new(),
+            frames_shared: None,
             socket,
+            ticks: TickScheduler::new(DEFAULT_TICK_RATE, Instant::now()),
+            snapshot_config: SnapshotConfig::default(),
+            priority_config: PriorityConfig::default(),
+            world: ReplicatedWorld::new(),
+            dispatcher: Dispatcher::new(),
+            messages_flushed: None,
+            introducer: None,
+            peer_grid: SpatialGrid::new(INTEREST_CELL_SIZE),
+            foreign_centers: HashMap::new(),
+            body_grid: SpatialGrid::new(INTEREST_CELL_SIZE),
             monitor_in: Default::default(),
             monitor_out: Default::default(),
         }
     }
 
-    /// Poll one read/write cycle
+    /// Returns the id this side is known by to all peers.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Starts connecting to the peer at `addr`, returns `false` when there is a connection to it
+    /// already.
+    pub fn connect(&mut self, addr: SocketAddr, time: Instant) -> bool {
+        if self.connections.contains_key(&addr) {
+            return false;
+        }
+        let con = Connection::outbound(addr, self.id, time);
+        self.connections.insert(addr, con);
+        true
+    }
+
+    /// Tells the peer at `addr` that the connection is closed, it is dropped with the next
+    /// update. Returns `false` for unknown peers.
+    pub fn disconnect(&mut self, addr: &SocketAddr, time: Instant) -> bool {
+        let con = match self.connections.get_mut(addr) {
+            Some(con) => con,
+            None => return false,
+        };
+        match con.disconnect(self.ticks.tick_at(time), time) {
+            Ok(p) => self.outgoing.push(p),
+            Err(e) => error!("could not disconnect {:?}: {}", con, e),
+        }
+        true
+    }
+
+    /// Returns the host of the mesh, the member with the lowest id among this side and all
+    /// connected peers. `None` when it is this side.
+    pub fn host(&self) -> Option<SocketAddr> {
+        self.connections
+            .iter()
+            .filter(|(_, con)| con.is_connected())
+            .filter_map(|(addr, con)| con.peer_id().map(|id| (id, *addr)))
+            .min()
+            .filter(|(id, _)| *id < self.id)
+            .map(|(_, addr)| addr)
+    }
+
+    /// Takes the connection changes since the last call, the oldest ones are dropped when more
+    /// than [MAX_PEER_EVENTS] pile up.
+    ///
+    /// [MAX_PEER_EVENTS]: ../constants/constant.MAX_PEER_EVENTS.html
+    pub fn drain_events(&mut self) -> vec_deque::Drain<'_, PeerEvent> {
+        self.events.drain(..)
+    }
+
+    fn push_event(events: &mut VecDeque<PeerEvent>, event: PeerEvent) {
+        if events.len() == MAX_PEER_EVENTS {
+            debug!("dropping peer event {:?}, nobody takes them", events.pop_front());
+        }
+        events.push_back(event);
+    }
+
+    /// Replaces the simulation clock, connections are updated on each of its ticks.
+    pub fn set_ticks(&mut self, ticks: TickScheduler) {
+        self.ticks = ticks;
+    }
+
+    /// Returns the simulation clock.
+    pub fn ticks(&self) -> &TickScheduler {
+        &self.ticks
+    }
+
+    pub fn set_snapshot_config(&mut self, config: SnapshotConfig) {
+        self.snapshot_config = config;
+    }
+
+    pub fn set_priority_config(&mut self, config: PriorityConfig) {
+        self.priority_config = config;
+    }
+
+    /// Sets the position the peer at `addr` looks from, returns `false` for unknown peers.
+    pub fn set_focus(&mut self, addr: &SocketAddr, focus: Option<[f32; 3]>) -> bool {
+        match self.connections.get_mut(addr) {
+            Some(con) => {
+                con.set_focus(focus);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restricts the updates the peer at `addr` receives to `area`, returns `false` for
+    /// unknown peers.
+    pub fn set_interest(&mut self, addr: &SocketAddr, area: Option<AreaOfInterest>) -> bool {
+        match self.connections.get_mut(addr) {
+            Some(con) => {
+                con.set_interest(area);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queues the snapshot of `states` at the current tick to every connected peer, delta
+    /// compressed against what each of them acknowledged.
+    ///
+    /// Peers with an area of interest only get the bodies inside it.
+    pub fn send_snapshot(&mut self, states: &[RigidBodyState], time: Instant) {
+        let tick = self.ticks.tick_at(time);
+        self.body_grid.clear();
+        if self.connections.values().any(|con| con.interest().is_some()) {
+            for state in states {
+                self.body_grid.insert(state.entity, state.position);
+            }
+        }
+        for con in self.connections.values_mut().filter(|con| con.is_connected()) {
+            let visible = con.visible_states(&self.body_grid, states);
+            let states = visible.as_deref().unwrap_or(states);
+            let payload =
+                con.encode_snapshot(&self.snapshot_config, &self.priority_config, tick, states, time);
+            let header = self.pool.acquire(MAX_HEADER_SIZE);
+            match con.process_out(payload, header, PacketType::Snapshot, tick, time) {
+                Ok(p) => self.outgoing.push(p),
+                Err(e) => error!("could not send snapshot to {:?}: {}", con, e),
+            }
+        }
+    }
+
+    /// Returns the entities replicated to all connected peers.
+    pub fn world(&mut self) -> &mut ReplicatedWorld {
+        &mut self.world
+    }
+
+    /// Queues the changes of the replicated entities each connected peer did not acknowledge
+    /// yet.
+    pub fn send_replication(&mut self, time: Instant) {
+        let tick = self.ticks.tick_at(time);
+        for con in self.connections.values_mut().filter(|con| con.is_connected()) {
+            let payload = match con.encode_replication(&self.world, tick) {
+                Some(payload) => payload,
+                None => continue,
+            };
+            let header = self.pool.acquire(MAX_HEADER_SIZE);
+            match con.process_out(payload, header, PacketType::Replication, tick, time) {
+                Ok(p) => self.outgoing.push(p),
+                Err(e) => error!("could not send replication to {:?}: {}", con, e),
+            }
+        }
+    }
+
+    /// Returns the optional features agreed on with the connected peer at `addr`.
+    pub fn capabilities(&self, addr: &SocketAddr) -> Option<Capabilities> {
+        self.connections
+            .get(addr)
+            .filter(|con| con.is_connected())
+            .map(Connection::capabilities)
+    }
+
+    /// Returns the largest datagram known to reach the connected peer at `addr`.
+    pub fn path_mtu(&self, addr: &SocketAddr) -> Option<u16> {
+        self.connections
+            .get(addr)
+            .filter(|con| con.is_connected())
+            .map(Connection::mtu)
+    }
+
+    /// Returns the session id of the connected peer at `addr`.
+    #[cfg(test)]
+    pub(crate) fn session_id(&self, addr: &SocketAddr) -> Option<u64> {
+        self.connections
+            .get(addr)
+            .filter(|con| con.is_connected())
+            .map(Connection::session_id)
+    }
+
+    /// Turns the introducer mode on or off, peers registering the same key are introduced to
+    /// each other while it is on.
+    pub fn set_introducer(&mut self, enabled: bool) {
+        if enabled != self.introducer.is_some() {
+            self.introducer = if enabled { Some(Introducer::new()) } else { None };
+        }
+    }
+
+    /// Shares the established sessions with the other shards of a server through `directory`,
+    /// datagrams of their sessions are handed over to them.
+    pub(crate) fn set_directory(&mut self, directory: SessionDirectory) {
+        self.directory = Some(directory);
+    }
+
+    /// Returns the datagrams of sessions owned by other shards since the last call, along with
+    /// the index of the owning shard.
+    pub(crate) fn drain_forwarded(&mut self) -> Drain<'_, (usize, Bytes, SocketAddr)> {
+        self.forwarded.drain(..)
+    }
+
+    /// Returns the latest rollback frames of the connected peers once per tick, so other shards
+    /// can compare their peers with them. `None` when they were shared in this tick already or
+    /// there are none, after the last ones went away.
+    pub(crate) fn share_frames(&mut self, time: Instant) -> Option<Vec<(SocketAddr, u32)>> {
+        let tick = self.ticks.tick_at(time);
+        let frames = self.frames();
+        let unchanged = frames.is_empty() && self.frames_shared.is_none();
+        if unchanged || self.frames_shared == Some(tick) {
+            return None;
+        }
+        self.frames_shared = Some(tick).filter(|_| !frames.is_empty());
+        Some(frames)
+    }
+
+    /// Replaces the rollback frames of the peers of the shard with `index`, the frame advantage
+    /// of the own peers is reported against them as well.
+    pub(crate) fn set_foreign_frames(&mut self, index: usize, frames: Vec<(SocketAddr, u32)>) {
+        if frames.is_empty() {
+            self.foreign_frames.remove(&index);
+        } else {
+            self.foreign_frames.insert(index, frames);
+        }
+    }
+
+    /// Returns the dispatcher of the messages received from all peers.
+    pub fn dispatcher(&mut self) -> &mut Dispatcher {
+        &mut self.dispatcher
+    }
+
+    /// Queues `batch` to the connected peer at `addr`, returns `false` for unknown peers.
+    pub fn send_messages(&mut self, addr: &SocketAddr, batch: MessageBatch, time: Instant) -> bool {
+        let con = match self.connections.get_mut(addr).filter(|con| con.is_connected()) {
+            Some(con) => con,
+            None => return false,
+        };
+        let tick = self.ticks.tick_at(time);
+        let header = self.pool.acquire(MAX_HEADER_SIZE);
+        match con.process_out(batch.finish(), header, PacketType::Message, tick, time) {
+            Ok(p) => self.outgoing.push(p),
+            Err(e) => error!("could not send messages to {:?}: {}", con, e),
+        }
+        true
+    }
+
+    /// Queues `message` to the connected peer at `addr`, returns `false` for unknown peers.
+    ///
+    /// Messages queued within a tick are coalesced into as few packets as possible and sent when
+    /// the next tick starts, or by [flush_messages](#method.flush_messages).
+    pub fn queue_message<M: Message>(&mut self, addr: &SocketAddr, message: &M) -> Result<bool> {
+        match self.connections.get_mut(addr).filter(|con| con.is_connected()) {
+            Some(con) => con.queued_messages().push(message).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Sends the messages queued to all peers with the next flush.
+    pub fn flush_messages(&mut self, time: Instant) {
+        let tick = self.ticks.tick_at(time);
+        for con in self.connections.values_mut() {
+            for payload in con.queued_messages().take() {
+                let header = self.pool.acquire(MAX_HEADER_SIZE);
+                match con.process_out(payload, header, PacketType::Message, tick, time) {
+                    Ok(p) => self.outgoing.push(p),
+                    Err(e) => error!("could not send messages to {:?}: {}", con, e),
+                }
+            }
+        }
+        self.messages_flushed = Some(tick);
+    }
+
+    /// Takes the oldest pending input of every connection, meant to be called once per tick.
+    pub fn next_inputs(&mut self) -> Vec<(SocketAddr, Input)> {
+        self.connections
+            .iter_mut()
+            .filter_map(|(addr, con)| con.pop_input().map(|input| (*addr, input)))
+            .collect()
+    }
+
+    /// Poll one read/write cycle starting at `time`, returns at the latest when the next tick
+    /// starts.
+    ///
+    /// What arrived is processed at the time the wait ended.
     pub async fn manual_poll(&mut self, time: Instant) -> Result<()> {
-        match self.socket.receive_packet(self.buffer.as_mut()).await {
-            Ok((payload, peer)) => {
-                debug!("************************{:?}", time.elapsed());
+        let deadline = self.ticks.next_deadline(time);
+        let received = tokio::select! {
+            received = self.receive() => Some(received),
+            _ = delay_until(deadline.into()) => None,
+        };
+        let now = Instant::now();
+        match received {
+            Some(Ok(())) => {
+                debug!("************************{:?}", now.saturating_duration_since(time));
+                self.process_received(now);
+            }
+            Some(Err(e)) => error!("encountered read socket error: {}", e),
+            None => (),
+        }
+        // relayed data already went to local connections, there is nobody else to forward to
+        self.relayed.clear();
 
+        self.update(now);
+        self.flush().await
+    }
+
+    /// Waits for at least one datagram on the socket, use
+    /// [process_received](#method.process_received) to handle them.
+    pub async fn receive(&mut self) -> Result<()> {
+        self.received.clear();
+        self.socket
+            .receive_packets(&mut self.buffers, &mut self.received)
+            .await
+    }
+
+    /// Processes all datagrams read by the last [receive](#method.receive).
+    ///
+    /// Data packets of clients are relayed to local connections and collected for
+    /// [drain_relayed](#method.drain_relayed), the ones of mesh members are not relayed again.
+    pub fn process_received(&mut self, time: Instant) {
+        for i in 0..self.received.len() {
+            let (len, peer) = self.received[i];
+            // hand the filled buffer over to the packet and put a fresh one in its slot
+            let fresh = self.pool.acquire_filled(MAX_MTU as usize);
+            let mut datagram = mem::replace(&mut self.buffers[i], fresh);
+            datagram.truncate(len);
+
+            if let Err(e) = self.process_datagram(datagram.freeze(), peer, time) {
+                error!("dropping datagram from {}: {}", peer, e);
+            }
+        }
+        self.received.clear();
+    }
+
+    /// Processes a single datagram received from `peer`.
+    ///
+    /// A datagram of an established session arriving from another address moves the
+    /// connection there, but only once its current address was quiet for
+    /// [MIGRATION_QUIET_TIME] and the datagram is valid. On a sharded server the datagram is
+    /// handed over to the shard owning the session, see
+    /// [drain_forwarded](#method.drain_forwarded).
+    pub fn process_datagram(&mut self, datagram: Bytes, peer: SocketAddr, time: Instant) -> Result<()> {
+        self.monitor_in.tick();
+
+        let mut migrating = None;
+        if !self.connections.contains_key(&peer) {
+            let session = read_session(&datagram);
+            if let Some(from) = session.and_then(|session| self.session_address(session)) {
+                if self.connections[&from].last_seen(time) < MIGRATION_QUIET_TIME {
+                    debug!("ignoring {} sending the session of {}, which is active", peer, from);
+                    return Ok(());
+                }
+                migrating = Some(from);
+            } else if let Some(owner) = session.and_then(|session| self.foreign_owner(session)) {
+                self.forwarded.push((owner, datagram, peer));
+                return Ok(());
+            }
+        }
+
+        let (was_connected, relayed) = match migrating {
+            Some(from) => (true, self.follow_migration(datagram, from, peer, time)),
+            None => {
+                let id = self.id;
                 let connection = self
                     .connections
                     .entry(peer)
-                    .or_insert_with(|| Connection::new(peer, time));
+                    .or_insert_with(|| Connection::new(peer, id, time));
+                (connection.is_connected(), connection.process_in(datagram, time))
+            }
+        };
+        // a rejected migration leaves the connection at its address
+        let (connected, session) = match self.connections.get(&peer) {
+            Some(connection) => (connection.is_connected(), connection.session_id()),
+            None => return relayed.map(|_| ()),
+        };
+        if !was_connected && connected {
+            // entities are owned by session, a peer must not take over the one of another
+            if matches!(self.sessions.get(&session), Some(other) if self.connections.contains_key(other)) {
+                debug!("dropping connection of {}, its session is in use", peer);
+                self.connections.remove(&peer);
+                return Ok(());
+            }
+            Self::push_event(&mut self.events, PeerEvent::Connected(peer));
+            self.sessions.insert(session, peer);
+            if let Some(directory) = &self.directory {
+                directory.insert(session);
+            }
+        }
+        let connection = match self.connections.get_mut(&peer) {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+
+        // resend data packets to other peers
+        let relayed = relayed?;
+        // members of a mesh are connected to each other, relaying their data again would loop
+        let relayed = relayed.filter(|_| !connection.is_mesh());
 
-                // resend data packets to other peers
-                match connection.process_in(payload, time)? {
-                    Some(packet) => self.push_to_all(packet, time).await?,
-                    _ => (),
+        // entity changes and authority requests are arbitrated here instead of relayed, the
+        // entities of a peer are owned by its session
+        let mut registration = None;
+        if let Some(peer_id) = connection.peer_id() {
+            registration = connection.take_registration().map(|key| (key, peer_id));
+            for diff in connection.take_entity_updates() {
+                self.world.apply_owned(session, diff);
+            }
+            let tick = self.ticks.tick_at(time);
+            for (entity, release) in connection.take_authority_requests() {
+                let owned = self.world.request_authority(session, entity, release);
+                let header = self.pool.acquire(MAX_HEADER_SIZE);
+                let payload = encode_authority(entity, owned);
+                match connection.process_out(payload, header, PacketType::AuthorityResponse, tick, time) {
+                    Ok(p) => self.outgoing.push(p),
+                    Err(e) => error!("could not answer authority request of {}: {}", peer, e),
                 }
             }
-            Err(e) => error!("encountered read socket error: {}", e),
         }
-        self.monitor_in.tick();
 
-        // update all connections
+        for payload in connection.take_messages() {
+            if let Err(e) = self.dispatcher.dispatch(peer, &payload) {
+                error!("dropping messages from {}: {}", peer, e);
+            }
+        }
+
+        if let Some(packet) = relayed {
+            let center = connection.interest().map(|area| area.center);
+            Self::push_to_all(
+                &mut self.connections,
+                &mut self.pool,
+                &mut self.outgoing,
+                &packet,
+                center.is_some(),
+                time,
+            );
+            self.relayed.push((packet, center));
+        }
+        if let Some((key, peer_id)) = registration {
+            self.introduce(key, peer, peer_id, time);
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the established connection of `session`.
+    fn session_address(&self, session: u64) -> Option<SocketAddr> {
+        self.sessions
+            .get(&session)
+            .filter(|from| self.connections.contains_key(from))
+            .copied()
+    }
+
+    /// Returns the index of the shard owning `session`, `None` when it is this one or unknown.
+    fn foreign_owner(&self, session: u64) -> Option<usize> {
+        let directory = self.directory.as_ref()?;
+        directory
+            .owner(session)
+            .filter(|owner| *owner != directory.shard())
+    }
+
+    /// Moves the connection at `from` to `to` if `datagram`, which arrived from there, is valid.
+    fn follow_migration(
+        &mut self,
+        datagram: Bytes,
+        from: SocketAddr,
+        to: SocketAddr,
+        time: Instant,
+    ) -> Result<Option<Packet>> {
+        let mut con = match self.connections.remove(&from) {
+            Some(con) => con,
+            None => return Ok(None),
+        };
+        let relayed = con.process_migrated(datagram, to, time);
+        if relayed.is_err() {
+            self.connections.insert(from, con);
+            return relayed;
+        }
+
+        info!("connection of {} migrated to {}", from, to);
+        self.sessions.insert(con.session_id(), to);
+        self.connections.insert(to, con);
+        if let Some(introducer) = &mut self.introducer {
+            introducer.migrate(&from, to);
+        }
+        Self::push_event(&mut self.events, PeerEvent::Migrated { from, to });
+        relayed
+    }
+
+    /// Registers the peer with `id` at `addr` for `key` and queues the introductions of a
+    /// completed pair.
+    fn introduce(&mut self, key: u64, addr: SocketAddr, id: u64, time: Instant) {
+        let introductions = match &mut self.introducer {
+            Some(introducer) => introducer.register(key, addr, id),
+            None => {
+                debug!("ignoring registration of {}, not an introducer", addr);
+                return;
+            }
+        };
+        let tick = self.ticks.tick_at(time);
+        for (to, payload) in introductions {
+            let con = match self.connections.get_mut(&to) {
+                Some(con) => con,
+                None => continue,
+            };
+            let header = self.pool.acquire(MAX_HEADER_SIZE);
+            match con.process_out(payload, header, PacketType::Introduction, tick, time) {
+                Ok(p) => self.outgoing.push(p),
+                Err(e) => error!("could not introduce {:?}: {}", con, e),
+            }
+        }
+    }
+
+    /// Returns the data packets received since the last call, so they can be relayed to
+    /// connections owned by someone else, along with the center of the sender's area of
+    /// interest.
+    pub fn drain_relayed(&mut self) -> Drain<'_, (Packet, Option<[f32; 3]>)> {
+        self.relayed.drain(..)
+    }
+
+    /// Queues `packet` of a peer of another shard to every ready connection.
+    ///
+    /// Data packets only go to the peers whose area of interest contains `center`, the one of
+    /// the sender's area. Senders without one reach everyone.
+    pub fn relay(&mut self, packet: &Packet, center: Option<[f32; 3]>, time: Instant) {
+        match center {
+            Some(center) => self.foreign_centers.insert(packet.addr(), (center, time)),
+            None => self.foreign_centers.remove(&packet.addr()),
+        };
+        Self::push_to_all(
+            &mut self.connections,
+            &mut self.pool,
+            &mut self.outgoing,
+            packet,
+            center.is_some(),
+            time,
+        );
+    }
+
+    /// Updates all connections, queuing connect and heartbeat packets and removing the ones
+    /// that should be dropped.
+    ///
+    /// # Remarks
+    /// - Peers that sent a new rollback frame are told how far they run ahead of the slowest
+    ///   other peer. On a sharded server the peers of other shards are compared by the frames
+    ///   their shard shared last.
+    /// - The first update of a tick sends the messages queued during the previous one.
+    /// - The path MTU of connected peers is probed until the largest working size is found.
+    /// - Dropped connections are reported as events, followed by the new host of the mesh when
+    ///   it changes.
+    pub fn update(&mut self, time: Instant) {
+        self.report_frame_advantage(time);
+        self.update_interest(time);
+        if self.messages_flushed != Some(self.ticks.tick_at(time)) {
+            self.flush_messages(time);
+        }
+
+        let tick = self.ticks.tick_at(time);
         for con in self.connections.values_mut() {
-            if let Some(packet) = con.update(time) {
-                debug!("send on update: {:?}", packet);
-                self.monitor_out.tick();
-                self.socket
-                    .send_packet(&packet.addr(), packet.payload())
-                    .await?;
+            match con.update(&self.ticks, time) {
+                Ok(Some(packet)) => {
+                    debug!("send on update: {:?}", packet.1);
+                    self.outgoing.push(packet);
+                }
+                Ok(None) => (),
+                Err(e) => error!("could not update {:?}: {}", con, e),
+            }
+            match con.create_mtu_probe(tick, time) {
+                Ok(Some(probe)) => self.outgoing.push(probe),
+                Ok(None) => (),
+                Err(e) => error!("could not probe the path MTU of {:?}: {}", con, e),
             }
         }
 
-        // iterate through all connections and remove those that should be dropped
-        self.connections.retain(|_, con| !con.should_drop(time));
+        // iterate through all connections and remove those that should be dropped, the server
+        // takes back authority over their entities and forgets their registrations
+        let world = &mut self.world;
+        let introducer = &mut self.introducer;
+        let events = &mut self.events;
+        let sessions = &mut self.sessions;
+        let directory = &self.directory;
+        self.connections.retain(|addr, con| {
+            let drop = con.should_drop(time);
+            if drop && sessions.get(&con.session_id()) == Some(addr) {
+                sessions.remove(&con.session_id());
+                if let Some(directory) = directory {
+                    directory.remove(con.session_id());
+                }
+            }
+            if drop && con.peer_id().is_some() {
+                world.release_all(con.session_id());
+            }
+            if let Some(introducer) = introducer.as_mut().filter(|_| drop) {
+                introducer.remove(addr);
+            }
+            // failed outbound connections are reported as well
+            if drop && (con.was_established() || con.is_outbound()) {
+                Self::push_event(events, PeerEvent::Disconnected(*addr));
+            }
+            !drop
+        });
+
+        let host = self.host();
+        if host != self.host {
+            self.host = host;
+            Self::push_event(&mut self.events, PeerEvent::HostMigrated(host));
+        }
+    }
+
+    /// Updates which peers are in the area of interest of each other, including the ones of
+    /// other shards which relayed data recently.
+    fn update_interest(&mut self, time: Instant) {
+        self.peer_grid.clear();
+        for (addr, con) in &self.connections {
+            if let Some(area) = con.interest() {
+                self.peer_grid.insert(*addr, area.center);
+            }
+        }
+        self.foreign_centers.retain(|_, (_, reported)| {
+            time.saturating_duration_since(*reported) < DEFAULT_IDLE_TIMEOUT
+        });
+        for (addr, (center, _)) in &self.foreign_centers {
+            self.peer_grid.insert(*addr, *center);
+        }
+        for con in self.connections.values_mut() {
+            con.update_visible_peers(&self.peer_grid);
+        }
+    }
+
+    /// Returns the latest rollback frames of the connected peers.
+    fn frames(&self) -> Vec<(SocketAddr, u32)> {
+        self.connections
+            .iter()
+            .filter(|(_, con)| con.is_connected())
+            .filter_map(|(addr, con)| con.frame().map(|frame| (*addr, frame)))
+            .collect()
+    }
+
+    fn report_frame_advantage(&mut self, time: Instant) {
+        let frames = self.frames();
+        let foreign = self.foreign_frames.values().flatten();
+        let all = frames.iter().chain(foreign).collect::<Vec<_>>();
+        if frames.is_empty() || all.len() < 2 {
+            return;
+        }
+
+        let tick = self.ticks.tick_at(time);
+        for (addr, _) in &frames {
+            let slowest = all
+                .iter()
+                .filter(|(other, _)| other != addr)
+                .map(|(_, frame)| *frame)
+                .min()
+                .unwrap();
+            let con = self.connections.get_mut(addr).unwrap();
+            match con.create_advantage_packet(slowest, tick, time) {
+                Ok(Some(packet)) => self.outgoing.push(packet),
+                Ok(None) => (),
+                Err(e) => error!("could not report frame advantage to {:?}: {}", con, e),
+            }
+        }
+    }
+
+    /// Sends all queued packets.
+    pub async fn flush(&mut self) -> Result<()> {
+        for _ in 0..self.outgoing.len() {
+            self.monitor_out.tick();
+        }
+        let sent = self.socket.send_packets(&self.outgoing).await;
+        self.outgoing.clear();
 
         self.monitor_out.report(|m| info!("out: {:?}", m));
         self.monitor_in.report(|m| info!("in: {:?}", m));
 
-        Ok(())
+        sent
     }
 
-    /// Relay incoming data all other peers
-    async fn push_to_all(&mut self, packet: Packet, time: Instant) -> Result<()> {
-        let outgoing = self
-            .connections
+    /// Relay incoming data all other peers, the payload is shared by all relayed packets
+    fn push_to_all(
+        connections: &mut HashMap<SocketAddr, Connection>,
+        pool: &mut BufferPool,
+        outgoing: &mut Vec<(SocketAddr, OutgoingPacket)>,
+        packet: &Packet,
+        located: bool,
+        time: Instant,
+    ) {
+        let relayed = connections
             .values_mut()
-            // filter send to self
-            .filter(|con| con.is_ready(&packet.addr()))
-            .map(|con| con.process_out(&packet, PacketType::Data, time))
-            .collect::<Vec<_>>();
+            // filter send to self and peers not interested in the sender
+            .filter(|con| con.is_ready(&packet.addr()) && con.wants_relay(packet, located))
+            .map(|con| {
+                let header = pool.acquire(MAX_HEADER_SIZE);
+                con.relay_out(packet, header, time)
+            });
 
-        for p in outgoing {
-            debug!("send relay: {:?}", packet);
-            self.monitor_out.tick();
-            self.socket.send_packet(&p.addr(), p.payload()).await?;
+        for p in relayed {
+            match p {
+                Ok(p) => {
+                    debug!("send relay: {:?}", p);
+                    outgoing.push(p);
+                }
+                Err(e) => error!("could not relay packet from {}: {}", packet.addr(), e),
+            }
         }
-
-        Ok(())
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr> {
         self.socket.local_addr()
     }
 }
+
+/// Reads the session id of a datagram of an established session, `None` for connect requests
+/// and datagrams which are not of the current protocol.
+pub(crate) fn read_session(datagram: &[u8]) -> Option<u64> {
+    let mut reader = PacketReader::new(datagram);
+    match (reader.read_base_header(), reader.read_session_header()) {
+        (Ok(header), Ok(session))
+            if header.is_current_protocol() && header.packet_type() != PacketType::Connect =>
+        {
+            Some(session.session_id())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use futures::future::join;
+    use tokio::net::UdpSocket;
+
+    use crate::features::AreaOfInterest;
+    use crate::net::Socket;
+    use crate::packet::{PacketReader, PacketType};
+    use crate::{OutgoingPacketBuilder, Packet};
+
+    use super::ConnectionManager;
+
+    /// Connects a client to a server, returns both along with the address of the client.
+    async fn connected() -> (ConnectionManager, ConnectionManager, SocketAddr) {
+        let bind = || async { UdpSocket::bind("127.0.0.1:0").await.unwrap() };
+        let mut server = ConnectionManager::new(Socket::new(bind().await));
+        let mut client = ConnectionManager::new(Socket::new(bind().await));
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        assert!(client.connect(server_addr, Instant::now()));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while server.session_id(&client_addr).is_none() && Instant::now() < deadline {
+            let (a, b) = join(
+                server.manual_poll(Instant::now()),
+                client.manual_poll(Instant::now()),
+            )
+            .await;
+            a.and(b).unwrap();
+        }
+        assert!(server.session_id(&client_addr).is_some());
+        (server, client, client_addr)
+    }
+
+    #[tokio::test]
+    async fn filters_data_of_other_shards_by_interest() {
+        let (mut server, _client, client_addr) = connected().await;
+        let area = AreaOfInterest {
+            center: [0.0; 3],
+            radius: 10.0,
+        };
+        assert!(server.set_interest(&client_addr, Some(area)));
+
+        let near = Packet::new("10.0.0.1:4000".parse().unwrap(), &b"near"[..]);
+        let far = Packet::new("10.0.0.2:4000".parse().unwrap(), &b"far"[..]);
+        let anywhere = Packet::new("10.0.0.3:4000".parse().unwrap(), &b"anywhere"[..]);
+        let now = Instant::now();
+        server.relay(&near, Some([1.0, 0.0, 0.0]), now);
+        server.relay(&far, Some([100.0, 0.0, 0.0]), now);
+        // senders of other shards are located with the next update
+        server.update(now);
+        server.outgoing.clear();
+
+        server.relay(&near, Some([1.0, 0.0, 0.0]), now);
+        server.relay(&far, Some([100.0, 0.0, 0.0]), now);
+        server.relay(&anywhere, None, now);
+        assert_eq!(server.outgoing.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reports_frame_advantage_against_other_shards() {
+        let (mut server, _client, client_addr) = connected().await;
+        let session = server.session_id(&client_addr).unwrap();
+        let frame_input = OutgoingPacketBuilder::new(&[])
+            .with_default_header(PacketType::FrameInput)
+            .with_session_header(session)
+            .with_tick_header(10)
+            .build()
+            .unwrap()
+            .contents();
+        let now = Instant::now();
+        server.process_datagram(frame_input, client_addr, now).unwrap();
+        assert_eq!(server.share_frames(now), Some(vec![(client_addr, 10)]));
+        assert_eq!(server.share_frames(now), None);
+
+        // a single peer has nobody to run ahead of until another shard reports one
+        server.outgoing.clear();
+        server.report_frame_advantage(now);
+        assert!(server.outgoing.is_empty());
+        server.set_foreign_frames(1, vec![("10.0.0.1:4000".parse().unwrap(), 4)]);
+        server.report_frame_advantage(now);
+
+        assert_eq!(server.outgoing.len(), 1);
+        let (to, packet) = &server.outgoing[0];
+        assert_eq!(*to, client_addr);
+        let contents = packet.contents();
+        let mut reader = PacketReader::new(&contents);
+        let header = reader.read_base_header().unwrap();
+        assert_eq!(header.packet_type(), PacketType::FrameAdvantage);
+        reader.read_session_header().unwrap();
+        reader.read_tick_header().unwrap();
+        assert_eq!(&reader.read_payload().unwrap()[..], &6_i32.to_be_bytes());
+    }
+}
@@ -1,11 +1,20 @@
 use tokio::time::Duration;
 
 /// The size of the standard header.
-pub const BASE_HEADER_SIZE: u8 = 3;
+pub const BASE_HEADER_SIZE: u8 = 4;
 /// The size of the client header.
 pub const SESSION_HEADER_SIZE: u8 = 8;
+/// The size of the tick header.
+pub const TICK_HEADER_SIZE: u8 = 4;
+/// Upper bound of all headers written in front of a payload.
+pub const MAX_HEADER_SIZE: usize =
+    BASE_HEADER_SIZE as usize + 2 * SESSION_HEADER_SIZE as usize + TICK_HEADER_SIZE as usize;
 /// Size of random data appended to connect request to discourage ddos amplification
 pub const CONNECT_PAYLOAD_SIZE: usize = 1024;
+/// Interval at which a peer repeats its connect request until the challenge arrives.
+pub const CONNECT_INTERVAL: Duration = Duration::from_millis(250);
+/// Connect requests a client sends before it gives up on the server.
+pub const CONNECT_ATTEMPTS: u32 = 20;
 /// Maximum transmission unit of the payload.
 ///
 /// Derived from ethernet_mtu - ipv6_header_size - udp_header_size - packet header size
@@ -14,12 +23,90 @@ pub const CONNECT_PAYLOAD_SIZE: usize = 1024;
 /// This is not strictly guaranteed -- there may be less room in an ethernet frame than this due to
 /// variability in ipv6 header size.
 pub const DEFAULT_MTU: u16 = 1452;
+/// Size of datagrams every path is assumed to carry, path MTU discovery starts from it.
+pub const MIN_MTU: u16 = 1200;
+/// Largest datagram sent or received, the UDP payload of an ethernet frame over IPv4.
+///
+/// Path MTU discovery probes up to this size, receive buffers are this large.
+pub const MAX_MTU: u16 = 1472;
+/// Time after which an unacknowledged MTU probe is sent again.
+pub const MTU_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Number of times a probe is sent before its size is considered too large for the path.
+pub const MTU_PROBE_ATTEMPTS: u32 = 3;
+/// Path MTU discovery stops once the largest working size is known up to this many bytes.
+pub const MTU_PROBE_PRECISION: u16 = 8;
+/// Time after which sizes found too large for a path are probed again, doubled every time they
+/// still do not arrive.
+pub const MTU_REPROBE_BACKOFF: Duration = Duration::from_secs(30);
+/// Longest time sizes found too large for a path are not probed.
+pub const MTU_REPROBE_MAX_BACKOFF: Duration = Duration::from_secs(600);
+/// Number of datagrams the server tries to read per poll when batched I/O is available.
+pub const RECV_BATCH_SIZE: usize = 32;
+/// Capacity of the channels between shards of a sharded server, packets are dropped when full.
+pub const SHARD_CHANNEL_CAPACITY: usize = 1024;
+/// Default simulation rate in ticks per second.
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Number of time sync exchanges kept to estimate the server clock.
+pub const TIME_SYNC_WINDOW: usize = 16;
+/// Number of time sync pings sent while connecting.
+pub const TIME_SYNC_BURST: usize = 8;
+/// Interval of time sync pings once connected.
+pub const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of ticks a snapshot can be used as a delta baseline for.
+pub const SNAPSHOT_HISTORY: u32 = 32;
+/// Maximum number of unacknowledged replication packets remembered per peer.
+pub const REPLICATION_HISTORY: usize = 32;
+/// Default delay of snapshot playback behind the server.
+pub const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_millis(100);
+/// Longest time bodies are moved along their velocities when snapshots are late.
+pub const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+/// Number of snapshots buffered for playback.
+pub const INTERPOLATION_BUFFER_SIZE: usize = 64;
+/// Largest payload of a single input.
+pub const MAX_INPUT_SIZE: usize = 256;
+/// Largest payload of the packets repeating unacknowledged inputs, it fits into every path. The
+/// most recent inputs are repeated up to this size.
+pub const MAX_INPUT_PAYLOAD: usize = MIN_MTU as usize - MAX_HEADER_SIZE;
+/// Number of unacknowledged inputs a client keeps.
+pub const INPUT_HISTORY_SIZE: usize = 128;
+/// Number of received inputs the server queues per client.
+pub const INPUT_BUFFER_SIZE: usize = 32;
+/// Number of frames a rollback session keeps remote inputs for.
+pub const ROLLBACK_HISTORY: u32 = 64;
+/// Edge length of the cells of the grid used to find what is in a peer's area of interest.
+pub const INTEREST_CELL_SIZE: f32 = 64.0;
+/// Fraction of its radius an area of interest is widened by for what is already in it.
+pub const INTEREST_HYSTERESIS: f32 = 0.1;
+/// Interval at which a client repeats its unchanged area of interest.
+pub const INTEREST_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval at which a client repeats its registration until the introducer pairs it.
+pub const REGISTER_INTERVAL: Duration = Duration::from_millis(250);
+/// Interval of the hole punching attempts towards an introduced peer.
+pub const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of punches sent before the traffic to an introduced peer is relayed by the server.
+pub const PUNCH_ATTEMPTS: u32 = 20;
+/// Interval of the punches keeping an open path to an introduced peer alive.
+pub const PUNCH_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Time without traffic from an introduced peer after which its path is considered closed and
+/// the traffic is relayed by the server again.
+pub const DIRECT_PATH_TIMEOUT: Duration = Duration::from_secs(3);
+/// Number of connection events a peer keeps until they are taken.
+pub const MAX_PEER_EVENTS: usize = 1024;
+/// Largest accepted difference of clock rates, in seconds per second.
+pub const MAX_CLOCK_DRIFT: f64 = 1e-3;
 /// Default connection timeout duration
 pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
 pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(1);
-/// This is the current protocol version.
+/// Time the address of a connection has to be quiet before another one may take over its
+/// session, longer than the heartbeat interval.
+pub const MIGRATION_QUIET_TIME: Duration = Duration::from_secs(2);
+/// Identifies the protocol, the crc16 of it starts every packet.
 ///
 /// It is used for:
 /// - Generating crc16 for the packet header.
-/// - Validating if arriving packets have the same protocol version.
-pub const PROTOCOL_VERSION: &str = "physync-0.1.0";
\ No newline at end of file
+/// - Validating if arriving packets belong to this protocol.
+pub const PROTOCOL_ID: &str = "physync";
+/// Newest protocol version, peers agree on a version when connecting.
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Oldest protocol version still supported.
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
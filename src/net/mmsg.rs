@@ -0,0 +1,222 @@
+//! Batched datagram I/O using `recvmmsg`/`sendmmsg`.
+//!
+//! Only available on Linux with the `mmsg` feature enabled. The functions operate on a raw,
+//! non-blocking socket descriptor and never wait for readiness; they return
+//! `io::ErrorKind::WouldBlock` when the kernel has nothing to read or no room to write, so the
+//! caller can fall back to the async socket to park the task.
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use crate::OutgoingPacket;
+
+/// Maximum number of datagrams passed to the kernel in one syscall.
+pub const BATCH_SIZE: usize = 64;
+
+/// Sends up to [BATCH_SIZE] packets with a single `sendmmsg` call.
+///
+/// Header and payload of each packet are passed as separate io vectors, so a shared payload is
+/// never copied. Returns the number of packets the kernel accepted, which may be less than
+/// `packets.len()`.
+pub fn send_batch(fd: RawFd, packets: &[(SocketAddr, OutgoingPacket)]) -> io::Result<usize> {
+    let count = packets.len().min(BATCH_SIZE);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovecs: [[libc::iovec; 2]; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    for (i, (addr, packet)) in packets[..count].iter().enumerate() {
+        let addr_len = write_sockaddr(addr, &mut addrs[i]);
+        iovecs[i] = [
+            libc::iovec {
+                iov_base: packet.header().as_ptr() as *mut libc::c_void,
+                iov_len: packet.header().len(),
+            },
+            libc::iovec {
+                iov_base: packet.payload().as_ptr() as *mut libc::c_void,
+                iov_len: packet.payload().len(),
+            },
+        ];
+        msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+        msgs[i].msg_hdr.msg_namelen = addr_len;
+        msgs[i].msg_hdr.msg_iov = iovecs[i].as_mut_ptr();
+        msgs[i].msg_hdr.msg_iovlen = 2;
+    }
+
+    let sent = unsafe {
+        libc::sendmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Receives up to `buffers.len()` (capped at [BATCH_SIZE]) datagrams with a single `recvmmsg`
+/// call, appending the length and source of each one to `received`.
+///
+/// Datagram `i` in `received` was written to `buffers[i]`.
+pub fn recv_batch<B: AsMut<[u8]>>(
+    fd: RawFd,
+    buffers: &mut [B],
+    received: &mut Vec<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    let count = buffers.len().min(BATCH_SIZE);
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+    for (i, buffer) in buffers[..count].iter_mut().enumerate() {
+        let buffer = buffer.as_mut();
+        iovecs[i] = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buffer.len(),
+        };
+        msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+        msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+        msgs[i].msg_hdr.msg_iovlen = 1;
+    }
+
+    let recv = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if recv < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let recv = recv as usize;
+    for i in 0..recv {
+        let addr = read_sockaddr(&addrs[i])?;
+        received.push((msgs[i].msg_len as usize, addr));
+    }
+    Ok(recv)
+}
+
+fn write_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { ptr::write(storage as *mut _ as *mut libc::sockaddr_in, sin) };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe { ptr::write(storage as *mut _ as *mut libc::sockaddr_in6, sin6) };
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+fn read_sockaddr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported address family",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    use crate::net::constants::DEFAULT_MTU;
+    use crate::OutgoingPacketBuilder;
+
+    use super::{recv_batch, send_batch};
+
+    #[test]
+    fn send_and_receive_batch() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = rx.local_addr().unwrap();
+
+        let packets = (0..4_u8)
+            .map(|i| {
+                let out = OutgoingPacketBuilder::new(&[i; 12])
+                    .with_session_header(u64::from(i))
+                    .build()
+                    .unwrap();
+                (target, out)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(send_batch(tx.as_raw_fd(), &packets).unwrap(), 4);
+
+        let mut buffers = vec![vec![0; DEFAULT_MTU as usize]; 8];
+        let mut received = Vec::new();
+        let mut total = 0;
+        while total < 4 {
+            total += recv_batch(rx.as_raw_fd(), &mut buffers[total..], &mut received).unwrap();
+        }
+
+        assert_eq!(received.len(), 4);
+        for (i, (len, addr)) in received.iter().enumerate() {
+            assert_eq!(*len, 20);
+            assert_eq!(*addr, tx.local_addr().unwrap());
+            assert_eq!(buffers[i][7], i as u8);
+            assert_eq!(buffers[i][8], i as u8);
+        }
+    }
+
+    #[test]
+    fn receive_would_block() {
+        let rx = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut buffers = vec![vec![0; DEFAULT_MTU as usize]; 2];
+        let mut received = Vec::new();
+
+        let err = recv_batch(rx.as_raw_fd(), &mut buffers, &mut received).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+}
@@ -1,11 +1,43 @@
 use crate::errors::Result;
+use crate::features::{AreaOfInterest, Capabilities, TickScheduler};
 use crate::net::connection_manager::ConnectionManager;
-use crate::net::Socket;
+use crate::net::{bind_udp, Socket};
+use crate::input::Input;
+use crate::message::{Dispatcher, Message, MessageBatch};
+use crate::replication::ReplicatedWorld;
+use crate::snapshot::{PriorityConfig, RigidBodyState, SnapshotConfig};
 use log::error;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::collections::vec_deque::Drain;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::Instant;
 use tokio::net::{ToSocketAddrs, UdpSocket};
 
+/// Change of the connections of a [Peer], inbound and outbound ones alike.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The connection to the peer at the address is established.
+    Connected(SocketAddr),
+    /// The connection to the peer at the address is closed, or could not be established when
+    /// this side initiated it.
+    Disconnected(SocketAddr),
+    /// The host of the mesh changed, `None` when it is this peer now.
+    HostMigrated(Option<SocketAddr>),
+    /// The peer at `from` went quiet and sent a valid packet of its session from `to`, its
+    /// connection moved there.
+    Migrated { from: SocketAddr, to: SocketAddr },
+}
+
+/// A peer accepting connections, which can connect to other peers as well.
+///
+/// # Remarks
+/// - Peers connecting to each other form a mesh, every connection gets the same snapshots,
+///   replication and messages as the ones clients initiate. Data of clients is relayed to the
+///   other members, data arriving from a member is not relayed again.
+/// - The host of a mesh is the member with the lowest [id](#method.id). Every member elects the
+///   same one once all of them are connected, a new one is elected when it leaves.
+/// - A connection follows its peer to a new address, like after a NAT rebinding, when a packet
+///   with its session id arrives from there. Session ids travel in the clear, packets are not
+///   authenticated beyond that.
 #[derive(Debug)]
 pub struct Peer {
     handler: ConnectionManager,
@@ -24,6 +56,16 @@ impl Peer {
         Self::bind_internal(socket)
     }
 
+    /// Binds to all IPv6 and IPv4 interfaces with a single dual-stack socket.
+    ///
+    /// # Remark
+    /// - IPv4 peers show up with their IPv4-mapped IPv6 address.
+    pub fn bind_dual_stack(port: u16) -> Result<Self> {
+        let address = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+        let socket = UdpSocket::from_std(bind_udp(address.into(), false)?)?;
+        Self::bind_internal(socket)
+    }
+
     fn bind_internal(socket: UdpSocket) -> Result<Self> {
         Ok(Self {
             handler: ConnectionManager::new(Socket::new(socket)),
@@ -39,6 +81,166 @@ impl Peer {
         }
     }
 
+    /// Returns the id this peer is known by to all others.
+    pub fn id(&self) -> u64 {
+        self.handler.id()
+    }
+
+    /// Starts connecting to the peer at `addr`, the handshake runs with the next polls.
+    ///
+    /// Returns `false` when there is a connection to `addr` already. Peers connecting to each
+    /// other at the same time end up with a single connection.
+    pub fn connect(&mut self, addr: SocketAddr) -> bool {
+        self.handler.connect(addr, Instant::now())
+    }
+
+    /// Tells the peer at `addr` right away that the connection is closed, it is dropped with
+    /// the next poll.
+    ///
+    /// Returns `false` when there is no connection to `addr`.
+    pub async fn disconnect(&mut self, addr: &SocketAddr) -> Result<bool> {
+        if !self.handler.disconnect(addr, Instant::now()) {
+            return Ok(false);
+        }
+        self.handler.flush().await.map(|_| true)
+    }
+
+    /// Returns the address of the host of the mesh, `None` when it is this peer.
+    pub fn host(&self) -> Option<SocketAddr> {
+        self.handler.host()
+    }
+
+    /// Takes the changes of the connections since the last call.
+    ///
+    /// Use them to hand the work of a host which left over to the new one.
+    pub fn drain_events(&mut self) -> Drain<'_, PeerEvent> {
+        self.handler.drain_events()
+    }
+
+    /// Restarts the simulation clock at tick 0, running at `rate` ticks per second.
+    ///
+    /// Polling returns at the latest when the next tick starts, so connections are updated at
+    /// least at this rate.
+    pub fn set_tick_rate(&mut self, rate: u32) {
+        self.handler.set_ticks(TickScheduler::new(rate, Instant::now()));
+    }
+
+    /// Returns the simulation tick running at `time`.
+    pub fn current_tick(&self, time: Instant) -> u32 {
+        self.handler.ticks().tick_at(time)
+    }
+
+    /// Sets the quantization of snapshots, peers have to use the same config to decode them.
+    pub fn set_snapshot_config(&mut self, config: SnapshotConfig) {
+        self.handler.set_snapshot_config(config);
+    }
+
+    /// Sets how fast bodies gain priority to be sent when not all of them fit into a packet.
+    pub fn set_priority_config(&mut self, config: PriorityConfig) {
+        self.handler.set_priority_config(config);
+    }
+
+    /// Sets the position the peer at `addr` looks from, bodies near it are sent more often.
+    ///
+    /// Returns `false` when there is no connection to `addr`.
+    pub fn set_focus(&mut self, addr: &SocketAddr, focus: Option<[f32; 3]>) -> bool {
+        self.handler.set_focus(addr, focus)
+    }
+
+    /// Restricts the updates the peer at `addr` receives to `area`, as if it reported it.
+    ///
+    /// Snapshots only contain the bodies in the area and relayed data only comes from peers
+    /// whose reported location is in it. Returns `false` when there is no connection to
+    /// `addr`.
+    pub fn set_interest(&mut self, addr: &SocketAddr, area: Option<AreaOfInterest>) -> bool {
+        self.handler.set_interest(addr, area)
+    }
+
+    /// Queues the snapshot of `states` at the current tick to all connected peers, it is sent
+    /// by the next poll.
+    ///
+    /// Each peer gets the changes since the latest snapshot it acknowledged, as many as fit
+    /// into a packet, ordered by their accumulated priority.
+    pub fn send_snapshot(&mut self, states: &[RigidBodyState]) {
+        self.handler.send_snapshot(states, Instant::now());
+    }
+
+    /// Returns the entities replicated to all connected peers.
+    ///
+    /// Peers change the components of entities they own, they ask for authority over an
+    /// entity and get it while nobody else holds it. Their changes and authority transfers
+    /// show up as events of the world.
+    pub fn replication(&mut self) -> &mut ReplicatedWorld {
+        self.handler.world()
+    }
+
+    /// Queues the changes of the replicated entities to all connected peers, they are sent by
+    /// the next poll.
+    ///
+    /// Each peer gets spawns, changes and despawns until it acknowledged them.
+    pub fn send_replication(&mut self) {
+        self.handler.send_replication(Instant::now());
+    }
+
+    /// Returns the optional features agreed on with the peer at `addr`, `None` when there is no
+    /// established connection to it.
+    pub fn capabilities(&self, addr: &SocketAddr) -> Option<Capabilities> {
+        self.handler.capabilities(addr)
+    }
+
+    /// Returns the largest datagram known to reach the peer at `addr`, snapshots to it are
+    /// sized to fit. `None` when there is no established connection to `addr`.
+    ///
+    /// Starts at a conservative size and grows while path MTU discovery probes the path.
+    pub fn path_mtu(&self, addr: &SocketAddr) -> Option<u16> {
+        self.handler.path_mtu(addr)
+    }
+
+    /// Turns the introducer mode on or off.
+    ///
+    /// Clients registering the same key are told the id and public address of each other, so
+    /// they can punch holes into their NATs and talk directly. Their traffic keeps going through
+    /// this peer when that fails.
+    pub fn set_introducer(&mut self, enabled: bool) {
+        self.handler.set_introducer(enabled);
+    }
+
+    /// Returns the dispatcher handing the messages received from all peers to the registered
+    /// handlers.
+    pub fn messages(&mut self) -> &mut Dispatcher {
+        self.handler.dispatcher()
+    }
+
+    /// Queues the messages of `batch` to the peer at `addr`, they are sent by the next poll.
+    ///
+    /// Returns `false` when there is no established connection to `addr`.
+    pub fn send_messages(&mut self, addr: &SocketAddr, batch: MessageBatch) -> bool {
+        self.handler.send_messages(addr, batch, Instant::now())
+    }
+
+    /// Queues `message` to the peer at `addr`, coalesced with the other messages queued to it
+    /// within the current tick.
+    ///
+    /// The messages are sent when the next tick starts, use [flush_messages](#method.flush_messages)
+    /// for latency-sensitive ones. Returns `false` when there is no established connection to
+    /// `addr` and fails when the message does not fit into a packet.
+    pub fn queue_message<M: Message>(&mut self, addr: &SocketAddr, message: &M) -> Result<bool> {
+        self.handler.queue_message(addr, message)
+    }
+
+    /// Sends the messages queued to all peers right away instead of at the end of the tick.
+    pub async fn flush_messages(&mut self) -> Result<()> {
+        self.handler.flush_messages(Instant::now());
+        self.handler.flush().await
+    }
+
+    /// Takes the oldest pending input of every peer, meant to be called once per tick.
+    ///
+    /// Taken inputs count as processed and are acknowledged with the next snapshot.
+    pub fn next_inputs(&mut self) -> Vec<(SocketAddr, Input)> {
+        self.handler.next_inputs()
+    }
+
     pub async fn manual_poll(&mut self, time: Instant) -> Result<()> {
         self.handler.manual_poll(time).await
     }
@@ -47,3 +249,178 @@ impl Peer {
         self.handler.local_addr()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use futures::future::join_all;
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+
+    use crate::packet::{OutgoingPacketBuilder, PacketType};
+
+    use super::{Peer, PeerEvent};
+
+    /// Polls all `peers` at once and collects their events until `done` holds, for at most a
+    /// few seconds.
+    async fn poll_until<F>(peers: &mut [Peer], events: &mut [Vec<PeerEvent>], mut done: F)
+    where
+        F: FnMut(&[Vec<PeerEvent>]) -> bool,
+    {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while !done(events) && Instant::now() < deadline {
+            join_all(peers.iter_mut().map(|peer| peer.manual_poll(Instant::now()))).await;
+            for (peer, events) in peers.iter_mut().zip(events.iter_mut()) {
+                events.extend(peer.drain_events());
+            }
+        }
+    }
+
+    fn connected(events: &[PeerEvent]) -> Vec<SocketAddr> {
+        let mut addrs = events
+            .iter()
+            .filter_map(|event| match event {
+                PeerEvent::Connected(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        addrs.sort();
+        addrs
+    }
+
+    #[tokio::test]
+    async fn forms_mesh_and_migrates_host() {
+        let mut peers = vec![
+            Peer::bind_any().await.unwrap(),
+            Peer::bind_any().await.unwrap(),
+            Peer::bind_any().await.unwrap(),
+        ];
+        let addrs = peers.iter().map(|peer| peer.local_addr().unwrap()).collect::<Vec<_>>();
+        assert!(peers[1].connect(addrs[0]));
+        assert!(peers[2].connect(addrs[0]));
+        assert!(peers[2].connect(addrs[1]));
+        // connects to the same peer at the same time as the other way around
+        assert!(peers[0].connect(addrs[2]));
+        assert!(!peers[0].connect(addrs[2]));
+
+        let mut events = vec![Vec::new(); 3];
+        poll_until(&mut peers, &mut events, |events| {
+            events.iter().all(|events| connected(events).len() >= 2)
+        })
+        .await;
+        for (i, events) in events.iter().enumerate() {
+            let mut others = addrs.clone();
+            others.remove(i);
+            others.sort();
+            assert_eq!(connected(events), others, "events of peer {}", i);
+        }
+
+        // all members elect the one with the lowest id
+        let host = (0..3).min_by_key(|i| peers[*i].id()).unwrap();
+        for (i, peer) in peers.iter().enumerate() {
+            let expected = if i == host { None } else { Some(addrs[host]) };
+            assert_eq!(peer.host(), expected, "host of peer {}", i);
+        }
+
+        // the host leaves, the remaining members elect the next one
+        let mut host_peer = peers.remove(host);
+        let host_addr = addrs[host];
+        let mut remaining = addrs.clone();
+        remaining.remove(host);
+        for addr in &remaining {
+            assert!(host_peer.disconnect(addr).await.unwrap());
+        }
+        drop(host_peer);
+
+        let mut events = vec![Vec::new(); 2];
+        poll_until(&mut peers, &mut events, |events| {
+            events.iter().all(|events| events.contains(&PeerEvent::Disconnected(host_addr)))
+        })
+        .await;
+        let next = if peers[0].id() < peers[1].id() { 0 } else { 1 };
+        for (i, events) in events.iter().enumerate() {
+            assert!(events.contains(&PeerEvent::Disconnected(host_addr)), "{:?}", events);
+            let expected = if i == next { None } else { Some(remaining[next]) };
+            assert_eq!(peers[i].host(), expected);
+            assert_eq!(events.last(), Some(&PeerEvent::HostMigrated(expected)));
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_peer_to_new_address() {
+        let mut peers = vec![Peer::bind_any().await.unwrap(), Peer::bind_any().await.unwrap()];
+        let addrs = peers.iter().map(|peer| peer.local_addr().unwrap()).collect::<Vec<_>>();
+        assert!(peers[1].connect(addrs[0]));
+        let mut events = vec![Vec::new(); 2];
+        poll_until(&mut peers, &mut events, |events| !connected(&events[0]).is_empty()).await;
+        assert_eq!(connected(&events[0]), vec![addrs[1]]);
+
+        let heartbeat = |session| {
+            OutgoingPacketBuilder::new(&[])
+                .with_default_header(PacketType::Heartbeat)
+                .with_session_header(session)
+                .with_tick_header(0)
+                .build()
+                .unwrap()
+                .contents()
+        };
+        let session = peers[0].id() ^ peers[1].id();
+        let host = peers[0].host();
+        let mut rebound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new_addr = rebound.local_addr().unwrap();
+
+        // neither a packet of the session while its address is active nor one of another
+        // session moves the connection
+        rebound.send_to(&heartbeat(session), addrs[0]).await.unwrap();
+        rebound.send_to(&heartbeat(1), addrs[0]).await.unwrap();
+        // the NAT of the second peer rebinds, its old address goes quiet
+        let mut peers = vec![peers.remove(0)];
+        let mut events = vec![Vec::new()];
+        poll_until(&mut peers, &mut events, |_| false).await;
+        assert!(events[0].is_empty(), "{:?}", events);
+        assert!(peers[0].path_mtu(&addrs[1]).is_some());
+        assert!(peers[0].path_mtu(&new_addr).is_none());
+
+        // an invalid packet of the session does not move it either
+        let mut forged = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forged_addr = forged.local_addr().unwrap();
+        let invalid = OutgoingPacketBuilder::new(&[])
+            .with_default_header(PacketType::Data)
+            .with_session_header(session)
+            .build()
+            .unwrap()
+            .contents();
+        forged.send_to(&invalid, addrs[0]).await.unwrap();
+        rebound.send_to(&heartbeat(session), addrs[0]).await.unwrap();
+        let migrated = PeerEvent::Migrated { from: addrs[1], to: new_addr };
+        let mut events = vec![Vec::new()];
+        let host_migrated = PeerEvent::HostMigrated(Some(new_addr));
+        poll_until(&mut peers, &mut events, |events| {
+            events[0].contains(&migrated) && (host.is_none() || events[0].contains(&host_migrated))
+        })
+        .await;
+        assert_eq!(events[0].first(), Some(&migrated));
+        let forged_migration = PeerEvent::Migrated { from: addrs[1], to: forged_addr };
+        assert!(!events[0].contains(&forged_migration));
+        if host.is_some() {
+            // the host moved with it
+            assert_eq!(peers[0].host(), Some(new_addr));
+            assert_eq!(events[0].last(), Some(&host_migrated));
+        }
+        assert!(peers[0].path_mtu(&addrs[1]).is_none());
+        assert!(peers[0].path_mtu(&new_addr).is_some());
+
+        // the connection is served at the new address
+        let mut buffer = [0; 2048];
+        let poll = peers[0].manual_poll(Instant::now());
+        let (_, (_, from)) = tokio::join!(poll, async {
+            timeout(Duration::from_secs(3), rebound.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap()
+        });
+        assert_eq!(from, addrs[0]);
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::future::try_join_all;
+use log::{debug, error, info};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::delay_until;
+
+use crate::errors::{ErrorKind, Result};
+use crate::features::TickScheduler;
+use crate::net::connection_manager::{read_session, ConnectionManager};
+use crate::net::constants::{MAX_MTU, SHARD_CHANNEL_CAPACITY};
+use crate::net::socket::bind_udp;
+use crate::net::Socket;
+use crate::packet::BufferPool;
+use crate::Packet;
+
+/// A datagram read by the dispatcher along with the address it came from.
+type Inbound = (Bytes, SocketAddr);
+
+/// What a shard hands over to another one.
+#[derive(Debug)]
+enum ShardMessage {
+    /// A data packet of a connection of the sender, to relay to the own connections, along with
+    /// the center of the area of interest of the connection.
+    Relay(Packet, Option<[f32; 3]>),
+    /// A datagram of a session the receiver owns, which arrived at the sender.
+    Datagram(Inbound),
+    /// The rollback frames of the peers of the shard with the index.
+    Frames(usize, Vec<(SocketAddr, u32)>),
+}
+
+/// The shard owning each established session, shared by all shards of a [ShardedPeer] and its
+/// dispatcher.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionDirectory {
+    /// Index of the shard using this handle.
+    shard: usize,
+    owners: Arc<RwLock<HashMap<u64, usize>>>,
+}
+
+impl SessionDirectory {
+    fn new() -> Self {
+        SessionDirectory {
+            shard: 0,
+            owners: Default::default(),
+        }
+    }
+
+    /// Returns a handle of the same directory for the shard with `index`.
+    fn for_shard(&self, index: usize) -> Self {
+        SessionDirectory {
+            shard: index,
+            owners: self.owners.clone(),
+        }
+    }
+
+    /// Returns the index of the shard using this handle.
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// Records that the shard of this handle owns `session`.
+    pub fn insert(&self, session: u64) {
+        self.owners.write().unwrap().insert(session, self.shard);
+    }
+
+    /// Forgets `session` unless another shard took it over meanwhile.
+    pub fn remove(&self, session: u64) {
+        let mut owners = self.owners.write().unwrap();
+        if owners.get(&session) == Some(&self.shard) {
+            owners.remove(&session);
+        }
+    }
+
+    /// Returns the index of the shard owning `session`.
+    pub fn owner(&self, session: u64) -> Option<usize> {
+        self.owners.read().unwrap().get(&session).copied()
+    }
+}
+
+/// Server side peer which spreads its connections over several tasks.
+///
+/// Every shard owns the connections whose address hashes to it. Data received by one shard is
+/// relayed to its own connections directly and handed to all other shards over channels.
+///
+/// # Remarks
+/// - With `reuse_port` every shard binds its own socket with `SO_REUSEPORT` and the kernel
+///   spreads incoming datagrams by address.
+/// - Without it a single dispatcher task reads the socket and forwards datagrams to shards,
+///   which send through their own handle of the same socket.
+/// - The shards share which of them owns each session. Datagrams of a session go to its
+///   owner whatever address they come from, so connections follow their peers to a new
+///   address on any shard.
+pub struct ShardedPeer {
+    shards: Vec<Shard>,
+    dispatcher: Option<Dispatcher>,
+    local_addr: SocketAddr,
+}
+
+impl ShardedPeer {
+    /// Binds `shards` shards to `addr`, a value of 0 is treated as 1.
+    pub fn bind(addr: SocketAddr, shards: usize, reuse_port: bool) -> Result<Self> {
+        let count = shards.max(1);
+
+        let first = bind_udp(addr, reuse_port)?;
+        let local_addr = first.local_addr()?;
+        let mut sockets = vec![first];
+        for _ in 1..count {
+            let socket = if reuse_port {
+                bind_udp(local_addr, true)?
+            } else {
+                sockets[0].try_clone()?
+            };
+            sockets.push(socket);
+        }
+
+        let dispatched = !reuse_port && count > 1;
+        let dispatcher_socket = if dispatched {
+            Some(UdpSocket::from_std(sockets[0].try_clone()?)?)
+        } else {
+            None
+        };
+
+        let directory = SessionDirectory::new();
+        let (relay_tx, relay_rx): (Vec<_>, Vec<_>) =
+            (0..count).map(|_| channel(SHARD_CHANNEL_CAPACITY)).unzip();
+        let (inbound_tx, inbound_rx): (Vec<_>, Vec<_>) = (0..count)
+            .map(|_| {
+                if dispatched {
+                    let (tx, rx) = channel(SHARD_CHANNEL_CAPACITY);
+                    (Some(tx), Some(rx))
+                } else {
+                    (None, None)
+                }
+            })
+            .unzip();
+
+        let shards = sockets
+            .into_iter()
+            .zip(relay_rx)
+            .zip(inbound_rx)
+            .enumerate()
+            .map(|(index, ((socket, relay_rx), inbound))| {
+                let mut manager = ConnectionManager::new(Socket::new(UdpSocket::from_std(socket)?));
+                manager.set_directory(directory.for_shard(index));
+                Ok(Shard {
+                    index,
+                    manager,
+                    inbound,
+                    relay_rx,
+                    relay_tx: relay_tx.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let dispatcher = dispatcher_socket.map(|socket| Dispatcher {
+            socket,
+            pool: BufferPool::default(),
+            directory,
+            shards: inbound_tx.into_iter().flatten().collect(),
+        });
+
+        Ok(ShardedPeer {
+            shards,
+            dispatcher,
+            local_addr,
+        })
+    }
+
+    /// Restarts the simulation clock of all shards at tick 0, running at `rate` ticks per
+    /// second.
+    pub fn set_tick_rate(&mut self, rate: u32) {
+        let ticks = TickScheduler::new(rate, Instant::now());
+        for shard in self.shards.iter_mut() {
+            shard.manager.set_ticks(ticks);
+        }
+    }
+
+    /// Turns the introducer mode on or off, fails to turn it on with more than one shard.
+    ///
+    /// # Remark
+    /// - Clients are only paired with the ones registering at the same shard, with several
+    ///   shards most pairs would never be introduced.
+    pub fn set_introducer(&mut self, enabled: bool) -> Result<()> {
+        if enabled && self.shards.len() > 1 {
+            let reason = "the introducer needs a single shard";
+            return Err(ErrorKind::InvalidConfig(reason.to_owned()));
+        }
+        for shard in self.shards.iter_mut() {
+            shard.manager.set_introducer(enabled);
+        }
+        Ok(())
+    }
+
+    /// Spawns a task for every shard (and the dispatcher) and waits until one of them fails.
+    pub async fn run(self) -> Result<()> {
+        info!(
+            "running {} shard(s), dispatched: {}",
+            self.shards.len(),
+            self.dispatcher.is_some()
+        );
+
+        let mut tasks = self
+            .shards
+            .into_iter()
+            .map(|shard| tokio::spawn(shard.run()))
+            .collect::<Vec<_>>();
+        if let Some(dispatcher) = self.dispatcher {
+            tasks.push(tokio::spawn(dispatcher.run()));
+        }
+
+        for result in try_join_all(tasks).await? {
+            result?;
+        }
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Returns the number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+}
+
+enum Received {
+    /// A datagram forwarded by the dispatcher.
+    Dispatched(Inbound),
+    /// Datagrams were read from the shard's socket and wait in its connection manager.
+    Socket,
+    /// The dispatcher has stopped.
+    Closed,
+}
+
+struct Shard {
+    index: usize,
+    manager: ConnectionManager,
+    /// Datagrams forwarded by the dispatcher, `None` when the shard reads its own socket.
+    inbound: Option<Receiver<Inbound>>,
+    relay_rx: Receiver<ShardMessage>,
+    /// Senders to all shards, by their index.
+    relay_tx: Vec<Sender<ShardMessage>>,
+}
+
+impl Shard {
+    async fn run(mut self) -> Result<()> {
+        loop {
+            let deadline = self.manager.ticks().next_deadline(Instant::now());
+            tokio::select! {
+                received = Self::receive(&mut self.manager, &mut self.inbound) => {
+                    let time = Instant::now();
+                    match received {
+                        Ok(Received::Dispatched((datagram, peer))) => {
+                            if let Err(e) = self.manager.process_datagram(datagram, peer, time) {
+                                error!("shard {} dropping datagram from {}: {}", self.index, peer, e);
+                            }
+                        }
+                        Ok(Received::Socket) => self.manager.process_received(time),
+                        Ok(Received::Closed) => {
+                            let stopped = io::Error::new(io::ErrorKind::BrokenPipe, "dispatcher stopped");
+                            return Err(ErrorKind::IOError(stopped));
+                        }
+                        Err(e) => error!("shard {} encountered read error: {}", self.index, e),
+                    }
+                }
+                Some(message) = self.relay_rx.recv() => match message {
+                    ShardMessage::Relay(packet, center) => {
+                        self.manager.relay(&packet, center, Instant::now());
+                    }
+                    ShardMessage::Datagram((datagram, peer)) => {
+                        let time = Instant::now();
+                        if let Err(e) = self.manager.process_datagram(datagram, peer, time) {
+                            error!("shard {} dropping datagram from {}: {}", self.index, peer, e);
+                        }
+                    }
+                    ShardMessage::Frames(index, frames) => {
+                        self.manager.set_foreign_frames(index, frames);
+                    }
+                },
+                _ = delay_until(deadline.into()) => (),
+            }
+
+            let index = self.index;
+            for (packet, center) in self.manager.drain_relayed() {
+                Self::broadcast(index, &mut self.relay_tx, || {
+                    ShardMessage::Relay(packet.clone(), center)
+                });
+            }
+            if let Some(frames) = self.manager.share_frames(Instant::now()) {
+                Self::broadcast(index, &mut self.relay_tx, || {
+                    ShardMessage::Frames(index, frames.clone())
+                });
+            }
+            for (owner, datagram, peer) in self.manager.drain_forwarded() {
+                let message = ShardMessage::Datagram((datagram, peer));
+                if self.relay_tx[owner].try_send(message).is_err() {
+                    debug!("shard {} relay channel full, dropping datagram", owner);
+                }
+            }
+
+            self.manager.update(Instant::now());
+            if let Err(e) = self.manager.flush().await {
+                error!("shard {} encountered send error: {}", self.index, e);
+            }
+        }
+    }
+
+    /// Hands a message created by `message` to every shard except the one with `index`.
+    fn broadcast<F>(index: usize, relay_tx: &mut [Sender<ShardMessage>], message: F)
+    where
+        F: Fn() -> ShardMessage,
+    {
+        for (_, tx) in relay_tx.iter_mut().enumerate().filter(|(i, _)| *i != index) {
+            if tx.try_send(message()).is_err() {
+                debug!("shard {} relay channel full, dropping message", index);
+            }
+        }
+    }
+
+    /// Waits for the next datagram from the dispatcher, or for datagrams on the shard's own
+    /// socket.
+    async fn receive(
+        manager: &mut ConnectionManager,
+        inbound: &mut Option<Receiver<Inbound>>,
+    ) -> Result<Received> {
+        match inbound {
+            Some(rx) => Ok(rx.recv().await.map_or(Received::Closed, Received::Dispatched)),
+            None => manager.receive().await.map(|_| Received::Socket),
+        }
+    }
+}
+
+/// Reads the shared socket and forwards every datagram to the shard owning its session, or its
+/// sender when the session is unknown.
+struct Dispatcher {
+    socket: UdpSocket,
+    pool: BufferPool,
+    directory: SessionDirectory,
+    shards: Vec<Sender<Inbound>>,
+}
+
+impl Dispatcher {
+    async fn run(mut self) -> Result<()> {
+        loop {
+            let mut buffer = self.pool.acquire_filled(MAX_MTU as usize);
+            let (len, peer) = match self.socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("dispatcher encountered read error: {}", e);
+                    continue;
+                }
+            };
+            buffer.truncate(len);
+
+            let index = read_session(&buffer)
+                .and_then(|session| self.directory.owner(session))
+                .unwrap_or_else(|| shard_index(&peer, self.shards.len()));
+            if self.shards[index].try_send((buffer.freeze(), peer)).is_err() {
+                debug!("shard {} inbound channel full, dropping datagram", index);
+            }
+        }
+    }
+}
+
+/// Returns the shard owning connections from `addr`.
+fn shard_index(addr: &SocketAddr, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    use bytes::Bytes;
+    use tokio::net::UdpSocket;
+    use tokio::time::{delay_for, timeout};
+
+    use crate::net::connection_manager::{read_session, ConnectionManager};
+    use crate::net::constants::MIGRATION_QUIET_TIME;
+    use crate::net::Socket;
+    use crate::packet::PacketType;
+    use crate::OutgoingPacketBuilder;
+
+    use super::{shard_index, SessionDirectory, ShardedPeer};
+
+    fn heartbeat(session: u64) -> Bytes {
+        OutgoingPacketBuilder::new(&[])
+            .with_default_header(PacketType::Heartbeat)
+            .with_session_header(session)
+            .with_tick_header(0)
+            .build()
+            .unwrap()
+            .contents()
+    }
+
+    #[test]
+    fn shard_index_is_stable() {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+        assert_eq!(shard_index(&addr, 8), shard_index(&addr, 8));
+        assert_eq!(shard_index(&addr, 1), 0);
+    }
+
+    #[test]
+    fn shard_index_spreads_addresses() {
+        let mut used = [false; 4];
+        for port in 4000..4064 {
+            let addr: SocketAddr = format!("10.0.0.1:{}", port).parse().unwrap();
+            used[shard_index(&addr, 4)] = true;
+        }
+
+        assert!(used.iter().all(|u| *u));
+    }
+
+    #[tokio::test]
+    async fn bind_shares_port() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+
+        let dispatched = super::ShardedPeer::bind(addr, 3, false).unwrap();
+        assert_eq!(dispatched.shards(), 3);
+        assert!(dispatched.dispatcher.is_some());
+
+        let mut reuse = super::ShardedPeer::bind(addr, 2, true).unwrap();
+        assert_eq!(reuse.shards(), 2);
+        assert!(reuse.set_introducer(true).is_err());
+        assert!(reuse.set_introducer(false).is_ok());
+        assert!(reuse.dispatcher.is_none());
+        assert_ne!(reuse.local_addr().unwrap().port(), 0);
+    }
+
+    #[tokio::test]
+    async fn hands_datagrams_to_session_owner() {
+        let directory = SessionDirectory::new();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut manager = ConnectionManager::new(Socket::new(socket));
+        manager.set_directory(directory.for_shard(1));
+        directory.for_shard(0).insert(7);
+
+        let addr = "10.0.0.1:4000".parse().unwrap();
+        manager.process_datagram(heartbeat(7), addr, Instant::now()).unwrap();
+        let forwarded = manager.drain_forwarded().collect::<Vec<_>>();
+        assert_eq!(forwarded, vec![(0, heartbeat(7), addr)]);
+
+        // unknown sessions stay with the shard of the address
+        manager.process_datagram(heartbeat(8), addr, Instant::now()).ok();
+        assert_eq!(manager.drain_forwarded().count(), 0);
+    }
+
+    /// Connects to a server with two shards, then sends from an address of the other shard.
+    async fn follow_session_across_shards(reuse_port: bool) {
+        let server = ShardedPeer::bind("127.0.0.1:0".parse().unwrap(), 2, reuse_port).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut client = ConnectionManager::new(Socket::new(socket));
+        assert!(client.connect(server_addr, Instant::now()));
+        let deadline = Instant::now() + Duration::from_secs(3);
+        while client.session_id(&server_addr).is_none() && Instant::now() < deadline {
+            client.manual_poll(Instant::now()).await.unwrap();
+        }
+        let session = client.session_id(&server_addr).unwrap();
+
+        // the kernel picks the shard of a reused port, the dispatcher the one of the address
+        let mut rebound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        while !reuse_port
+            && shard_index(&rebound.local_addr().unwrap(), 2) == shard_index(&addr, 2)
+        {
+            rebound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        }
+        drop(client);
+        delay_for(MIGRATION_QUIET_TIME + Duration::from_millis(100)).await;
+
+        rebound.send_to(&heartbeat(session), server_addr).await.unwrap();
+
+        // the session is served at the new address
+        let mut buffer = [0; 2048];
+        let served = timeout(Duration::from_secs(3), async {
+            loop {
+                let (len, from) = rebound.recv_from(&mut buffer).await.unwrap();
+                if from == server_addr && read_session(&buffer[..len]) == Some(session) {
+                    break;
+                }
+            }
+        });
+        assert!(served.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dispatcher_follows_session_across_shards() {
+        follow_session_across_shards(false).await;
+    }
+
+    #[tokio::test]
+    async fn reused_port_follows_session_across_shards() {
+        follow_session_across_shards(true).await;
+    }
+}
@@ -1,23 +1,71 @@
-use std::net::SocketAddr;
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
 
+use socket2::{Domain, Protocol, Type};
 use tokio::net::UdpSocket;
 use tokio::time::timeout;
 
 use crate::errors::Result;
+use crate::net::address::is_dual_stack;
 use crate::net::constants::DEFAULT_IDLE_TIMEOUT;
+use crate::OutgoingPacket;
 
 #[derive(Debug)]
 pub struct Socket {
     pub socket: UdpSocket,
+    scratch: Vec<u8>,
 }
 
 impl Socket {
     pub fn new(socket: UdpSocket) -> Self {
-        Socket { socket }
+        Socket {
+            socket,
+            scratch: Vec::new(),
+        }
     }
 
-    pub async fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> Result<usize> {
-        Ok(self.socket.send_to(payload, addr).await?)
+    /// Sends a packet whose header and payload are stored apart.
+    ///
+    /// The parts are joined in a scratch buffer which is reused between calls.
+    pub async fn send_outgoing(&mut self, addr: &SocketAddr, packet: &OutgoingPacket) -> Result<usize> {
+        self.scratch.clear();
+        packet.write_to(&mut self.scratch);
+        Ok(self.socket.send_to(&self.scratch, addr).await?)
+    }
+
+    /// Sends all `packets`, one `send_to` per packet.
+    #[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+    pub async fn send_packets(&mut self, packets: &[(SocketAddr, OutgoingPacket)]) -> Result<()> {
+        for (addr, packet) in packets {
+            self.send_outgoing(addr, packet).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends all `packets`, handing them to the kernel in batches with `sendmmsg`.
+    ///
+    /// # Remark
+    /// - When the send buffer is full a single packet is sent through the async socket, which
+    ///   parks the task until the socket is writable again.
+    #[cfg(all(target_os = "linux", feature = "mmsg"))]
+    pub async fn send_packets(&mut self, packets: &[(SocketAddr, OutgoingPacket)]) -> Result<()> {
+        use std::io;
+        use std::os::unix::io::AsRawFd;
+
+        use crate::net::mmsg::send_batch;
+
+        let mut sent = 0;
+        while sent < packets.len() {
+            match send_batch(self.socket.as_raw_fd(), &packets[sent..]) {
+                Ok(n) => sent += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let (addr, packet) = &packets[sent];
+                    self.send_outgoing(addr, packet).await?;
+                    sent += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
     }
 
     pub async fn receive_packet<'a>(
@@ -30,7 +78,60 @@ impl Socket {
             .map(move |(recv_len, address)| (&buffer[..recv_len], address))?)
     }
 
+    /// Waits for at least one datagram and appends the length and source of each received one
+    /// to `received`; datagram `i` is written to `buffers[i]`.
+    ///
+    /// Without the `mmsg` feature only a single datagram is read per call.
+    pub async fn receive_packets<B: AsMut<[u8]>>(
+        &mut self,
+        buffers: &mut [B],
+        received: &mut Vec<(usize, SocketAddr)>,
+    ) -> Result<()> {
+        let (first, rest) = buffers.split_at_mut(1);
+        let (payload, addr) = self.receive_packet(first[0].as_mut()).await?;
+        received.push((payload.len(), addr));
+
+        #[cfg(all(target_os = "linux", feature = "mmsg"))]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            use crate::net::mmsg::recv_batch;
+
+            // drain whatever else is already queued, an empty queue is not an error here
+            let _ = recv_batch(self.socket.as_raw_fd(), rest, received);
+        }
+        #[cfg(not(all(target_os = "linux", feature = "mmsg")))]
+        let _ = rest;
+
+        Ok(())
+    }
+
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(self.socket.local_addr()?)
     }
 }
+
+/// Binds a non-blocking UDP socket to `addr`.
+///
+/// # Remarks
+/// - The unspecified IPv6 address is bound dual-stack, so IPv4 peers are reachable as
+///   IPv4-mapped addresses regardless of the system default.
+/// - `reuse_port` sets `SO_REUSEPORT` and is ignored on platforms without it.
+pub fn bind_udp(addr: SocketAddr, reuse_port: bool) -> Result<StdUdpSocket> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = socket2::Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+    if is_dual_stack(&addr) {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into_udp_socket())
+}
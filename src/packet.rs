@@ -2,13 +2,16 @@ pub use packet_struct::Packet;
 pub use enums::PacketType;
 pub use outgoing::{OutgoingPacket, OutgoingPacketBuilder};
 pub use packet_reader::PacketReader;
+pub use buffer_pool::BufferPool;
 
 pub mod header;
 
+mod compression;
 mod packet_struct;
 mod enums;
 mod outgoing;
 mod packet_reader;
+mod buffer_pool;
 
 pub trait EnumConverter {
     type Enum;
@@ -0,0 +1,79 @@
+use std::mem;
+
+use bytes::BytesMut;
+
+/// Hands out byte buffers carved from large, reference-counted chunks.
+///
+/// # Remarks
+/// - Every buffer handed out shares the allocation of the chunk it was carved from, once all
+///   buffers (and the `Bytes` frozen from them) of a chunk are dropped the chunk is reclaimed
+///   instead of allocating a new one.
+/// - Buffers are meant to be short lived, e.g. a received datagram or a packet header waiting to
+///   be sent, a single long lived buffer keeps its whole chunk from being reused.
+#[derive(Debug)]
+pub struct BufferPool {
+    arena: BytesMut,
+    chunk_size: usize,
+}
+
+impl BufferPool {
+    /// Constructs a new pool that allocates chunks of `chunk_size` bytes.
+    pub fn new(chunk_size: usize) -> Self {
+        BufferPool {
+            arena: BytesMut::with_capacity(chunk_size),
+            chunk_size,
+        }
+    }
+
+    /// Returns an empty buffer which can hold at least `capacity` bytes without reallocating.
+    pub fn acquire(&mut self, capacity: usize) -> BytesMut {
+        if self.arena.capacity() < capacity {
+            // reclaims the current chunk if nothing references it anymore, allocates otherwise
+            self.arena.reserve(self.chunk_size.max(capacity));
+        }
+        let rest = self.arena.split_off(capacity);
+        mem::replace(&mut self.arena, rest)
+    }
+
+    /// Returns a zeroed buffer of exactly `len` bytes, ready to be read into.
+    pub fn acquire_filled(&mut self, len: usize) -> BytesMut {
+        let mut buffer = self.acquire(len);
+        buffer.resize(len, 0);
+        buffer
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(64 * 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::BufferPool;
+
+    #[test]
+    fn acquire_has_capacity() {
+        let mut pool = BufferPool::new(64);
+
+        let buffer = pool.acquire(16);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 16);
+
+        let buffer = pool.acquire_filled(100);
+        assert_eq!(buffer.len(), 100);
+    }
+
+    #[test]
+    fn reclaims_released_chunk() {
+        let mut pool = BufferPool::new(64);
+
+        let first = pool.acquire(32).as_ptr();
+        let _ = pool.acquire(32);
+        // chunk is exhausted and all buffers are dropped, next acquire reuses the chunk
+        let reused = pool.acquire(32).as_ptr();
+
+        assert_eq!(first, reused);
+    }
+}
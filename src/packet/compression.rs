@@ -0,0 +1,95 @@
+//! LZ4 compression of packet payloads, enabled with the `compression` feature.
+//!
+//! A compressed payload starts with its uncompressed length, which is at most
+//! [MAX_MTU](../../net/constants/constant.MAX_MTU.html) like any payload that is not compressed.
+use bytes::Bytes;
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+#[cfg(feature = "compression")]
+use crate::net::constants::MAX_MTU;
+
+/// Returns the compressed `payload`, or `None` when compression does not shrink it.
+#[cfg(feature = "compression")]
+pub fn compress(payload: &[u8]) -> Option<Bytes> {
+    use bytes::{BufMut, BytesMut};
+
+    if payload.len() > MAX_MTU as usize {
+        return None;
+    }
+    let block = lz4_flex::block::compress(payload);
+    if 2 + block.len() >= payload.len() {
+        return None;
+    }
+    let mut compressed = BytesMut::with_capacity(2 + block.len());
+    compressed.put_u16(payload.len() as u16);
+    compressed.put_slice(&block);
+    Some(compressed.freeze())
+}
+
+/// Compression is not available, payloads are sent as they are.
+#[cfg(not(feature = "compression"))]
+pub fn compress(_payload: &[u8]) -> Option<Bytes> {
+    None
+}
+
+/// Decompresses a payload created by [compress].
+#[cfg(feature = "compression")]
+pub fn decompress(payload: &[u8]) -> Result<Bytes> {
+    if payload.len() < 2 {
+        return Err(ErrorKind::BufferUnderflow);
+    }
+    let len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    if len > MAX_MTU as usize {
+        return Err(ErrorKind::DecodingError(DecodingErrorKind::Compression));
+    }
+    let mut decompressed = vec![0; len];
+    match lz4_flex::block::decompress_into(&payload[2..], &mut decompressed) {
+        Ok(written) if written == len => Ok(Bytes::from(decompressed)),
+        _ => Err(ErrorKind::DecodingError(DecodingErrorKind::Compression)),
+    }
+}
+
+/// Compressed packets cannot be read without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_payload: &[u8]) -> Result<Bytes> {
+    Err(ErrorKind::DecodingError(DecodingErrorKind::Compression))
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use crate::net::constants::MAX_MTU;
+
+    use super::{compress, decompress};
+
+    #[test]
+    fn compresses_only_when_smaller() {
+        let payload = vec![7; 512];
+        let compressed = compress(&payload).unwrap();
+        assert!(compressed.len() < payload.len());
+        assert_eq!(&decompress(&compressed).unwrap()[..], &payload[..]);
+
+        assert!(compress(&[1, 2, 3, 4]).is_none());
+    }
+
+    #[test]
+    fn rejects_corrupt_payload() {
+        let mut compressed = compress(&[7; 512]).unwrap().to_vec();
+        compressed[1] = 0xff;
+        assert!(decompress(&compressed).is_err());
+        assert!(decompress(&compressed[..1]).is_err());
+        assert!(decompress(&compressed[..8]).is_err());
+    }
+
+    #[test]
+    fn limits_length_to_mtu() {
+        let mtu = MAX_MTU as usize;
+        assert!(compress(&vec![7; mtu + 1]).is_none());
+        let compressed = compress(&vec![7; mtu]).unwrap();
+        assert_eq!(decompress(&compressed).unwrap().len(), mtu);
+
+        // a forged length is rejected before anything is allocated for it
+        let mut forged = compressed.to_vec();
+        forged[..2].copy_from_slice(&(MAX_MTU + 1).to_be_bytes());
+        assert!(decompress(&forged).is_err());
+    }
+}
@@ -1,16 +1,59 @@
 use std::convert::TryFrom;
 
+use bytes::BufMut;
+
 use crate::ErrorKind;
 use crate::errors::DecodingErrorKind;
 use crate::packet::EnumConverter;
+use crate::serialize::{BitReader, BitWriter, NetSerialize};
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq)]
 /// Id to identify a certain packet type.
 pub enum PacketType {
     Data = 0,
     Connect = 1,
     Disconnect = 2,
     Heartbeat = 3,
+    /// Time sync request carrying the sender's clock.
+    TimePing = 4,
+    /// Time sync response carrying both clocks.
+    TimePong = 5,
+    /// Rigid body snapshot, possibly delta compressed.
+    Snapshot = 6,
+    /// Acknowledges the snapshot of the tick in the tick header.
+    SnapshotAck = 7,
+    /// Tick tagged player inputs.
+    Input = 8,
+    /// Rollback inputs, relayed to all other peers along with the id of their sender.
+    FrameInput = 9,
+    /// Number of frames the receiver runs ahead of the slowest other peer.
+    FrameAdvantage = 10,
+    /// Spawns, changes and despawns of replicated entities.
+    Replication = 11,
+    /// Acknowledges the replication packet of the tick in the tick header.
+    ReplicationAck = 12,
+    /// Changes of replicated entities owned by the sender.
+    EntityUpdate = 13,
+    /// Asks the server for authority over an entity or gives it back.
+    AuthorityRequest = 14,
+    /// Answers an authority request.
+    AuthorityResponse = 15,
+    /// Area the sender wants to receive updates about.
+    Interest = 16,
+    /// Typed messages for the receiver, they are not relayed.
+    Message = 17,
+    /// Answers a connect request the server cannot accept, carries its supported versions.
+    Reject = 18,
+    /// Padded to the size probed by path MTU discovery.
+    MtuProbe = 19,
+    /// Acknowledges the size of a received `MtuProbe`.
+    MtuAck = 20,
+    /// Asks the introducer to pair the sender with the peer registering the same key.
+    Register = 21,
+    /// Id and public address of the peer the introducer paired the receiver with.
+    Introduction = 22,
+    /// Opens the NAT of the sender for a direct path to an introduced peer.
+    Punch = 23,
 }
 
 impl EnumConverter for PacketType {
@@ -29,7 +72,39 @@ impl TryFrom<u8> for PacketType {
             1 => Ok(PacketType::Connect),
             2 => Ok(PacketType::Disconnect),
             3 => Ok(PacketType::Heartbeat),
+            4 => Ok(PacketType::TimePing),
+            5 => Ok(PacketType::TimePong),
+            6 => Ok(PacketType::Snapshot),
+            7 => Ok(PacketType::SnapshotAck),
+            8 => Ok(PacketType::Input),
+            9 => Ok(PacketType::FrameInput),
+            10 => Ok(PacketType::FrameAdvantage),
+            11 => Ok(PacketType::Replication),
+            12 => Ok(PacketType::ReplicationAck),
+            13 => Ok(PacketType::EntityUpdate),
+            14 => Ok(PacketType::AuthorityRequest),
+            15 => Ok(PacketType::AuthorityResponse),
+            16 => Ok(PacketType::Interest),
+            17 => Ok(PacketType::Message),
+            18 => Ok(PacketType::Reject),
+            19 => Ok(PacketType::MtuProbe),
+            20 => Ok(PacketType::MtuAck),
+            21 => Ok(PacketType::Register),
+            22 => Ok(PacketType::Introduction),
+            23 => Ok(PacketType::Punch),
             _ => Err(ErrorKind::DecodingError(DecodingErrorKind::PacketType)),
         }
     }
 }
+
+/// Written as its id in a full byte, the [BaseHeader](./header/struct.BaseHeader.html) keeps its
+/// flags in the byte after it.
+impl NetSerialize for PacketType {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_bits(u64::from(self.to_u8()), 8);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> crate::Result<Self> {
+        PacketType::try_from(reader.read_bits(8)? as u8)
+    }
+}
@@ -1,9 +1,36 @@
+use std::io::Cursor;
+
+use bytes::BufMut;
+
+use crate::errors::Result;
+use crate::serialize::{BitReader, BitWriter, NetSerialize};
+
 pub use header_writer::HeaderWriter;
 pub use header_reader::HeaderReader;
 pub use base_header::BaseHeader;
 pub use session_header::SessionHeader;
+pub use tick_header::TickHeader;
 
 mod header_reader;
 mod header_writer;
 mod base_header;
-mod session_header;
\ No newline at end of file
+mod session_header;
+mod tick_header;
+
+/// Writes a header derived with `NetSerialize` at the end of `buffer`.
+fn write_header<H: NetSerialize, B: BufMut>(header: &H, buffer: &mut B) {
+    let mut writer = BitWriter::wrap(buffer);
+    header.serialize(&mut writer);
+    writer.into_inner();
+}
+
+/// Reads a header derived with `NetSerialize` and moves the cursor past it.
+fn read_header<H: NetSerialize>(rdr: &mut Cursor<&[u8]>) -> Result<H> {
+    let start = rdr.position() as usize;
+    let buffer: &[u8] = rdr.get_ref().get(start..).unwrap_or_default();
+    let mut reader = BitReader::new(buffer);
+    let header = H::deserialize(&mut reader)?;
+    reader.align();
+    rdr.set_position((buffer.len() - reader.remaining_bits() / 8 + start) as u64);
+    Ok(header)
+}
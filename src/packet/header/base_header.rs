@@ -1,21 +1,62 @@
-use std::convert::TryFrom;
 use std::io::Cursor;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BufMut;
 
-use crate::errors::Result;
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
 use crate::net::constants::BASE_HEADER_SIZE;
 use crate::packet::enums::PacketType;
 use crate::packet::header::header_reader::HeaderReader;
 use crate::packet::header::header_writer::HeaderWriter;
-use crate::packet::EnumConverter;
+use crate::packet::header::{read_header, write_header};
+use crate::serialize::{BitReader, BitWriter, NetSerialize};
 use crate::protocol_version::ProtocolVersion;
 
-#[derive(Copy, Clone, Debug)]
+/// Per packet flags extending the meaning of the header.
+///
+/// A peer only sets the flags of capabilities negotiated on connect, packets with flags unknown
+/// to this version fail to decode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeaderFlags(u8);
+
+impl HeaderFlags {
+    /// The payload is compressed.
+    pub const COMPRESSED: HeaderFlags = HeaderFlags(0b0000_0001);
+    const KNOWN: u8 = Self::COMPRESSED.0;
+
+    pub fn contains(self, flags: HeaderFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Sets or clears `flags`.
+    pub fn set(&mut self, flags: HeaderFlags, value: bool) {
+        if value {
+            self.0 |= flags.0;
+        } else {
+            self.0 &= !flags.0;
+        }
+    }
+}
+
+impl NetSerialize for HeaderFlags {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_bits(u64::from(self.0), 8);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        let flags = reader.read_bits(8)? as u8;
+        if flags & !Self::KNOWN != 0 {
+            return Err(ErrorKind::DecodingError(DecodingErrorKind::Flags));
+        }
+        Ok(HeaderFlags(flags))
+    }
+}
+
+#[derive(Copy, Clone, Debug, NetSerialize)]
 /// This header will be included in each packet, and contains some basic information.
 pub struct BaseHeader {
     protocol_version: u16,
     packet_type: PacketType,
+    flags: HeaderFlags,
 }
 
 impl BaseHeader {
@@ -24,9 +65,16 @@ impl BaseHeader {
         BaseHeader {
             protocol_version: ProtocolVersion::get_crc16(),
             packet_type,
+            flags: HeaderFlags::default(),
         }
     }
 
+    /// Flags the payload as compressed.
+    pub fn with_compression(mut self, compressed: bool) -> Self {
+        self.flags.set(HeaderFlags::COMPRESSED, compressed);
+        self
+    }
+
     /// Returns the protocol version
     #[cfg(test)]
     pub fn protocol_version(&self) -> u16 {
@@ -38,6 +86,17 @@ impl BaseHeader {
         self.packet_type
     }
 
+    /// Returns `true` if the payload is compressed
+    pub fn is_compressed(&self) -> bool {
+        self.flags.contains(HeaderFlags::COMPRESSED)
+    }
+
+    /// Returns the flags of the packet
+    #[cfg(test)]
+    pub fn flags(&self) -> HeaderFlags {
+        self.flags
+    }
+
     /// Checks if the protocol version in the packet is a valid version
     pub fn is_current_protocol(&self) -> bool {
         ProtocolVersion::valid_version(self.protocol_version)
@@ -53,9 +112,8 @@ impl Default for BaseHeader {
 impl HeaderWriter for BaseHeader {
     type Output = Result<()>;
 
-    fn parse(&self, buffer: &mut Vec<u8>) -> Self::Output {
-        buffer.write_u16::<BigEndian>(self.protocol_version)?;
-        buffer.write_u8(self.packet_type.to_u8())?;
+    fn parse<B: BufMut>(&self, buffer: &mut B) -> Self::Output {
+        write_header(self, buffer);
         Ok(())
     }
 }
@@ -64,15 +122,7 @@ impl HeaderReader for BaseHeader {
     type Header = Result<BaseHeader>;
 
     fn read(rdr: &mut Cursor<&[u8]>) -> Self::Header {
-        let protocol_version = rdr.read_u16::<BigEndian>()?; /* protocol id */
-        let packet_id = rdr.read_u8()?;
-
-        let header = BaseHeader {
-            protocol_version,
-            packet_type: PacketType::try_from(packet_id)?,
-        };
-
-        Ok(header)
+        read_header(rdr)
     }
 
     /// Returns the size of this header.
@@ -93,6 +143,8 @@ mod tests {
     use crate::packet::EnumConverter;
     use crate::protocol_version::ProtocolVersion;
 
+    use super::HeaderFlags;
+
     #[test]
     fn serialize() {
         let mut buffer = Vec::new();
@@ -104,11 +156,12 @@ mod tests {
             ProtocolVersion::get_crc16()
         );
         assert_eq!(buffer[2], PacketType::Data.to_u8());
+        assert_eq!(buffer[3], 0);
     }
 
     #[test]
     fn deserialize() {
-        let buffer = vec![0, 1, 0];
+        let buffer = vec![0, 1, 0, 0];
 
         let mut cursor = Cursor::new(buffer.as_slice());
 
@@ -116,6 +169,25 @@ mod tests {
 
         assert_eq!(header.protocol_version(), 1);
         assert_eq!(header.packet_type(), PacketType::Data);
+        assert!(!header.is_compressed());
+    }
+
+    #[test]
+    fn compression_flag() {
+        let mut buffer = Vec::new();
+        let header = BaseHeader::new(PacketType::Snapshot).with_compression(true);
+        assert![header.parse(&mut buffer).is_ok()];
+        assert_eq!(buffer[2], PacketType::Snapshot.to_u8());
+        assert_eq!(buffer[3], 0b0000_0001);
+
+        let header = BaseHeader::read(&mut Cursor::new(buffer.as_slice())).unwrap();
+        assert!(header.is_compressed());
+        assert!(header.flags().contains(HeaderFlags::COMPRESSED));
+        assert_eq!(header.packet_type(), PacketType::Snapshot);
+
+        // flags of capabilities this version does not know
+        buffer[3] = 0b1000_0001;
+        assert!(BaseHeader::read(&mut Cursor::new(buffer.as_slice())).is_err());
     }
 
     #[test]
@@ -1,13 +1,15 @@
 use std::io::Cursor;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BufMut;
 
 use crate::errors::Result;
 use crate::net::constants::SESSION_HEADER_SIZE;
 use crate::packet::header::header_reader::HeaderReader;
 use crate::packet::header::header_writer::HeaderWriter;
+use crate::packet::header::{read_header, write_header};
+use crate::serialize::NetSerialize;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, NetSerialize)]
 /// This header will be included in each packet sent by client, with server_salt^client_salt value
 ///
 /// When requesting connection intermediate values are used:
@@ -31,8 +33,8 @@ impl SessionHeader {
 impl HeaderWriter for SessionHeader {
     type Output = Result<()>;
 
-    fn parse(&self, buffer: &mut Vec<u8>) -> Self::Output {
-        buffer.write_u64::<BigEndian>(self.session_id)?;
+    fn parse<B: BufMut>(&self, buffer: &mut B) -> Self::Output {
+        write_header(self, buffer);
         Ok(())
     }
 }
@@ -41,13 +43,7 @@ impl HeaderReader for SessionHeader {
     type Header = Result<SessionHeader>;
 
     fn read(rdr: &mut Cursor<&[u8]>) -> Self::Header {
-        let client_id = rdr.read_u64::<BigEndian>()?;
-
-        let header = SessionHeader {
-            session_id: client_id,
-        };
-
-        Ok(header)
+        read_header(rdr)
     }
 
     /// Returns the size of this header.
@@ -0,0 +1,85 @@
+use std::io::Cursor;
+
+use bytes::BufMut;
+
+use crate::errors::Result;
+use crate::net::constants::TICK_HEADER_SIZE;
+use crate::packet::header::header_reader::HeaderReader;
+use crate::packet::header::header_writer::HeaderWriter;
+use crate::packet::header::{read_header, write_header};
+use crate::serialize::NetSerialize;
+
+#[derive(Copy, Clone, Debug, NetSerialize)]
+/// This header will be included in each packet, and contains the simulation tick of the sender.
+///
+/// Relayed data keeps the tick of the peer that produced it.
+pub struct TickHeader {
+    tick: u32,
+}
+
+impl TickHeader {
+    /// Creates new header.
+    pub fn new(tick: u32) -> Self {
+        TickHeader { tick }
+    }
+
+    /// Returns the simulation tick
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+}
+
+impl HeaderWriter for TickHeader {
+    type Output = Result<()>;
+
+    fn parse<B: BufMut>(&self, buffer: &mut B) -> Self::Output {
+        write_header(self, buffer);
+        Ok(())
+    }
+}
+
+impl HeaderReader for TickHeader {
+    type Header = Result<TickHeader>;
+
+    fn read(rdr: &mut Cursor<&[u8]>) -> Self::Header {
+        read_header(rdr)
+    }
+
+    /// Returns the size of this header.
+    fn size() -> u8 {
+        TICK_HEADER_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::net::constants::TICK_HEADER_SIZE;
+    use crate::packet::header::{HeaderReader, HeaderWriter, TickHeader};
+
+    #[test]
+    fn serialize() {
+        let mut buffer = Vec::new();
+        let header = TickHeader::new(258);
+        assert![header.parse(&mut buffer).is_ok()];
+
+        assert_eq!(buffer, vec![0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn deserialize() {
+        let buffer = vec![0, 0, 1, 2];
+
+        let mut cursor = Cursor::new(buffer.as_slice());
+
+        let header = TickHeader::read(&mut cursor).unwrap();
+
+        assert_eq!(header.tick(), 258);
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(TickHeader::size(), TICK_HEADER_SIZE);
+    }
+}
@@ -1,28 +1,62 @@
-use crate::packet::header::{BaseHeader, HeaderWriter, SessionHeader};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{ErrorKind, Result};
+use crate::net::constants::{BASE_HEADER_SIZE, MAX_HEADER_SIZE, MAX_MTU};
+use crate::packet::compression;
+use crate::packet::header::{BaseHeader, HeaderWriter, SessionHeader, TickHeader};
 use crate::packet::PacketType;
 
 /// Builder that could be used to construct an outgoing packet.
-pub struct OutgoingPacketBuilder<'p> {
-    header: Vec<u8>,
-    payload: &'p [u8],
+pub struct OutgoingPacketBuilder {
+    header: BytesMut,
+    payload: Bytes,
+    error: Option<ErrorKind>,
+    /// Position and value of the `BaseHeader`, rewritten when the payload gets compressed.
+    base: Option<(usize, BaseHeader)>,
+    compress: bool,
 }
 
-impl<'p> OutgoingPacketBuilder<'p> {
+impl OutgoingPacketBuilder {
     /// Construct a new builder from the given `payload`.
-    pub fn new(payload: &'p [u8]) -> OutgoingPacketBuilder<'p> {
+    ///
+    /// # Remark
+    /// - The payload is copied, use [`shared`](#method.shared) to send an existing `Bytes`.
+    pub fn new(payload: &[u8]) -> OutgoingPacketBuilder {
+        Self::shared(
+            Bytes::copy_from_slice(payload),
+            BytesMut::with_capacity(MAX_HEADER_SIZE),
+        )
+    }
+
+    /// Construct a new builder from a reference counted `payload`, headers are written into
+    /// `header` which should come from a [`BufferPool`](./buffer_pool) with room for
+    /// [MAX_HEADER_SIZE](../net/constants/constant.MAX_HEADER_SIZE.html).
+    ///
+    /// The payload is not copied, it is shared by every packet built from it.
+    pub fn shared(payload: Bytes, header: BytesMut) -> OutgoingPacketBuilder {
         OutgoingPacketBuilder {
-            header: Vec::new(),
+            header,
             payload,
+            error: None,
+            base: None,
+            compress: false,
         }
     }
 
     /// Adds the `SessionHeader` to the header.
     pub fn with_session_header(mut self, session_id: u64) -> Self {
         let header = SessionHeader::new(session_id);
+        let written = header.parse(&mut self.header);
+        self.record(written);
+
+        self
+    }
 
-        header
-            .parse(&mut self.header)
-            .expect("Could not write session header to buffer");
+    /// Adds the `TickHeader` with the simulation tick of the sender to the header.
+    pub fn with_tick_header(mut self, tick: u32) -> Self {
+        let header = TickHeader::new(tick);
+        let written = header.parse(&mut self.header);
+        self.record(written);
 
         self
     }
@@ -30,45 +64,117 @@ impl<'p> OutgoingPacketBuilder<'p> {
     /// Adds the [`BaseHeader`](./header/base_header) to the header.
     pub fn with_default_header(mut self, packet_type: PacketType) -> Self {
         let header = BaseHeader::new(packet_type);
-        header
-            .parse(&mut self.header)
-            .expect("Could not write default header to buffer");
+        self.base = Some((self.header.len(), header));
+        let written = header.parse(&mut self.header);
+        self.record(written);
 
         self
     }
 
+    /// Compresses the payload when that makes it smaller, the receiver decompresses it in
+    /// [`PacketReader::read_payload`](./struct.PacketReader.html#method.read_payload).
+    ///
+    /// # Remark
+    /// - Only has an effect with the `compression` feature and along with the
+    ///   [`BaseHeader`](./header/base_header) which flags the compressed payload.
+    pub fn with_compression(mut self) -> Self {
+        self.compress = true;
+        self
+    }
+
     /// Constructs an `OutgoingPacket` from the contents constructed with this builder.
-    pub fn build(self) -> OutgoingPacket<'p> {
-        OutgoingPacket {
-            header: self.header,
+    ///
+    /// Fails with the first error encountered while writing a header, or with `PayloadTooLarge`
+    /// when the packet does not fit into [MAX_MTU](../net/constants/constant.MAX_MTU.html).
+    /// Payloads of a connection are sized to its discovered path MTU beforehand.
+    pub fn build(mut self) -> Result<OutgoingPacket> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        if let (true, Some((offset, base))) = (self.compress, self.base) {
+            if let Some(compressed) = compression::compress(&self.payload) {
+                let mut slot = &mut self.header[offset..offset + BASE_HEADER_SIZE as usize];
+                base.with_compression(true).parse(&mut slot)?;
+                self.payload = compressed;
+            }
+        }
+
+        let size = self.header.len() + self.payload.len();
+        if size > MAX_MTU as usize {
+            return Err(ErrorKind::PayloadTooLarge(size));
+        }
+
+        Ok(OutgoingPacket {
+            header: self.header.freeze(),
             payload: self.payload,
+        })
+    }
+
+    /// Keeps the first header write error, it is reported by [build](#method.build).
+    fn record(&mut self, written: Result<()>) {
+        if let Err(e) = written {
+            self.error.get_or_insert(e);
         }
     }
 }
 
 /// Packet that that contains data which is ready to be sent to a remote endpoint.
-#[derive(Debug)]
-pub struct OutgoingPacket<'p> {
-    header: Vec<u8>,
-    payload: &'p [u8],
+///
+/// The header and payload are kept apart so that a payload relayed to many endpoints is never
+/// copied, sockets supporting scatter/gather writes send both parts directly.
+#[derive(Clone, Debug)]
+pub struct OutgoingPacket {
+    header: Bytes,
+    payload: Bytes,
 }
 
-impl<'p> OutgoingPacket<'p> {
+impl OutgoingPacket {
+    /// Returns the header bytes of this packet.
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Returns the payload bytes of this packet.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns the total size of this packet in bytes.
+    pub fn len(&self) -> usize {
+        self.header.len() + self.payload.len()
+    }
+
+    /// Returns `true` if the packet has neither header nor payload.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the header followed by the payload to the end of `buffer`.
+    pub fn write_to<B: BufMut>(&self, buffer: &mut B) {
+        buffer.put_slice(&self.header);
+        buffer.put_slice(&self.payload);
+    }
+
     /// Return the contents of this packet; the content includes the header and payload bytes.
     ///
     /// # Remark
-    /// - Until here we could use a reference to the outgoing data but here we need to do a hard copy.
-    /// Because the header could vary in size but should be in front of the payload provided by the user.
-    pub fn contents(&self) -> Box<[u8]> {
-        [self.header.as_slice(), &self.payload]
-            .concat()
-            .into_boxed_slice()
+    /// - This copies header and payload into a new buffer, prefer [`write_to`](#method.write_to)
+    ///   with a reused buffer on hot paths.
+    pub fn contents(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(self.len());
+        self.write_to(&mut buffer);
+        buffer.freeze()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::net::constants::{BASE_HEADER_SIZE, MAX_MTU};
     use crate::packet::{OutgoingPacketBuilder, PacketType};
+    use crate::ErrorKind;
 
     fn test_payload() -> Vec<u8> {
         b"test".to_vec()
@@ -80,7 +186,8 @@ mod tests {
 
         let outgoing = OutgoingPacketBuilder::new(&payload)
             .with_session_header(1_u64)
-            .build();
+            .build()
+            .unwrap();
 
         let expected: Vec<u8> = [vec![0, 0, 0, 0, 0, 0, 0, 1], test_payload()]
             .concat()
@@ -97,13 +204,65 @@ mod tests {
             .with_default_header(
                 PacketType::Connect,
             )
-            .build();
+            .build()
+            .unwrap();
 
-        let expected: Vec<u8> = [vec![1], test_payload()].concat().to_vec();
+        let expected: Vec<u8> = [vec![1, 0], test_payload()].concat().to_vec();
 
         assert_eq!(
             outgoing.contents()[2..outgoing.contents().len()].to_vec(),
             expected
         );
     }
+
+    #[test]
+    fn shared_payload_is_not_copied() {
+        let payload = Bytes::from(test_payload());
+
+        let outgoing = OutgoingPacketBuilder::shared(payload.clone(), BytesMut::new())
+            .with_session_header(1_u64)
+            .build()
+            .unwrap();
+
+        assert_eq!(outgoing.payload().as_ptr(), payload.as_ptr());
+        assert_eq!(outgoing.header(), &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(outgoing.len(), 12);
+    }
+
+    #[test]
+    fn reject_payload_too_large() {
+        let payload = vec![0; MAX_MTU as usize];
+
+        let outgoing = OutgoingPacketBuilder::new(&payload)
+            .with_default_header(PacketType::Data)
+            .build();
+
+        match outgoing {
+            Err(ErrorKind::PayloadTooLarge(size)) => assert_eq!(size, payload.len() + BASE_HEADER_SIZE as usize),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compresses_only_when_smaller() {
+        // does not fit uncompressed along with the header
+        let compressible = vec![0; MAX_MTU as usize];
+        let outgoing = OutgoingPacketBuilder::new(&compressible)
+            .with_default_header(PacketType::Snapshot)
+            .with_compression()
+            .build()
+            .unwrap();
+        assert!(outgoing.payload().len() < 100);
+        assert_eq!(outgoing.header()[2], PacketType::Snapshot as u8);
+        assert_eq!(outgoing.header()[3], 0b0000_0001);
+
+        let outgoing = OutgoingPacketBuilder::new(&test_payload())
+            .with_default_header(PacketType::Snapshot)
+            .with_compression()
+            .build()
+            .unwrap();
+        assert_eq!(outgoing.payload(), &test_payload()[..]);
+        assert_eq!(outgoing.header()[3], 0);
+    }
 }
@@ -1,7 +1,12 @@
+use std::borrow::Cow;
 use std::io::Cursor;
 
+use bytes::Bytes;
+
+use crate::errors::DecodingErrorKind;
 use crate::{ErrorKind, Result};
-use crate::packet::header::{BaseHeader, HeaderReader, SessionHeader};
+use crate::packet::compression;
+use crate::packet::header::{BaseHeader, HeaderReader, SessionHeader, TickHeader};
 
 /// Can be used to read the packet contents.
 ///
@@ -11,6 +16,8 @@ use crate::packet::header::{BaseHeader, HeaderReader, SessionHeader};
 pub struct PacketReader<'s> {
     buffer: &'s [u8],
     cursor: Cursor<&'s [u8]>,
+    /// Whether compression was agreed with the sender, compressed payloads are rejected otherwise.
+    compression: bool,
 }
 
 impl<'s> PacketReader<'s> {
@@ -19,9 +26,17 @@ impl<'s> PacketReader<'s> {
         PacketReader {
             buffer,
             cursor: Cursor::new(buffer),
+            compression: false,
         }
     }
 
+    /// Accepts compressed payloads when compression was `agreed` with the sender, reading one
+    /// fails with `DecodingError(Compression)` otherwise.
+    pub fn with_compression(mut self, agreed: bool) -> Self {
+        self.compression = agreed;
+        self
+    }
+
     /// Reads the `BaseHeader` from the underlying buffer.
     ///
     /// # Remark
@@ -44,12 +59,28 @@ impl<'s> PacketReader<'s> {
         self.session_header(u64::from(BaseHeader::size()), "session id")
     }
 
+    /// Reads the `TickHeader` from the underlying buffer.
+    ///
+    /// # Remark
+    /// - Will change the position to the location of `TickHeader`
+    pub fn read_tick_header(&mut self) -> Result<TickHeader> {
+        self.cursor
+            .set_position(u64::from(BaseHeader::size() + SessionHeader::size()));
+
+        if self.can_read(TickHeader::size() as usize) {
+            TickHeader::read(&mut self.cursor)
+        } else {
+            Err(ErrorKind::CouldNotReadHeader(String::from("tick")))
+        }
+    }
+
     /// Reads the `SessionHeader` for peer id from the underlying buffer.
     ///
     /// # Remark
     /// - Will change the position to the location of `SessionHeader`
     pub fn read_id_header(&mut self) -> Result<SessionHeader> {
-        self.session_header(u64::from(BaseHeader::size() + SessionHeader::size()), "peer id")
+        let pos = BaseHeader::size() + SessionHeader::size() + TickHeader::size();
+        self.session_header(u64::from(pos), "peer id")
     }
 
     fn session_header(&mut self, pos: u64, msg: &str) -> Result<SessionHeader> {
@@ -62,21 +93,59 @@ impl<'s> PacketReader<'s> {
         }
     }
 
-    /// Reads the payload` from the underlying buffer.
+    /// Reads the payload` from the underlying buffer, decompressed if the `BaseHeader` flags it
+    /// as compressed.
     ///
     /// # Remark
     /// - Notice that this will continue on the position of last read header;
-    /// e.g. when reading `BaseHeader` the position of the underlying `Cursor` will be at the end where it left of,
-    /// when calling this function afterward it will read all the bytes from there on.
-    pub fn read_payload(&self) -> Box<[u8]> {
-        self.buffer[self.cursor.position() as usize..self.buffer.len()]
-            .to_vec()
-            .into_boxed_slice()
+    ///   e.g. when reading `BaseHeader` the position of the underlying `Cursor` will be at the end where it left of,
+    ///   when calling this function afterward it will read all the bytes from there on.
+    /// - An uncompressed payload borrows the underlying buffer, use
+    ///   [read_shared_payload](#method.read_shared_payload) to get a shared handle to it without
+    ///   copying.
+    /// - Fails with `BufferUnderflow` when the position is past the end of the buffer.
+    pub fn read_payload(&self) -> Result<Cow<'s, [u8]>> {
+        let payload = self.raw_payload()?;
+        if self.is_compressed()? {
+            Ok(Cow::Owned(compression::decompress(payload)?.to_vec()))
+        } else {
+            Ok(Cow::Borrowed(payload))
+        }
+    }
+
+    /// Reads the payload like [read_payload](#method.read_payload), an uncompressed payload
+    /// shares `datagram`, which has to be the underlying buffer.
+    pub fn read_shared_payload(&self, datagram: &Bytes) -> Result<Bytes> {
+        let payload = self.raw_payload()?;
+        if self.is_compressed()? {
+            compression::decompress(payload)
+        } else {
+            Ok(datagram.slice_ref(payload))
+        }
+    }
+
+    fn raw_payload(&self) -> Result<&'s [u8]> {
+        self.buffer
+            .get(self.cursor.position() as usize..)
+            .ok_or(ErrorKind::BufferUnderflow)
+    }
+
+    fn is_compressed(&self) -> Result<bool> {
+        let compressed = BaseHeader::read(&mut Cursor::new(self.buffer))?.is_compressed();
+        if compressed && !self.compression {
+            return Err(ErrorKind::DecodingError(DecodingErrorKind::Compression));
+        }
+        Ok(compressed)
+    }
+
+    /// Returns the number of bytes left to read, zero when the position is past the end.
+    pub fn remaining(&self) -> usize {
+        (self.buffer.len() as u64).saturating_sub(self.cursor.position()) as usize
     }
 
     // Checks if a given length of bytes could be read with the buffer.
     pub fn can_read(&self, length: usize) -> bool {
-        (self.buffer.len() - self.cursor.position() as usize) >= length
+        self.remaining() >= length
     }
 }
 
@@ -89,14 +158,14 @@ mod tests {
         let buffer = vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
 
         let reader = PacketReader::new(buffer.as_slice());
-        assert_eq!(reader.can_read(buffer.len() as usize), true);
-        assert_eq!(reader.can_read((buffer.len() + 1) as usize), false);
+        assert!(reader.can_read(buffer.len()));
+        assert!(!reader.can_read(buffer.len() + 1));
     }
 
     #[test]
     fn assure_read_base_header() {
         // base header
-        let payload: Vec<u8> = vec![vec![0, 1, 0]].concat();
+        let payload: Vec<u8> = [vec![0, 1, 0, 0]].concat();
 
         let mut reader = PacketReader::new(payload.as_slice());
 
@@ -110,7 +179,7 @@ mod tests {
     fn assure_read_session_header() {
         // base header, session header
         let payload: Vec<u8> =
-            vec![vec![0, 1, 0], vec![0, 0, 0, 0, 0, 0, 0, 3]].concat();
+            [vec![0, 1, 0, 0], vec![0, 0, 0, 0, 0, 0, 0, 3]].concat();
 
         let mut reader = PacketReader::new(payload.as_slice());
 
@@ -120,10 +189,29 @@ mod tests {
     }
 
     #[test]
-    fn assure_read_id_header() {
-        // base header, session header, id header
+    fn assure_read_tick_header() {
+        // base header, session header, tick header
         let payload: Vec<u8> =
-            vec![vec![0, 1, 0], vec![0, 0, 0, 0, 0, 0, 0, 3], vec![0, 0, 0, 0, 0, 0, 0, 5]].concat();
+            [vec![0, 1, 0, 0], vec![0, 0, 0, 0, 0, 0, 0, 3], vec![0, 0, 0, 7]].concat();
+
+        let mut reader = PacketReader::new(payload.as_slice());
+
+        let header = reader.read_tick_header().unwrap();
+
+        assert_eq!(header.tick(), 7);
+        assert_eq!(&reader.read_payload().unwrap()[..], &[] as &[u8]);
+    }
+
+    #[test]
+    fn assure_read_id_header() {
+        // base header, session header, tick header, id header
+        let payload: Vec<u8> = [
+            vec![0, 1, 0, 0],
+            vec![0, 0, 0, 0, 0, 0, 0, 3],
+            vec![0, 0, 0, 7],
+            vec![0, 0, 0, 0, 0, 0, 0, 5],
+        ]
+        .concat();
 
         let mut reader = PacketReader::new(payload.as_slice());
 
@@ -132,13 +220,61 @@ mod tests {
         assert_eq!(header.session_id(), 5);
     }
 
+    #[test]
+    fn no_underflow_past_end() {
+        // base header only, the id header would start past the end
+        let payload: Vec<u8> = vec![0, 1, 0, 0];
+
+        let mut reader = PacketReader::new(payload.as_slice());
+
+        assert!(reader.read_id_header().is_err());
+        assert!(!reader.can_read(1));
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.read_payload().is_err());
+    }
+
     #[test]
     fn expect_read_error() {
         // base header (with one corrupt byte)
-        let payload: Vec<u8> = vec![vec![0, 1]].concat();
+        let payload: Vec<u8> = [vec![0, 1]].concat();
 
         let mut reader = PacketReader::new(payload.as_slice());
 
         assert!(reader.read_base_header().is_err());
     }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn decompresses_payload() {
+        use bytes::Bytes;
+
+        use crate::errors::{DecodingErrorKind, ErrorKind};
+        use crate::packet::OutgoingPacketBuilder;
+
+        let payload = vec![3; 600];
+        let datagram = OutgoingPacketBuilder::new(&payload)
+            .with_default_header(PacketType::Snapshot)
+            .with_session_header(1)
+            .with_tick_header(7)
+            .with_compression()
+            .build()
+            .unwrap()
+            .contents();
+        assert!(datagram.len() < payload.len());
+
+        let mut reader = PacketReader::new(&datagram).with_compression(true);
+        assert!(reader.read_base_header().unwrap().is_compressed());
+        reader.read_tick_header().unwrap();
+        assert_eq!(&reader.read_payload().unwrap()[..], &payload[..]);
+        assert_eq!(reader.read_shared_payload(&datagram).unwrap(), Bytes::from(payload));
+
+        // the flag is not trusted unless compression was agreed
+        let mut reader = PacketReader::new(&datagram);
+        reader.read_tick_header().unwrap();
+        assert!(matches!(
+            reader.read_payload(),
+            Err(ErrorKind::DecodingError(DecodingErrorKind::Compression))
+        ));
+        assert!(reader.read_shared_payload(&datagram).is_err());
+    }
 }
@@ -1,17 +1,64 @@
 use std::net::SocketAddr;
 
+use bytes::Bytes;
+
+use crate::packet::PacketType;
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Packet {
     /// The endpoint from where it came.
     addr: SocketAddr,
     /// The raw payload of the packet.
-    payload: Box<[u8]>,
+    payload: Bytes,
+    /// The simulation tick of the peer that produced the payload.
+    tick: u32,
+    /// The type the packet is relayed as.
+    ptype: PacketType,
+    /// Id of the peer that produced the payload, only relayed with `FrameInput` packets.
+    origin: u64,
 }
 
 impl Packet {
     /// Creates a new packet by passing the receiver and data
-    pub fn new(addr: SocketAddr, payload: Box<[u8]>) -> Packet {
-        Packet { addr, payload }
+    pub fn new<P: Into<Bytes>>(addr: SocketAddr, payload: P) -> Packet {
+        Packet {
+            addr,
+            payload: payload.into(),
+            tick: 0,
+            ptype: PacketType::Data,
+            origin: 0,
+        }
+    }
+
+    /// Sets the simulation tick the payload belongs to.
+    pub fn with_tick(mut self, tick: u32) -> Packet {
+        self.tick = tick;
+        self
+    }
+
+    /// Marks the payload as rollback inputs of the peer with id `origin`.
+    pub(crate) fn with_frame_origin(mut self, origin: u64) -> Packet {
+        self.ptype = PacketType::FrameInput;
+        self.origin = origin;
+        self
+    }
+
+    /// Returns the type the packet is relayed as, `Data` unless it carries rollback inputs.
+    pub fn packet_type(&self) -> PacketType {
+        self.ptype
+    }
+
+    /// Returns the id of the peer that produced a `FrameInput` payload.
+    pub(crate) fn origin(&self) -> u64 {
+        self.origin
+    }
+
+    /// Returns the simulation tick of the peer that produced the payload.
+    ///
+    /// # Remark
+    /// Relayed packets keep the tick of their original sender.
+    pub fn tick(&self) -> u32 {
+        self.tick
     }
 
     /// Returns the payload of this packet.
@@ -19,6 +66,14 @@ impl Packet {
         &self.payload
     }
 
+    /// Returns a handle to the payload of this packet.
+    ///
+    /// # Remark
+    /// - The payload is reference counted, the returned handle shares it without copying.
+    pub fn shared_payload(&self) -> Bytes {
+        self.payload.clone()
+    }
+
     /// Returns the address of this packet.
     ///
     /// # Remark
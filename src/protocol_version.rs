@@ -1,12 +1,14 @@
+use std::ops::RangeInclusive;
+
 use crc::crc16;
 
 use lazy_static::lazy_static;
 
-pub use crate::net::constants::PROTOCOL_VERSION;
+pub use crate::net::constants::{MIN_PROTOCOL_VERSION, PROTOCOL_ID, PROTOCOL_VERSION};
 
 lazy_static! {
-    // The CRC16 of the current protocol version.
-    static ref VERSION_CRC16: u16 = crc16::checksum_x25(PROTOCOL_VERSION.as_bytes());
+    // The CRC16 of the protocol id.
+    static ref VERSION_CRC16: u16 = crc16::checksum_x25(PROTOCOL_ID.as_bytes());
 }
 
 /// Wrapper to provide some functions to perform with the current protocol version.
@@ -16,17 +18,29 @@ impl ProtocolVersion {
     /// Returns the current protocol version.
     #[inline]
     #[cfg(test)]
-    pub fn get_version() -> &'static str {
+    pub fn get_version() -> u16 {
         PROTOCOL_VERSION
     }
 
-    /// This will return the crc16 from the current protocol version.
+    /// Returns the protocol versions this build can talk.
+    #[inline]
+    pub fn supported() -> RangeInclusive<u16> {
+        MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION
+    }
+
+    /// Returns the newest version supported by both sides, when a peer supports `versions`.
+    pub fn negotiate(versions: RangeInclusive<u16>) -> Option<u16> {
+        let newest = *versions.end().min(&PROTOCOL_VERSION);
+        Some(newest).filter(|v| *v >= MIN_PROTOCOL_VERSION && versions.contains(v))
+    }
+
+    /// This will return the crc16 from the protocol id.
     #[inline]
     pub fn get_crc16() -> u16 {
         *VERSION_CRC16
     }
 
-    /// Validate a crc16 with the current protocol version and return the results.
+    /// Validate a crc16 with the protocol id and return the results.
     #[inline]
     pub fn valid_version(protocol_version_crc16: u16) -> bool {
         protocol_version_crc16 == ProtocolVersion::get_crc16()
@@ -35,13 +49,13 @@ impl ProtocolVersion {
 
 #[cfg(test)]
 mod test {
-    use crate::net::constants::PROTOCOL_VERSION;
+    use crate::net::constants::{PROTOCOL_ID, PROTOCOL_VERSION};
 
     use super::*;
 
     #[test]
     fn valid_version() {
-        let protocol_id = crc16::checksum_x25(PROTOCOL_VERSION.as_bytes());
+        let protocol_id = crc16::checksum_x25(PROTOCOL_ID.as_bytes());
         assert!(ProtocolVersion::valid_version(protocol_id));
     }
 
@@ -60,4 +74,15 @@ mod test {
     fn get_version() {
         assert_eq!(ProtocolVersion::get_version(), PROTOCOL_VERSION);
     }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(ProtocolVersion::negotiate(0..=u16::MAX), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            ProtocolVersion::negotiate(MIN_PROTOCOL_VERSION..=MIN_PROTOCOL_VERSION),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+        assert_eq!(ProtocolVersion::negotiate(PROTOCOL_VERSION + 1..=u16::MAX), None);
+        assert_eq!(ProtocolVersion::negotiate(0..=MIN_PROTOCOL_VERSION - 1), None);
+    }
 }
@@ -0,0 +1,15 @@
+//! Replication of server owned entities to connected peers.
+//!
+//! The server registers entities with a type id and components serialized by the app, every
+//! connected peer receives their spawn, the changes of their components and their despawn.
+//! Each entity has an owner, either the server or the peer with authority over it. Only the
+//! owner changes the components of an entity, peers ask the server for authority, which grants
+//! it only when nobody else holds it. Peers are identified by the session id of their
+//! connection, which the server keeps unique.
+pub use self::world::{Entity, ReplicatedWorld, ReplicationEvent};
+pub(crate) use self::codec::{
+    decode_authority, encode_authority, ReplicationDecoder, ReplicationDiff, ReplicationEncoder,
+};
+
+mod world;
+mod codec;
@@ -0,0 +1,587 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use log::debug;
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::REPLICATION_HISTORY;
+use crate::replication::{Entity, ReplicatedWorld};
+
+/// Set when an entity update carries its owner.
+const OWNER: u8 = 0b0001;
+
+/// Entity versions sent with a tick, `None` for despawns.
+type Sent = (u32, Vec<(u32, Option<u32>)>);
+
+/// A newly replicated entity with all its components.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EntitySpawn {
+    pub entity: u32,
+    pub type_id: u16,
+    pub owner: Option<u64>,
+    pub components: Vec<(u16, Bytes)>,
+}
+
+/// The changes of an entity the receiver already knows.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EntityChanges {
+    pub entity: u32,
+    /// The new owner, `None` when it did not change.
+    pub owner: Option<Option<u64>>,
+    pub components: Vec<(u16, Bytes)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ReplicationDiff {
+    pub spawns: Vec<EntitySpawn>,
+    pub updates: Vec<EntityChanges>,
+    pub despawns: Vec<u32>,
+}
+
+/// Encodes the entities of a [ReplicatedWorld] for one peer, against the versions it
+/// acknowledged.
+///
+/// The payload is `[u16 spawns][spawn]*[u16 updates][update]*[u16 despawns][u32 entity]*`, a
+/// spawn is `[u32 entity][u16 type][u64 owner][components]`, an update `[u32 entity][u8
+/// flags][u64 owner if flagged][components]`, components are `[u8 count]([u16 id][u16
+/// len][data])*`. Owner 0 is the server.
+///
+/// # Remarks
+/// - Every packet repeats all changes not acknowledged yet, so acknowledging a packet also
+///   covers all sent before it.
+#[derive(Debug, Default)]
+pub(crate) struct ReplicationEncoder {
+    /// Latest version of each entity the peer acknowledged.
+    acked: HashMap<u32, u32>,
+    pending: VecDeque<Sent>,
+}
+
+impl ReplicationEncoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Marks the packet of `tick` as received by the peer.
+    pub fn ack(&mut self, tick: u32) {
+        while let Some((sent, _)) = self.pending.front() {
+            if *sent > tick {
+                break;
+            }
+            let (sent, entities) = self.pending.pop_front().unwrap();
+            if sent != tick {
+                continue;
+            }
+            for (entity, version) in entities {
+                match version {
+                    Some(version) => {
+                        let acked = self.acked.entry(entity).or_insert(version);
+                        *acked = version.max(*acked);
+                    }
+                    None => {
+                        self.acked.remove(&entity);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Encodes spawns, changes and despawns of `world` for the peer `receiver` at `tick` into
+    /// at most `budget` bytes, `None` when the peer is up to date.
+    ///
+    /// Components of entities the receiver owns are only sent when they changed before the
+    /// handover, it made the later changes itself. Entities that do not fit wait for a later
+    /// packet, one larger than the whole budget is never sent.
+    pub fn encode(
+        &mut self,
+        world: &ReplicatedWorld,
+        tick: u32,
+        receiver: Option<u64>,
+        budget: usize,
+    ) -> Option<Bytes> {
+        let mut diff = ReplicationDiff::default();
+        let mut sent = Vec::new();
+        // counts of spawns, updates and despawns
+        let mut size = 6;
+
+        let known = self
+            .acked
+            .keys()
+            .copied()
+            .chain(self.pending.iter().flat_map(|(_, sent)| {
+                sent.iter()
+                    .filter(|(_, version)| version.is_some())
+                    .map(|(entity, _)| *entity)
+            }))
+            .collect::<HashSet<_>>();
+        let mut despawns = known
+            .into_iter()
+            .filter(|entity| world.entity(*entity).is_none())
+            .collect::<Vec<_>>();
+        despawns.sort_unstable();
+        diff.despawns = despawns
+            .into_iter()
+            .filter(|_| fits(&mut size, 4, budget))
+            .collect();
+        sent.extend(diff.despawns.iter().map(|entity| (*entity, None)));
+
+        for (entity, e) in world.entities() {
+            let version = e.version();
+            match self.acked.get(&entity) {
+                None => {
+                    let spawn = EntitySpawn {
+                        entity,
+                        type_id: e.type_id(),
+                        owner: e.owner(),
+                        components: e.components().map(|(id, c)| (id, c.clone())).collect(),
+                    };
+                    if !fits(&mut size, 14 + components_size(&spawn.components), budget) {
+                        debug!("entity {} does not fit into the replication packet", entity);
+                        continue;
+                    }
+                    diff.spawns.push(spawn);
+                }
+                Some(acked) if version > *acked => {
+                    let owner = Some(e.owner()).filter(|_| e.owner_version() > *acked);
+                    let until = if e.owner().is_some() && e.owner() == receiver {
+                        e.owner_version()
+                    } else {
+                        u32::MAX
+                    };
+                    let components = Self::changed(e, *acked, until);
+                    if owner.is_some() || !components.is_empty() {
+                        let changes = EntityChanges { entity, owner, components };
+                        if !fits(&mut size, changes_size(&changes), budget) {
+                            debug!("entity {} does not fit into the replication packet", entity);
+                            continue;
+                        }
+                        diff.updates.push(changes);
+                    }
+                }
+                Some(_) => continue,
+            }
+            sent.push((entity, Some(version)));
+        }
+
+        self.push_pending(tick, sent);
+        if diff.spawns.is_empty() && diff.updates.is_empty() && diff.despawns.is_empty() {
+            None
+        } else {
+            Some(encode_diff(&diff))
+        }
+    }
+
+    /// Encodes the local changes of entities owned by `owner` for the server into at most
+    /// `budget` bytes, `None` when the server is up to date.
+    pub fn encode_owned(
+        &mut self,
+        world: &ReplicatedWorld,
+        tick: u32,
+        owner: u64,
+        budget: usize,
+    ) -> Option<Bytes> {
+        let mut diff = ReplicationDiff::default();
+        let mut sent = Vec::new();
+        let mut size = 6;
+
+        for (entity, e) in world.entities().filter(|(_, e)| e.owner() == Some(owner)) {
+            let acked = self.acked.get(&entity).copied().unwrap_or_default();
+            let components = Self::changed(e, acked, u32::MAX);
+            if components.is_empty() {
+                continue;
+            }
+            let changes = EntityChanges { entity, owner: None, components };
+            if fits(&mut size, changes_size(&changes), budget) {
+                diff.updates.push(changes);
+                sent.push((entity, Some(e.version())));
+            }
+        }
+
+        if sent.is_empty() {
+            return None;
+        }
+        self.push_pending(tick, sent);
+        Some(encode_diff(&diff))
+    }
+
+    fn changed(entity: &Entity, after: u32, until: u32) -> Vec<(u16, Bytes)> {
+        entity
+            .changed_between(after, until)
+            .map(|(id, c)| (id, c.clone()))
+            .collect()
+    }
+
+    fn push_pending(&mut self, tick: u32, sent: Vec<(u32, Option<u32>)>) {
+        if sent.is_empty() {
+            return;
+        }
+        // the peer decodes only one packet per tick, so an ack of a repeated tick only covers
+        // the versions all its packets carried
+        if let Some((last, previous)) = self.pending.back_mut() {
+            if *last == tick {
+                for (entity, version) in previous.iter_mut() {
+                    let repeated = sent.iter().find(|(other, _)| other == entity);
+                    if let (Some(old), Some((_, Some(new)))) = (*version, repeated) {
+                        *version = Some(old.min(*new));
+                    }
+                }
+                return;
+            }
+        }
+        if self.pending.len() == REPLICATION_HISTORY {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((tick, sent));
+    }
+}
+
+/// Reads packets written by [ReplicationEncoder], dropping the ones older than the latest
+/// one.
+#[derive(Debug, Default)]
+pub(crate) struct ReplicationDecoder {
+    latest: Option<u32>,
+}
+
+impl ReplicationDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Decodes the packet of `tick`, `None` when a newer one was decoded already.
+    ///
+    /// The components share the memory of `payload`.
+    pub fn decode(&mut self, tick: u32, payload: &Bytes) -> Result<Option<ReplicationDiff>> {
+        if matches!(self.latest, Some(latest) if latest >= tick) {
+            return Ok(None);
+        }
+        let diff = decode_diff(payload)?;
+        self.latest = Some(tick);
+        Ok(Some(diff))
+    }
+}
+
+/// Creates the payload of an authority request or response about `entity`.
+///
+/// `flag` releases authority in a request and tells whether it was granted in a response.
+pub(crate) fn encode_authority(entity: u32, flag: bool) -> Bytes {
+    let mut payload = BytesMut::with_capacity(5);
+    payload.put_u32(entity);
+    payload.put_u8(flag as u8);
+    payload.freeze()
+}
+
+/// Reads a payload written by [encode_authority].
+pub(crate) fn decode_authority(payload: &[u8]) -> Result<(u32, bool)> {
+    let mut rdr = Cursor::new(payload);
+    Ok((rdr.read_u32::<BigEndian>()?, rdr.read_u8()? != 0))
+}
+
+fn encode_diff(diff: &ReplicationDiff) -> Bytes {
+    let mut payload = BytesMut::new();
+    payload.put_u16(diff.spawns.len() as u16);
+    for spawn in &diff.spawns {
+        payload.put_u32(spawn.entity);
+        payload.put_u16(spawn.type_id);
+        payload.put_u64(spawn.owner.unwrap_or_default());
+        put_components(&spawn.components, &mut payload);
+    }
+    payload.put_u16(diff.updates.len() as u16);
+    for changes in &diff.updates {
+        payload.put_u32(changes.entity);
+        match changes.owner {
+            Some(owner) => {
+                payload.put_u8(OWNER);
+                payload.put_u64(owner.unwrap_or_default());
+            }
+            None => payload.put_u8(0),
+        }
+        put_components(&changes.components, &mut payload);
+    }
+    payload.put_u16(diff.despawns.len() as u16);
+    for entity in &diff.despawns {
+        payload.put_u32(*entity);
+    }
+    payload.freeze()
+}
+
+fn decode_diff(payload: &Bytes) -> Result<ReplicationDiff> {
+    let mut rdr = Cursor::new(&payload[..]);
+    let mut diff = ReplicationDiff::default();
+
+    for _ in 0..rdr.read_u16::<BigEndian>()? {
+        diff.spawns.push(EntitySpawn {
+            entity: rdr.read_u32::<BigEndian>()?,
+            type_id: rdr.read_u16::<BigEndian>()?,
+            owner: Some(rdr.read_u64::<BigEndian>()?).filter(|owner| *owner != 0),
+            components: read_components(&mut rdr, payload)?,
+        });
+    }
+    for _ in 0..rdr.read_u16::<BigEndian>()? {
+        let entity = rdr.read_u32::<BigEndian>()?;
+        let owner = if rdr.read_u8()? & OWNER != 0 {
+            Some(Some(rdr.read_u64::<BigEndian>()?).filter(|owner| *owner != 0))
+        } else {
+            None
+        };
+        diff.updates.push(EntityChanges {
+            entity,
+            owner,
+            components: read_components(&mut rdr, payload)?,
+        });
+    }
+    for _ in 0..rdr.read_u16::<BigEndian>()? {
+        diff.despawns.push(rdr.read_u32::<BigEndian>()?);
+    }
+    Ok(diff)
+}
+
+/// Adds an entry of `len` bytes to the `size` of a payload if it stays within `budget`.
+fn fits(size: &mut usize, len: usize, budget: usize) -> bool {
+    if *size + len > budget {
+        return false;
+    }
+    *size += len;
+    true
+}
+
+fn changes_size(changes: &EntityChanges) -> usize {
+    let owner = if changes.owner.is_some() { 8 } else { 0 };
+    5 + owner + components_size(&changes.components)
+}
+
+fn components_size(components: &[(u16, Bytes)]) -> usize {
+    1 + components.iter().map(|(_, data)| 4 + data.len()).sum::<usize>()
+}
+
+fn put_components(components: &[(u16, Bytes)], payload: &mut BytesMut) {
+    payload.put_u8(components.len() as u8);
+    for (id, data) in components {
+        payload.put_u16(*id);
+        payload.put_u16(data.len() as u16);
+        payload.put_slice(data);
+    }
+}
+
+fn read_components(rdr: &mut Cursor<&[u8]>, payload: &Bytes) -> Result<Vec<(u16, Bytes)>> {
+    (0..rdr.read_u8()?)
+        .map(|_| {
+            let id = rdr.read_u16::<BigEndian>()?;
+            let len = rdr.read_u16::<BigEndian>()? as usize;
+            let start = rdr.position() as usize;
+            if start + len > payload.len() {
+                return Err(ErrorKind::DecodingError(DecodingErrorKind::Replication));
+            }
+            rdr.set_position((start + len) as u64);
+            Ok((id, payload.slice(start..start + len)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::replication::{ReplicatedWorld, ReplicationEvent};
+
+    use super::{EntityChanges, ReplicationDecoder, ReplicationDiff, ReplicationEncoder};
+
+    const BUDGET: usize = 1200;
+
+    fn data(value: u8) -> Bytes {
+        Bytes::from(vec![value; 2])
+    }
+
+    /// Sends the packet of `tick` from `encoder` to `replica`, if there is one.
+    fn deliver(
+        server: &ReplicatedWorld,
+        encoder: &mut ReplicationEncoder,
+        replica: &mut ReplicatedWorld,
+        tick: u32,
+        ack: bool,
+    ) {
+        if let Some(payload) = encoder.encode(server, tick, Some(7), BUDGET) {
+            let diff = ReplicationDecoder::new().decode(tick, &payload).unwrap();
+            replica.apply_remote(diff.unwrap());
+        }
+        if ack {
+            encoder.ack(tick);
+        }
+    }
+
+    #[test]
+    fn replicates_spawn_update_despawn() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+
+        let entity = server.spawn(3);
+        server.set_component(entity, 1, data(1));
+        deliver(&server, &mut encoder, &mut replica, 1, true);
+        assert_eq!(
+            replica.drain_events().collect::<Vec<_>>(),
+            vec![
+                ReplicationEvent::Spawned { entity, type_id: 3 },
+                ReplicationEvent::Updated { entity, component: 1 },
+            ]
+        );
+        assert!(encoder.encode(&server, 2, Some(7), BUDGET).is_none());
+
+        server.set_component(entity, 1, data(2));
+        server.set_component(entity, 2, data(3));
+        deliver(&server, &mut encoder, &mut replica, 3, true);
+        assert_eq!(replica.entity(entity).unwrap().component(1), Some(&data(2)));
+        assert_eq!(replica.drain_events().count(), 2);
+
+        server.despawn(entity);
+        deliver(&server, &mut encoder, &mut replica, 4, true);
+        assert!(replica.is_empty());
+        assert!(encoder.encode(&server, 5, Some(7), BUDGET).is_none());
+    }
+
+    #[test]
+    fn repeats_lost_changes() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+        let entity = server.spawn(3);
+        deliver(&server, &mut encoder, &mut replica, 1, true);
+
+        // the change is lost, the despawn of an entity whose spawn never arrived is repeated
+        server.set_component(entity, 1, data(1));
+        let short_lived = server.spawn(4);
+        encoder.encode(&server, 2, Some(7), BUDGET);
+        server.despawn(short_lived);
+
+        deliver(&server, &mut encoder, &mut replica, 3, true);
+        assert_eq!(replica.entity(entity).unwrap().component(1), Some(&data(1)));
+        assert!(replica.entity(short_lived).is_none());
+        assert!(encoder.encode(&server, 4, Some(7), BUDGET).is_none());
+    }
+
+    #[test]
+    fn owner_changes_its_entities() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+        let entity = server.spawn(3);
+        let other = server.spawn(3);
+
+        assert!(server.request_authority(7, entity, false));
+        assert!(!server.request_authority(8, entity, false));
+        deliver(&server, &mut encoder, &mut replica, 1, true);
+        assert_eq!(replica.entity(entity).unwrap().owner(), Some(7));
+
+        // only changes of owned entities are sent back
+        replica.set_component(entity, 1, data(5));
+        replica.set_component(other, 1, data(6));
+        let mut owned = ReplicationEncoder::new();
+        let payload = owned.encode_owned(&replica, 1, 7, BUDGET).unwrap();
+        let diff = ReplicationDecoder::new().decode(1, &payload).unwrap();
+        server.apply_owned(7, diff.unwrap());
+        assert_eq!(server.entity(entity).unwrap().component(1), Some(&data(5)));
+        assert!(server.entity(other).unwrap().component(1).is_none());
+        owned.ack(1);
+        assert!(owned.encode_owned(&replica, 2, 7, BUDGET).is_none());
+
+        // the owner does not get its own changes back
+        assert!(encoder.encode(&server, 2, Some(7), BUDGET).is_none());
+
+        server.release_all(7);
+        deliver(&server, &mut encoder, &mut replica, 3, true);
+        assert_eq!(replica.entity(entity).unwrap().owner(), None);
+    }
+
+    #[test]
+    fn new_owner_gets_changes_before_handover() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+        let entity = server.spawn(3);
+        server.set_component(entity, 1, data(1));
+        deliver(&server, &mut encoder, &mut replica, 1, true);
+
+        // the change is not acknowledged before authority is granted
+        server.set_component(entity, 1, data(2));
+        assert!(server.request_authority(7, entity, false));
+        deliver(&server, &mut encoder, &mut replica, 2, true);
+        assert_eq!(replica.entity(entity).unwrap().owner(), Some(7));
+        assert_eq!(replica.entity(entity).unwrap().component(1), Some(&data(2)));
+
+        // later changes are the owner's own
+        let changes = EntityChanges {
+            entity,
+            owner: None,
+            components: vec![(1, data(3))],
+        };
+        let diff = ReplicationDiff {
+            updates: vec![changes],
+            ..Default::default()
+        };
+        server.apply_owned(7, diff);
+        assert!(encoder.encode(&server, 3, Some(7), BUDGET).is_none());
+    }
+
+    #[test]
+    fn repeated_tick_acks_first_packet() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+        let mut decoder = ReplicationDecoder::new();
+        let entity = server.spawn(3);
+
+        // two packets of the same tick, only the first one is decoded
+        let first = encoder.encode(&server, 1, Some(7), BUDGET).unwrap();
+        assert!(server.request_authority(7, entity, false));
+        let second = encoder.encode(&server, 1, Some(7), BUDGET).unwrap();
+        for payload in [first, second].iter() {
+            if let Some(diff) = decoder.decode(1, payload).unwrap() {
+                replica.apply_remote(diff);
+            }
+        }
+        assert_eq!(replica.entity(entity).unwrap().owner(), None);
+
+        encoder.ack(1);
+        let payload = encoder.encode(&server, 2, Some(7), BUDGET).unwrap();
+        replica.apply_remote(decoder.decode(2, &payload).unwrap().unwrap());
+        assert_eq!(replica.entity(entity).unwrap().owner(), Some(7));
+    }
+
+    #[test]
+    fn splits_worlds_larger_than_a_packet() {
+        let mut server = ReplicatedWorld::new();
+        let mut replica = ReplicatedWorld::new();
+        let mut encoder = ReplicationEncoder::new();
+        for _ in 0..200 {
+            let entity = server.spawn(3);
+            server.set_component(entity, 1, Bytes::from(vec![1; 16]));
+        }
+
+        // the first packet is lost, the entities it carried are sent again
+        let lost = encoder.encode(&server, 1, Some(7), BUDGET).unwrap();
+        assert!(lost.len() <= BUDGET);
+        for tick in 2..10 {
+            if let Some(payload) = encoder.encode(&server, tick, Some(7), BUDGET) {
+                assert!(payload.len() <= BUDGET);
+                let diff = ReplicationDecoder::new().decode(tick, &payload).unwrap();
+                replica.apply_remote(diff.unwrap());
+                encoder.ack(tick);
+            }
+        }
+        assert_eq!(replica.len(), 200);
+        assert!(encoder.encode(&server, 10, Some(7), BUDGET).is_none());
+    }
+
+    #[test]
+    fn drops_stale_packets() {
+        let mut decoder = ReplicationDecoder::new();
+        let mut server = ReplicatedWorld::new();
+        server.spawn(1);
+        let payload = ReplicationEncoder::new().encode(&server, 2, None, BUDGET).unwrap();
+
+        assert!(decoder.decode(2, &payload).unwrap().is_some());
+        assert!(decoder.decode(1, &payload).unwrap().is_none());
+        assert!(decoder.decode(3, &payload.slice(..3)).is_err());
+    }
+}
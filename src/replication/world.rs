@@ -0,0 +1,295 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::vec::Drain;
+
+use bytes::Bytes;
+
+use crate::replication::codec::{EntityChanges, ReplicationDiff};
+
+/// Change of the replicated entities caused by a remote peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplicationEvent {
+    Spawned { entity: u32, type_id: u16 },
+    Updated { entity: u32, component: u16 },
+    Despawned { entity: u32 },
+    /// The owner of `entity` changed, `None` is the server.
+    AuthorityChanged { entity: u32, owner: Option<u64> },
+    /// The server refused to hand over authority over `entity`.
+    AuthorityDenied { entity: u32 },
+}
+
+#[derive(Clone, Debug)]
+struct Component {
+    data: Bytes,
+    /// World version of the last local change, 0 for remote changes.
+    version: u32,
+}
+
+/// A replicated entity with its serialized components.
+#[derive(Clone, Debug)]
+pub struct Entity {
+    type_id: u16,
+    owner: Option<u64>,
+    owner_version: u32,
+    components: BTreeMap<u16, Component>,
+}
+
+impl Entity {
+    fn new(type_id: u16, owner: Option<u64>) -> Self {
+        Entity {
+            type_id,
+            owner,
+            owner_version: 0,
+            components: BTreeMap::new(),
+        }
+    }
+
+    pub fn type_id(&self) -> u16 {
+        self.type_id
+    }
+
+    /// Returns the session id of the peer with authority over the entity, `None` for the
+    /// server.
+    pub fn owner(&self) -> Option<u64> {
+        self.owner
+    }
+
+    pub fn component(&self, component: u16) -> Option<&Bytes> {
+        self.components.get(&component).map(|c| &c.data)
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = (u16, &Bytes)> {
+        self.components.iter().map(|(id, c)| (*id, &c.data))
+    }
+
+    /// Returns the world version of the last local change of the entity.
+    pub(crate) fn version(&self) -> u32 {
+        self.components
+            .values()
+            .map(|c| c.version)
+            .fold(self.owner_version, u32::max)
+    }
+
+    pub(crate) fn owner_version(&self) -> u32 {
+        self.owner_version
+    }
+
+    /// Returns the components changed locally after version `after`, up to version `until`.
+    pub(crate) fn changed_between(
+        &self,
+        after: u32,
+        until: u32,
+    ) -> impl Iterator<Item = (u16, &Bytes)> {
+        self.components
+            .iter()
+            .filter(move |(_, c)| c.version > after && c.version <= until)
+            .map(|(id, c)| (*id, &c.data))
+    }
+}
+
+/// Set of replicated entities, the server's authoritative one or a peer's replica of it.
+///
+/// # Remarks
+/// - Local changes are versioned, the server sends each peer what changed since the version
+///   it acknowledged, until it is acknowledged. Packet loss delays changes but never loses
+///   them, intermediate values of a component may be skipped.
+/// - Changes applied from the network do not count as local changes, a peer only sends back
+///   what it changed on entities it owns.
+/// - Changes made by remote peers are reported as [ReplicationEvent]s.
+#[derive(Debug, Default)]
+pub struct ReplicatedWorld {
+    entities: BTreeMap<u32, Entity>,
+    next_entity: u32,
+    version: u32,
+    events: Vec<ReplicationEvent>,
+}
+
+impl ReplicatedWorld {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers a new server owned entity of `type_id` and returns its id.
+    pub fn spawn(&mut self, type_id: u16) -> u32 {
+        let entity = self.next_entity;
+        self.next_entity += 1;
+        let mut spawned = Entity::new(type_id, None);
+        spawned.owner_version = self.next_version();
+        self.entities.insert(entity, spawned);
+        entity
+    }
+
+    /// Removes `entity`, returns `false` when it does not exist.
+    pub fn despawn(&mut self, entity: u32) -> bool {
+        self.entities.remove(&entity).is_some()
+    }
+
+    /// Sets the serialized `component` of `entity`, returns `false` when it does not exist.
+    ///
+    /// Peers only send changes of entities they own to the server.
+    pub fn set_component(&mut self, entity: u32, component: u16, data: Bytes) -> bool {
+        let version = self.version + 1;
+        match self.entities.get_mut(&entity) {
+            Some(e) => {
+                e.components.insert(component, Component { data, version });
+                self.version = version;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hands authority over `entity` to the peer with the session id `owner`, `None` for the
+    /// server.
+    pub fn set_owner(&mut self, entity: u32, owner: Option<u64>) -> bool {
+        let version = self.version + 1;
+        match self.entities.get_mut(&entity) {
+            Some(e) => {
+                if e.owner != owner {
+                    e.owner = owner;
+                    e.owner_version = version;
+                    self.version = version;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn entity(&self, entity: u32) -> Option<&Entity> {
+        self.entities.get(&entity)
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = (u32, &Entity)> {
+        self.entities.iter().map(|(id, e)| (*id, e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Takes the changes made by remote peers since the last call.
+    pub fn drain_events(&mut self) -> Drain<'_, ReplicationEvent> {
+        self.events.drain(..)
+    }
+
+    /// Arbitrates a request of `peer` for authority over `entity`, or to give it back when
+    /// `release` is set.
+    ///
+    /// Authority is granted when the server or `peer` itself holds it and only its holder can
+    /// release it, returns whether `peer` owns the entity afterwards.
+    pub(crate) fn request_authority(&mut self, peer: u64, entity: u32, release: bool) -> bool {
+        let owner = match self.entities.get(&entity) {
+            Some(e) => e.owner,
+            None => return false,
+        };
+        match (owner, release) {
+            (Some(owner), _) if owner != peer => false,
+            (None, true) => false,
+            (Some(_), true) => {
+                self.set_owner(entity, None);
+                self.events.push(ReplicationEvent::AuthorityChanged { entity, owner: None });
+                false
+            }
+            (Some(_), false) => true,
+            (None, false) => {
+                self.set_owner(entity, Some(peer));
+                self.events.push(ReplicationEvent::AuthorityChanged {
+                    entity,
+                    owner: Some(peer),
+                });
+                true
+            }
+        }
+    }
+
+    /// Gives authority over all entities owned by `peer` back to the server.
+    pub(crate) fn release_all(&mut self, peer: u64) {
+        let owned = self
+            .entities
+            .iter()
+            .filter(|(_, e)| e.owner == Some(peer))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for entity in owned {
+            self.request_authority(peer, entity, true);
+        }
+    }
+
+    /// Applies the changes the server sent.
+    pub(crate) fn apply_remote(&mut self, diff: ReplicationDiff) {
+        for spawn in diff.spawns {
+            let entity = spawn.entity;
+            if let Entry::Vacant(vacant) = self.entities.entry(entity) {
+                vacant.insert(Entity::new(spawn.type_id, spawn.owner));
+                self.events.push(ReplicationEvent::Spawned {
+                    entity,
+                    type_id: spawn.type_id,
+                });
+            }
+            self.apply_changes(EntityChanges {
+                entity,
+                owner: Some(spawn.owner),
+                components: spawn.components,
+            });
+        }
+        for changes in diff.updates {
+            self.apply_changes(changes);
+        }
+        for entity in diff.despawns {
+            if self.entities.remove(&entity).is_some() {
+                self.events.push(ReplicationEvent::Despawned { entity });
+            }
+        }
+    }
+
+    /// Applies the changes `peer` sent, ignoring entities it does not own.
+    pub(crate) fn apply_owned(&mut self, peer: u64, diff: ReplicationDiff) {
+        for changes in diff.updates {
+            let entity = changes.entity;
+            if !matches!(self.entities.get(&entity), Some(e) if e.owner == Some(peer)) {
+                continue;
+            }
+            for (component, data) in changes.components {
+                self.set_component(entity, component, data);
+                self.events.push(ReplicationEvent::Updated { entity, component });
+            }
+        }
+    }
+
+    /// Records that the server refused authority over `entity`.
+    pub(crate) fn deny_authority(&mut self, entity: u32) {
+        self.events.push(ReplicationEvent::AuthorityDenied { entity });
+    }
+
+    fn apply_changes(&mut self, changes: EntityChanges) {
+        let entity = changes.entity;
+        let e = match self.entities.get_mut(&entity) {
+            Some(e) => e,
+            None => return,
+        };
+        if let Some(owner) = changes.owner {
+            if e.owner != owner {
+                e.owner = owner;
+                self.events
+                    .push(ReplicationEvent::AuthorityChanged { entity, owner });
+            }
+        }
+        for (component, data) in changes.components {
+            let unchanged = matches!(e.components.get(&component), Some(c) if c.data == data);
+            if !unchanged {
+                e.components.insert(component, Component { data, version: 0 });
+                self.events.push(ReplicationEvent::Updated { entity, component });
+            }
+        }
+    }
+
+    fn next_version(&mut self) -> u32 {
+        self.version += 1;
+        self.version
+    }
+}
@@ -0,0 +1,399 @@
+//! Rollback input synchronization for small matches.
+//!
+//! Every peer simulates all players each frame. Inputs of remote players which did not arrive
+//! yet are predicted by repeating their last known input, when the real input differs the
+//! session asks the app to roll back to that frame and simulate again. Peers broadcast their
+//! inputs every frame through the server, which relays them and reports how far each peer runs
+//! ahead of the others. Every payload acknowledges the frames confirmed from the other peers
+//! and repeats all local inputs one of them has not acknowledged yet, so no loss leaves a gap.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::Result;
+use crate::input::{check_input_size, decode_inputs, encode_inputs, Input};
+use crate::net::constants::{MAX_INPUT_PAYLOAD, MAX_INPUT_SIZE, ROLLBACK_HISTORY};
+
+/// Size of a peer id and frame acknowledging its inputs.
+const ACK_SIZE: usize = 12;
+/// Largest number of acknowledgements in a payload, they leave room for the largest input.
+const MAX_ACKS: usize = (MAX_INPUT_PAYLOAD - MAX_INPUT_SIZE - 8) / ACK_SIZE;
+
+/// The input of a remote peer for one frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameInput {
+    pub payload: Bytes,
+    /// `false` when the input is a prediction which may be corrected later.
+    pub confirmed: bool,
+}
+
+/// Inputs received from and predicted for a single remote peer.
+#[derive(Debug, Default)]
+struct PeerInputs {
+    confirmed: BTreeMap<u32, Bytes>,
+    predicted: BTreeMap<u32, Bytes>,
+    /// Last frame up to which all inputs were received.
+    contiguous: Option<u32>,
+}
+
+impl PeerInputs {
+    /// Stores a received input, returns the frame to roll back to when it contradicts a
+    /// prediction.
+    fn receive(&mut self, input: Input) -> Option<u32> {
+        if matches!(self.contiguous, Some(c) if input.tick <= c)
+            || self.confirmed.contains_key(&input.tick)
+        {
+            return None;
+        }
+        let mispredicted = match self.predicted.remove(&input.tick) {
+            Some(prediction) => prediction != input.payload,
+            None => false,
+        };
+        self.confirmed.insert(input.tick, input.payload);
+
+        let mut next = self.contiguous.map_or(input.tick, |c| c + 1);
+        while self.confirmed.contains_key(&next) {
+            self.contiguous = Some(next);
+            next += 1;
+        }
+
+        if mispredicted {
+            Some(input.tick)
+        } else {
+            None
+        }
+    }
+
+    fn input(&mut self, frame: u32) -> FrameInput {
+        if let Some(payload) = self.confirmed.get(&frame) {
+            return FrameInput {
+                payload: payload.clone(),
+                confirmed: true,
+            };
+        }
+        let payload = self
+            .confirmed
+            .range(..frame)
+            .next_back()
+            .map(|(_, payload)| payload.clone())
+            .unwrap_or_default();
+        self.predicted.insert(frame, payload.clone());
+        FrameInput {
+            payload,
+            confirmed: false,
+        }
+    }
+
+    /// Forgets inputs of frames before `oldest`, keeping the last one for predictions.
+    fn forget(&mut self, oldest: u32) {
+        let keep_from = self
+            .confirmed
+            .range(..oldest)
+            .next_back()
+            .map_or(oldest, |(frame, _)| *frame);
+        self.confirmed = self.confirmed.split_off(&keep_from);
+        self.predicted = self.predicted.split_off(&oldest);
+    }
+}
+
+/// Input state of one peer of a rollback match.
+///
+/// # Remarks
+/// - Call [input](#method.input) for every remote peer each simulated frame, it returns the
+///   confirmed input or records a prediction.
+/// - After receiving, [take_rollback](#method.take_rollback) returns the oldest frame whose
+///   prediction turned out wrong, the app restores its state before that frame and simulates
+///   up to the current frame again.
+#[derive(Debug)]
+pub struct RollbackSession {
+    id: u64,
+    local: VecDeque<Input>,
+    /// Last local frame each remote peer confirmed.
+    acked: HashMap<u64, u32>,
+    peers: HashMap<u64, PeerInputs>,
+    rollback: Option<u32>,
+    advantage: i32,
+}
+
+impl RollbackSession {
+    /// Creates the session of the peer whose inputs are relayed tagged with `id`.
+    pub fn new(id: u64) -> Self {
+        RollbackSession {
+            id,
+            local: VecDeque::new(),
+            acked: HashMap::new(),
+            peers: HashMap::new(),
+            rollback: None,
+            advantage: 0,
+        }
+    }
+
+    /// Adds the local input of `frame`, frames have to be added in order.
+    ///
+    /// Fails with `PayloadTooLarge` for inputs larger than
+    /// [MAX_INPUT_SIZE](../net/constants/constant.MAX_INPUT_SIZE.html).
+    pub fn add_local_input(&mut self, frame: u32, payload: Bytes) -> Result<()> {
+        check_input_size(&payload)?;
+        if matches!(self.local.back(), Some(last) if last.tick >= frame) {
+            return Ok(());
+        }
+        if self.local.len() == ROLLBACK_HISTORY as usize {
+            self.local.pop_front();
+        }
+        self.local.push_back(Input {
+            tick: frame,
+            payload,
+        });
+
+        let oldest = frame.saturating_sub(ROLLBACK_HISTORY);
+        for peer in self.peers.values_mut() {
+            peer.forget(oldest);
+        }
+        Ok(())
+    }
+
+    /// Returns the last local frame.
+    pub fn frame(&self) -> Option<u32> {
+        self.local.back().map(|input| input.tick)
+    }
+
+    /// Creates the payload of a `FrameInput` packet, `None` before the first local input.
+    ///
+    /// The payload is written as `[u8 count]([u64 peer][u32 frame])*` acknowledging the
+    /// confirmed frames of the remote peers, followed by the most recent local inputs not
+    /// acknowledged by all of them that fit into the payload.
+    pub fn local_payload(&self) -> Option<Bytes> {
+        if self.local.is_empty() {
+            return None;
+        }
+        let mut payload = BytesMut::new();
+        let acks = self
+            .peers
+            .iter()
+            .filter_map(|(peer, inputs)| inputs.contiguous.map(|frame| (*peer, frame)))
+            .take(MAX_ACKS)
+            .collect::<Vec<_>>();
+        payload.put_u8(acks.len() as u8);
+        for (peer, frame) in acks {
+            payload.put_u64(peer);
+            payload.put_u32(frame);
+        }
+
+        let acked = self
+            .peers
+            .keys()
+            .map(|peer| self.acked.get(peer).copied())
+            .min()
+            .flatten();
+        let unacked = self
+            .local
+            .iter()
+            .filter(move |input| !matches!(acked, Some(acked) if input.tick <= acked));
+        let budget = MAX_INPUT_PAYLOAD - payload.len();
+        payload.extend_from_slice(&encode_inputs(unacked, budget));
+        Some(payload.freeze())
+    }
+
+    /// Adds the inputs of a `FrameInput` packet relayed from `peer`, they share the memory of
+    /// `payload`.
+    pub fn receive(&mut self, peer: u64, payload: &Bytes) -> Result<()> {
+        let mut rdr = Cursor::new(&payload[..]);
+        for _ in 0..rdr.read_u8()? {
+            let acked_peer = rdr.read_u64::<BigEndian>()?;
+            let frame = rdr.read_u32::<BigEndian>()?;
+            if acked_peer == self.id {
+                let acked = self.acked.entry(peer).or_insert(frame);
+                *acked = frame.max(*acked);
+            }
+        }
+
+        let inputs = self.peers.entry(peer).or_default();
+        let payload = payload.slice(rdr.position() as usize..);
+        for input in decode_inputs(&payload)? {
+            if let Some(frame) = inputs.receive(input) {
+                self.rollback = Some(self.rollback.map_or(frame, |r| r.min(frame)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the input of `peer` for `frame`, predicted when it did not arrive yet.
+    pub fn input(&mut self, peer: u64, frame: u32) -> FrameInput {
+        self.peers.entry(peer).or_default().input(frame)
+    }
+
+    /// Returns the remote peers inputs were received from.
+    pub fn peers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.peers.keys().copied()
+    }
+
+    /// Returns the last frame up to which all inputs of `peer` are confirmed.
+    pub fn confirmed_frame(&self, peer: u64) -> Option<u32> {
+        self.peers.get(&peer).and_then(|inputs| inputs.contiguous)
+    }
+
+    /// Returns the last frame up to which the inputs of all remote peers are confirmed, the
+    /// app never has to roll back before it.
+    pub fn confirmed(&self) -> Option<u32> {
+        self.peers
+            .values()
+            .map(|inputs| inputs.contiguous)
+            .min()
+            .flatten()
+    }
+
+    /// Returns the oldest frame whose predicted inputs were wrong since the last call.
+    pub fn take_rollback(&mut self) -> Option<u32> {
+        self.rollback.take()
+    }
+
+    /// Sets the frame advantage reported by the server.
+    pub fn set_frame_advantage(&mut self, advantage: i32) {
+        self.advantage = advantage;
+    }
+
+    /// Returns how many frames this peer runs ahead of the slowest other peer.
+    ///
+    /// A peer far ahead predicts more and rolls back further, it should skip about half of
+    /// its advantage in frames to let the others catch up.
+    pub fn frame_advantage(&self) -> i32 {
+        self.advantage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::net::constants::{MAX_INPUT_PAYLOAD, MAX_INPUT_SIZE, ROLLBACK_HISTORY};
+
+    use super::RollbackSession;
+
+    fn input(value: u8) -> Bytes {
+        Bytes::from(vec![value])
+    }
+
+    /// Payload of a remote session which added `inputs` for frames starting at `first`.
+    fn remote(first: u32, inputs: &[u8]) -> Bytes {
+        let mut session = RollbackSession::new(7);
+        for (i, value) in inputs.iter().enumerate() {
+            session.add_local_input(first + i as u32, input(*value)).unwrap();
+        }
+        session.local_payload().unwrap()
+    }
+
+    #[test]
+    fn predicts_last_input() {
+        let mut session = RollbackSession::new(1);
+        let payload = remote(0, &[1, 2]);
+        session.receive(7, &payload).unwrap();
+
+        let confirmed = session.input(7, 1);
+        assert!(confirmed.confirmed);
+        assert_eq!(confirmed.payload, input(2));
+
+        let predicted = session.input(7, 3);
+        assert!(!predicted.confirmed);
+        assert_eq!(predicted.payload, input(2));
+        assert_eq!(session.input(8, 0).payload, Bytes::new());
+    }
+
+    #[test]
+    fn signals_rollback_on_misprediction() {
+        let mut session = RollbackSession::new(1);
+        let payload = remote(0, &[1]);
+        session.receive(7, &payload).unwrap();
+        for frame in 1..=4 {
+            session.input(7, frame);
+        }
+
+        // frame 1 and 2 were predicted right, frame 3 not
+        let payload = remote(0, &[1, 1, 1, 5, 5]);
+        session.receive(7, &payload).unwrap();
+        assert_eq!(session.take_rollback(), Some(3));
+        assert_eq!(session.take_rollback(), None);
+        assert_eq!(session.confirmed_frame(7), Some(4));
+        assert_eq!(session.input(7, 4).payload, input(5));
+    }
+
+    #[test]
+    fn redundancy_fills_gaps() {
+        let mut session = RollbackSession::new(1);
+        let first = remote(0, &[1]);
+        let later = remote(0, &[1, 2, 3, 4]);
+        session.receive(7, &later).unwrap();
+        session.receive(7, &first).unwrap();
+        assert_eq!(session.confirmed_frame(7), Some(3));
+
+        let other = remote(0, &[9]);
+        session.receive(8, &other).unwrap();
+        assert_eq!(session.confirmed(), Some(0));
+        assert_eq!(session.take_rollback(), None);
+    }
+
+    #[test]
+    fn local_payload_is_redundant() {
+        let mut session = RollbackSession::new(1);
+        assert!(session.local_payload().is_none());
+        for frame in 0..20 {
+            session.add_local_input(frame, input(frame as u8)).unwrap();
+        }
+        assert_eq!(session.frame(), Some(19));
+
+        let mut receiver = RollbackSession::new(2);
+        let payload = session.local_payload().unwrap();
+        receiver.receive(1, &payload).unwrap();
+        assert_eq!(receiver.confirmed_frame(1), Some(19));
+        assert!(receiver.input(1, 0).confirmed);
+    }
+
+    #[test]
+    fn repeats_inputs_until_acknowledged() {
+        let mut sender = RollbackSession::new(1);
+        let mut receiver = RollbackSession::new(2);
+        sender.add_local_input(0, input(0)).unwrap();
+        let payload = sender.local_payload().unwrap();
+        receiver.receive(1, &payload).unwrap();
+
+        // a burst longer than any fixed redundancy is lost
+        for frame in 1..40 {
+            sender.add_local_input(frame, input(frame as u8)).unwrap();
+        }
+        sender.add_local_input(40, input(40)).unwrap();
+        let payload = sender.local_payload().unwrap();
+        receiver.receive(1, &payload).unwrap();
+        assert_eq!(receiver.confirmed_frame(1), Some(40));
+        assert_eq!(receiver.input(1, 20).payload, input(20));
+        assert_eq!(receiver.take_rollback(), None);
+
+        // once acknowledged the inputs are not repeated anymore
+        receiver.add_local_input(0, input(9)).unwrap();
+        let ack = receiver.local_payload().unwrap();
+        sender.receive(2, &ack).unwrap();
+        sender.add_local_input(41, input(41)).unwrap();
+        let payload = sender.local_payload().unwrap();
+        receiver.receive(1, &payload).unwrap();
+        assert_eq!(receiver.confirmed_frame(1), Some(41));
+        assert!(payload.len() < 32, "{:?}", payload);
+    }
+
+    #[test]
+    fn payload_fits_into_a_packet() {
+        let mut session = RollbackSession::new(1);
+        let oversized = Bytes::from(vec![0; MAX_INPUT_SIZE + 1]);
+        assert!(session.add_local_input(0, oversized).is_err());
+        assert!(session.local_payload().is_none());
+
+        // without acknowledgements only the most recent inputs fit
+        for frame in 0..ROLLBACK_HISTORY {
+            session.add_local_input(frame, Bytes::from(vec![0; MAX_INPUT_SIZE])).unwrap();
+        }
+        let payload = session.local_payload().unwrap();
+        assert!(payload.len() <= MAX_INPUT_PAYLOAD);
+        let mut receiver = RollbackSession::new(2);
+        receiver.receive(1, &payload).unwrap();
+        assert!(receiver.input(1, ROLLBACK_HISTORY - 1).confirmed);
+    }
+}
@@ -0,0 +1,240 @@
+//! Bit packed serialization of app messages.
+//!
+//! Values are written with as many bits as they need, integers within a known range and
+//! quantized floats take a fraction of their in-memory size. Reading checks every access
+//! against the end of the payload, a truncated or forged datagram fails to decode instead of
+//! panicking.
+//!
+//! `#[derive(NetSerialize)]` implements [NetSerialize] for structs and enums, attributes on the
+//! fields select the bits of ranged integers and quantized floats.
+pub use self::bits::{range_bits, BitReader, BitWriter};
+pub use bytes::BufMut;
+pub use physync_derive::NetSerialize;
+
+use std::convert::TryInto;
+
+use bytes::Bytes;
+
+use crate::errors::Result;
+
+mod bits;
+
+/// A value which can be written into and read from a bit packed payload.
+pub trait NetSerialize: Sized {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>);
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self>;
+
+    /// Returns the value serialized into a payload of whole bytes.
+    fn to_payload(&self) -> Bytes {
+        let mut writer = BitWriter::new();
+        self.serialize(&mut writer);
+        writer.finish()
+    }
+
+    /// Reads a value from a payload created by [to_payload](#method.to_payload).
+    fn from_payload(payload: &[u8]) -> Result<Self> {
+        Self::deserialize(&mut BitReader::new(payload))
+    }
+}
+
+impl NetSerialize for bool {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_bool(*self);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        reader.read_bool()
+    }
+}
+
+macro_rules! impl_integer {
+    ($($int:ty),*) => {$(
+        impl NetSerialize for $int {
+            fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+                writer.write_bits(*self as u64, <$int>::BITS);
+            }
+
+            fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+                Ok(reader.read_bits(<$int>::BITS)? as $int)
+            }
+        }
+    )*};
+}
+
+impl_integer!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl NetSerialize for f32 {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_f32(*self);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        reader.read_f32()
+    }
+}
+
+impl NetSerialize for f64 {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_f64(*self);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        reader.read_f64()
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Option<T> {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        writer.write_bool(self.is_some());
+        if let Some(value) = self {
+            value.serialize(writer);
+        }
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        if reader.read_bool()? {
+            Ok(Some(T::deserialize(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: NetSerialize, const N: usize> NetSerialize for [T; N] {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        for value in self {
+            value.serialize(writer);
+        }
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        let values = (0..N)
+            .map(|_| T::deserialize(reader))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(values
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("read exactly {} values", N)))
+    }
+}
+
+/// Written with a `u16` length, longer vectors are truncated.
+impl<T: NetSerialize> NetSerialize for Vec<T> {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        let len = self.len().min(u16::MAX as usize);
+        writer.write_bits(len as u64, 16);
+        for value in &self[..len] {
+            value.serialize(writer);
+        }
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        let len = reader.read_bits(16)? as usize;
+        // no element takes less than a bit, a forged length cannot allocate more than that
+        let mut values = Vec::with_capacity(len.min(reader.remaining_bits()));
+        for _ in 0..len {
+            values.push(T::deserialize(reader)?);
+        }
+        Ok(values)
+    }
+}
+
+/// Written with a `u16` length, starting at a byte boundary.
+impl NetSerialize for Bytes {
+    fn serialize<B: BufMut>(&self, writer: &mut BitWriter<B>) {
+        let len = self.len().min(u16::MAX as usize);
+        writer.write_bits(len as u64, 16);
+        writer.write_bytes(&self[..len]);
+    }
+
+    fn deserialize(reader: &mut BitReader<'_>) -> Result<Self> {
+        let len = reader.read_bits(16)? as usize;
+        Ok(Bytes::copy_from_slice(reader.read_bytes(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::ErrorKind;
+
+    use crate::serialize::{BitReader, BitWriter, NetSerialize};
+
+    #[test]
+    fn round_trips() {
+        let value = (
+            Some(-5_i16),
+            [1.5_f32, -2.0, 0.25],
+            vec![true, false, true],
+            Bytes::from_static(b"payload"),
+        );
+        let payload = {
+            let mut writer = BitWriter::new();
+            value.0.serialize(&mut writer);
+            value.1.serialize(&mut writer);
+            value.2.serialize(&mut writer);
+            value.3.serialize(&mut writer);
+            writer.finish()
+        };
+
+        let mut reader = BitReader::new(&payload);
+        assert_eq!(Option::<i16>::deserialize(&mut reader).unwrap(), value.0);
+        assert_eq!(<[f32; 3]>::deserialize(&mut reader).unwrap(), value.1);
+        assert_eq!(Vec::<bool>::deserialize(&mut reader).unwrap(), value.2);
+        assert_eq!(Bytes::deserialize(&mut reader).unwrap(), value.3);
+        assert_eq!(reader.remaining_bits(), 0);
+    }
+
+    #[derive(Debug, PartialEq, NetSerialize)]
+    struct Move {
+        #[net(range(-8, 7))]
+        turn: i8,
+        #[net(bits = 3)]
+        weapon: u8,
+        #[net(quantize(0.0, 1.0, 8))]
+        throttle: f32,
+        #[net(skip)]
+        local: u32,
+        action: Action,
+    }
+
+    #[derive(Debug, PartialEq, NetSerialize)]
+    enum Action {
+        Idle,
+        Jump(bool),
+        Aim { target: Option<u16> },
+    }
+
+    #[test]
+    fn derives_compact_encoding() {
+        let value = Move {
+            turn: -3,
+            weapon: 5,
+            throttle: 0.5,
+            local: 42,
+            action: Action::Aim { target: Some(7) },
+        };
+        let payload = value.to_payload();
+        // 4 + 3 + 8 bits, 2 for the variant, 1 + 16 for the target
+        assert_eq!(payload.len(), 5);
+
+        let read = Move::from_payload(&payload).unwrap();
+        assert_eq!(read.action, value.action);
+        assert_eq!((read.turn, read.weapon, read.local), (-3, 5, 0));
+        assert!((read.throttle - 0.5).abs() < 0.01);
+
+        // the variant index is range checked
+        assert!(Action::from_payload(&[0xc0]).is_err());
+        assert_eq!(Action::from_payload(&[0x60]).unwrap(), Action::Jump(true));
+    }
+
+    #[test]
+    fn forged_length_fails() {
+        // claims 1000 values but holds 8 bits
+        let payload = [0x03, 0xe8, 0xff];
+        assert!(matches!(Vec::<u16>::from_payload(&payload), Err(ErrorKind::BufferUnderflow)));
+        assert!(matches!(Bytes::from_payload(&payload), Err(ErrorKind::BufferUnderflow)));
+        assert_eq!(u64::from_payload(&42_u64.to_payload()).unwrap(), 42);
+    }
+}
@@ -0,0 +1,298 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::snapshot::QuantizedRange;
+
+/// Writes values at bit granularity, most significant bit first.
+///
+/// Bytes are appended to `B` as soon as they are complete, wrapping the buffer of an outgoing
+/// packet writes into it without an intermediate copy.
+#[derive(Debug, Default)]
+pub struct BitWriter<B = BytesMut> {
+    buffer: B,
+    /// Bytes appended to `buffer`.
+    written: usize,
+    /// Bits not yet written to `buffer`, right aligned.
+    scratch: u64,
+    pending: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_capacity(bytes: usize) -> Self {
+        BitWriter::wrap(BytesMut::with_capacity(bytes))
+    }
+
+    /// Returns the written bits padded to whole bytes.
+    pub fn finish(self) -> Bytes {
+        self.into_inner().freeze()
+    }
+}
+
+impl<B: BufMut> BitWriter<B> {
+    /// Creates a writer appending to the end of `buffer`.
+    pub fn wrap(buffer: B) -> Self {
+        BitWriter {
+            buffer,
+            written: 0,
+            scratch: 0,
+            pending: 0,
+        }
+    }
+
+    /// Writes the lowest `bits` bits of `value`, `bits` is limited to 64.
+    pub fn write_bits(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 64, "cannot write {} bits at once", bits);
+        if bits > 32 {
+            self.write_bits(value >> 32, bits - 32);
+            self.write_bits(value & u64::from(u32::MAX), 32);
+            return;
+        }
+        self.scratch = (self.scratch << bits) | (value & mask(bits));
+        self.pending += bits;
+        while self.pending >= 8 {
+            self.pending -= 8;
+            self.buffer.put_u8((self.scratch >> self.pending) as u8);
+            self.written += 1;
+        }
+        self.scratch &= mask(self.pending);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    /// Writes `value` in the bits needed for `min..=max`, values outside are clamped.
+    ///
+    /// Panics if `min` is greater than `max`.
+    pub fn write_ranged(&mut self, value: i64, min: i64, max: i64) {
+        let offset = value.clamp(min, max).wrapping_sub(min) as u64;
+        self.write_bits(offset, range_bits(min, max));
+    }
+
+    /// Writes `value` quantized to the bits of `range`.
+    pub fn write_quantized(&mut self, value: f32, range: &QuantizedRange) {
+        self.write_bits(u64::from(range.quantize(value)), u32::from(range.bits()));
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(u64::from(value.to_bits()), 32);
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_bits(value.to_bits(), 64);
+    }
+
+    /// Writes `bytes` starting at the next byte boundary.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.align();
+        self.buffer.put_slice(bytes);
+        self.written += bytes.len();
+    }
+
+    /// Pads the written bits with zeros up to the next byte boundary.
+    pub fn align(&mut self) {
+        if self.pending > 0 {
+            self.write_bits(0, 8 - self.pending);
+        }
+    }
+
+    /// Returns the number of bits written.
+    pub fn bit_len(&self) -> usize {
+        self.written * 8 + self.pending as usize
+    }
+
+    /// Returns the buffer with the written bits padded to whole bytes.
+    pub fn into_inner(mut self) -> B {
+        self.align();
+        self.buffer
+    }
+}
+
+/// Reads values written by a [BitWriter].
+///
+/// Every read fails with `BufferUnderflow` instead of reading past the end of the buffer.
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+    buffer: &'a [u8],
+    /// Position in bits.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        BitReader { buffer, position: 0 }
+    }
+
+    /// Reads `bits` bits, limited to 64.
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64> {
+        debug_assert!(bits <= 64, "cannot read {} bits at once", bits);
+        if bits as usize > self.remaining_bits() {
+            return Err(ErrorKind::BufferUnderflow);
+        }
+        let mut value = 0_u64;
+        let mut left = bits;
+        while left > 0 {
+            let byte = self.buffer[self.position / 8];
+            let available = 8 - (self.position % 8) as u32;
+            let taken = available.min(left);
+            let chunk = (u64::from(byte) >> (available - taken)) & mask(taken);
+            value = (value << taken) | chunk;
+            left -= taken;
+            self.position += taken as usize;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Reads a value written with [write_ranged](BitWriter::write_ranged), fails with
+    /// `DecodingError(Payload)` when it is not within `min..=max`.
+    pub fn read_ranged(&mut self, min: i64, max: i64) -> Result<i64> {
+        let offset = self.read_bits(range_bits(min, max))?;
+        let value = min.wrapping_add(offset as i64);
+        if value < min || value > max {
+            return Err(ErrorKind::DecodingError(DecodingErrorKind::Payload));
+        }
+        Ok(value)
+    }
+
+    pub fn read_quantized(&mut self, range: &QuantizedRange) -> Result<f32> {
+        let value = self.read_bits(u32::from(range.bits()))?;
+        Ok(range.dequantize(value as u32))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_bits(self.read_bits(32)? as u32))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64> {
+        Ok(f64::from_bits(self.read_bits(64)?))
+    }
+
+    /// Reads `len` bytes starting at the next byte boundary, without copying.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        self.align();
+        let start = self.position / 8;
+        let bytes = self
+            .buffer
+            .get(start..start + len)
+            .ok_or(ErrorKind::BufferUnderflow)?;
+        self.position += len * 8;
+        Ok(bytes)
+    }
+
+    /// Skips the padding up to the next byte boundary.
+    pub fn align(&mut self) {
+        self.position = (self.position.div_ceil(8) * 8).min(self.buffer.len() * 8);
+    }
+
+    /// Returns the number of bits left to read.
+    pub fn remaining_bits(&self) -> usize {
+        self.buffer.len() * 8 - self.position
+    }
+}
+
+/// Returns the number of bits needed for any value of `min..=max`.
+pub fn range_bits(min: i64, max: i64) -> u32 {
+    let span = max.wrapping_sub(min) as u64;
+    64 - span.leading_zeros()
+}
+
+fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::ErrorKind;
+    use crate::snapshot::QuantizedRange;
+
+    use super::{range_bits, BitReader, BitWriter};
+
+    #[test]
+    fn packs_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        writer.write_bool(true);
+        writer.write_ranged(-3, -4, 3);
+        writer.write_bits(u64::MAX - 1, 64);
+        assert_eq!(writer.bit_len(), 3 + 1 + 3 + 64);
+
+        let payload = writer.finish();
+        assert_eq!(payload.len(), 9);
+        assert_eq!(payload[0] >> 1, 0b101_1001);
+
+        let mut reader = BitReader::new(&payload);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_ranged(-4, 3).unwrap(), -3);
+        assert_eq!(reader.read_bits(64).unwrap(), u64::MAX - 1);
+        assert_eq!(reader.remaining_bits(), 1);
+    }
+
+    #[test]
+    fn clamps_ranged() {
+        let mut writer = BitWriter::new();
+        writer.write_ranged(9, -4, 3);
+        writer.write_ranged(-9, -4, 3);
+
+        let payload = writer.finish();
+        let mut reader = BitReader::new(&payload);
+        assert_eq!(reader.read_ranged(-4, 3).unwrap(), 3);
+        assert_eq!(reader.read_ranged(-4, 3).unwrap(), -4);
+    }
+
+    #[test]
+    fn floats_and_bytes() {
+        let range = QuantizedRange::new(-1.0, 1.0, 10);
+        let mut writer = BitWriter::with_capacity(16);
+        writer.write_quantized(0.3, &range);
+        writer.write_f32(-2.5);
+        writer.write_bytes(b"abc");
+        writer.write_f64(1e100);
+
+        let payload = writer.finish();
+        let mut reader = BitReader::new(&payload);
+        assert!((reader.read_quantized(&range).unwrap() - 0.3).abs() <= range.precision());
+        assert_eq!(reader.read_f32().unwrap(), -2.5);
+        assert_eq!(reader.read_bytes(3).unwrap(), b"abc");
+        assert_eq!(reader.read_f64().unwrap(), 1e100);
+    }
+
+    #[test]
+    fn appends_to_buffer() {
+        let mut buffer = BytesMut::from(&b"ab"[..]);
+        let mut writer = BitWriter::wrap(&mut buffer);
+        writer.write_bits(0b1, 1);
+        assert_eq!(writer.bit_len(), 1);
+        writer.into_inner();
+        assert_eq!(&buffer[..], &[b'a', b'b', 0x80]);
+    }
+
+    #[test]
+    fn never_overruns() {
+        let mut reader = BitReader::new(&[0xff, 0x01]);
+        assert_eq!(reader.read_ranged(0, 255).unwrap(), 0xff);
+        assert!(matches!(reader.read_bits(9), Err(ErrorKind::BufferUnderflow)));
+        assert!(matches!(reader.read_bytes(2), Err(ErrorKind::BufferUnderflow)));
+        assert_eq!(reader.read_bits(8).unwrap(), 1);
+
+        // a value of the bits needed for 0..=5 may still be out of range
+        let mut reader = BitReader::new(&[0xe0]);
+        assert!(reader.read_ranged(0, 5).is_err());
+        assert_eq!(range_bits(0, 0), 0);
+        assert_eq!(range_bits(i64::MIN, i64::MAX), 64);
+    }
+}
@@ -1,21 +1,53 @@
 use crate::errors::Result;
-use crate::Peer;
+use crate::net::constants::DEFAULT_TICK_RATE;
+use crate::net::resolve;
+use crate::ShardedPeer;
+
+/// Settings of a [Server].
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Number of tasks the connections are spread over.
+    pub shards: usize,
+    /// Gives every shard its own `SO_REUSEPORT` socket instead of dispatching from a single one.
+    pub reuse_port: bool,
+    /// Simulation ticks per second, connections are updated and stamped at this rate.
+    pub tick_rate: u32,
+    /// Introduces clients registering the same key to each other for hole punching, only
+    /// supported with a single shard.
+    pub introducer: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            shards: 1,
+            reuse_port: false,
+            tick_rate: DEFAULT_TICK_RATE,
+            introducer: false,
+        }
+    }
+}
 
 pub struct Server {
-    peer: Peer,
+    peer: ShardedPeer,
 }
 
 impl Server {
     pub async fn new(addr: &str) -> Result<Self> {
-        let peer = Peer::bind(addr).await?;
+        Self::with_config(addr, ServerConfig::default()).await
+    }
+
+    pub async fn with_config(addr: &str, config: ServerConfig) -> Result<Self> {
+        let addr = resolve(addr).await?[0];
+        let mut peer = ShardedPeer::bind(addr, config.shards, config.reuse_port)?;
+        peer.set_tick_rate(config.tick_rate);
+        peer.set_introducer(config.introducer)?;
         println!("Listening on: {}", peer.local_addr()?);
 
         Ok(Server { peer })
     }
 
     pub async fn run(self) -> Result<()> {
-        let mut peer = self.peer;
-        peer.in_loop().await;
-        Ok(())
+        self.peer.run().await
     }
 }
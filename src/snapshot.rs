@@ -0,0 +1,17 @@
+//! Built-in encoding of rigid body states for `Data` payloads.
+//!
+//! A snapshot is the state of a set of rigid bodies at one simulation tick, the tick itself
+//! travels in the packet header. Both ends have to use the same [SnapshotConfig].
+pub use self::quantize::QuantizedRange;
+pub use self::rigid_body::{QuantizedBody, RigidBodyState, SnapshotConfig};
+pub use self::codec::{SnapshotDecoder, SnapshotEncoder};
+pub use self::delta::{DeltaDecoder, DeltaEncoder};
+pub use self::interpolation::InterpolationBuffer;
+pub use self::priority::{PriorityAccumulator, PriorityConfig};
+
+mod quantize;
+mod rigid_body;
+mod codec;
+mod delta;
+mod interpolation;
+mod priority;
@@ -0,0 +1,177 @@
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::{MAX_HEADER_SIZE, MIN_MTU};
+use crate::snapshot::{RigidBodyState, SnapshotConfig};
+
+/// Size of the body count preceding the bodies.
+const COUNT_SIZE: usize = 2;
+
+/// Collects rigid body states into a snapshot payload.
+///
+/// The payload is `[u16 count][body]*`, ready to be passed to
+/// [OutgoingPacketBuilder](../struct.OutgoingPacketBuilder.html).
+#[derive(Debug)]
+pub struct SnapshotEncoder {
+    config: SnapshotConfig,
+    buffer: BytesMut,
+    count: u16,
+    budget: usize,
+}
+
+impl SnapshotEncoder {
+    /// Constructs an encoder whose payloads fit into a single packet on every path.
+    pub fn new(config: SnapshotConfig) -> Self {
+        Self::with_budget(config, MIN_MTU as usize - MAX_HEADER_SIZE)
+    }
+
+    /// Constructs an encoder whose payloads do not exceed `budget` bytes.
+    pub fn with_budget(config: SnapshotConfig, budget: usize) -> Self {
+        let mut buffer = BytesMut::with_capacity(budget);
+        buffer.put_u16(0);
+        SnapshotEncoder {
+            config,
+            buffer,
+            count: 0,
+            budget,
+        }
+    }
+
+    /// Appends `state` to the snapshot.
+    ///
+    /// Fails with `PayloadTooLarge` and leaves the snapshot as is when `state` does not fit
+    /// into the budget.
+    pub fn push(&mut self, state: &RigidBodyState) -> Result<()> {
+        let size = self.buffer.len() + self.config.encoded_size(state);
+        if size > self.budget || self.count == u16::MAX {
+            return Err(ErrorKind::PayloadTooLarge(size));
+        }
+        self.config.write(state, &mut self.buffer);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the number of bodies in the snapshot.
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the number of bytes left in the budget.
+    pub fn remaining(&self) -> usize {
+        self.budget.saturating_sub(self.buffer.len())
+    }
+
+    /// Returns the payload of the snapshot and starts a new, empty one.
+    pub fn finish(&mut self) -> Bytes {
+        self.buffer[..COUNT_SIZE].copy_from_slice(&self.count.to_be_bytes());
+        let payload = self.buffer.split().freeze();
+        self.buffer.reserve(self.budget);
+        self.buffer.put_u16(0);
+        self.count = 0;
+        payload
+    }
+}
+
+/// Reads snapshot payloads created by [SnapshotEncoder].
+#[derive(Copy, Clone, Debug)]
+pub struct SnapshotDecoder {
+    config: SnapshotConfig,
+}
+
+impl SnapshotDecoder {
+    pub fn new(config: SnapshotConfig) -> Self {
+        SnapshotDecoder { config }
+    }
+
+    /// Reads all bodies of the snapshot `payload`.
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<RigidBodyState>> {
+        let mut rdr = Cursor::new(payload);
+        let bodies = (|| {
+            let count = rdr.read_u16::<BigEndian>()?;
+            (0..count)
+                .map(|_| self.config.read(&mut rdr))
+                .collect::<Result<Vec<_>>>()
+        })();
+        bodies.map_err(|_| ErrorKind::DecodingError(DecodingErrorKind::Snapshot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorKind, OutgoingPacketBuilder};
+    use crate::errors::DecodingErrorKind;
+    use crate::packet::PacketType;
+    use crate::snapshot::{RigidBodyState, SnapshotConfig, SnapshotDecoder, SnapshotEncoder};
+
+    fn body(entity: u32, sleeping: bool) -> RigidBodyState {
+        RigidBodyState {
+            entity,
+            position: [entity as f32, 1.0, -2.0],
+            sleeping,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::new(config);
+        for entity in 0..10 {
+            encoder.push(&body(entity, entity % 2 == 0)).unwrap();
+        }
+        assert_eq!(encoder.len(), 10);
+
+        let payload = encoder.finish();
+        assert!(encoder.is_empty());
+        let out = OutgoingPacketBuilder::new(&payload)
+            .with_default_header(PacketType::Data)
+            .with_session_header(1)
+            .with_tick_header(1)
+            .build();
+        assert!(out.is_ok());
+
+        let bodies = SnapshotDecoder::new(config).decode(&payload).unwrap();
+        assert_eq!(bodies.len(), 10);
+        for (entity, state) in bodies.iter().enumerate() {
+            assert_eq!(state.entity, entity as u32);
+            assert_eq!(state.sleeping, entity % 2 == 0);
+            assert!((state.position[0] - entity as f32).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn respects_budget() {
+        let config = SnapshotConfig::default();
+        let size = config.encoded_size(&body(0, false));
+        let mut encoder = SnapshotEncoder::with_budget(config, 2 + 2 * size);
+
+        encoder.push(&body(0, false)).unwrap();
+        encoder.push(&body(1, false)).unwrap();
+        match encoder.push(&body(2, false)) {
+            Err(ErrorKind::PayloadTooLarge(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(encoder.remaining(), 0);
+        assert_eq!(encoder.finish().len(), 2 + 2 * size);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let config = SnapshotConfig::default();
+        let mut encoder = SnapshotEncoder::new(config);
+        encoder.push(&body(0, false)).unwrap();
+        let payload = encoder.finish();
+
+        match SnapshotDecoder::new(config).decode(&payload[..payload.len() - 1]) {
+            Err(ErrorKind::DecodingError(DecodingErrorKind::Snapshot)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::errors::{DecodingErrorKind, ErrorKind, Result};
+use crate::net::constants::SNAPSHOT_HISTORY;
+use crate::snapshot::{PriorityAccumulator, QuantizedBody, RigidBodyState, SnapshotConfig};
+
+/// Snapshot containing all bodies.
+const FULL: u8 = 0;
+/// Snapshot containing the changes since a baseline.
+const DELTA: u8 = 1;
+
+/// Fields written for a changed body.
+const FLAGS: u8 = 0b0001;
+const POSITION: u8 = 0b0010;
+const ROTATION: u8 = 0b0100;
+const VELOCITY: u8 = 0b1000;
+
+/// Snapshots of a single tick, quantized and sorted by entity.
+type Entry = (u32, Vec<QuantizedBody>);
+
+/// Encodes snapshots for one peer against the latest snapshot it acknowledged.
+///
+/// The payload is `[u8 kind][u32 baseline tick if delta][u16 changed][changed body]*[u16
+/// removed][u32 entity]*`, a changed body is `[u32 entity][u8 fields][fields]`. Bodies equal to
+/// the baseline are left out, a full snapshot is a delta against nothing.
+///
+/// # Remarks
+/// - A baseline is used only while it is less than [SNAPSHOT_HISTORY] ticks old, so a peer
+///   which stopped acknowledging gets full snapshots.
+/// - Lost acks only make the encoder fall back to an older baseline.
+#[derive(Debug, Default)]
+pub struct DeltaEncoder {
+    history: VecDeque<Entry>,
+    acked: Option<u32>,
+}
+
+impl DeltaEncoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Marks the snapshot of `tick` as received by the peer.
+    pub fn ack(&mut self, tick: u32) {
+        if !matches!(self.acked, Some(acked) if acked >= tick) {
+            self.acked = Some(tick);
+        }
+    }
+
+    /// Returns the tick of the baseline a snapshot of `tick` would be encoded against.
+    pub fn baseline(&self, tick: u32) -> Option<u32> {
+        self.baseline_entry(tick).map(|(baseline, _)| *baseline)
+    }
+
+    /// Encodes the snapshot of `states` at `tick` and remembers it as a future baseline.
+    pub fn encode(&mut self, config: &SnapshotConfig, tick: u32, states: &[RigidBodyState]) -> Bytes {
+        self.encode_within(config, tick, states, None, usize::MAX)
+    }
+
+    /// Encodes the snapshot of `states` at `tick` into at most `budget` bytes, filled with the
+    /// changed bodies of the highest priority.
+    ///
+    /// # Remarks
+    /// - Bodies left out keep their baseline state on the peer, the baseline remembered for
+    ///   `tick` is what the peer decodes.
+    /// - The priority of every body the peer is up to date with afterwards is reset, the
+    ///   others keep gaining until they are sent.
+    /// - Removed bodies are always included, `budget` has to leave room for them.
+    pub fn encode_prioritized(
+        &mut self,
+        config: &SnapshotConfig,
+        tick: u32,
+        states: &[RigidBodyState],
+        priorities: &mut PriorityAccumulator,
+        budget: usize,
+    ) -> Bytes {
+        self.encode_within(config, tick, states, Some(priorities), budget)
+    }
+
+    fn encode_within(
+        &mut self,
+        config: &SnapshotConfig,
+        tick: u32,
+        states: &[RigidBodyState],
+        priorities: Option<&mut PriorityAccumulator>,
+        budget: usize,
+    ) -> Bytes {
+        let mut bodies = states.iter().map(|s| config.quantize(s)).collect::<Vec<_>>();
+        bodies.sort_by_key(|b| b.entity);
+        bodies.dedup_by_key(|b| b.entity);
+
+        let mut buffer = BytesMut::new();
+        let empty = Vec::new();
+        let baseline = match self.baseline_entry(tick) {
+            Some((baseline, bodies)) => {
+                buffer.put_u8(DELTA);
+                buffer.put_u32(*baseline);
+                bodies
+            }
+            None => {
+                buffer.put_u8(FULL);
+                &empty
+            }
+        };
+
+        let mut changed = changed_bodies(baseline, &bodies);
+        if let Some(priorities) = &priorities {
+            changed.sort_by(|(a, _), (b, _)| {
+                let (a, b) = (priorities.priority(a.entity), priorities.priority(b.entity));
+                b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+            });
+        }
+        let removed = baseline
+            .iter()
+            .filter(|old| find(&bodies, old.entity).is_none())
+            .map(|old| old.entity)
+            .collect::<Vec<_>>();
+
+        // counts of changed and removed bodies
+        let mut size = buffer.len() + 4 + 4 * removed.len();
+        let mut selected = Vec::with_capacity(changed.len());
+        for (body, fields) in changed {
+            let body_size = changed_size(config, body, fields);
+            if size + body_size <= budget {
+                size += body_size;
+                selected.push((body, fields));
+            }
+        }
+        selected.sort_by_key(|(body, _)| body.entity);
+        write_delta(config, &selected, &removed, &mut buffer);
+
+        // what the peer holds after decoding
+        let mut sent = baseline
+            .iter()
+            .filter(|old| find(&bodies, old.entity).is_some())
+            .copied()
+            .collect::<Vec<_>>();
+        for (body, _) in selected {
+            match sent.binary_search_by_key(&body.entity, |b| b.entity) {
+                Ok(i) => sent[i] = *body,
+                Err(i) => sent.insert(i, *body),
+            }
+        }
+        if let Some(priorities) = priorities {
+            for body in &sent {
+                if find(&bodies, body.entity) == Some(body) {
+                    priorities.reset(body.entity);
+                }
+            }
+        }
+
+        self.history.retain(|(t, _)| *t < tick && tick - *t < SNAPSHOT_HISTORY);
+        self.history.push_back((tick, sent));
+        buffer.freeze()
+    }
+
+    fn baseline_entry(&self, tick: u32) -> Option<&Entry> {
+        let acked = self.acked?;
+        if acked >= tick || tick - acked >= SNAPSHOT_HISTORY {
+            return None;
+        }
+        self.history.iter().find(|(t, _)| *t == acked)
+    }
+}
+
+/// Decodes snapshots created by [DeltaEncoder] and keeps them as baselines.
+///
+/// Every decoded snapshot should be acknowledged to the sender, a snapshot which fails with
+/// `DecodingError(Baseline)` or is older than the last decoded one must not.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    received: VecDeque<Entry>,
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Decodes the snapshot `payload` of `tick`, `None` when a newer one was decoded already.
+    ///
+    /// A later snapshot of the same tick replaces the earlier one as baseline, like in the
+    /// encoder.
+    pub fn decode(
+        &mut self,
+        config: &SnapshotConfig,
+        tick: u32,
+        payload: &[u8],
+    ) -> Result<Option<Vec<RigidBodyState>>> {
+        if matches!(self.received.back(), Some((latest, _)) if *latest > tick) {
+            return Ok(None);
+        }
+        let mut rdr = Cursor::new(payload);
+        let kind = rdr.read_u8().map_err(|_| snapshot_error())?;
+        let empty = Vec::new();
+        let baseline = match kind {
+            FULL => &empty,
+            DELTA => {
+                let baseline = rdr.read_u32::<BigEndian>().map_err(|_| snapshot_error())?;
+                self.received
+                    .iter()
+                    .find(|(t, _)| *t == baseline)
+                    .map(|(_, bodies)| bodies)
+                    .ok_or(ErrorKind::DecodingError(DecodingErrorKind::Baseline))?
+            }
+            _ => return Err(snapshot_error()),
+        };
+        let bodies = read_delta(config, baseline, &mut rdr).map_err(|_| snapshot_error())?;
+        let states = bodies.iter().map(|b| config.dequantize(b)).collect();
+
+        self.received
+            .retain(|(t, _)| *t != tick && tick.saturating_sub(*t) < SNAPSHOT_HISTORY);
+        self.received.push_back((tick, bodies));
+        Ok(Some(states))
+    }
+}
+
+fn snapshot_error() -> ErrorKind {
+    ErrorKind::DecodingError(DecodingErrorKind::Snapshot)
+}
+
+/// Returns the bodies which differ from `baseline` along with their changed fields.
+fn changed_bodies<'a>(baseline: &[QuantizedBody], bodies: &'a [QuantizedBody]) -> Vec<(&'a QuantizedBody, u8)> {
+    bodies
+        .iter()
+        .filter_map(|body| {
+            let fields = match find(baseline, body.entity) {
+                Some(old) => changed_fields(old, body),
+                None => FLAGS | POSITION | ROTATION | VELOCITY,
+            };
+            if fields == 0 {
+                None
+            } else {
+                Some((body, fields))
+            }
+        })
+        .collect()
+}
+
+/// Returns the encoded size of the changed `fields` of `body`.
+fn changed_size(config: &SnapshotConfig, body: &QuantizedBody, fields: u8) -> usize {
+    // entity id and fields
+    let mut size = 5;
+    if fields & FLAGS != 0 {
+        size += 1;
+    }
+    if fields & POSITION != 0 {
+        size += config.position_size();
+    }
+    if fields & ROTATION != 0 {
+        size += config.rotation_size();
+    }
+    if fields & VELOCITY != 0 && !body.is_sleeping() {
+        size += config.velocity_size();
+    }
+    size
+}
+
+fn write_delta(config: &SnapshotConfig, changed: &[(&QuantizedBody, u8)], removed: &[u32], buffer: &mut BytesMut) {
+    buffer.put_u16(changed.len() as u16);
+    for (body, fields) in changed {
+        let fields = *fields;
+        buffer.put_u32(body.entity);
+        buffer.put_u8(fields);
+        if fields & FLAGS != 0 {
+            buffer.put_u8(body.flags);
+        }
+        if fields & POSITION != 0 {
+            config.write_position(body, buffer);
+        }
+        if fields & ROTATION != 0 {
+            config.write_rotation(body, buffer);
+        }
+        if fields & VELOCITY != 0 && !body.is_sleeping() {
+            config.write_velocity(body, buffer);
+        }
+    }
+
+    buffer.put_u16(removed.len() as u16);
+    for entity in removed {
+        buffer.put_u32(*entity);
+    }
+}
+
+fn read_delta(config: &SnapshotConfig, baseline: &[QuantizedBody], rdr: &mut Cursor<&[u8]>) -> Result<Vec<QuantizedBody>> {
+    let mut bodies = baseline.to_vec();
+
+    let changed = rdr.read_u16::<BigEndian>()?;
+    for _ in 0..changed {
+        let entity = rdr.read_u32::<BigEndian>()?;
+        let fields = rdr.read_u8()?;
+        let mut body = find(baseline, entity).copied().unwrap_or(QuantizedBody {
+            entity,
+            flags: 0,
+            position: [0; 3],
+            rotation: [0; 3],
+            linear_velocity: [0; 3],
+            angular_velocity: [0; 3],
+        });
+        if fields & FLAGS != 0 {
+            body.flags = rdr.read_u8()?;
+        }
+        if fields & POSITION != 0 {
+            config.read_position(&mut body, rdr)?;
+        }
+        if fields & ROTATION != 0 {
+            config.read_rotation(&mut body, rdr)?;
+        }
+        if body.is_sleeping() {
+            body.linear_velocity = [0; 3];
+            body.angular_velocity = [0; 3];
+        } else if fields & VELOCITY != 0 {
+            config.read_velocity(&mut body, rdr)?;
+        }
+
+        match bodies.binary_search_by_key(&entity, |b| b.entity) {
+            Ok(i) => bodies[i] = body,
+            Err(i) => bodies.insert(i, body),
+        }
+    }
+
+    let removed = rdr.read_u16::<BigEndian>()?;
+    for _ in 0..removed {
+        let entity = rdr.read_u32::<BigEndian>()?;
+        if let Ok(i) = bodies.binary_search_by_key(&entity, |b| b.entity) {
+            bodies.remove(i);
+        }
+    }
+    Ok(bodies)
+}
+
+fn find(bodies: &[QuantizedBody], entity: u32) -> Option<&QuantizedBody> {
+    bodies
+        .binary_search_by_key(&entity, |b| b.entity)
+        .ok()
+        .map(|i| &bodies[i])
+}
+
+fn changed_fields(old: &QuantizedBody, new: &QuantizedBody) -> u8 {
+    let mut fields = 0;
+    if old.flags != new.flags {
+        fields |= FLAGS;
+    }
+    if old.position != new.position {
+        fields |= POSITION;
+    }
+    if old.rotation != new.rotation {
+        fields |= ROTATION;
+    }
+    if old.linear_velocity != new.linear_velocity || old.angular_velocity != new.angular_velocity {
+        fields |= VELOCITY;
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::ErrorKind;
+    use crate::errors::DecodingErrorKind;
+    use crate::net::constants::SNAPSHOT_HISTORY;
+    use crate::snapshot::{PriorityAccumulator, PriorityConfig, RigidBodyState, SnapshotConfig};
+
+    use super::{DeltaDecoder, DeltaEncoder};
+
+    fn world(count: u32, moved: f32) -> Vec<RigidBodyState> {
+        (0..count)
+            .map(|entity| RigidBodyState {
+                entity,
+                position: [entity as f32 + if entity == 0 { moved } else { 0.0 }, 0.0, 0.0],
+                linear_velocity: [1.0, 0.0, 0.0],
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn assert_world(decoded: &[RigidBodyState], expected: &[RigidBodyState]) {
+        assert_eq!(decoded.len(), expected.len());
+        for (d, e) in decoded.iter().zip(expected) {
+            assert_eq!(d.entity, e.entity);
+            assert!((d.position[0] - e.position[0]).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn full_without_baseline() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        let first = encoder.encode(&config, 1, &world(10, 0.0));
+        let second = encoder.encode(&config, 2, &world(10, 1.0));
+        assert_eq!(encoder.baseline(3), None);
+        assert_eq!(first.len(), second.len());
+
+        assert_world(&decoder.decode(&config, 2, &second).unwrap().unwrap(), &world(10, 1.0));
+    }
+
+    #[test]
+    fn delta_against_acked() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        let full = encoder.encode(&config, 1, &world(10, 0.0));
+        decoder.decode(&config, 1, &full).unwrap().unwrap();
+        encoder.ack(1);
+
+        let delta = encoder.encode(&config, 2, &world(10, 1.0));
+        assert_eq!(encoder.baseline(3), Some(1));
+        assert!(delta.len() < full.len() / 4);
+        assert_world(&decoder.decode(&config, 2, &delta).unwrap().unwrap(), &world(10, 1.0));
+    }
+
+    #[test]
+    fn spawn_and_remove() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        decoder.decode(&config, 1, &encoder.encode(&config, 1, &world(5, 0.0))).unwrap().unwrap();
+        encoder.ack(1);
+
+        let mut next = world(8, 0.0);
+        next.remove(2);
+        next[0].sleeping = true;
+        let decoded = decoder.decode(&config, 2, &encoder.encode(&config, 2, &next)).unwrap().unwrap();
+        assert_world(&decoded, &next);
+        assert!(decoded[0].sleeping);
+        assert_eq!(decoded[0].linear_velocity, [0.0; 3]);
+    }
+
+    #[test]
+    fn lost_ack_uses_older_baseline() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        decoder.decode(&config, 1, &encoder.encode(&config, 1, &world(4, 0.0))).unwrap().unwrap();
+        encoder.ack(1);
+        // the peer decodes tick 2 but its ack never arrives
+        decoder.decode(&config, 2, &encoder.encode(&config, 2, &world(4, 1.0))).unwrap().unwrap();
+
+        let third = encoder.encode(&config, 3, &world(4, 2.0));
+        assert_eq!(encoder.baseline(4), Some(1));
+        assert_world(&decoder.decode(&config, 3, &third).unwrap().unwrap(), &world(4, 2.0));
+    }
+
+    #[test]
+    fn lost_snapshot_uses_older_baseline() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        decoder.decode(&config, 1, &encoder.encode(&config, 1, &world(4, 0.0))).unwrap().unwrap();
+        encoder.ack(1);
+        // tick 2 is lost on the way
+        encoder.encode(&config, 2, &world(4, 1.0));
+
+        let third = encoder.encode(&config, 3, &world(4, 2.0));
+        assert_world(&decoder.decode(&config, 3, &third).unwrap().unwrap(), &world(4, 2.0));
+    }
+
+    #[test]
+    fn expired_baseline_falls_back_to_full() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        decoder.decode(&config, 1, &encoder.encode(&config, 1, &world(4, 0.0))).unwrap().unwrap();
+        encoder.ack(1);
+
+        let late = 1 + SNAPSHOT_HISTORY;
+        assert_eq!(encoder.baseline(late), None);
+        let mut fresh = DeltaDecoder::new();
+        let full = encoder.encode(&config, late, &world(4, 3.0));
+        assert_world(&fresh.decode(&config, late, &full).unwrap().unwrap(), &world(4, 3.0));
+    }
+
+    #[test]
+    fn late_snapshot_keeps_newer_baselines() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        decoder.decode(&config, 1, &encoder.encode(&config, 1, &world(4, 0.0))).unwrap();
+        encoder.ack(1);
+        let second = encoder.encode(&config, 2, &world(4, 1.0));
+        let third = encoder.encode(&config, 3, &world(4, 2.0));
+
+        // the second snapshot arrives after the third one
+        assert_world(&decoder.decode(&config, 3, &third).unwrap().unwrap(), &world(4, 2.0));
+        encoder.ack(3);
+        assert!(decoder.decode(&config, 2, &second).unwrap().is_none());
+
+        let fourth = encoder.encode(&config, 4, &world(4, 3.0));
+        assert_eq!(encoder.baseline(5), Some(3));
+        assert_world(&decoder.decode(&config, 4, &fourth).unwrap().unwrap(), &world(4, 3.0));
+    }
+
+    #[test]
+    fn missing_baseline_is_rejected() {
+        let config = SnapshotConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+
+        encoder.encode(&config, 1, &world(4, 0.0));
+        // an ack the decoder did not send
+        encoder.ack(1);
+        let delta = encoder.encode(&config, 2, &world(4, 1.0));
+
+        match decoder.decode(&config, 2, &delta) {
+            Err(ErrorKind::DecodingError(DecodingErrorKind::Baseline)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(decoder.decode(&config, 2, &delta[..3]).is_err());
+    }
+
+    #[test]
+    fn budget_sends_highest_priority_first() {
+        let config = SnapshotConfig::default();
+        let priority_config = PriorityConfig::default();
+        let mut encoder = DeltaEncoder::new();
+        let mut decoder = DeltaDecoder::new();
+        let mut priorities = PriorityAccumulator::new();
+        priorities.set_focus(Some([0.0; 3]));
+
+        // the nearest bodies have the highest entity ids
+        let mut states = world(200, 0.0);
+        for state in &mut states {
+            state.position[0] = 400.0 - state.entity as f32;
+        }
+        let budget = 500;
+        priorities.accumulate(&priority_config, &states, Duration::from_millis(16));
+        let first = encoder.encode_prioritized(&config, 1, &states, &mut priorities, budget);
+        assert!(first.len() <= budget);
+        let decoded = decoder.decode(&config, 1, &first).unwrap().unwrap();
+        assert!(!decoded.is_empty() && decoded.len() < states.len());
+        assert!(decoded.iter().all(|body| body.entity >= 200 - decoded.len() as u32));
+        assert_eq!(priorities.priority(199), 0.0);
+        assert!(priorities.priority(0) > 0.0);
+
+        // unsent bodies follow until the peer has all of them
+        let mut tick = 1;
+        let mut received = decoded.len();
+        while received < states.len() {
+            assert!(tick < 100);
+            encoder.ack(tick);
+            tick += 1;
+            priorities.accumulate(&priority_config, &states, Duration::from_millis(16));
+            let payload = encoder.encode_prioritized(&config, tick, &states, &mut priorities, budget);
+            assert!(payload.len() <= budget);
+            received = decoder.decode(&config, tick, &payload).unwrap().unwrap().len();
+        }
+        encoder.ack(tick);
+        // kind, baseline and both counts
+        assert_eq!(encoder.encode(&config, tick + 1, &states).len(), 9);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::net::constants::{
+    DEFAULT_INTERPOLATION_DELAY, INTERPOLATION_BUFFER_SIZE, MAX_EXTRAPOLATION,
+};
+use crate::snapshot::RigidBodyState;
+
+/// Plays received snapshots back a fixed delay behind the sender.
+///
+/// Snapshots are stamped with the sender's time. A sample at time `t` shows the world at
+/// `t - delay`, interpolated between the two snapshots around it, so a late or lost snapshot
+/// goes unnoticed as long as the next one arrives within the delay.
+///
+/// # Remarks
+/// - When no snapshot is newer than the playback time the bodies are moved along their
+///   velocities, for at most `max_extrapolation`.
+/// - Bodies are matched by entity, a body missing in the newer snapshot stays where it was, a
+///   body missing in the older one shows up once its snapshot is reached.
+#[derive(Clone, Debug)]
+pub struct InterpolationBuffer {
+    delay: Duration,
+    max_extrapolation: Duration,
+    /// Snapshots sorted by time, the states of each sorted by entity.
+    snapshots: VecDeque<(Duration, Vec<RigidBodyState>)>,
+}
+
+impl InterpolationBuffer {
+    pub fn new(delay: Duration) -> Self {
+        InterpolationBuffer {
+            delay,
+            max_extrapolation: MAX_EXTRAPOLATION,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    pub fn set_max_extrapolation(&mut self, max: Duration) {
+        self.max_extrapolation = max;
+    }
+
+    /// Returns the number of buffered snapshots.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Stores the snapshot of `states` taken at sender time `time`, duplicates are ignored.
+    pub fn push(&mut self, time: Duration, mut states: Vec<RigidBodyState>) {
+        states.sort_by_key(|s| s.entity);
+        let index = match self.snapshots.binary_search_by_key(&time, |(t, _)| *t) {
+            Ok(_) => return,
+            Err(index) => index,
+        };
+        self.snapshots.insert(index, (time, states));
+        if self.snapshots.len() > INTERPOLATION_BUFFER_SIZE {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Returns the world as seen at sender time `now` minus the delay.
+    ///
+    /// Snapshots no longer needed for playback are dropped.
+    pub fn sample(&mut self, now: Duration) -> Vec<RigidBodyState> {
+        let playback = now.checked_sub(self.delay).unwrap_or_default();
+
+        // keep the newest snapshot not after the playback time
+        while self.snapshots.len() > 1 && self.snapshots[1].0 <= playback {
+            self.snapshots.pop_front();
+        }
+
+        match (self.snapshots.front(), self.snapshots.get(1)) {
+            (None, _) => Vec::new(),
+            (Some((from_time, from)), Some((to_time, to))) if playback >= *from_time => {
+                let alpha = (playback - *from_time).as_secs_f32()
+                    / (*to_time - *from_time).as_secs_f32();
+                from.iter()
+                    .map(|a| match to.binary_search_by_key(&a.entity, |b| b.entity) {
+                        Ok(i) => interpolate(a, &to[i], alpha),
+                        Err(_) => *a,
+                    })
+                    .collect()
+            }
+            (Some((time, states)), None) if playback > *time => {
+                let ahead = (playback - *time).min(self.max_extrapolation);
+                states.iter().map(|s| extrapolate(s, ahead.as_secs_f32())).collect()
+            }
+            // playback has not reached the oldest snapshot yet
+            (Some((_, states)), _) => states.clone(),
+        }
+    }
+}
+
+impl Default for InterpolationBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERPOLATION_DELAY)
+    }
+}
+
+fn interpolate(a: &RigidBodyState, b: &RigidBodyState, alpha: f32) -> RigidBodyState {
+    RigidBodyState {
+        entity: a.entity,
+        position: lerp(a.position, b.position, alpha),
+        rotation: slerp(a.rotation, b.rotation, alpha),
+        linear_velocity: lerp(a.linear_velocity, b.linear_velocity, alpha),
+        angular_velocity: lerp(a.angular_velocity, b.angular_velocity, alpha),
+        sleeping: a.sleeping && b.sleeping,
+    }
+}
+
+fn extrapolate(state: &RigidBodyState, dt: f32) -> RigidBodyState {
+    if state.sleeping {
+        return *state;
+    }
+    let mut moved = *state;
+    for (p, v) in moved.position.iter_mut().zip(state.linear_velocity.iter()) {
+        *p += v * dt;
+    }
+    moved.rotation = integrate(state.rotation, state.angular_velocity, dt);
+    moved
+}
+
+fn lerp(a: [f32; 3], b: [f32; 3], alpha: f32) -> [f32; 3] {
+    [
+        a[0] + (b[0] - a[0]) * alpha,
+        a[1] + (b[1] - a[1]) * alpha,
+        a[2] + (b[2] - a[2]) * alpha,
+    ]
+}
+
+/// Spherical interpolation of unit quaternions along the shorter arc.
+fn slerp(a: [f32; 4], b: [f32; 4], alpha: f32) -> [f32; 4] {
+    let mut dot = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
+    let b = if dot < 0.0 {
+        dot = -dot;
+        [-b[0], -b[1], -b[2], -b[3]]
+    } else {
+        b
+    };
+
+    let (wa, wb) = if dot > 0.9995 {
+        // nearly the same rotation, a normalized lerp is exact enough
+        (1.0 - alpha, alpha)
+    } else {
+        let theta = dot.acos();
+        let sin = theta.sin();
+        (((1.0 - alpha) * theta).sin() / sin, (alpha * theta).sin() / sin)
+    };
+    normalize([
+        a[0] * wa + b[0] * wb,
+        a[1] * wa + b[1] * wb,
+        a[2] * wa + b[2] * wb,
+        a[3] * wa + b[3] * wb,
+    ])
+}
+
+/// Rotates `q` by the world space angular velocity `omega` for `dt` seconds.
+fn integrate(q: [f32; 4], omega: [f32; 3], dt: f32) -> [f32; 4] {
+    let speed = omega.iter().map(|w| w * w).sum::<f32>().sqrt();
+    if speed * dt <= f32::EPSILON {
+        return q;
+    }
+    let half = speed * dt / 2.0;
+    let s = half.sin() / speed;
+    let d = [omega[0] * s, omega[1] * s, omega[2] * s, half.cos()];
+    // d * q
+    normalize([
+        d[3] * q[0] + d[0] * q[3] + d[1] * q[2] - d[2] * q[1],
+        d[3] * q[1] - d[0] * q[2] + d[1] * q[3] + d[2] * q[0],
+        d[3] * q[2] + d[0] * q[1] - d[1] * q[0] + d[2] * q[3],
+        d[3] * q[3] - d[0] * q[0] - d[1] * q[1] - d[2] * q[2],
+    ])
+}
+
+fn normalize(q: [f32; 4]) -> [f32; 4] {
+    let length = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+    [q[0] / length, q[1] / length, q[2] / length, q[3] / length]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2};
+    use std::time::Duration;
+
+    use crate::snapshot::RigidBodyState;
+
+    use super::{integrate, slerp, InterpolationBuffer};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn at(x: f32, velocity: f32) -> Vec<RigidBodyState> {
+        vec![RigidBodyState {
+            entity: 1,
+            position: [x, 0.0, 0.0],
+            linear_velocity: [velocity, 0.0, 0.0],
+            ..Default::default()
+        }]
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn interpolates_with_delay() {
+        let mut buffer = InterpolationBuffer::new(ms(100));
+        buffer.push(ms(1000), at(0.0, 10.0));
+        buffer.push(ms(1100), at(1.0, 10.0));
+
+        assert_close(buffer.sample(ms(1100))[0].position[0], 0.0);
+        assert_close(buffer.sample(ms(1125))[0].position[0], 0.25);
+        assert_close(buffer.sample(ms(1200))[0].position[0], 1.0);
+    }
+
+    #[test]
+    fn accepts_out_of_order() {
+        let mut buffer = InterpolationBuffer::new(ms(0));
+        buffer.push(ms(200), at(2.0, 0.0));
+        buffer.push(ms(100), at(1.0, 0.0));
+        buffer.push(ms(100), at(5.0, 0.0));
+
+        assert_eq!(buffer.len(), 2);
+        assert_close(buffer.sample(ms(150))[0].position[0], 1.5);
+    }
+
+    #[test]
+    fn extrapolates_briefly() {
+        let mut buffer = InterpolationBuffer::new(ms(0));
+        buffer.set_max_extrapolation(ms(200));
+        buffer.push(ms(0), at(0.0, 10.0));
+        buffer.push(ms(100), at(1.0, 10.0));
+
+        assert_close(buffer.sample(ms(150))[0].position[0], 1.5);
+        assert_close(buffer.sample(ms(1000))[0].position[0], 3.0);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn holds_oldest_before_playback() {
+        let mut buffer = InterpolationBuffer::new(ms(100));
+        assert!(buffer.sample(ms(0)).is_empty());
+
+        buffer.push(ms(500), at(4.0, 10.0));
+        assert_close(buffer.sample(ms(100))[0].position[0], 4.0);
+    }
+
+    #[test]
+    fn slerp_takes_shorter_arc() {
+        let identity = [0.0, 0.0, 0.0, 1.0];
+        let quarter = [0.0, 0.0, FRAC_1_SQRT_2, FRAC_1_SQRT_2];
+        let half_way = slerp(identity, quarter, 0.5);
+        assert_close(half_way[2], (FRAC_PI_2 / 4.0).sin());
+        assert_close(half_way[3], (FRAC_PI_2 / 4.0).cos());
+
+        let flipped = [0.0, 0.0, -FRAC_1_SQRT_2, -FRAC_1_SQRT_2];
+        assert_eq!(slerp(identity, flipped, 0.5), half_way);
+    }
+
+    #[test]
+    fn integrates_angular_velocity() {
+        let rotated = integrate([0.0, 0.0, 0.0, 1.0], [0.0, 0.0, FRAC_PI_2], 1.0);
+        assert_close(rotated[2], FRAC_1_SQRT_2);
+        assert_close(rotated[3], FRAC_1_SQRT_2);
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::snapshot::RigidBodyState;
+
+/// Priority a body gains per second, the more the sooner it is sent when not all bodies fit
+/// into a packet.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PriorityConfig {
+    /// Gain of a body at rest at the focus.
+    pub base: f32,
+    /// Additional gain per unit of linear speed.
+    pub velocity: f32,
+    /// Distance from the focus at which the gain is halved.
+    pub falloff: f32,
+    /// Factor applied to the gain of sleeping bodies.
+    pub sleeping: f32,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        PriorityConfig {
+            base: 1.0,
+            velocity: 0.25,
+            falloff: 32.0,
+            sleeping: 0.1,
+        }
+    }
+}
+
+impl PriorityConfig {
+    /// Returns the priority `state` gains per second for a peer looking from `focus`.
+    pub fn gain(&self, state: &RigidBodyState, focus: Option<[f32; 3]>) -> f32 {
+        let speed = length(state.linear_velocity);
+        let mut gain = self.base + self.velocity * speed;
+        if let Some(focus) = focus {
+            let offset = [
+                state.position[0] - focus[0],
+                state.position[1] - focus[1],
+                state.position[2] - focus[2],
+            ];
+            gain *= self.falloff / (self.falloff + length(offset));
+        }
+        if state.sleeping {
+            gain *= self.sleeping;
+        }
+        gain
+    }
+}
+
+/// Priorities of the bodies sent to one peer.
+///
+/// Every body gains priority while it is not sent, so bodies which lose against nearer or
+/// faster ones are still sent eventually.
+#[derive(Clone, Debug, Default)]
+pub struct PriorityAccumulator {
+    priorities: HashMap<u32, f32>,
+    focus: Option<[f32; 3]>,
+}
+
+impl PriorityAccumulator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the position the peer looks from, bodies near it gain priority faster.
+    pub fn set_focus(&mut self, focus: Option<[f32; 3]>) {
+        self.focus = focus;
+    }
+
+    pub fn focus(&self) -> Option<[f32; 3]> {
+        self.focus
+    }
+
+    /// Adds the priority `states` gained during `elapsed` and forgets bodies not among them.
+    pub fn accumulate(&mut self, config: &PriorityConfig, states: &[RigidBodyState], elapsed: Duration) {
+        let mut priorities = HashMap::with_capacity(states.len());
+        for state in states {
+            let old = self.priorities.get(&state.entity).copied().unwrap_or_default();
+            let gain = config.gain(state, self.focus) * elapsed.as_secs_f32();
+            priorities.insert(state.entity, old + gain);
+        }
+        self.priorities = priorities;
+    }
+
+    /// Returns the accumulated priority of `entity`.
+    pub fn priority(&self, entity: u32) -> f32 {
+        self.priorities.get(&entity).copied().unwrap_or_default()
+    }
+
+    /// Resets the priority of a body the peer is up to date with.
+    pub fn reset(&mut self, entity: u32) {
+        if let Some(priority) = self.priorities.get_mut(&entity) {
+            *priority = 0.0;
+        }
+    }
+}
+
+fn length(v: [f32; 3]) -> f32 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::snapshot::RigidBodyState;
+
+    use super::{PriorityAccumulator, PriorityConfig};
+
+    fn body(entity: u32, x: f32, speed: f32) -> RigidBodyState {
+        RigidBodyState {
+            entity,
+            position: [x, 0.0, 0.0],
+            linear_velocity: [speed, 0.0, 0.0],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn near_and_fast_bodies_gain_faster() {
+        let config = PriorityConfig::default();
+        let mut priorities = PriorityAccumulator::new();
+        priorities.set_focus(Some([0.0; 3]));
+        let states = [body(0, 1.0, 0.0), body(1, 100.0, 0.0), body(2, 1.0, 10.0)];
+
+        priorities.accumulate(&config, &states, Duration::from_millis(100));
+        assert!(priorities.priority(0) > priorities.priority(1));
+        assert!(priorities.priority(2) > priorities.priority(0));
+
+        let sleeping = RigidBodyState { sleeping: true, ..body(3, 1.0, 0.0) };
+        assert!(config.gain(&sleeping, None) < config.gain(&states[0], None));
+    }
+
+    #[test]
+    fn unsent_bodies_keep_gaining() {
+        let config = PriorityConfig::default();
+        let mut priorities = PriorityAccumulator::new();
+        let states = [body(0, 0.0, 0.0), body(1, 0.0, 0.0)];
+
+        priorities.accumulate(&config, &states, Duration::from_secs(1));
+        priorities.reset(0);
+        priorities.accumulate(&config, &states, Duration::from_secs(1));
+        assert_eq!(priorities.priority(0), 1.0);
+        assert_eq!(priorities.priority(1), 2.0);
+
+        priorities.accumulate(&config, &states[..1], Duration::from_secs(1));
+        assert_eq!(priorities.priority(1), 0.0);
+    }
+}
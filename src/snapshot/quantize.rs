@@ -0,0 +1,115 @@
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::BufMut;
+
+use crate::errors::Result;
+
+/// Maps floats within `min..=max` to integers of `bits` bits.
+///
+/// # Remarks
+/// - Values outside of the range are clamped.
+/// - Quantized values are written in as few whole bytes as `bits` allow.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct QuantizedRange {
+    min: f32,
+    max: f32,
+    bits: u8,
+}
+
+impl QuantizedRange {
+    /// Constructs a range, `bits` is limited to `1..=32`.
+    ///
+    /// # Panics
+    /// - When `min` is not smaller than `max`.
+    pub fn new(min: f32, max: f32, bits: u8) -> Self {
+        assert!(min < max, "empty quantization range {}..{}", min, max);
+        QuantizedRange {
+            min,
+            max,
+            bits: bits.clamp(1, 32),
+        }
+    }
+
+    /// Returns the number of bits of a quantized value.
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    /// Returns the number of bytes a quantized value is written in.
+    pub fn bytes(&self) -> usize {
+        (self.bits as usize).div_ceil(8)
+    }
+
+    /// Returns the distance between two neighbouring quantized values.
+    pub fn precision(&self) -> f32 {
+        (self.max - self.min) / self.steps() as f32
+    }
+
+    pub fn quantize(&self, value: f32) -> u32 {
+        let clamped = value.clamp(self.min, self.max);
+        let normalized = f64::from(clamped - self.min) / f64::from(self.max - self.min);
+        (normalized * f64::from(self.steps())).round() as u32
+    }
+
+    pub fn dequantize(&self, value: u32) -> f32 {
+        let normalized = f64::from(value.min(self.steps())) / f64::from(self.steps());
+        (f64::from(self.min) + normalized * f64::from(self.max - self.min)) as f32
+    }
+
+    /// Quantizes `value` and writes it to `buffer`.
+    pub fn write<B: BufMut>(&self, value: f32, buffer: &mut B) {
+        buffer.put_uint(u64::from(self.quantize(value)), self.bytes());
+    }
+
+    /// Reads a quantized value from `rdr`.
+    pub fn read(&self, rdr: &mut Cursor<&[u8]>) -> Result<f32> {
+        let value = rdr.read_uint::<BigEndian>(self.bytes())?;
+        Ok(self.dequantize(value as u32))
+    }
+
+    fn steps(&self) -> u32 {
+        (u64::MAX >> (64 - u32::from(self.bits))) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::QuantizedRange;
+
+    #[test]
+    fn round_trip_within_precision() {
+        let range = QuantizedRange::new(-100.0, 100.0, 16);
+
+        assert_eq!(range.bytes(), 2);
+        for &value in &[-100.0, -33.3, 0.0, 0.001, 42.42, 100.0] {
+            let restored = range.dequantize(range.quantize(value));
+            assert!((restored - value).abs() <= range.precision() / 2.0 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn clamps_out_of_range() {
+        let range = QuantizedRange::new(0.0, 1.0, 8);
+
+        assert_eq!(range.quantize(-5.0), 0);
+        assert_eq!(range.quantize(5.0), 255);
+        assert_eq!(range.dequantize(1000), 1.0);
+    }
+
+    #[test]
+    fn writes_whole_bytes() {
+        let range = QuantizedRange::new(-1.0, 1.0, 20);
+        let mut buffer = Vec::new();
+        range.write(0.5, &mut buffer);
+        range.write(-0.25, &mut buffer);
+
+        assert_eq!(buffer.len(), 6);
+        let mut rdr = Cursor::new(&buffer[..]);
+        assert!((range.read(&mut rdr).unwrap() - 0.5).abs() <= range.precision());
+        assert!((range.read(&mut rdr).unwrap() + 0.25).abs() <= range.precision());
+        assert!(range.read(&mut rdr).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::BufMut;
+
+use crate::errors::Result;
+use crate::snapshot::QuantizedRange;
+
+/// Flag of a sleeping body, sleeping bodies are sent without velocities.
+const SLEEPING: u8 = 0b100;
+/// Bits of the flags holding the index of the rotation component left out.
+const LARGEST_MASK: u8 = 0b11;
+
+/// State of a single rigid body.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RigidBodyState {
+    pub entity: u32,
+    pub position: [f32; 3],
+    /// Unit quaternion as `[x, y, z, w]`.
+    pub rotation: [f32; 4],
+    pub linear_velocity: [f32; 3],
+    pub angular_velocity: [f32; 3],
+    pub sleeping: bool,
+}
+
+impl Default for RigidBodyState {
+    fn default() -> Self {
+        RigidBodyState {
+            entity: 0,
+            position: [0.0; 3],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            linear_velocity: [0.0; 3],
+            angular_velocity: [0.0; 3],
+            sleeping: false,
+        }
+    }
+}
+
+/// Quantization of the rigid body fields.
+///
+/// Rotations are sent as the three smallest quaternion components, each within
+/// `±1/√2` in `rotation_bits` bits.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    pub position: QuantizedRange,
+    pub linear_velocity: QuantizedRange,
+    pub angular_velocity: QuantizedRange,
+    pub rotation_bits: u8,
+}
+
+impl Default for SnapshotConfig {
+    /// A 1km cube around the origin in 24 bits per axis, velocities up to 64m/s and 32rad/s.
+    fn default() -> Self {
+        SnapshotConfig {
+            position: QuantizedRange::new(-512.0, 512.0, 24),
+            linear_velocity: QuantizedRange::new(-64.0, 64.0, 16),
+            angular_velocity: QuantizedRange::new(-32.0, 32.0, 16),
+            rotation_bits: 16,
+        }
+    }
+}
+
+/// A rigid body state as sent over the wire.
+///
+/// Both ends compare quantized values, so deltas never depend on float rounding.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QuantizedBody {
+    pub entity: u32,
+    /// Sleeping flag and index of the rotation component left out.
+    pub flags: u8,
+    pub position: [u32; 3],
+    pub rotation: [u32; 3],
+    pub linear_velocity: [u32; 3],
+    pub angular_velocity: [u32; 3],
+}
+
+impl QuantizedBody {
+    pub fn is_sleeping(&self) -> bool {
+        self.flags & SLEEPING != 0
+    }
+}
+
+impl SnapshotConfig {
+    /// Returns the encoded size of `state`.
+    pub fn encoded_size(&self, state: &RigidBodyState) -> usize {
+        // entity id and flags
+        5 + self.fields_size(state.sleeping)
+    }
+
+    /// Returns the encoded size of position, rotation and velocities.
+    pub(crate) fn fields_size(&self, sleeping: bool) -> usize {
+        let mut size = self.position_size() + self.rotation_size();
+        if !sleeping {
+            size += self.velocity_size();
+        }
+        size
+    }
+
+    pub(crate) fn position_size(&self) -> usize {
+        3 * self.position.bytes()
+    }
+
+    pub(crate) fn rotation_size(&self) -> usize {
+        3 * self.rotation().bytes()
+    }
+
+    pub(crate) fn velocity_size(&self) -> usize {
+        3 * (self.linear_velocity.bytes() + self.angular_velocity.bytes())
+    }
+
+    pub fn quantize(&self, state: &RigidBodyState) -> QuantizedBody {
+        let (largest, smallest) = smallest_three(state.rotation);
+        let sleeping = if state.sleeping { SLEEPING } else { 0 };
+        let rotation = self.rotation();
+        let mut body = QuantizedBody {
+            entity: state.entity,
+            flags: largest | sleeping,
+            position: [0; 3],
+            rotation: [0; 3],
+            linear_velocity: [0; 3],
+            angular_velocity: [0; 3],
+        };
+        for (i, &c) in smallest.iter().enumerate() {
+            body.position[i] = self.position.quantize(state.position[i]);
+            body.rotation[i] = rotation.quantize(c);
+            if !state.sleeping {
+                body.linear_velocity[i] = self.linear_velocity.quantize(state.linear_velocity[i]);
+                body.angular_velocity[i] =
+                    self.angular_velocity.quantize(state.angular_velocity[i]);
+            }
+        }
+        body
+    }
+
+    pub fn dequantize(&self, body: &QuantizedBody) -> RigidBodyState {
+        let rotation = self.rotation();
+        let mut state = RigidBodyState {
+            entity: body.entity,
+            sleeping: body.is_sleeping(),
+            ..Default::default()
+        };
+        let mut smallest = [0.0; 3];
+        for (i, c) in smallest.iter_mut().enumerate() {
+            state.position[i] = self.position.dequantize(body.position[i]);
+            *c = rotation.dequantize(body.rotation[i]);
+            if !state.sleeping {
+                state.linear_velocity[i] = self.linear_velocity.dequantize(body.linear_velocity[i]);
+                state.angular_velocity[i] =
+                    self.angular_velocity.dequantize(body.angular_velocity[i]);
+            }
+        }
+        state.rotation = from_smallest_three(body.flags & LARGEST_MASK, smallest);
+        state
+    }
+
+    /// Writes `state` to `buffer`.
+    pub fn write<B: BufMut>(&self, state: &RigidBodyState, buffer: &mut B) {
+        self.write_quantized(&self.quantize(state), buffer);
+    }
+
+    /// Reads a state written by [write](#method.write).
+    pub fn read(&self, rdr: &mut Cursor<&[u8]>) -> Result<RigidBodyState> {
+        Ok(self.dequantize(&self.read_quantized(rdr)?))
+    }
+
+    pub(crate) fn write_quantized<B: BufMut>(&self, body: &QuantizedBody, buffer: &mut B) {
+        buffer.put_u32(body.entity);
+        buffer.put_u8(body.flags);
+        self.write_position(body, buffer);
+        self.write_rotation(body, buffer);
+        if !body.is_sleeping() {
+            self.write_velocity(body, buffer);
+        }
+    }
+
+    pub(crate) fn read_quantized(&self, rdr: &mut Cursor<&[u8]>) -> Result<QuantizedBody> {
+        let mut body = QuantizedBody {
+            entity: rdr.read_u32::<BigEndian>()?,
+            flags: rdr.read_u8()?,
+            position: [0; 3],
+            rotation: [0; 3],
+            linear_velocity: [0; 3],
+            angular_velocity: [0; 3],
+        };
+        self.read_position(&mut body, rdr)?;
+        self.read_rotation(&mut body, rdr)?;
+        if !body.is_sleeping() {
+            self.read_velocity(&mut body, rdr)?;
+        }
+        Ok(body)
+    }
+
+    pub(crate) fn write_position<B: BufMut>(&self, body: &QuantizedBody, buffer: &mut B) {
+        for &p in &body.position {
+            buffer.put_uint(u64::from(p), self.position.bytes());
+        }
+    }
+
+    pub(crate) fn write_rotation<B: BufMut>(&self, body: &QuantizedBody, buffer: &mut B) {
+        for &c in &body.rotation {
+            buffer.put_uint(u64::from(c), self.rotation().bytes());
+        }
+    }
+
+    pub(crate) fn write_velocity<B: BufMut>(&self, body: &QuantizedBody, buffer: &mut B) {
+        for &v in &body.linear_velocity {
+            buffer.put_uint(u64::from(v), self.linear_velocity.bytes());
+        }
+        for &v in &body.angular_velocity {
+            buffer.put_uint(u64::from(v), self.angular_velocity.bytes());
+        }
+    }
+
+    pub(crate) fn read_position(&self, body: &mut QuantizedBody, rdr: &mut Cursor<&[u8]>) -> Result<()> {
+        for p in body.position.iter_mut() {
+            *p = rdr.read_uint::<BigEndian>(self.position.bytes())? as u32;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_rotation(&self, body: &mut QuantizedBody, rdr: &mut Cursor<&[u8]>) -> Result<()> {
+        for c in body.rotation.iter_mut() {
+            *c = rdr.read_uint::<BigEndian>(self.rotation().bytes())? as u32;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_velocity(&self, body: &mut QuantizedBody, rdr: &mut Cursor<&[u8]>) -> Result<()> {
+        for v in body.linear_velocity.iter_mut() {
+            *v = rdr.read_uint::<BigEndian>(self.linear_velocity.bytes())? as u32;
+        }
+        for v in body.angular_velocity.iter_mut() {
+            *v = rdr.read_uint::<BigEndian>(self.angular_velocity.bytes())? as u32;
+        }
+        Ok(())
+    }
+
+    fn rotation(&self) -> QuantizedRange {
+        QuantizedRange::new(-FRAC_1_SQRT_2, FRAC_1_SQRT_2, self.rotation_bits)
+    }
+}
+
+/// Splits a quaternion into the index of its largest component and the other three, flipped
+/// so the largest is positive.
+///
+/// Quaternions which cannot be normalized, zero length or not finite, are sent as the identity.
+fn smallest_three(rotation: [f32; 4]) -> (u8, [f32; 3]) {
+    let length = rotation.iter().map(|c| c * c).sum::<f32>().sqrt();
+    let q = if length.is_finite() && length > 0.0 {
+        [
+            rotation[0] / length,
+            rotation[1] / length,
+            rotation[2] / length,
+            rotation[3] / length,
+        ]
+    } else {
+        [0.0, 0.0, 0.0, 1.0]
+    };
+
+    let largest = (0..4)
+        .max_by(|&a, &b| q[a].abs().total_cmp(&q[b].abs()))
+        .unwrap();
+    let sign = if q[largest] < 0.0 { -1.0 } else { 1.0 };
+
+    let mut smallest = [0.0; 3];
+    for (c, &value) in smallest
+        .iter_mut()
+        .zip(q.iter().enumerate().filter(|(i, _)| *i != largest).map(|(_, v)| v))
+    {
+        *c = value * sign;
+    }
+    (largest as u8, smallest)
+}
+
+/// Restores a quaternion from [smallest_three].
+fn from_smallest_three(largest: u8, smallest: [f32; 3]) -> [f32; 4] {
+    let sum = smallest.iter().map(|c| c * c).sum::<f32>();
+    let mut components = smallest.iter();
+
+    let mut q = [0.0; 4];
+    for (i, c) in q.iter_mut().enumerate() {
+        *c = if i == largest as usize {
+            (1.0 - sum).max(0.0).sqrt()
+        } else {
+            *components.next().unwrap()
+        };
+    }
+    q
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{from_smallest_three, smallest_three, RigidBodyState, SnapshotConfig};
+
+    fn assert_same_rotation(a: [f32; 4], b: [f32; 4], epsilon: f32) {
+        // q and -q are the same rotation
+        let dot = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>();
+        assert!(1.0 - dot.abs() < epsilon, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn smallest_three_round_trip() {
+        let half = 0.5_f32;
+        for &q in &[
+            [0.0, 0.0, 0.0, 1.0],
+            [half, -half, half, -half],
+            [0.1, -0.9, 0.3, 0.2],
+            [0.0, 0.0, -1.0, 0.0],
+        ] {
+            let (largest, smallest) = smallest_three(q);
+            let length = q.iter().map(|c| c * c).sum::<f32>().sqrt();
+            let normalized = [q[0] / length, q[1] / length, q[2] / length, q[3] / length];
+            assert_same_rotation(normalized, from_smallest_three(largest, smallest), 1e-6);
+        }
+    }
+
+    #[test]
+    fn invalid_rotation_is_identity() {
+        let identity = [0.0, 0.0, 0.0, 1.0];
+        for &q in &[
+            [f32::NAN, 0.0, 0.0, 1.0],
+            [0.0, f32::INFINITY, 0.0, 0.0],
+            [f32::NEG_INFINITY, 0.0, f32::INFINITY, 0.5],
+            [0.0; 4],
+        ] {
+            let (largest, smallest) = smallest_three(q);
+            assert_eq!(from_smallest_three(largest, smallest), identity);
+        }
+
+        let config = SnapshotConfig::default();
+        let state = RigidBodyState {
+            rotation: [f32::NAN; 4],
+            ..Default::default()
+        };
+        let mut buffer = Vec::new();
+        config.write(&state, &mut buffer);
+        let restored = config.read(&mut Cursor::new(&buffer[..])).unwrap();
+        assert_same_rotation(identity, restored.rotation, 1e-4);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let config = SnapshotConfig::default();
+        let state = RigidBodyState {
+            entity: 7,
+            position: [12.5, -3.25, 400.0],
+            rotation: [0.1, -0.9, 0.3, 0.2],
+            linear_velocity: [1.0, -2.0, 30.0],
+            angular_velocity: [0.5, 0.0, -3.0],
+            sleeping: false,
+        };
+
+        let mut buffer = Vec::new();
+        config.write(&state, &mut buffer);
+        assert_eq!(buffer.len(), config.encoded_size(&state));
+
+        let restored = config.read(&mut Cursor::new(&buffer[..])).unwrap();
+        assert_eq!(restored.entity, 7);
+        assert!(!restored.sleeping);
+        for i in 0..3 {
+            assert!((restored.position[i] - state.position[i]).abs() <= config.position.precision());
+            assert!(
+                (restored.linear_velocity[i] - state.linear_velocity[i]).abs()
+                    <= config.linear_velocity.precision()
+            );
+            assert!(
+                (restored.angular_velocity[i] - state.angular_velocity[i]).abs()
+                    <= config.angular_velocity.precision()
+            );
+        }
+        let length = state.rotation.iter().map(|c| c * c).sum::<f32>().sqrt();
+        let normalized = [
+            state.rotation[0] / length,
+            state.rotation[1] / length,
+            state.rotation[2] / length,
+            state.rotation[3] / length,
+        ];
+        assert_same_rotation(normalized, restored.rotation, 1e-4);
+    }
+
+    #[test]
+    fn sleeping_omits_velocities() {
+        let config = SnapshotConfig::default();
+        let state = RigidBodyState {
+            entity: 1,
+            linear_velocity: [5.0; 3],
+            sleeping: true,
+            ..Default::default()
+        };
+
+        let mut buffer = Vec::new();
+        config.write(&state, &mut buffer);
+        assert_eq!(buffer.len(), 5 + 9 + 6);
+
+        let restored = config.read(&mut Cursor::new(&buffer[..])).unwrap();
+        assert!(restored.sleeping);
+        assert_eq!(restored.linear_velocity, [0.0; 3]);
+    }
+}